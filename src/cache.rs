@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Local, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::Instance;
+use crate::profile::{get_config_dir, Cloud};
+
+/// How long fetched instance descriptions are reused before hitting the cloud API
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// How long a running instance's cached description is reused to connect to it
+pub const DEFAULT_ADDRESS_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    fetched_at: i64,
+    instances: Vec<Instance>,
+    /// Set once the instances were modified, kept only for offline viewing
+    #[serde(default)]
+    stale: bool,
+}
+
+/// On-disk cache of instance descriptions, keyed by cloud, profile and instance
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct DescriptionCache {
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl DescriptionCache {
+    /// The key for a single instance, or for a whole listing if `instance_id` is None
+    pub fn key(cloud: &Cloud, profile: &str, instance_id: Option<&str>) -> String {
+        format!("{}:{}:{}", cloud, profile, instance_id.unwrap_or("*"))
+    }
+
+    /// Load the cache, treating a missing or unreadable file as empty
+    pub fn load() -> Self {
+        get_cache_path()
+            .ok()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|raw| serde_json::from_slice(&raw).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = get_cache_path()?;
+        std::fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    /// Get cached instances younger than `ttl`
+    pub fn get(&self, key: &str, ttl: Duration) -> Option<&Vec<Instance>> {
+        let entry = self.entries.get(key).filter(|entry| !entry.stale)?;
+        let age = Utc::now().timestamp() - entry.fetched_at;
+        match age >= 0 && (age as u64) < ttl.as_secs() {
+            true => Some(&entry.instances),
+            false => None,
+        }
+    }
+
+    /// Get the last fetched instances regardless of age, with the time they were fetched
+    pub fn get_last(&self, key: &str) -> Option<(DateTime<Local>, &Vec<Instance>)> {
+        let entry = self.entries.get(key)?;
+        let fetched_at = Local.timestamp_opt(entry.fetched_at, 0).single()?;
+        Some((fetched_at, &entry.instances))
+    }
+
+    pub fn put(&mut self, key: String, instances: Vec<Instance>) {
+        let entry = CacheEntry {
+            fetched_at: Utc::now().timestamp(),
+            instances,
+            stale: false,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    /// Mark an instance and its profile's listing stale after it was modified
+    pub fn invalidate(cloud: &Cloud, profile: &str, instance_id: &str) -> Result<()> {
+        let mut cache = Self::load();
+        let keys = [
+            Self::key(cloud, profile, Some(instance_id)),
+            Self::key(cloud, profile, None),
+        ];
+        for key in keys.iter() {
+            if let Some(entry) = cache.entries.get_mut(key) {
+                entry.stale = true;
+            }
+        }
+        cache.save()
+    }
+}
+
+fn get_cache_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("cache.json"))
+}
@@ -1,188 +1,4548 @@
-use std::string::ToString;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{Error, Result};
 use async_trait::async_trait;
-use rusoto_core::{HttpClient, Region};
-use rusoto_credential::{ChainProvider, ProfileProvider};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use log::{debug, info};
+use rusoto_cloudwatch::{CloudWatch, CloudWatchClient, Dimension, GetMetricStatisticsInput};
+use rusoto_core::{HttpClient, Region, RusotoError};
+use rusoto_credential::{
+    AutoRefreshingProvider, AwsCredentials, ContainerProvider, CredentialsError,
+    EnvironmentProvider, InstanceMetadataProvider, ProfileProvider, ProvideAwsCredentials,
+};
 use rusoto_ec2::{
-    filter, AttributeValue, DescribeInstancesRequest, Ec2Client, ModifyInstanceAttributeRequest,
-    StartInstancesRequest, StopInstancesRequest,
+    filter, AllocateAddressRequest, AssociateAddressRequest, AttributeValue, CreateImageRequest,
+    CreateSnapshotRequest, CreateTagsRequest, DeleteTagsRequest, DescribeAddressesRequest,
+    DescribeImagesRequest, DescribeInstanceStatusRequest, DescribeInstanceTypeOfferingsRequest,
+    DescribeInstanceTypesRequest, DescribeInstancesRequest, DescribeKeyPairsRequest,
+    DescribeSecurityGroupsRequest, DescribeVolumesModificationsRequest, DisassociateAddressRequest,
+    Ec2Client, Filter, GetConsoleOutputRequest, InstanceMarketOptionsRequest, InstanceStateChange,
+    IpPermission, ModifyInstanceAttributeRequest, ModifyVolumeRequest, RebootInstancesRequest,
+    ReleaseAddressRequest, RunInstancesRequest, SpotMarketOptions, StartInstancesRequest,
+    StopInstancesRequest, Tag, TagSpecification, TerminateInstancesRequest,
 };
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::RemoteError;
+use crate::http::{with_query, HttpRequest, HttpSend, HyperClient};
+use crate::table::color_state;
 
 pub use rusoto_ec2::Ec2;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Instance {
     pub instance_type: String,
     pub instance_id: String,
+    #[serde(default)]
     pub public_dns: String,
+    #[serde(default)]
+    pub private_ip: String,
+    #[serde(default)]
     pub tags: Vec<InstanceTag>,
     pub state: String,
+    pub availability_zone: Option<String>,
+    /// When the instance was last started, as an RFC 3339 timestamp
+    #[serde(default)]
+    pub launch_time: Option<String>,
+    /// How the instance is billed when not on-demand, e.g. "spot"
+    #[serde(default)]
+    pub lifecycle: Option<String>,
+    /// The instance's first IPv6 address, which IPv6-only instances are reached on
+    #[serde(default)]
+    pub ipv6: Option<String>,
+}
+
+impl Instance {
+    /// The value of the instance's Name tag
+    pub fn name(&self) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.key == "Name")
+            .map(|tag| tag.value.as_str())
+    }
+
+    /// Whether the instance is tagged `protected=true`, asking for care before stopping it
+    pub fn is_protected(&self) -> bool {
+        self.tags
+            .iter()
+            .any(|tag| tag.key == "protected" && tag.value.eq_ignore_ascii_case("true"))
+    }
+
+    /// The region, derived from the availability zone (e.g. us-east-1a -> us-east-1,
+    /// or us-central1-a -> us-central1 on GCP)
+    pub fn region(&self) -> Option<&str> {
+        self.availability_zone.as_deref().map(|az| {
+            az.trim_end_matches(|c: char| c.is_ascii_alphabetic())
+                .trim_end_matches('-')
+        })
+    }
+
+    /// How long the instance has been up, if it's running and its launch time is known
+    pub fn uptime(&self) -> Option<chrono::Duration> {
+        if self.state != "running" {
+            return None;
+        }
+        let launched = DateTime::parse_from_rfc3339(self.launch_time.as_deref()?).ok()?;
+        Some(Utc::now() - launched.with_timezone(&Utc))
+    }
+
+    /// The launch time in local time, with the uptime while the instance is running
+    fn launch_time_string(&self) -> String {
+        let raw = match self.launch_time.as_deref() {
+            Some(raw) => raw,
+            None => return "-".to_string(),
+        };
+        let launched = match DateTime::parse_from_rfc3339(raw) {
+            Ok(launched) => launched.with_timezone(&Utc),
+            Err(_) => return raw.to_string(),
+        };
+        let formatted = launched
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string();
+        match self.uptime() {
+            Some(uptime) => format!("{} (up {})", formatted, format_uptime(uptime)),
+            None => formatted,
+        }
+    }
+}
+
+/// A short human readable duration, e.g. "2d 4h", "3h 12m" or "5m"
+pub fn format_uptime(uptime: chrono::Duration) -> String {
+    let minutes = uptime.num_minutes().max(0);
+    let (days, hours, minutes) = (minutes / 1440, minutes / 60 % 24, minutes % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
 }
 
-impl ToString for Instance {
-    fn to_string(&self) -> String {
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let tag_string = self
             .tags
             .iter()
             .map(|tag| format!("\"{}\"=\"{}\"", tag.key, tag.value))
             .collect::<Vec<String>>()
             .join(", ");
-        format!(
+        write!(
+            f,
             "Instance ID: {}\n\
              Type: {}\n\
              Tags: {}\n\
-             State: {}",
-            self.instance_id, self.instance_type, tag_string, self.state
-        )
+             State: {}\n\
+             Private IP: {}\n\
+             Launch Time: {}\n\
+             Availability Zone: {}",
+            self.instance_id,
+            self.instance_type,
+            tag_string,
+            color_state(&self.state),
+            match self.private_ip.as_str() {
+                "" => "-",
+                ip => ip,
+            },
+            self.launch_time_string(),
+            self.availability_zone.as_deref().unwrap_or("-")
+        )?;
+        if let Some(lifecycle) = &self.lifecycle {
+            write!(f, "\nLifecycle: {}", lifecycle)?;
+        }
+        if let Some(ipv6) = &self.ipv6 {
+            write!(f, "\nIPv6: {}", ipv6)?;
+        }
+        Ok(())
+    }
+}
+
+/// A client-side `field=value` match against a fetched instance
+#[derive(Debug, Clone)]
+pub enum InstanceFilter {
+    State(String),
+    InstanceType(String),
+    Tag(String, String),
+}
+
+impl InstanceFilter {
+    pub fn matches(&self, instance: &Instance) -> bool {
+        match self {
+            InstanceFilter::State(state) => &instance.state == state,
+            InstanceFilter::InstanceType(instance_type) => &instance.instance_type == instance_type,
+            InstanceFilter::Tag(key, value) => instance
+                .tags
+                .iter()
+                .any(|tag| &tag.key == key && &tag.value == value),
+        }
+    }
+}
+
+impl FromStr for InstanceFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let field = parts.next().unwrap_or_default();
+        let value = parts
+            .next()
+            .ok_or_else(|| Error::msg(format!("Expected field=value, got '{}'", s)))?
+            .to_string();
+        match field {
+            "state" => Ok(InstanceFilter::State(value)),
+            "instance_type" => Ok(InstanceFilter::InstanceType(value)),
+            _ if field.starts_with("tag:") => Ok(InstanceFilter::Tag(
+                field.trim_start_matches("tag:").to_string(),
+                value,
+            )),
+            _ => Err(Error::msg(format!(
+                "Unknown filter field '{}', expected state, instance_type or tag:<key>",
+                field
+            ))),
+        }
     }
 }
 
+/// The EC2 filter on instance state, the one listing filter every cloud supports
+pub const STATE_FILTER: &str = "instance-state-name";
+
+/// A provider-side `name=value[,value...]` filter on a listing, named as in EC2's
+/// DescribeInstances, e.g. `tag:Owner=alice`
 #[derive(Debug, Clone)]
+pub struct ListFilter {
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl FromStr for ListFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, values)) if !name.is_empty() && !values.is_empty() => Ok(ListFilter {
+                name: name.to_string(),
+                values: values.split(',').map(str::to_string).collect(),
+            }),
+            _ => Err(Error::msg(format!("Expected name=value, got '{}'", s))),
+        }
+    }
+}
+
+impl From<&ListFilter> for Filter {
+    fn from(filter: &ListFilter) -> Self {
+        Filter {
+            name: Some(filter.name.clone()),
+            values: Some(filter.values.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct InstanceTag {
     pub key: String,
     pub value: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StateChange {
     pub previous: String,
     pub current: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct InstanceEvent {
+    pub code: String,
+    pub description: String,
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+}
+
+impl fmt::Display for InstanceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.description)?;
+        match (&self.not_before, &self.not_after) {
+            (Some(start), Some(end)) => write!(f, " (scheduled {} - {})", start, end),
+            (Some(start), None) => write!(f, " (scheduled {})", start),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An instance metric that can be read as a series of datapoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceMetric {
+    Cpu,
+    NetworkIn,
+    NetworkOut,
+}
+
+impl InstanceMetric {
+    /// The metric's name in CloudWatch
+    pub fn cloudwatch_name(&self) -> &'static str {
+        match self {
+            InstanceMetric::Cpu => "CPUUtilization",
+            InstanceMetric::NetworkIn => "NetworkIn",
+            InstanceMetric::NetworkOut => "NetworkOut",
+        }
+    }
+
+    /// The unit of the series' values
+    pub fn unit(&self) -> &'static str {
+        match self {
+            InstanceMetric::Cpu => "percent, average per period",
+            InstanceMetric::NetworkIn | InstanceMetric::NetworkOut => "bytes, total per period",
+        }
+    }
+
+    /// CPU is averaged over each period while traffic is summed
+    fn statistic(&self) -> &'static str {
+        match self {
+            InstanceMetric::Cpu => "Average",
+            InstanceMetric::NetworkIn | InstanceMetric::NetworkOut => "Sum",
+        }
+    }
+}
+
+impl FromStr for InstanceMetric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cpu" => Ok(InstanceMetric::Cpu),
+            "network-in" | "net-in" => Ok(InstanceMetric::NetworkIn),
+            "network-out" | "net-out" => Ok(InstanceMetric::NetworkOut),
+            _ => Err(Error::msg(format!(
+                "Unknown metric '{}', expected cpu, network-in or network-out",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for InstanceMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstanceMetric::Cpu => write!(f, "cpu"),
+            InstanceMetric::NetworkIn => write!(f, "network-in"),
+            InstanceMetric::NetworkOut => write!(f, "network-out"),
+        }
+    }
+}
+
+/// Parameters for launching a new instance
+#[derive(Debug, Clone, Default)]
+pub struct LaunchSpec {
+    pub image_id: String,
+    pub instance_type: String,
+    pub key_name: Option<String>,
+    pub security_group_ids: Vec<String>,
+    pub subnet_id: Option<String>,
+    pub tags: Vec<InstanceTag>,
+    /// Launch as a spot instance instead of on-demand
+    pub spot: Option<SpotSpec>,
+}
+
+/// Spot market settings for a launch
+#[derive(Debug, Clone, Default)]
+pub struct SpotSpec {
+    /// Highest USD price per hour to pay, defaulting to the on-demand price
+    pub max_price: Option<String>,
+    /// What happens on interruption: "terminate" (default), "stop" or "hibernate"
+    pub interruption_behavior: Option<String>,
+}
+
+/// EC2 error codes meaning a spot request can't be fulfilled right now
+const SPOT_UNAVAILABLE_CODES: &[&str] = &[
+    "InsufficientInstanceCapacity",
+    "SpotMaxPriceTooLow",
+    "MaxSpotInstanceCountExceeded",
+];
+
+/// A static public IP associated with an instance
+#[derive(Debug, Clone, Serialize)]
+pub struct ElasticIp {
+    pub allocation_id: String,
+    pub public_ip: String,
+}
+
+/// A firewall group attached to an instance, with the traffic it lets in
+#[derive(Debug, Clone, Serialize)]
+pub struct SecurityGroup {
+    pub group_id: String,
+    pub name: String,
+    pub inbound: Vec<SecurityRule>,
+}
+
+/// Traffic allowed in on a protocol and port range from one source
+#[derive(Debug, Clone, Serialize)]
+pub struct SecurityRule {
+    /// "tcp", "udp", "icmp" or "all"
+    pub protocol: String,
+    /// The first and last port, none when every port is allowed
+    pub ports: Option<(i64, i64)>,
+    /// A CIDR block, security group or prefix list
+    pub source: String,
+}
+
+impl SecurityRule {
+    /// The port range for display, e.g. "22" or "8000-8999"
+    pub fn port_range(&self) -> String {
+        match self.ports {
+            None => "all".to_string(),
+            Some((from, to)) if from == to => from.to_string(),
+            Some((from, to)) => format!("{}-{}", from, to),
+        }
+    }
+}
+
+/// Hardware of an instance type
+#[derive(Debug, Clone, Serialize)]
+pub struct InstanceTypeSummary {
+    pub instance_type: String,
+    pub vcpus: i64,
+    pub memory_mib: i64,
+    pub network_performance: String,
+}
+
+/// How often `wait_for_state` polls the instance
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long `set_volume_size` waits for a volume modification to take effect
+const VOLUME_MODIFY_TIMEOUT: Duration = Duration::from_secs(600);
+
 #[async_trait]
-pub trait InstanceManager {
+pub trait InstanceManager: Send + Sync {
     async fn list_instances(&self) -> Result<Vec<Instance>>;
+    /// The instances matching every filter. Only AWS filters on its side, other clouds
+    /// support `STATE_FILTER` by checking each listed instance.
+    async fn list_instances_filtered(&self, filters: &[ListFilter]) -> Result<Vec<Instance>> {
+        if let Some(filter) = filters.iter().find(|filter| filter.name != STATE_FILTER) {
+            return Err(Error::msg(format!(
+                "Filtering listings by '{}' is only supported on AWS, use {} or --running",
+                filter.name, STATE_FILTER
+            )));
+        }
+        let mut instances = self.list_instances().await?;
+        for filter in filters {
+            instances.retain(|inst| filter.values.contains(&inst.state));
+        }
+        Ok(instances)
+    }
     async fn get_instance(&self, instance_id: &str) -> Result<Instance>;
+    /// The one instance whose Name tag is `name`
+    async fn get_instance_by_name(&self, name: &str) -> Result<Instance> {
+        let instances = self
+            .list_instances()
+            .await?
+            .into_iter()
+            .filter(|inst| inst.name() == Some(name))
+            .collect();
+        single_named(name, instances)
+    }
+    /// The one instance whose ID starts with `prefix`, for short IDs like "i-0ab"
+    async fn get_instance_by_prefix(&self, prefix: &str) -> Result<Instance> {
+        let instances = self
+            .list_instances()
+            .await?
+            .into_iter()
+            .filter(|inst| inst.instance_id.starts_with(prefix))
+            .collect();
+        single_prefixed(prefix, instances)
+    }
     async fn start_instance(&self, instance_id: &str) -> Result<StateChange>;
     async fn stop_instance(&self, instance_id: &str) -> Result<StateChange>;
+    /// Reboot a running instance in place
+    async fn reboot_instance(&self, instance_id: &str) -> Result<StateChange>;
+    /// Permanently destroy the instance
+    async fn terminate_instance(&self, instance_id: &str) -> Result<StateChange>;
     async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()>;
+    /// Grow the instance's root volume to `size_gb`, returning once the OS can use the space
+    async fn set_volume_size(&self, instance_id: &str, size_gb: i64) -> Result<()>;
+    /// Set tags on the instance, overwriting the values of existing keys
+    async fn add_tags(&self, instance_id: &str, tags: &[InstanceTag]) -> Result<()>;
+    /// Remove the tags with these keys from the instance
+    async fn remove_tags(&self, instance_id: &str, keys: &[String]) -> Result<()>;
+    async fn get_instance_events(&self, instance_id: &str) -> Result<Vec<InstanceEvent>>;
+    /// The instance's serial console output, empty if there is none yet. `latest` asks
+    /// for the most recent output instead of the boot-time snapshot where supported.
+    async fn get_console_output(&self, instance_id: &str, latest: bool) -> Result<String>;
+    /// Average CPU utilization in percent over the last `minutes`, none without datapoints
+    async fn get_cpu_utilization(&self, instance_id: &str, minutes: u32) -> Result<Option<f64>>;
+    /// `metric` over the last `since`, one datapoint per `period` in time order. Periods
+    /// without data, such as while the instance was stopped, are left out.
+    async fn get_metric_series(
+        &self,
+        _instance_id: &str,
+        _metric: InstanceMetric,
+        _since: Duration,
+        _period: Duration,
+    ) -> Result<Vec<(DateTime<Utc>, f64)>> {
+        Err(Error::msg("Reading metric series is only supported on AWS"))
+    }
+    async fn create_image(
+        &self,
+        instance_id: &str,
+        name: &str,
+        no_reboot: bool,
+        tags: &[InstanceTag],
+    ) -> Result<String>;
+    /// The state of an image made by `create_image`, e.g. "pending" or "available"
+    async fn get_image_state(&self, image_id: &str) -> Result<String>;
+    async fn create_snapshot(
+        &self,
+        instance_id: &str,
+        description: &str,
+        tags: &[InstanceTag],
+    ) -> Result<String>;
+    async fn launch_instance(&self, spec: LaunchSpec) -> Result<Instance>;
+    /// Build a launch spec matching an existing instance's type, key and network
+    async fn launch_spec_from(&self, instance_id: &str) -> Result<LaunchSpec>;
+    /// Instance types offered in the region, optionally limited to a family like "t3"
+    async fn list_instance_types(&self, family: Option<&str>) -> Result<Vec<InstanceTypeSummary>>;
+    /// Names of the instance types that can be launched in `zone`, or anywhere in the region
+    async fn list_offered_types(&self, _zone: Option<&str>) -> Result<Vec<String>> {
+        let types = self.list_instance_types(None).await?;
+        Ok(types.into_iter().map(|info| info.instance_type).collect())
+    }
+    /// The security groups attached to the instance and their inbound rules
+    async fn get_security_groups(&self, instance_id: &str) -> Result<Vec<SecurityGroup>>;
+    /// Names of the SSH key pairs registered with the account
+    async fn list_key_pairs(&self) -> Result<Vec<String>>;
+    /// Associate a static public IP with the instance, allocating a new one unless
+    /// `allocation_id` names an existing allocation
+    async fn associate_elastic_ip(
+        &self,
+        instance_id: &str,
+        allocation_id: Option<&str>,
+    ) -> Result<ElasticIp>;
+    /// Disassociate the static IP from any instance and give it back to the cloud
+    async fn release_elastic_ip(&self, allocation_id: &str) -> Result<()>;
+
+    /// The conventional SSH user of the instance's OS image, none if it can't be told
+    async fn guess_ssh_user(&self, _instance_id: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Poll until the instance reaches the `target` state. Lookup errors are
+    /// retried until the timeout since new instances may not be visible yet.
+    async fn wait_for_state(
+        &self,
+        instance_id: &str,
+        target: &str,
+        timeout: Duration,
+    ) -> Result<Instance> {
+        self.wait_until(instance_id, target, timeout, &|instance| {
+            instance.state == target
+        })
+        .await
+    }
+
+    /// Poll until `ready` holds for the instance, which is described as `goal` if it
+    /// times out. Lookup errors are retried like in `wait_for_state`.
+    async fn wait_until(
+        &self,
+        instance_id: &str,
+        goal: &str,
+        timeout: Duration,
+        ready: &(dyn for<'i> Fn(&'i Instance) -> bool + Send + Sync),
+    ) -> Result<Instance> {
+        let start = Instant::now();
+        loop {
+            let last_error = match self.get_instance(instance_id).await {
+                Ok(instance) if ready(&instance) => return Ok(instance),
+                Ok(instance) => Error::msg(format!(
+                    "Timed out waiting for {} to be {} (currently {})",
+                    instance_id, goal, instance.state
+                )),
+                Err(e) => e,
+            };
+            if start.elapsed() >= timeout {
+                return Err(last_error);
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Poll until an image made by `create_image` is available, failing if it fails
+    async fn wait_for_image(&self, image_id: &str, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            let state = self.get_image_state(image_id).await?;
+            match state.as_str() {
+                "available" => return Ok(()),
+                "pending" => {}
+                _ => {
+                    return Err(Error::msg(format!(
+                        "Image {} is {} instead of available",
+                        image_id, state
+                    )))
+                }
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::msg(format!(
+                    "Timed out waiting for image {} to be available (currently {})",
+                    image_id, state
+                )));
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
 }
 
-pub struct AwsCloud {
-    client: Ec2Client,
+/// The only instance in `instances`, which were matched by `name`
+fn single_named(name: &str, instances: Vec<Instance>) -> Result<Instance> {
+    match instances.len() {
+        0 => Err(Error::msg(format!("No instance named '{}' found", name))),
+        1 => Ok(instances.into_iter().next().unwrap()),
+        _ => Err(Error::msg(format!(
+            "Multiple instances are named '{}': {}",
+            name,
+            instances
+                .iter()
+                .map(|inst| inst.instance_id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
 }
 
-impl AwsCloud {
-    pub fn new(client: Ec2Client) -> Self {
-        Self { client }
+fn single_prefixed(prefix: &str, instances: Vec<Instance>) -> Result<Instance> {
+    match instances.len() {
+        0 => Err(Error::msg(format!(
+            "No instance ID starts with '{}'",
+            prefix
+        ))),
+        1 => Ok(instances.into_iter().next().unwrap()),
+        _ => Err(Error::msg(format!(
+            "Instance ID prefix '{}' is ambiguous: {}",
+            prefix,
+            instances
+                .iter()
+                .map(|inst| inst.instance_id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
     }
+}
 
-    pub fn from_profile(profile: &str) -> Result<Self> {
-        let mut provider = ProfileProvider::new()?;
-        provider.set_profile(profile);
-        let client = Ec2Client::new_with(
-            HttpClient::new()?,
-            ChainProvider::with_profile_provider(provider),
-            Region::default(),
-        );
-        Ok(Self::new(client))
+/// Lengths of complete EC2 instance IDs, in the old and current formats
+const EC2_ID_LENGTHS: [usize; 2] = [10, 19];
+
+/// Whether `identifier` is a complete EC2 instance ID rather than a prefix of one
+fn is_full_ec2_id(identifier: &str) -> bool {
+    identifier.starts_with("i-") && EC2_ID_LENGTHS.contains(&identifier.len())
+}
+
+/// The first IPv6 address on any of an EC2 instance's network interfaces
+fn ec2_ipv6(inst: &rusoto_ec2::Instance) -> Option<String> {
+    inst.network_interfaces
+        .iter()
+        .flatten()
+        .flat_map(|iface| iface.ipv_6_addresses.iter().flatten())
+        .find_map(|addr| addr.ipv_6_address.clone())
+}
+
+/// The default SSH user of an AMI, from words in its lowercased name and description.
+/// More specific distributions are checked first, e.g. Fedora CoreOS before Fedora.
+fn image_default_user(text: &str) -> Option<&'static str> {
+    const USERS: &[(&[&str], &str)] = &[
+        (&["coreos", "flatcar"], "core"),
+        (&["bitnami"], "bitnami"),
+        (&["ubuntu"], "ubuntu"),
+        (&["debian"], "admin"),
+        (&["centos"], "centos"),
+        (&["rocky"], "rocky"),
+        (&["fedora"], "fedora"),
+        (
+            &[
+                "amzn",
+                "amazon linux",
+                "al2023",
+                "rhel",
+                "red hat",
+                "suse",
+                "sles",
+                "almalinux",
+                "freebsd",
+            ],
+            "ec2-user",
+        ),
+    ];
+    // Windows images are logged in to over RDP, not with a conventional SSH user
+    if text.contains("windows") {
+        return None;
     }
+    USERS
+        .iter()
+        .find(|(words, _)| words.iter().any(|word| text.contains(word)))
+        .map(|(_, user)| *user)
+}
 
-    async fn describe_instances(&self, instance_id: Option<&str>) -> Result<Vec<Instance>> {
-        let filters = match instance_id {
-            Some(id) => {
-                let filter = filter!("instance-id", id);
-                Some(vec![filter])
-            }
-            None => None,
-        };
-        let req = DescribeInstancesRequest {
-            filters,
-            ..Default::default()
-        };
-        let res: Vec<Instance> = self
-            .client
-            .describe_instances(req)
-            .await?
-            .reservations
-            .unwrap()
+/// Convert an EC2 instance description. Only the ID is required; stopped
+/// instances have no DNS name and untagged ones no tags.
+fn to_instance(inst: rusoto_ec2::Instance) -> Result<Instance> {
+    let ipv6 = ec2_ipv6(&inst);
+    Ok(Instance {
+        instance_id: inst
+            .instance_id
+            .ok_or_else(|| Error::msg("EC2 returned an instance without an ID"))?,
+        instance_type: inst.instance_type.unwrap_or_default(),
+        public_dns: inst.public_dns_name.unwrap_or_default(),
+        private_ip: inst.private_ip_address.unwrap_or_default(),
+        availability_zone: inst.placement.and_then(|p| p.availability_zone),
+        launch_time: inst.launch_time,
+        lifecycle: inst.instance_lifecycle,
+        ipv6,
+        state: inst
+            .state
+            .and_then(|state| state.name)
+            .unwrap_or_else(|| "unknown".to_string()),
+        tags: inst
+            .tags
+            .unwrap_or_default()
             .into_iter()
-            .map(|res| res.instances.unwrap())
-            .flatten()
-            .map(|inst| Instance {
-                instance_type: inst.instance_type.unwrap(),
-                instance_id: inst.instance_id.unwrap(),
-                public_dns: inst.public_dns_name.unwrap(),
-                state: inst.state.unwrap().name.unwrap(),
-                tags: inst
-                    .tags
-                    .unwrap()
-                    .into_iter()
-                    .map(|tag| InstanceTag {
-                        key: tag.key.unwrap(),
-                        value: tag.value.unwrap(),
-                    })
-                    .collect(),
+            .filter_map(|tag| {
+                Some(InstanceTag {
+                    key: tag.key?,
+                    value: tag.value.unwrap_or_default(),
+                })
             })
-            .collect();
-        Ok(res)
+            .collect(),
+    })
+}
+
+/// The state change EC2 reported for `instance_id`
+fn to_state_change(
+    instance_id: &str,
+    changes: Option<Vec<InstanceStateChange>>,
+) -> Result<StateChange> {
+    let change = changes
+        .unwrap_or_default()
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::msg(format!("EC2 returned no state change for {}", instance_id)))?;
+    let name = |state: Option<rusoto_ec2::InstanceState>| {
+        state
+            .and_then(|state| state.name)
+            .unwrap_or_else(|| "unknown".to_string())
+    };
+    Ok(StateChange {
+        previous: name(change.previous_state),
+        current: name(change.current_state),
+    })
+}
+
+static HTTP_CLIENT: OnceLock<Arc<HttpClient>> = OnceLock::new();
+
+/// The HTTP client shared by every AWS client, so they reuse one connection pool
+pub fn shared_http_client() -> Result<Arc<HttpClient>> {
+    if let Some(client) = HTTP_CLIENT.get() {
+        return Ok(client.clone());
     }
+    let client = Arc::new(HttpClient::new()?);
+    Ok(HTTP_CLIENT.get_or_init(|| client).clone())
 }
 
-#[async_trait]
-impl InstanceManager for AwsCloud {
-    async fn list_instances(&self) -> Result<Vec<Instance>> {
-        self.describe_instances(None).await
+fn to_ec2_tags(tags: &[InstanceTag]) -> Vec<Tag> {
+    tags.iter()
+        .map(|tag| Tag {
+            key: Some(tag.key.clone()),
+            value: Some(tag.value.clone()),
+        })
+        .collect()
+}
+
+/// Environment variable overriding how often throttled or failed EC2 calls are retried
+const AWS_MAX_RETRIES_VAR: &str = "REMOTE_AWS_MAX_RETRIES";
+const DEFAULT_AWS_MAX_RETRIES: u32 = 3;
+/// Delay before the first retry, doubling on each further attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+/// EC2 error codes worth retrying, everything else (like InvalidInstanceID.NotFound) fails
+const RETRYABLE_ERROR_CODES: &[&str] = &[
+    "RequestLimitExceeded",
+    "Throttling",
+    "InternalError",
+    "Unavailable",
+];
+
+/// Whether an EC2 call failed on throttling or a transient network or server error
+fn is_retryable<E>(error: &RusotoError<E>) -> bool {
+    match error {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(res) => {
+            let body = res.body_as_str();
+            res.status.is_server_error()
+                || RETRYABLE_ERROR_CODES
+                    .iter()
+                    .any(|code| body.contains(&format!("<Code>{}</Code>", code)))
+        }
+        _ => false,
     }
+}
 
-    async fn get_instance(&self, instance_id: &str) -> Result<Instance> {
-        let instances = self.describe_instances(Some(instance_id)).await?;
-        match instances.len() {
-            0 => Err(Error::msg(format!(
-                "Could not find instance {}",
-                instance_id
-            ))),
-            _ => Ok(instances[0].clone()),
+/// Await an AWS call that isn't retried, logging how long it took
+async fn timed<T, E>(
+    operation: &str,
+    call: impl Future<Output = Result<T, RusotoError<E>>>,
+) -> Result<T>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let start = Instant::now();
+    let result = call.await;
+    log_call(operation, start, &result);
+    result.map_err(cloud_error)
+}
+
+fn log_call<T, E: std::error::Error + 'static>(
+    operation: &str,
+    start: Instant,
+    result: &Result<T, RusotoError<E>>,
+) {
+    match result {
+        Ok(_) => debug!("{} took {:?}", operation, start.elapsed()),
+        Err(e) => debug!("{} failed after {:?}: {}", operation, start.elapsed(), e),
+    }
+}
+
+/// Mark a failed AWS call as a cloud error, to tell it apart from local failures
+fn cloud_error<E: std::error::Error + Send + Sync + 'static>(error: RusotoError<E>) -> Error {
+    RemoteError::Cloud(error.into()).into()
+}
+
+/// The error code of a failed launch if it means there's no spot capacity at the price
+fn spot_unavailable_code<E>(error: &RusotoError<E>) -> Option<&'static str> {
+    match error {
+        RusotoError::Unknown(res) => {
+            let body = res.body_as_str();
+            SPOT_UNAVAILABLE_CODES
+                .iter()
+                .find(|code| body.contains(&format!("<Code>{}</Code>", code)))
+                .copied()
         }
+        _ => None,
     }
+}
 
-    async fn start_instance(&self, instance_id: &str) -> Result<StateChange> {
-        let req = StartInstancesRequest {
-            instance_ids: vec![instance_id.to_string()],
-            ..Default::default()
-        };
-        let res = self
-            .client
-            .start_instances(req)
-            .await?
-            .starting_instances
-            .unwrap()[0]
-            .clone();
-        Ok(StateChange {
-            previous: res.previous_state.unwrap().name.unwrap(),
-            current: res.current_state.unwrap().name.unwrap(),
+/// An AWS region and where it was configured
+#[derive(Debug, Clone)]
+pub struct ResolvedRegion {
+    pub region: Region,
+    pub source: String,
+}
+
+/// The region for an AWS profile: `configured` (a region and where it was set), then
+/// `AWS_REGION` or `AWS_DEFAULT_REGION`, then the profile's region in `~/.aws/config`.
+/// Unlike `Region::default()` this fails instead of quietly using us-east-1.
+pub fn resolve_region(profile: &str, configured: Option<(&str, &str)>) -> Result<ResolvedRegion> {
+    let from = |name: &str, source: String| {
+        Ok(ResolvedRegion {
+            region: Region::from_str(name)
+                .map_err(|e| Error::msg(format!("Invalid region from {}: {}", source, e)))?,
+            source,
         })
+    };
+    if let Some((name, source)) = configured {
+        return from(name, source.to_string());
+    }
+    for var in ["AWS_REGION", "AWS_DEFAULT_REGION"] {
+        match std::env::var(var) {
+            Ok(name) if !name.is_empty() => return from(&name, var.to_string()),
+            _ => continue,
+        }
+    }
+    let config_path = match std::env::var_os("AWS_CONFIG_FILE") {
+        Some(path) if !path.is_empty() => Some(std::path::PathBuf::from(path)),
+        _ => dirs::home_dir().map(|home| home.join(".aws").join("config")),
+    };
+    if let Some(config_path) = config_path {
+        let provider = ProfileProvider::with_configuration(&config_path, profile);
+        if let Ok(Some(name)) = provider.region_from_profile() {
+            return from(
+                &name,
+                format!("profile {} in {}", profile, config_path.display()),
+            );
+        }
     }
+    Err(Error::msg(format!(
+        "No AWS region configured for profile '{}'. Set one with\n  \
+         aws configure set region <region> --profile {}\n\
+         or with AWS_REGION, --region or the instance's region in the config",
+        profile, profile
+    )))
+}
 
-    async fn stop_instance(&self, instance_id: &str) -> Result<StateChange> {
-        let req = StopInstancesRequest {
-            instance_ids: vec![instance_id.to_string()],
-            ..Default::default()
-        };
-        let res = self
-            .client
-            .stop_instances(req)
-            .await?
-            .stopping_instances
-            .unwrap()[0]
-            .clone();
-        Ok(StateChange {
-            previous: res.previous_state.unwrap().name.unwrap(),
-            current: res.current_state.unwrap().name.unwrap(),
+/// Environment variable pointing AWS clients at another endpoint, e.g. LocalStack
+const AWS_ENDPOINT_VAR: &str = "REMOTE_AWS_ENDPOINT";
+
+/// `region` with its requests sent to `endpoint`, e.g. "http://localhost:4566"
+pub fn region_with_endpoint(region: Region, endpoint: &str) -> Region {
+    Region::Custom {
+        name: region.name().to_string(),
+        endpoint: endpoint.to_string(),
+    }
+}
+
+/// Environment variable moving the shared AWS credentials file from ~/.aws/credentials
+const CREDENTIALS_FILE_VAR: &str = "AWS_SHARED_CREDENTIALS_FILE";
+
+/// Session name of assumed roles, which shows up in CloudTrail
+const ROLE_SESSION_NAME: &str = "remote-cli";
+
+/// AWS credentials for a named profile, tried from each source in turn.
+///
+/// The "default" profile prefers `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY` like the
+/// AWS CLI does, while other profiles read the shared credentials file first and only
+/// fall back to the environment when the profile isn't there. Container and instance
+/// role credentials are tried last.
+#[derive(Clone)]
+pub struct ProfileCredentials {
+    profile: String,
+    environment: EnvironmentProvider,
+    /// The credentials file provider, or why the file could not be located
+    file: std::result::Result<ProfileProvider, String>,
+    container: ContainerProvider,
+    instance_role: InstanceMetadataProvider,
+}
+
+impl ProfileCredentials {
+    pub fn new(profile: &str) -> Self {
+        let file = ProfileProvider::new()
+            .map(|mut provider| {
+                provider.set_profile(profile);
+                provider
+            })
+            .map_err(|e| e.to_string());
+        Self {
+            profile: profile.to_string(),
+            environment: EnvironmentProvider::default(),
+            file,
+            container: ContainerProvider::new(),
+            instance_role: InstanceMetadataProvider::new(),
+        }
+    }
+
+    /// The shared credentials file, if it's where it should be but doesn't exist
+    fn missing_file(&self) -> Option<&Path> {
+        match &self.file {
+            Ok(provider) if !provider.file_path().exists() => Some(provider.file_path()),
+            _ => None,
+        }
+    }
+
+    async fn file_credentials(&self) -> std::result::Result<AwsCredentials, String> {
+        if let Some(path) = self.missing_file() {
+            return Err(format!(
+                "profile '{}': there is no credentials file at {}",
+                self.profile,
+                path.display()
+            ));
+        }
+        match &self.file {
+            Ok(provider) => provider.credentials().await.map_err(|e| {
+                format!(
+                    "profile '{}' in {}: {}",
+                    self.profile,
+                    provider.file_path().display(),
+                    e
+                )
+            }),
+            Err(e) => Err(format!("credentials file: {}", e)),
+        }
+    }
+
+    /// Fail with every source tried if no credentials can be found for the profile, so
+    /// setup mistakes show up before any API call
+    pub async fn check(&self) -> Result<()> {
+        self.credentials().await.map(|_| ()).map_err(|e| {
+            // A missing file already comes with its own hint
+            match self.missing_file() {
+                Some(_) => Error::msg(e.to_string()),
+                None => Error::msg(format!(
+                    "{}\nAdd the profile to ~/.aws/credentials, e.g. with `aws configure --profile {}`",
+                    e, self.profile
+                )),
+            }
         })
     }
 
-    async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()> {
-        let value = AttributeValue {
-            value: Some(instance_type.to_string()),
-        };
-        let req = ModifyInstanceAttributeRequest {
-            instance_id: instance_id.to_string(),
-            instance_type: Some(value),
-            ..Default::default()
+    async fn environment_credentials(&self) -> std::result::Result<AwsCredentials, String> {
+        self.environment
+            .credentials()
+            .await
+            .map_err(|e| format!("environment variables: {}", e))
+    }
+}
+
+#[async_trait]
+impl ProvideAwsCredentials for ProfileCredentials {
+    async fn credentials(&self) -> std::result::Result<AwsCredentials, CredentialsError> {
+        let mut attempts = Vec::new();
+        let sources = match self.profile.as_str() {
+            "default" => [
+                self.environment_credentials().await,
+                self.file_credentials().await,
+            ],
+            _ => [
+                self.file_credentials().await,
+                self.environment_credentials().await,
+            ],
         };
-        self.client.modify_instance_attribute(req).await?;
-        Ok(())
+        for source in sources {
+            match source {
+                Ok(credentials) => return Ok(credentials),
+                Err(e) => attempts.push(e),
+            }
+        }
+        match self.container.credentials().await {
+            Ok(credentials) => return Ok(credentials),
+            Err(e) => attempts.push(format!("container role: {}", e)),
+        }
+        match self.instance_role.credentials().await {
+            Ok(credentials) => return Ok(credentials),
+            Err(e) => attempts.push(format!("instance role: {}", e)),
+        }
+        let mut message = format!(
+            "No AWS credentials found for profile '{}'. Tried:\n  {}",
+            self.profile,
+            attempts.join("\n  ")
+        );
+        // The usual first run failure, so say how to fix it
+        if let Some(path) = self.missing_file() {
+            message.push_str(&format!(
+                "\nThe AWS credentials file {} doesn't exist. Create it with `aws configure --profile {}`, \
+                 point {} at another file, or set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY",
+                path.display(),
+                self.profile,
+                CREDENTIALS_FILE_VAR
+            ));
+        }
+        Err(CredentialsError::new(message))
+    }
+}
+
+pub struct AwsCloud {
+    client: Box<dyn Ec2 + Send + Sync>,
+    cloudwatch: Box<dyn CloudWatch + Send + Sync>,
+    max_retries: u32,
+}
+
+impl AwsCloud {
+    /// Manage instances through any EC2 and CloudWatch implementations, such as
+    /// `Ec2Client` and `CloudWatchClient` or stand-ins returning canned responses
+    pub fn new<E, C>(client: E, cloudwatch: C) -> Self
+    where
+        E: Ec2 + Send + Sync + 'static,
+        C: CloudWatch + Send + Sync + 'static,
+    {
+        let max_retries = std::env::var(AWS_MAX_RETRIES_VAR)
+            .ok()
+            .and_then(|retries| retries.parse().ok())
+            .unwrap_or(DEFAULT_AWS_MAX_RETRIES);
+        Self {
+            client: Box::new(client),
+            cloudwatch: Box::new(cloudwatch),
+            max_retries,
+        }
+    }
+
+    /// Run an EC2 call, retrying retryable failures with exponential backoff
+    async fn with_retry<T, E, F, Fut>(&self, operation: &str, mut call: F) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RusotoError<E>>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            let result = call().await;
+            log_call(operation, start, &result);
+            match result {
+                Err(e) if attempt < self.max_retries && is_retryable(&e) => {
+                    let delay = RETRY_BASE_DELAY * 2u32.pow(attempt);
+                    info!(
+                        "Retrying {} in {:?} ({} of {}) after: {}",
+                        operation,
+                        delay,
+                        attempt + 1,
+                        self.max_retries,
+                        e
+                    );
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                result => return result.map_err(cloud_error),
+            }
+        }
+    }
+
+    /// Use the profile in `AWS_PROFILE`, or the default profile, like the AWS CLI does
+    pub fn from_env() -> Result<Self> {
+        let profile = std::env::var("AWS_PROFILE")
+            .ok()
+            .filter(|profile| !profile.is_empty())
+            .unwrap_or_else(|| "default".to_string());
+        Self::from_profile(&profile)
+    }
+
+    /// Use the profile's credentials in its resolved region, see `resolve_region`
+    pub fn from_profile(profile: &str) -> Result<Self> {
+        Self::from_profile_in_region(profile, resolve_region(profile, None)?.region)
+    }
+
+    pub fn from_profile_in_region(profile: &str, region: Region) -> Result<Self> {
+        Self::from_profile_with_role(profile, region, None)
+    }
+
+    /// Use the profile's credentials, or with `role_arn` the credentials of a session
+    /// assuming that role, which is renewed whenever it expires
+    pub fn from_profile_with_role(
+        profile: &str,
+        region: Region,
+        role_arn: Option<&str>,
+    ) -> Result<Self> {
+        let credentials = ProfileCredentials::new(profile);
+        info!(
+            "AWS profile {} in region {}{}",
+            profile,
+            region.name(),
+            role_arn
+                .map(|role_arn| format!(", assuming {}", role_arn))
+                .unwrap_or_default()
+        );
+        // Regions already given an endpoint by the instance config keep it
+        let region = match (std::env::var(AWS_ENDPOINT_VAR), &region) {
+            (Ok(endpoint), Region::Custom { .. }) if !endpoint.is_empty() => region,
+            (Ok(endpoint), _) if !endpoint.is_empty() => region_with_endpoint(region, &endpoint),
+            _ => region,
+        };
+        let role_arn = match role_arn {
+            Some(role_arn) => role_arn,
+            None => return Self::with_credentials(credentials, region),
+        };
+        let sts = StsClient::new_with(shared_http_client()?, credentials, region.clone());
+        let session = StsAssumeRoleSessionCredentialsProvider::new(
+            sts,
+            role_arn.to_string(),
+            ROLE_SESSION_NAME.to_string(),
+            None,
+            None,
+            None,
+            None,
+        );
+        Self::with_credentials(Arc::new(AutoRefreshingProvider::new(session)?), region)
+    }
+
+    fn with_credentials<P>(credentials: P, region: Region) -> Result<Self>
+    where
+        P: ProvideAwsCredentials + Clone + Send + Sync + 'static,
+    {
+        let client =
+            Ec2Client::new_with(shared_http_client()?, credentials.clone(), region.clone());
+        let cloudwatch = CloudWatchClient::new_with(shared_http_client()?, credentials, region);
+        Ok(Self::new(client, cloudwatch))
+    }
+
+    async fn describe_instances(&self, filters: Option<Vec<Filter>>) -> Result<Vec<Instance>> {
+        let mut instances = Vec::new();
+        let mut next_token = None;
+        loop {
+            let req = DescribeInstancesRequest {
+                filters: filters.clone(),
+                next_token,
+                ..Default::default()
+            };
+            let res = self
+                .with_retry("describe_instances", || {
+                    self.client.describe_instances(req.clone())
+                })
+                .await?;
+            for inst in res
+                .reservations
+                .unwrap_or_default()
+                .into_iter()
+                .flat_map(|res| res.instances.unwrap_or_default())
+            {
+                instances.push(to_instance(inst)?);
+            }
+            next_token = res.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        Ok(instances)
+    }
+
+    /// The instance ID for a configured identifier, which is either an ID like
+    /// "i-0abc...", a unique prefix of one, or the value of the instance's Name tag
+    async fn resolve_id(&self, identifier: &str) -> Result<String> {
+        if is_full_ec2_id(identifier) {
+            return Ok(identifier.to_string());
+        }
+        Ok(self.get_instance(identifier).await?.instance_id)
+    }
+
+    async fn describe_raw_instance(&self, instance_id: &str) -> Result<rusoto_ec2::Instance> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = DescribeInstancesRequest {
+            instance_ids: Some(vec![instance_id.to_string()]),
+            ..Default::default()
+        };
+        timed("describe_instances", self.client.describe_instances(req))
+            .await?
+            .reservations
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|res| res.instances.unwrap_or_default())
+            .next()
+            .ok_or_else(|| Error::msg(format!("Could not find instance {}", instance_id)))
+    }
+
+    async fn root_volume_id(&self, instance_id: &str) -> Result<String> {
+        let instance = self.describe_raw_instance(instance_id).await?;
+        let root_device = instance.root_device_name.unwrap_or_default();
+        instance
+            .block_device_mappings
+            .unwrap_or_default()
+            .into_iter()
+            .find(|mapping| mapping.device_name.as_deref() == Some(root_device.as_str()))
+            .and_then(|mapping| mapping.ebs)
+            .and_then(|ebs| ebs.volume_id)
+            .ok_or_else(|| Error::msg(format!("Instance {} has no EBS root volume", instance_id)))
+    }
+
+    async fn create_tags(&self, resource_id: &str, tags: &[InstanceTag]) -> Result<()> {
+        let req = CreateTagsRequest {
+            resources: vec![resource_id.to_string()],
+            tags: to_ec2_tags(tags),
+            ..Default::default()
+        };
+        timed("create_tags", self.client.create_tags(req)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl InstanceManager for AwsCloud {
+    async fn list_instances(&self) -> Result<Vec<Instance>> {
+        self.describe_instances(None).await
+    }
+
+    async fn list_instances_filtered(&self, filters: &[ListFilter]) -> Result<Vec<Instance>> {
+        let filters = match filters.is_empty() {
+            true => None,
+            false => Some(filters.iter().map(Filter::from).collect()),
+        };
+        self.describe_instances(filters).await
+    }
+
+    async fn get_instance(&self, instance_id: &str) -> Result<Instance> {
+        if !instance_id.starts_with("i-") {
+            return self.get_instance_by_name(instance_id).await;
+        }
+        if !is_full_ec2_id(instance_id) {
+            // A Name tag can look like an ID prefix too, e.g. "i-dev"
+            let instances = self.list_instances().await?;
+            if instances
+                .iter()
+                .any(|inst| inst.instance_id.starts_with(instance_id))
+            {
+                let prefixed = instances
+                    .into_iter()
+                    .filter(|inst| inst.instance_id.starts_with(instance_id))
+                    .collect();
+                return single_prefixed(instance_id, prefixed);
+            }
+            let named = instances
+                .into_iter()
+                .filter(|inst| inst.name() == Some(instance_id))
+                .collect::<Vec<_>>();
+            if named.is_empty() {
+                return Err(Error::msg(format!(
+                    "No instance ID starts with '{}' and no instance is named that",
+                    instance_id
+                )));
+            }
+            return single_named(instance_id, named);
+        }
+        let instances = self
+            .describe_instances(Some(vec![filter!("instance-id", instance_id)]))
+            .await?;
+        match instances.len() {
+            0 => Err(Error::msg(format!(
+                "Could not find instance {}",
+                instance_id
+            ))),
+            _ => Ok(instances[0].clone()),
+        }
+    }
+
+    async fn get_instance_by_name(&self, name: &str) -> Result<Instance> {
+        let instances = self
+            .describe_instances(Some(vec![filter!("tag:Name", name)]))
+            .await?;
+        single_named(name, instances)
+    }
+
+    async fn start_instance(&self, instance_id: &str) -> Result<StateChange> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = StartInstancesRequest {
+            instance_ids: vec![instance_id.to_string()],
+            ..Default::default()
+        };
+        let res = self
+            .with_retry("start_instances", || {
+                self.client.start_instances(req.clone())
+            })
+            .await?;
+        to_state_change(instance_id, res.starting_instances)
+    }
+
+    async fn stop_instance(&self, instance_id: &str) -> Result<StateChange> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = StopInstancesRequest {
+            instance_ids: vec![instance_id.to_string()],
+            ..Default::default()
+        };
+        let res = self
+            .with_retry("stop_instances", || self.client.stop_instances(req.clone()))
+            .await?;
+        to_state_change(instance_id, res.stopping_instances)
+    }
+
+    async fn reboot_instance(&self, instance_id: &str) -> Result<StateChange> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        // RebootInstances returns no state, so read it around the call
+        let previous = self.get_instance(instance_id).await?.state;
+        let req = RebootInstancesRequest {
+            instance_ids: vec![instance_id.to_string()],
+            ..Default::default()
+        };
+        timed("reboot_instances", self.client.reboot_instances(req)).await?;
+        let current = self.get_instance(instance_id).await?.state;
+        Ok(StateChange { previous, current })
+    }
+
+    async fn terminate_instance(&self, instance_id: &str) -> Result<StateChange> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = TerminateInstancesRequest {
+            instance_ids: vec![instance_id.to_string()],
+            ..Default::default()
+        };
+        let res = timed("terminate_instances", self.client.terminate_instances(req)).await?;
+        to_state_change(instance_id, res.terminating_instances)
+    }
+
+    async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let value = AttributeValue {
+            value: Some(instance_type.to_string()),
+        };
+        let req = ModifyInstanceAttributeRequest {
+            instance_id: instance_id.to_string(),
+            instance_type: Some(value),
+            ..Default::default()
+        };
+        self.with_retry("modify_instance_attribute", || {
+            self.client.modify_instance_attribute(req.clone())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn add_tags(&self, instance_id: &str, tags: &[InstanceTag]) -> Result<()> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        self.create_tags(instance_id, tags).await
+    }
+
+    async fn remove_tags(&self, instance_id: &str, keys: &[String]) -> Result<()> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = DeleteTagsRequest {
+            resources: vec![instance_id.to_string()],
+            tags: Some(
+                keys.iter()
+                    .map(|key| Tag {
+                        key: Some(key.clone()),
+                        value: None,
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        timed("delete_tags", self.client.delete_tags(req)).await?;
+        Ok(())
+    }
+
+    async fn get_instance_events(&self, instance_id: &str) -> Result<Vec<InstanceEvent>> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = DescribeInstanceStatusRequest {
+            instance_ids: Some(vec![instance_id.to_string()]),
+            include_all_instances: Some(true),
+            ..Default::default()
+        };
+        let events = timed(
+            "describe_instance_status",
+            self.client.describe_instance_status(req),
+        )
+        .await?
+        .instance_statuses
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|status| status.events.unwrap_or_default())
+        .map(|event| InstanceEvent {
+            code: event.code.unwrap_or_default(),
+            description: event.description.unwrap_or_default(),
+            not_before: event.not_before,
+            not_after: event.not_after,
+        })
+        .collect();
+        Ok(events)
+    }
+
+    async fn get_console_output(&self, instance_id: &str, latest: bool) -> Result<String> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = GetConsoleOutputRequest {
+            instance_id: instance_id.to_string(),
+            latest: Some(latest),
+            ..Default::default()
+        };
+        let encoded = match timed("get_console_output", self.client.get_console_output(req))
+            .await?
+            .output
+        {
+            Some(encoded) => encoded,
+            None => return Ok(String::new()),
+        };
+        let output = base64::decode(encoded.trim()).map_err(|e| {
+            Error::msg(format!("Invalid console output for {}: {}", instance_id, e))
+        })?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    async fn set_volume_size(&self, instance_id: &str, size_gb: i64) -> Result<()> {
+        let volume_id = self.root_volume_id(instance_id).await?;
+        let req = ModifyVolumeRequest {
+            volume_id: volume_id.clone(),
+            size: Some(size_gb),
+            ..Default::default()
+        };
+        self.with_retry("modify_volume", || self.client.modify_volume(req.clone()))
+            .await?;
+        // The new size can be used once the modification is optimizing, long before it completes
+        let start = Instant::now();
+        loop {
+            let req = DescribeVolumesModificationsRequest {
+                volume_ids: Some(vec![volume_id.clone()]),
+                ..Default::default()
+            };
+            let modification = self
+                .with_retry("describe_volumes_modifications", || {
+                    self.client.describe_volumes_modifications(req.clone())
+                })
+                .await?
+                .volumes_modifications
+                .unwrap_or_default()
+                .into_iter()
+                .next();
+            let state = modification
+                .as_ref()
+                .and_then(|modification| modification.modification_state.clone())
+                .unwrap_or_default();
+            match state.as_str() {
+                "optimizing" | "completed" => return Ok(()),
+                "failed" => {
+                    return Err(Error::msg(format!(
+                        "Resizing volume {} failed: {}",
+                        volume_id,
+                        modification
+                            .and_then(|modification| modification.status_message)
+                            .unwrap_or_default()
+                    )))
+                }
+                _ => {}
+            }
+            if start.elapsed() >= VOLUME_MODIFY_TIMEOUT {
+                return Err(Error::msg(format!(
+                    "Timed out waiting for volume {} to resize (currently {})",
+                    volume_id, state
+                )));
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn get_cpu_utilization(&self, instance_id: &str, minutes: u32) -> Result<Option<f64>> {
+        let minutes = minutes.max(1);
+        // One period over the whole window, which CloudWatch needs in whole minutes
+        let window = Duration::from_secs(u64::from(minutes) * 60);
+        let averages = self
+            .get_metric_series(instance_id, InstanceMetric::Cpu, window, window)
+            .await?;
+        match averages.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(
+                averages.iter().map(|(_, value)| value).sum::<f64>() / averages.len() as f64,
+            )),
+        }
+    }
+
+    async fn get_metric_series(
+        &self,
+        instance_id: &str,
+        metric: InstanceMetric,
+        since: Duration,
+        period: Duration,
+    ) -> Result<Vec<(DateTime<Utc>, f64)>> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let end = Utc::now();
+        let start = end - chrono::Duration::from_std(since)?;
+        let req = GetMetricStatisticsInput {
+            namespace: "AWS/EC2".to_string(),
+            metric_name: metric.cloudwatch_name().to_string(),
+            dimensions: Some(vec![Dimension {
+                name: "InstanceId".to_string(),
+                value: instance_id.to_string(),
+            }]),
+            start_time: start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            end_time: end.to_rfc3339_opts(SecondsFormat::Secs, true),
+            // CloudWatch periods are whole minutes
+            period: (period.as_secs() as i64 / 60).max(1) * 60,
+            statistics: Some(vec![metric.statistic().to_string()]),
+            ..Default::default()
+        };
+        let mut series = timed(
+            "get_metric_statistics",
+            self.cloudwatch.get_metric_statistics(req),
+        )
+        .await?
+        .datapoints
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|point| {
+            let value = match metric {
+                InstanceMetric::Cpu => point.average,
+                InstanceMetric::NetworkIn | InstanceMetric::NetworkOut => point.sum,
+            }?;
+            let time = DateTime::parse_from_rfc3339(point.timestamp.as_deref()?).ok()?;
+            Some((time.with_timezone(&Utc), value))
+        })
+        .collect::<Vec<_>>();
+        // CloudWatch returns datapoints in no particular order
+        series.sort_by_key(|(time, _)| *time);
+        Ok(series)
+    }
+
+    async fn create_image(
+        &self,
+        instance_id: &str,
+        name: &str,
+        no_reboot: bool,
+        tags: &[InstanceTag],
+    ) -> Result<String> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let req = CreateImageRequest {
+            instance_id: instance_id.to_string(),
+            name: name.to_string(),
+            no_reboot: Some(no_reboot),
+            ..Default::default()
+        };
+        let image_id = timed("create_image", self.client.create_image(req))
+            .await?
+            .image_id
+            .ok_or_else(|| Error::msg("No image ID returned"))?;
+        self.create_tags(&image_id, tags).await?;
+        Ok(image_id)
+    }
+
+    async fn get_image_state(&self, image_id: &str) -> Result<String> {
+        let req = DescribeImagesRequest {
+            image_ids: Some(vec![image_id.to_string()]),
+            ..Default::default()
+        };
+        timed("describe_images", self.client.describe_images(req))
+            .await?
+            .images
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .and_then(|image| image.state)
+            .ok_or_else(|| Error::msg(format!("Image {} not found", image_id)))
+    }
+
+    async fn create_snapshot(
+        &self,
+        instance_id: &str,
+        description: &str,
+        tags: &[InstanceTag],
+    ) -> Result<String> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let volume_id = self.root_volume_id(instance_id).await?;
+        let req = CreateSnapshotRequest {
+            volume_id,
+            description: Some(description.to_string()),
+            tag_specifications: Some(vec![TagSpecification {
+                resource_type: Some("snapshot".to_string()),
+                tags: Some(to_ec2_tags(tags)),
+            }]),
+            ..Default::default()
+        };
+        timed("create_snapshot", self.client.create_snapshot(req))
+            .await?
+            .snapshot_id
+            .ok_or_else(|| Error::msg("No snapshot ID returned"))
+    }
+
+    async fn launch_instance(&self, spec: LaunchSpec) -> Result<Instance> {
+        let tag_specifications = match spec.tags.is_empty() {
+            true => None,
+            false => Some(vec![TagSpecification {
+                resource_type: Some("instance".to_string()),
+                tags: Some(to_ec2_tags(&spec.tags)),
+            }]),
+        };
+        let security_group_ids = match spec.security_group_ids.is_empty() {
+            true => None,
+            false => Some(spec.security_group_ids),
+        };
+        let is_spot = spec.spot.is_some();
+        let instance_market_options = spec.spot.map(|spot| {
+            // Only persistent requests can stop or hibernate instead of terminating
+            let spot_instance_type = match spot.interruption_behavior.as_deref() {
+                None | Some("terminate") => "one-time",
+                Some(_) => "persistent",
+            };
+            InstanceMarketOptionsRequest {
+                market_type: Some("spot".to_string()),
+                spot_options: Some(SpotMarketOptions {
+                    max_price: spot.max_price,
+                    instance_interruption_behavior: spot.interruption_behavior,
+                    spot_instance_type: Some(spot_instance_type.to_string()),
+                    ..Default::default()
+                }),
+            }
+        });
+        let req = RunInstancesRequest {
+            image_id: Some(spec.image_id),
+            instance_type: Some(spec.instance_type),
+            key_name: spec.key_name,
+            security_group_ids,
+            subnet_id: spec.subnet_id,
+            tag_specifications,
+            instance_market_options,
+            min_count: 1,
+            max_count: 1,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let result = self.client.run_instances(req).await;
+        log_call("run_instances", start, &result);
+        let inst = result
+            .map_err(|e| match spot_unavailable_code(&e).filter(|_| is_spot) {
+                Some(code) => Error::msg(format!(
+                    "The spot request can't be fulfilled right now ({}), try another \
+                     instance type or zone, a higher --max-price, or an on-demand launch",
+                    code
+                )),
+                None => cloud_error(e),
+            })?
+            .instances
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::msg("No instance returned from launch"))?;
+        let ipv6 = ec2_ipv6(&inst);
+        Ok(Instance {
+            instance_type: inst.instance_type.unwrap_or_default(),
+            instance_id: inst
+                .instance_id
+                .ok_or_else(|| Error::msg("No instance ID returned from launch"))?,
+            public_dns: inst.public_dns_name.unwrap_or_default(),
+            private_ip: inst.private_ip_address.unwrap_or_default(),
+            availability_zone: inst.placement.and_then(|p| p.availability_zone),
+            launch_time: inst.launch_time,
+            lifecycle: inst.instance_lifecycle,
+            ipv6,
+            state: inst
+                .state
+                .and_then(|state| state.name)
+                .unwrap_or_else(|| "pending".to_string()),
+            tags: spec.tags,
+        })
+    }
+
+    async fn launch_spec_from(&self, instance_id: &str) -> Result<LaunchSpec> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let inst = self.describe_raw_instance(instance_id).await?;
+        Ok(LaunchSpec {
+            image_id: inst.image_id.unwrap_or_default(),
+            instance_type: inst.instance_type.unwrap_or_default(),
+            key_name: inst.key_name,
+            security_group_ids: inst
+                .security_groups
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|group| group.group_id)
+                .collect(),
+            subnet_id: inst.subnet_id,
+            tags: Vec::new(),
+            spot: None,
+        })
+    }
+
+    async fn list_key_pairs(&self) -> Result<Vec<String>> {
+        let res = self
+            .with_retry("describe_key_pairs", || {
+                self.client
+                    .describe_key_pairs(DescribeKeyPairsRequest::default())
+            })
+            .await?;
+        let mut names = res
+            .key_pairs
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|pair| pair.key_name)
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn associate_elastic_ip(
+        &self,
+        instance_id: &str,
+        allocation_id: Option<&str>,
+    ) -> Result<ElasticIp> {
+        let instance_id = &self.resolve_id(instance_id).await?;
+        let (elastic_ip, allocated) = match allocation_id {
+            Some(allocation_id) => {
+                let req = DescribeAddressesRequest {
+                    allocation_ids: Some(vec![allocation_id.to_string()]),
+                    ..Default::default()
+                };
+                let public_ip = self
+                    .with_retry("describe_addresses", || {
+                        self.client.describe_addresses(req.clone())
+                    })
+                    .await?
+                    .addresses
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find_map(|address| address.public_ip)
+                    .ok_or_else(|| {
+                        Error::msg(format!("Could not find Elastic IP {}", allocation_id))
+                    })?;
+                let elastic_ip = ElasticIp {
+                    allocation_id: allocation_id.to_string(),
+                    public_ip,
+                };
+                (elastic_ip, false)
+            }
+            None => {
+                let req = AllocateAddressRequest {
+                    domain: Some("vpc".to_string()),
+                    ..Default::default()
+                };
+                let res = timed("allocate_address", self.client.allocate_address(req)).await?;
+                let elastic_ip = ElasticIp {
+                    allocation_id: res.allocation_id.unwrap_or_default(),
+                    public_ip: res.public_ip.unwrap_or_default(),
+                };
+                (elastic_ip, true)
+            }
+        };
+        let req = AssociateAddressRequest {
+            allocation_id: Some(elastic_ip.allocation_id.clone()),
+            instance_id: Some(instance_id.to_string()),
+            ..Default::default()
+        };
+        if let Err(e) = self
+            .with_retry("associate_address", || {
+                self.client.associate_address(req.clone())
+            })
+            .await
+        {
+            // Don't leave a new address allocated, since unassociated ones are billed
+            if allocated {
+                self.release_elastic_ip(&elastic_ip.allocation_id).await?;
+            }
+            return Err(e);
+        }
+        Ok(elastic_ip)
+    }
+
+    async fn release_elastic_ip(&self, allocation_id: &str) -> Result<()> {
+        let req = DescribeAddressesRequest {
+            allocation_ids: Some(vec![allocation_id.to_string()]),
+            ..Default::default()
+        };
+        let association_id = self
+            .with_retry("describe_addresses", || {
+                self.client.describe_addresses(req.clone())
+            })
+            .await?
+            .addresses
+            .unwrap_or_default()
+            .into_iter()
+            .find_map(|address| address.association_id);
+        if let Some(association_id) = association_id {
+            let req = DisassociateAddressRequest {
+                association_id: Some(association_id),
+                ..Default::default()
+            };
+            self.with_retry("disassociate_address", || {
+                self.client.disassociate_address(req.clone())
+            })
+            .await?;
+        }
+        let req = ReleaseAddressRequest {
+            allocation_id: Some(allocation_id.to_string()),
+            ..Default::default()
+        };
+        self.with_retry("release_address", || {
+            self.client.release_address(req.clone())
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn guess_ssh_user(&self, instance_id: &str) -> Result<Option<String>> {
+        let image_id = match self.describe_raw_instance(instance_id).await?.image_id {
+            Some(image_id) => image_id,
+            None => return Ok(None),
+        };
+        let req = DescribeImagesRequest {
+            image_ids: Some(vec![image_id]),
+            ..Default::default()
+        };
+        // Images that were since deregistered can't be described, which is inconclusive
+        let image = match timed("describe_images", self.client.describe_images(req))
+            .await?
+            .images
+        {
+            Some(images) if !images.is_empty() => images.into_iter().next().unwrap(),
+            _ => return Ok(None),
+        };
+        let text = [image.name, image.description, image.platform_details]
+            .iter()
+            .flatten()
+            .map(|field| field.to_lowercase())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(image_default_user(&text).map(String::from))
+    }
+
+    async fn get_security_groups(&self, instance_id: &str) -> Result<Vec<SecurityGroup>> {
+        let group_ids = self
+            .describe_raw_instance(instance_id)
+            .await?
+            .security_groups
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|group| group.group_id)
+            .collect::<Vec<_>>();
+        if group_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let req = DescribeSecurityGroupsRequest {
+            group_ids: Some(group_ids),
+            ..Default::default()
+        };
+        let res = self
+            .with_retry("describe_security_groups", || {
+                self.client.describe_security_groups(req.clone())
+            })
+            .await?;
+        Ok(res
+            .security_groups
+            .unwrap_or_default()
+            .into_iter()
+            .map(|group| SecurityGroup {
+                group_id: group.group_id.unwrap_or_default(),
+                name: group.group_name.unwrap_or_default(),
+                inbound: group
+                    .ip_permissions
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flat_map(to_security_rules)
+                    .collect(),
+            })
+            .collect())
+    }
+
+    async fn list_instance_types(&self, family: Option<&str>) -> Result<Vec<InstanceTypeSummary>> {
+        let filters = family.map(|family| vec![filter!("instance-type", format!("{}.*", family))]);
+        let mut types = Vec::new();
+        let mut next_token = None;
+        loop {
+            let req = DescribeInstanceTypesRequest {
+                filters: filters.clone(),
+                next_token,
+                ..Default::default()
+            };
+            let res = timed(
+                "describe_instance_types",
+                self.client.describe_instance_types(req),
+            )
+            .await?;
+            types.extend(
+                res.instance_types
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|info| InstanceTypeSummary {
+                        instance_type: info.instance_type.unwrap_or_default(),
+                        vcpus: info
+                            .v_cpu_info
+                            .and_then(|cpu| cpu.default_v_cpus)
+                            .unwrap_or_default(),
+                        memory_mib: info
+                            .memory_info
+                            .and_then(|mem| mem.size_in_mi_b)
+                            .unwrap_or_default(),
+                        network_performance: info
+                            .network_info
+                            .and_then(|net| net.network_performance)
+                            .unwrap_or_default(),
+                    }),
+            );
+            next_token = res.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        types.sort_by(|a, b| {
+            (a.vcpus, a.memory_mib, &a.instance_type).cmp(&(
+                b.vcpus,
+                b.memory_mib,
+                &b.instance_type,
+            ))
+        });
+        Ok(types)
+    }
+
+    async fn list_offered_types(&self, zone: Option<&str>) -> Result<Vec<String>> {
+        let (location_type, filters) = match zone {
+            Some(zone) => (
+                "availability-zone",
+                Some(vec![filter!("location", zone.to_string())]),
+            ),
+            None => ("region", None),
+        };
+        let mut types = Vec::new();
+        let mut next_token = None;
+        loop {
+            let req = DescribeInstanceTypeOfferingsRequest {
+                location_type: Some(location_type.to_string()),
+                filters: filters.clone(),
+                next_token,
+                ..Default::default()
+            };
+            let res = timed(
+                "describe_instance_type_offerings",
+                self.client.describe_instance_type_offerings(req),
+            )
+            .await?;
+            types.extend(
+                res.instance_type_offerings
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|offering| offering.instance_type),
+            );
+            next_token = res.next_token;
+            if next_token.is_none() {
+                break;
+            }
+        }
+        types.sort();
+        Ok(types)
+    }
+}
+
+/// One rule per source of an EC2 permission, with "-1" protocols and ports meaning all
+fn to_security_rules(permission: IpPermission) -> Vec<SecurityRule> {
+    let protocol = match permission.ip_protocol.as_deref() {
+        Some("-1") | None => "all".to_string(),
+        Some(protocol) => protocol.to_string(),
+    };
+    let ports = match (permission.from_port, permission.to_port) {
+        (Some(from), Some(to)) if from >= 0 && !(from == 0 && to == 65535) => Some((from, to)),
+        _ => None,
+    };
+    let sources = permission
+        .ip_ranges
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|range| range.cidr_ip)
+        .chain(
+            permission
+                .ipv_6_ranges
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|range| range.cidr_ipv_6),
+        )
+        .chain(
+            permission
+                .user_id_group_pairs
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|pair| pair.group_id),
+        )
+        .chain(
+            permission
+                .prefix_list_ids
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|prefix| prefix.prefix_list_id),
+        );
+    sources
+        .map(|source| SecurityRule {
+            protocol: protocol.clone(),
+            ports,
+            source,
+        })
+        .collect()
+}
+
+/// Run a cloud CLI (az, ...) and return its stdout. Its stderr is shown to the user.
+async fn run_cli(program: &str, args: &[String]) -> Result<Vec<u8>> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .await
+        .map_err(|e| Error::msg(format!("Could not run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(RemoteError::Cloud(Error::msg(format!(
+            "{} {} failed ({})",
+            program,
+            args.first().map(String::as_str).unwrap_or_default(),
+            output.status
+        )))
+        .into());
+    }
+    Ok(output.stdout)
+}
+
+/// Run a cloud CLI and parse its JSON output
+async fn run_cli_json<T: DeserializeOwned>(program: &str, args: &[String]) -> Result<T> {
+    let stdout = run_cli(program, args).await?;
+    serde_json::from_slice(&stdout)
+        .map_err(|e| Error::msg(format!("Invalid JSON from {}: {}", program, e)))
+}
+
+/// The last path segment of a Compute Engine resource URL
+fn gce_resource_name(url: &str) -> &str {
+    url.rsplit('/').next().unwrap_or(url)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GceAccessConfig {
+    nat_i_p: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GceNetworkInterface {
+    #[serde(default)]
+    network_i_p: String,
+    #[serde(default)]
+    access_configs: Vec<GceAccessConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GceInstance {
+    name: String,
+    machine_type: String,
+    status: String,
+    zone: String,
+    #[serde(default)]
+    network_interfaces: Vec<GceNetworkInterface>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    last_start_timestamp: Option<String>,
+    /// Changes when the labels do, and must be sent back to set them
+    #[serde(default)]
+    label_fingerprint: Option<String>,
+}
+
+/// The EC2 state name the rest of the tool compares against for a GCE status
+fn gce_state(status: &str) -> String {
+    match status {
+        "PROVISIONING" | "STAGING" | "REPAIRING" => "pending".to_string(),
+        "RUNNING" => "running".to_string(),
+        "STOPPING" | "SUSPENDING" => "stopping".to_string(),
+        "TERMINATED" | "SUSPENDED" => "stopped".to_string(),
+        other => other.to_lowercase(),
+    }
+}
+
+impl From<GceInstance> for Instance {
+    fn from(inst: GceInstance) -> Self {
+        let interface = inst.network_interfaces.into_iter().next();
+        let public_ip = interface
+            .as_ref()
+            .and_then(|iface| {
+                iface
+                    .access_configs
+                    .iter()
+                    .find_map(|ac| ac.nat_i_p.clone())
+            })
+            .unwrap_or_default();
+        Instance {
+            instance_type: gce_resource_name(&inst.machine_type).to_string(),
+            instance_id: inst.name,
+            public_dns: public_ip,
+            private_ip: interface.map(|iface| iface.network_i_p).unwrap_or_default(),
+            tags: inst
+                .labels
+                .into_iter()
+                .map(|(key, value)| InstanceTag { key, value })
+                .collect(),
+            state: gce_state(&inst.status),
+            availability_zone: Some(gce_resource_name(&inst.zone).to_string()),
+            launch_time: inst.last_start_timestamp,
+            lifecycle: None,
+            ipv6: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GceMachineType {
+    name: String,
+    guest_cpus: i64,
+    memory_mb: i64,
+}
+
+/// Base URL of the Compute Engine API
+const GCE_ENDPOINT: &str = "https://compute.googleapis.com/compute/v1";
+/// Where Google credentials are exchanged for an access token
+const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+/// The OAuth scope access tokens are requested for
+const GCE_SCOPE: &str = "https://www.googleapis.com/auth/compute";
+/// Environment variable naming a Google credentials file
+pub const GOOGLE_CREDENTIALS_VAR: &str = "GOOGLE_APPLICATION_CREDENTIALS";
+/// How long an operation like starting an instance may take to finish
+const GCE_OPERATION_TIMEOUT: Duration = Duration::from_secs(300);
+/// Items per page of Compute Engine listings, the most the API allows
+const GCE_PAGE_SIZE: u32 = 500;
+
+#[derive(Debug, Deserialize)]
+struct GceApiError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GceErrorResponse {
+    error: GceApiError,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct GceOperationErrors {
+    #[serde(default)]
+    errors: Vec<GceApiError>,
+}
+
+/// A zonal operation, e.g. starting an instance, which is "DONE" once it finishes
+#[derive(Debug, Deserialize)]
+struct GceOperation {
+    name: String,
+    status: String,
+    error: Option<GceOperationErrors>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GoogleToken {
+    access_token: String,
+}
+
+/// Application default credentials, as written by `gcloud auth application-default login`
+/// or downloaded as a service account key
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GoogleCredentials {
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+        quota_project_id: Option<String>,
+    },
+    ServiceAccount {
+        client_email: String,
+        private_key: String,
+        project_id: Option<String>,
+        token_uri: Option<String>,
+    },
+}
+
+/// The directory gcloud keeps its configuration in
+fn gcloud_config_dir() -> Option<std::path::PathBuf> {
+    match std::env::var_os("CLOUDSDK_CONFIG") {
+        Some(path) if !path.is_empty() => Some(path.into()),
+        _ => Some(dirs::home_dir()?.join(".config").join("gcloud")),
+    }
+}
+
+/// The project set in gcloud's active configuration
+fn gcloud_project() -> Option<String> {
+    if let Some(project) = std::env::var("CLOUDSDK_CORE_PROJECT")
+        .ok()
+        .filter(|project| !project.is_empty())
+    {
+        return Some(project);
+    }
+    let dir = gcloud_config_dir()?;
+    let active = std::fs::read_to_string(dir.join("active_config"))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "default".to_string());
+    let config = std::fs::read_to_string(
+        dir.join("configurations")
+            .join(format!("config_{}", active)),
+    )
+    .ok()?;
+    let mut section = "";
+    for line in config.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line;
+        } else if let (Some(("project", value)), "[core]") = (
+            line.split_once('=').map(|(k, v)| (k.trim(), v.trim())),
+            section,
+        ) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+impl GoogleCredentials {
+    /// The credentials in `GOOGLE_APPLICATION_CREDENTIALS`, or else gcloud's application
+    /// default credentials
+    fn load() -> Result<Self> {
+        let path = match std::env::var_os(GOOGLE_CREDENTIALS_VAR) {
+            Some(path) if !path.is_empty() => std::path::PathBuf::from(path),
+            _ => gcloud_config_dir()
+                .ok_or_else(|| Error::msg("Could not find home directory"))?
+                .join("application_default_credentials.json"),
+        };
+        let raw = std::fs::read_to_string(&path).map_err(|e| {
+            Error::msg(format!(
+                "No Google credentials found at {} ({}). Set {} to a service account key, \
+                 or run `gcloud auth application-default login`",
+                path.display(),
+                e,
+                GOOGLE_CREDENTIALS_VAR
+            ))
+        })?;
+        serde_json::from_str(&raw).map_err(|e| {
+            Error::msg(format!(
+                "Invalid Google credentials in {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    fn project(&self) -> Option<&str> {
+        match self {
+            GoogleCredentials::AuthorizedUser {
+                quota_project_id, ..
+            } => quota_project_id.as_deref(),
+            GoogleCredentials::ServiceAccount { project_id, .. } => project_id.as_deref(),
+        }
+    }
+
+    /// The request exchanging these credentials for an access token
+    fn token_request(&self) -> Result<HttpRequest> {
+        match self {
+            GoogleCredentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+                ..
+            } => Ok(HttpRequest::new("POST", GOOGLE_TOKEN_URL).form(&[
+                ("grant_type", "refresh_token"),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("refresh_token", refresh_token),
+            ])),
+            GoogleCredentials::ServiceAccount {
+                client_email,
+                private_key,
+                token_uri,
+                ..
+            } => {
+                let token_uri = token_uri.as_deref().unwrap_or(GOOGLE_TOKEN_URL);
+                let now = Utc::now().timestamp();
+                let claims = serde_json::json!({
+                    "iss": client_email,
+                    "scope": GCE_SCOPE,
+                    "aud": token_uri,
+                    "iat": now,
+                    "exp": now + 3600,
+                });
+                let assertion = signed_jwt(&claims, private_key)?;
+                Ok(HttpRequest::new("POST", token_uri).form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", &assertion),
+                ]))
+            }
+        }
+    }
+}
+
+/// A JWT of `claims` signed with RS256 by a PEM `private_key`, as service accounts
+/// authenticate with
+fn signed_jwt(claims: &serde_json::Value, private_key: &str) -> Result<String> {
+    let encode = |data: &[u8]| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+    let header = serde_json::json!({ "alg": "RS256", "typ": "JWT" });
+    let message = format!(
+        "{}.{}",
+        encode(header.to_string().as_bytes()),
+        encode(claims.to_string().as_bytes())
+    );
+    let sign = || -> std::result::Result<Vec<u8>, openssl::error::ErrorStack> {
+        let key = openssl::pkey::PKey::private_key_from_pem(private_key.as_bytes())?;
+        let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key)?;
+        signer.update(message.as_bytes())?;
+        signer.sign_to_vec()
+    };
+    let signature =
+        sign().map_err(|e| Error::msg(format!("Could not sign with the private key: {}", e)))?;
+    Ok(format!("{}.{}", message, encode(&signature)))
+}
+
+/// Google Compute Engine instances, managed through the Compute Engine API with the
+/// application default credentials. Instances are identified by name; the profile is the
+/// GCP project.
+pub struct GcpCloud {
+    project: String,
+    zone: Option<String>,
+    client: Box<dyn HttpSend>,
+    /// The access token, fetched on the first request
+    token: std::sync::Mutex<Option<String>>,
+}
+
+impl GcpCloud {
+    /// `zone` may be omitted, in which case it is looked up from the instance name
+    pub fn new(project: &str, zone: Option<&str>) -> Self {
+        Self::with_client(HyperClient::shared(), project, zone, None)
+    }
+
+    /// Send API requests through `client`, such as a stand-in returning canned responses,
+    /// with `token` as the access token if given
+    pub fn with_client<H: HttpSend + 'static>(
+        client: H,
+        project: &str,
+        zone: Option<&str>,
+        token: Option<String>,
+    ) -> Self {
+        Self {
+            project: project.to_string(),
+            zone: zone.map(str::to_string),
+            client: Box::new(client),
+            token: std::sync::Mutex::new(token),
+        }
+    }
+
+    /// An access token for the API, exchanged for the credentials once and then reused
+    async fn access_token(&self) -> Result<String> {
+        if let Some(token) = self.token.lock().unwrap().clone() {
+            return Ok(token);
+        }
+        let req = GoogleCredentials::load()?.token_request()?;
+        let res = self.client.send(req).await.map_err(RemoteError::Cloud)?;
+        if !res.is_success() {
+            return Err(RemoteError::Cloud(Error::msg(format!(
+                "Could not get a Google access token ({}): {}",
+                res.status,
+                String::from_utf8_lossy(&res.body)
+            )))
+            .into());
+        }
+        let token: GoogleToken = serde_json::from_slice(&res.body)
+            .map_err(|e| Error::msg(format!("Invalid Google access token: {}", e)))?;
+        *self.token.lock().unwrap() = Some(token.access_token.clone());
+        Ok(token.access_token)
+    }
+
+    /// The project to manage. "default" is the profile placeholder, so it falls back to
+    /// gcloud's configured project and then the credentials' own.
+    fn project(&self) -> Result<String> {
+        if !self.project.is_empty() && self.project != "default" {
+            return Ok(self.project.clone());
+        }
+        gcloud_project()
+            .or_else(|| {
+                GoogleCredentials::load()
+                    .ok()
+                    .and_then(|credentials| credentials.project().map(str::to_string))
+            })
+            .ok_or_else(|| {
+                Error::msg("No GCP project found, set the project as the instance's profile")
+            })
+    }
+
+    /// The URL of `path` within the project
+    fn url(&self, path: &str) -> Result<String> {
+        Ok(format!(
+            "{}/projects/{}{}",
+            GCE_ENDPOINT,
+            self.project()?,
+            path
+        ))
+    }
+
+    /// Call the API and parse its JSON response, failing with the API's error message
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let mut req = HttpRequest::new(method, url).bearer(&self.access_token().await?);
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+
+        let operation = format!("{} {}", method, req.path());
+        let start = Instant::now();
+        let res = match self.client.send(req).await {
+            Ok(res) => {
+                debug!("Compute Engine {} took {:?}", operation, start.elapsed());
+                res
+            }
+            Err(e) => {
+                debug!(
+                    "Compute Engine {} failed after {:?}: {}",
+                    operation,
+                    start.elapsed(),
+                    e
+                );
+                return Err(RemoteError::Cloud(e).into());
+            }
+        };
+        if !res.is_success() {
+            let message = serde_json::from_slice::<GceErrorResponse>(&res.body)
+                .map(|res| res.error.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&res.body).into_owned());
+            return Err(RemoteError::Cloud(Error::msg(format!(
+                "Compute Engine {} failed ({}): {}",
+                operation, res.status, message
+            )))
+            .into());
+        }
+        serde_json::from_slice(&res.body).map_err(|e| {
+            Error::msg(format!(
+                "Invalid JSON from Compute Engine {}: {}",
+                operation, e
+            ))
+        })
+    }
+
+    /// Every page of a listing at `path`, following `nextPageToken`
+    async fn pages(&self, path: &str, filter: Option<&str>) -> Result<Vec<serde_json::Value>> {
+        let url = self.url(path)?;
+        let max_results = GCE_PAGE_SIZE.to_string();
+        let mut pages = Vec::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut query = vec![("maxResults", max_results.as_str())];
+            if let Some(filter) = filter {
+                query.push(("filter", filter));
+            }
+            if let Some(token) = &page_token {
+                query.push(("pageToken", token));
+            }
+            let page: serde_json::Value =
+                self.request("GET", &with_query(&url, &query), None).await?;
+            page_token = page["nextPageToken"].as_str().map(str::to_string);
+            pages.push(page);
+            if page_token.is_none() {
+                return Ok(pages);
+            }
+        }
+    }
+
+    /// Instances across every zone, those named `name` if given
+    async fn list(&self, name: Option<&str>) -> Result<Vec<GceInstance>> {
+        let filter = name.map(|name| format!("name = \"{}\"", name));
+        let mut instances = Vec::new();
+        for page in self
+            .pages("/aggregated/instances", filter.as_deref())
+            .await?
+        {
+            // Zones without instances only carry a warning
+            for scoped in page["items"]
+                .as_object()
+                .into_iter()
+                .flat_map(|items| items.values())
+            {
+                if let Some(listed) = scoped.get("instances") {
+                    let listed: Vec<GceInstance> = serde_json::from_value(listed.clone())
+                        .map_err(|e| Error::msg(format!("Invalid instances from GCE: {}", e)))?;
+                    instances.extend(listed);
+                }
+            }
+        }
+        Ok(instances)
+    }
+
+    async fn describe(&self, name: &str) -> Result<GceInstance> {
+        match &self.zone {
+            Some(zone) => {
+                let url = self.url(&format!("/zones/{}/instances/{}", zone, name))?;
+                self.request("GET", &url, None).await
+            }
+            None => self
+                .list(Some(name))
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::msg(format!("No instance named {} found", name))),
+        }
+    }
+
+    async fn zone_of(&self, name: &str) -> Result<String> {
+        match &self.zone {
+            Some(zone) => Ok(zone.clone()),
+            None => Ok(gce_resource_name(&self.describe(name).await?.zone).to_string()),
+        }
+    }
+
+    /// Call an instance method like `start` in `zone` and wait for its operation to finish
+    async fn run_operation(
+        &self,
+        method: &str,
+        zone: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<()> {
+        let url = self.url(&format!("/zones/{}/instances/{}", zone, path))?;
+        let mut operation: GceOperation = self.request(method, &url, body).await?;
+        let start = Instant::now();
+        while operation.status != "DONE" {
+            if start.elapsed() > GCE_OPERATION_TIMEOUT {
+                return Err(Error::msg(format!(
+                    "Timed out after {:?} waiting for {} {}",
+                    GCE_OPERATION_TIMEOUT, method, path
+                )));
+            }
+            // Waiting returns once the operation is done, or after about two minutes
+            let url = self.url(&format!(
+                "/zones/{}/operations/{}/wait",
+                zone, operation.name
+            ))?;
+            operation = self.request("POST", &url, None).await?;
+        }
+        match operation
+            .error
+            .and_then(|errors| errors.errors.into_iter().next())
+        {
+            Some(error) => Err(RemoteError::Cloud(Error::msg(format!(
+                "Compute Engine {} {} failed: {}",
+                method, path, error.message
+            )))
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Run an instance action like start or stop, returning its state before and after
+    async fn change_state(&self, name: &str, action: &str) -> Result<StateChange> {
+        let previous: Instance = self.describe(name).await?.into();
+        let zone = previous.availability_zone.clone().unwrap_or_default();
+        match action {
+            "delete" => self.run_operation("DELETE", &zone, name, None).await?,
+            action => {
+                let path = format!("{}/{}", name, action);
+                self.run_operation("POST", &zone, &path, None).await?
+            }
+        }
+        let current = match action {
+            // Deleted instances can no longer be described
+            "delete" => "terminated".to_string(),
+            _ => self.get_instance(name).await?.state,
+        };
+        Ok(StateChange {
+            previous: previous.state,
+            current,
+        })
+    }
+
+    /// Replace the instance's labels with the result of `update`
+    async fn update_labels<F>(&self, name: &str, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut BTreeMap<String, String>),
+    {
+        let instance = self.describe(name).await?;
+        let zone = gce_resource_name(&instance.zone).to_string();
+        let mut labels = instance.labels;
+        update(&mut labels);
+        // The fingerprint makes the update fail if the labels changed in the meantime
+        let body = serde_json::json!({
+            "labels": labels,
+            "labelFingerprint": instance.label_fingerprint,
+        });
+        self.run_operation("POST", &zone, &format!("{}/setLabels", name), Some(body))
+            .await
+    }
+
+    fn unsupported<T>(&self, operation: &str) -> Result<T> {
+        Err(Error::msg(format!("{} is not supported on GCP", operation)))
+    }
+}
+
+#[async_trait]
+impl InstanceManager for GcpCloud {
+    async fn list_instances(&self) -> Result<Vec<Instance>> {
+        let instances = self.list(None).await?;
+        Ok(instances.into_iter().map(Instance::from).collect())
+    }
+
+    async fn get_instance(&self, instance_id: &str) -> Result<Instance> {
+        Ok(self.describe(instance_id).await?.into())
+    }
+
+    async fn start_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "start").await
+    }
+
+    async fn stop_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "stop").await
+    }
+
+    async fn reboot_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "reset").await
+    }
+
+    async fn terminate_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "delete").await
+    }
+
+    async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()> {
+        let zone = self.zone_of(instance_id).await?;
+        let body = serde_json::json!({
+            "machineType": format!("zones/{}/machineTypes/{}", zone, instance_type),
+        });
+        let path = format!("{}/setMachineType", instance_id);
+        self.run_operation("POST", &zone, &path, Some(body)).await
+    }
+
+    async fn add_tags(&self, instance_id: &str, tags: &[InstanceTag]) -> Result<()> {
+        self.update_labels(instance_id, |labels| {
+            for tag in tags {
+                labels.insert(tag.key.clone(), tag.value.clone());
+            }
+        })
+        .await
+    }
+
+    async fn remove_tags(&self, instance_id: &str, keys: &[String]) -> Result<()> {
+        self.update_labels(instance_id, |labels| {
+            labels.retain(|key, _| !keys.contains(key));
+        })
+        .await
+    }
+
+    async fn get_instance_events(&self, _instance_id: &str) -> Result<Vec<InstanceEvent>> {
+        self.unsupported("Listing events")
+    }
+
+    async fn get_console_output(&self, name: &str, _latest: bool) -> Result<String> {
+        // The serial port output is always read up to the latest line
+        let zone = self.zone_of(name).await?;
+        let url = self.url(&format!("/zones/{}/instances/{}/serialPort", zone, name))?;
+        let output: serde_json::Value = self.request("GET", &url, None).await?;
+        Ok(output["contents"].as_str().unwrap_or_default().to_string())
+    }
+
+    async fn get_cpu_utilization(&self, _instance_id: &str, _minutes: u32) -> Result<Option<f64>> {
+        self.unsupported("Reading CPU metrics")
+    }
+
+    async fn set_volume_size(&self, _instance_id: &str, _size_gb: i64) -> Result<()> {
+        self.unsupported("Resizing disks")
+    }
+
+    async fn create_image(
+        &self,
+        _instance_id: &str,
+        _name: &str,
+        _no_reboot: bool,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn get_image_state(&self, _image_id: &str) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn create_snapshot(
+        &self,
+        _instance_id: &str,
+        _description: &str,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating snapshots")
+    }
+
+    async fn launch_instance(&self, _spec: LaunchSpec) -> Result<Instance> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn launch_spec_from(&self, _instance_id: &str) -> Result<LaunchSpec> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn list_key_pairs(&self) -> Result<Vec<String>> {
+        self.unsupported("Listing key pairs")
+    }
+
+    async fn associate_elastic_ip(
+        &self,
+        _instance_id: &str,
+        _allocation_id: Option<&str>,
+    ) -> Result<ElasticIp> {
+        self.unsupported("Associating Elastic IPs")
+    }
+
+    async fn release_elastic_ip(&self, _allocation_id: &str) -> Result<()> {
+        self.unsupported("Releasing Elastic IPs")
+    }
+
+    async fn get_security_groups(&self, _instance_id: &str) -> Result<Vec<SecurityGroup>> {
+        self.unsupported("Listing security groups")
+    }
+
+    async fn list_instance_types(&self, family: Option<&str>) -> Result<Vec<InstanceTypeSummary>> {
+        let zone = self
+            .zone
+            .as_deref()
+            .ok_or_else(|| Error::msg("Listing GCP machine types needs an instance zone"))?;
+        let filter = family.map(|family| format!("name eq {}-.*", family));
+        let mut machine_types = Vec::new();
+        for mut page in self
+            .pages(&format!("/zones/{}/machineTypes", zone), filter.as_deref())
+            .await?
+        {
+            // Pages without matches have no items at all
+            if let Some(items) = page.get_mut("items") {
+                let listed: Vec<GceMachineType> = serde_json::from_value(items.take())
+                    .map_err(|e| Error::msg(format!("Invalid machine types from GCE: {}", e)))?;
+                machine_types.extend(listed);
+            }
+        }
+        let mut types: Vec<InstanceTypeSummary> = machine_types
+            .into_iter()
+            .map(|machine| InstanceTypeSummary {
+                instance_type: machine.name,
+                vcpus: machine.guest_cpus,
+                memory_mib: machine.memory_mb,
+                network_performance: String::new(),
+            })
+            .collect();
+        types.sort_by(|a, b| {
+            (a.vcpus, a.memory_mib, &a.instance_type).cmp(&(
+                b.vcpus,
+                b.memory_mib,
+                &b.instance_type,
+            ))
+        });
+        Ok(types)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureHardwareProfile {
+    vm_size: String,
+}
+
+/// A VM as printed by `az vm show --show-details`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureVm {
+    name: String,
+    resource_group: String,
+    location: String,
+    hardware_profile: AzureHardwareProfile,
+    #[serde(default)]
+    power_state: Option<String>,
+    #[serde(default)]
+    public_ips: Option<String>,
+    #[serde(default)]
+    private_ips: Option<String>,
+    #[serde(default)]
+    fqdns: Option<String>,
+    #[serde(default)]
+    tags: Option<BTreeMap<String, String>>,
+}
+
+/// The EC2 state name the rest of the tool compares against for an Azure power state
+fn azure_state(power_state: Option<&str>) -> String {
+    match power_state {
+        Some("VM starting") => "pending".to_string(),
+        Some("VM running") => "running".to_string(),
+        Some("VM stopping") | Some("VM deallocating") => "stopping".to_string(),
+        Some("VM stopped") | Some("VM deallocated") => "stopped".to_string(),
+        Some(other) => other.trim_start_matches("VM ").to_lowercase(),
+        None => "unknown".to_string(),
+    }
+}
+
+/// The first address of a comma separated `az` address list
+fn first_address(addresses: Option<String>) -> String {
+    addresses
+        .unwrap_or_default()
+        .split(',')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+impl From<AzureVm> for Instance {
+    fn from(vm: AzureVm) -> Self {
+        let public_dns = match vm.fqdns.as_deref() {
+            Some(fqdns) if !fqdns.is_empty() => first_address(vm.fqdns),
+            _ => first_address(vm.public_ips),
+        };
+        Instance {
+            instance_type: vm.hardware_profile.vm_size,
+            instance_id: vm.name,
+            public_dns,
+            private_ip: first_address(vm.private_ips),
+            tags: vm
+                .tags
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(key, value)| InstanceTag { key, value })
+                .collect(),
+            state: azure_state(vm.power_state.as_deref()),
+            availability_zone: Some(vm.location),
+            launch_time: None,
+            lifecycle: None,
+            ipv6: None,
+        }
+    }
+}
+
+/// Azure VMs, managed through the `az` CLI and its login. VMs are identified by
+/// resource group and name; the profile is the subscription.
+pub struct AzureCloud {
+    subscription: String,
+    resource_group: Option<String>,
+}
+
+impl AzureCloud {
+    /// `resource_group` may be omitted to list VMs across the whole subscription
+    pub fn new(subscription: &str, resource_group: Option<&str>) -> Self {
+        Self {
+            subscription: subscription.to_string(),
+            resource_group: resource_group.map(str::to_string),
+        }
+    }
+
+    /// Arguments for `az vm <args>` against the subscription
+    fn args(&self, args: &[&str]) -> Vec<String> {
+        let mut all: Vec<String> = std::iter::once("vm")
+            .chain(args.iter().copied())
+            .map(str::to_string)
+            .collect();
+        // "default" is the profile placeholder, so fall back to az's active subscription
+        if !self.subscription.is_empty() && self.subscription != "default" {
+            all.push(format!("--subscription={}", self.subscription));
+        }
+        all.push("--output=json".to_string());
+        all
+    }
+
+    async fn describe(&self, name: &str) -> Result<AzureVm> {
+        match &self.resource_group {
+            Some(group) => {
+                run_cli_json(
+                    "az",
+                    &self.args(&["show", "--show-details", "-g", group, "-n", name]),
+                )
+                .await
+            }
+            None => {
+                let vms: Vec<AzureVm> =
+                    run_cli_json("az", &self.args(&["list", "--show-details"])).await?;
+                vms.into_iter()
+                    .find(|vm| vm.name == name)
+                    .ok_or_else(|| Error::msg(format!("No VM named {} found", name)))
+            }
+        }
+    }
+
+    /// Run a VM action like start or deallocate, returning its power state before and after
+    async fn change_state(&self, name: &str, action: &[&str]) -> Result<StateChange> {
+        let previous = self.describe(name).await?;
+        let group = previous.resource_group.clone();
+        let mut args = action.to_vec();
+        args.extend(["-g", &group, "-n", name]);
+        run_cli("az", &self.args(&args)).await?;
+        let current = match action[0] {
+            // Deleted VMs can no longer be described
+            "delete" => "terminated".to_string(),
+            _ => self.get_instance(name).await?.state,
+        };
+        Ok(StateChange {
+            previous: azure_state(previous.power_state.as_deref()),
+            current,
+        })
+    }
+
+    /// Run `az vm update` on the VM with these generic update arguments
+    async fn update(&self, name: &str, updates: Vec<String>) -> Result<()> {
+        let group = self.describe(name).await?.resource_group;
+        let mut args = vec!["update", "-g", &group, "-n", name];
+        args.extend(updates.iter().map(String::as_str));
+        run_cli("az", &self.args(&args)).await?;
+        Ok(())
+    }
+
+    fn unsupported<T>(&self, operation: &str) -> Result<T> {
+        Err(Error::msg(format!(
+            "{} is not supported on Azure",
+            operation
+        )))
+    }
+}
+
+#[async_trait]
+impl InstanceManager for AzureCloud {
+    async fn list_instances(&self) -> Result<Vec<Instance>> {
+        let mut args = vec!["list", "--show-details"];
+        if let Some(group) = &self.resource_group {
+            args.extend(["-g", group]);
+        }
+        let vms: Vec<AzureVm> = run_cli_json("az", &self.args(&args)).await?;
+        Ok(vms.into_iter().map(Instance::from).collect())
+    }
+
+    async fn get_instance(&self, instance_id: &str) -> Result<Instance> {
+        Ok(self.describe(instance_id).await?.into())
+    }
+
+    async fn start_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, &["start"]).await
+    }
+
+    async fn stop_instance(&self, instance_id: &str) -> Result<StateChange> {
+        // Deallocate rather than stop, a stopped but allocated VM is still billed
+        self.change_state(instance_id, &["deallocate"]).await
+    }
+
+    async fn reboot_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, &["restart"]).await
+    }
+
+    async fn terminate_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, &["delete", "--yes"]).await
+    }
+
+    async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()> {
+        let group = self.describe(instance_id).await?.resource_group;
+        run_cli(
+            "az",
+            &self.args(&[
+                "resize",
+                "-g",
+                &group,
+                "-n",
+                instance_id,
+                "--size",
+                instance_type,
+            ]),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn add_tags(&self, instance_id: &str, tags: &[InstanceTag]) -> Result<()> {
+        let updates = tags
+            .iter()
+            .flat_map(|tag| {
+                vec![
+                    "--set".to_string(),
+                    format!("tags.{}={}", tag.key, tag.value),
+                ]
+            })
+            .collect();
+        self.update(instance_id, updates).await
+    }
+
+    async fn remove_tags(&self, instance_id: &str, keys: &[String]) -> Result<()> {
+        let updates = keys
+            .iter()
+            .flat_map(|key| vec!["--remove".to_string(), format!("tags.{}", key)])
+            .collect();
+        self.update(instance_id, updates).await
+    }
+
+    async fn get_instance_events(&self, _instance_id: &str) -> Result<Vec<InstanceEvent>> {
+        self.unsupported("Listing events")
+    }
+
+    async fn get_console_output(&self, _instance_id: &str, _latest: bool) -> Result<String> {
+        self.unsupported("Reading console output")
+    }
+
+    async fn get_cpu_utilization(&self, _instance_id: &str, _minutes: u32) -> Result<Option<f64>> {
+        self.unsupported("Reading CPU metrics")
+    }
+
+    async fn set_volume_size(&self, _instance_id: &str, _size_gb: i64) -> Result<()> {
+        self.unsupported("Resizing disks")
+    }
+
+    async fn create_image(
+        &self,
+        _instance_id: &str,
+        _name: &str,
+        _no_reboot: bool,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn get_image_state(&self, _image_id: &str) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn create_snapshot(
+        &self,
+        _instance_id: &str,
+        _description: &str,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating snapshots")
+    }
+
+    async fn launch_instance(&self, _spec: LaunchSpec) -> Result<Instance> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn launch_spec_from(&self, _instance_id: &str) -> Result<LaunchSpec> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn list_key_pairs(&self) -> Result<Vec<String>> {
+        self.unsupported("Listing key pairs")
+    }
+
+    async fn associate_elastic_ip(
+        &self,
+        _instance_id: &str,
+        _allocation_id: Option<&str>,
+    ) -> Result<ElasticIp> {
+        self.unsupported("Associating Elastic IPs")
+    }
+
+    async fn release_elastic_ip(&self, _allocation_id: &str) -> Result<()> {
+        self.unsupported("Releasing Elastic IPs")
+    }
+
+    async fn get_security_groups(&self, _instance_id: &str) -> Result<Vec<SecurityGroup>> {
+        self.unsupported("Listing security groups")
+    }
+
+    async fn list_instance_types(&self, _family: Option<&str>) -> Result<Vec<InstanceTypeSummary>> {
+        self.unsupported("Listing instance types")
+    }
+}
+
+/// Environment variable holding a DigitalOcean API token
+pub const DIGITALOCEAN_TOKEN_VAR: &str = "DIGITALOCEAN_TOKEN";
+
+#[derive(Debug, Deserialize)]
+struct DropletAddress {
+    ip_address: String,
+    #[serde(rename = "type")]
+    type_: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DropletNetworks {
+    #[serde(default)]
+    v4: Vec<DropletAddress>,
+    #[serde(default)]
+    v6: Vec<DropletAddress>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DropletRegion {
+    slug: String,
+}
+
+/// A droplet as returned by the DigitalOcean API
+#[derive(Debug, Deserialize)]
+struct Droplet {
+    id: u64,
+    name: String,
+    status: String,
+    size_slug: String,
+    #[serde(default)]
+    networks: DropletNetworks,
+    region: Option<DropletRegion>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DropletSize {
+    slug: String,
+    vcpus: i64,
+    memory: i64,
+    transfer: f64,
+}
+
+/// The EC2 state name the rest of the tool compares against for a droplet status
+fn droplet_state(status: &str) -> String {
+    match status {
+        "new" => "pending".to_string(),
+        "active" => "running".to_string(),
+        "off" => "stopped".to_string(),
+        "archive" => "terminated".to_string(),
+        other => other.to_string(),
+    }
+}
+
+impl From<Droplet> for Instance {
+    fn from(droplet: Droplet) -> Self {
+        let address = |kind: &str| {
+            droplet
+                .networks
+                .v4
+                .iter()
+                .find(|addr| addr.type_ == kind)
+                .map(|addr| addr.ip_address.clone())
+                .unwrap_or_default()
+        };
+        let public_dns = address("public");
+        let private_ip = address("private");
+        let ipv6 = droplet
+            .networks
+            .v6
+            .iter()
+            .find(|addr| addr.type_ == "public")
+            .map(|addr| addr.ip_address.clone());
+        // Droplet tags are plain names, so "key:value" is read as a key and value
+        let mut tags = vec![InstanceTag {
+            key: "Name".to_string(),
+            value: droplet.name,
+        }];
+        tags.extend(
+            droplet
+                .tags
+                .into_iter()
+                .map(|tag| match tag.split_once(':') {
+                    Some((key, value)) => InstanceTag {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    },
+                    None => InstanceTag {
+                        key: tag,
+                        value: String::new(),
+                    },
+                }),
+        );
+        Instance {
+            instance_type: droplet.size_slug,
+            instance_id: droplet.id.to_string(),
+            public_dns,
+            private_ip,
+            tags,
+            state: droplet_state(&droplet.status),
+            availability_zone: droplet.region.map(|region| region.slug),
+            launch_time: None,
+            lifecycle: None,
+            ipv6,
+        }
+    }
+}
+
+/// Base URL of the DigitalOcean API
+const DIGITALOCEAN_ENDPOINT: &str = "https://api.digitalocean.com";
+/// How long a droplet action like power_on may take to finish
+const DIGITALOCEAN_ACTION_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often a running droplet action is checked on
+const DIGITALOCEAN_ACTION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Items per page of DigitalOcean listings, the most the API allows
+const DIGITALOCEAN_PAGE_SIZE: u32 = 200;
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanErrorResponse {
+    message: String,
+}
+
+/// An asynchronous droplet action, e.g. a power_on, which is "in-progress" until it finishes
+#[derive(Debug, Deserialize)]
+struct DropletAction {
+    id: u64,
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DropletActionResponse {
+    action: DropletAction,
+}
+
+#[derive(Debug, Deserialize)]
+struct DropletResponse {
+    droplet: Droplet,
+}
+
+/// The token of a `doctl` auth context, or of the current one for the "default" profile,
+/// so droplets set up for the CLI work without another token
+fn doctl_context_token(context: &str) -> Option<String> {
+    let path = dirs::config_dir()?.join("doctl").join("config.yaml");
+    let config: serde_yaml::Value =
+        serde_yaml::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    let name = match context {
+        "" | "default" => config
+            .get("context")
+            .and_then(|name| name.as_str())
+            .unwrap_or("default"),
+        name => name,
+    };
+    // doctl keeps the token of its own "default" context at the top level
+    let token = match name {
+        "default" => config.get("access-token"),
+        name => config.get("auth-contexts")?.get(name),
+    };
+    token?.as_str().map(str::to_string)
+}
+
+/// DigitalOcean droplets, managed through the DigitalOcean API. Droplets are identified
+/// by numeric ID. Without a `token` the profile names a `doctl` auth context whose token
+/// is used.
+pub struct DigitalOceanCloud {
+    client: Box<dyn HttpSend>,
+    token: Option<String>,
+}
+
+impl DigitalOceanCloud {
+    pub fn new(context: &str, token: Option<String>) -> Self {
+        let token = token.or_else(|| doctl_context_token(context));
+        Self::with_client(HyperClient::shared(), token)
+    }
+
+    /// Send API requests through `client`, such as a stand-in returning canned responses
+    pub fn with_client<H: HttpSend + 'static>(client: H, token: Option<String>) -> Self {
+        Self {
+            client: Box::new(client),
+            token,
+        }
+    }
+
+    /// Call the API and return its response body, failing with the API's error message.
+    /// Deleting a droplet responds with no body at all.
+    async fn send(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Vec<u8>> {
+        let token = self.token.as_deref().ok_or_else(|| {
+            Error::msg(format!(
+                "No DigitalOcean token found. Set {}, digitalocean_token in the config, \
+                 or create a doctl auth context",
+                DIGITALOCEAN_TOKEN_VAR
+            ))
+        })?;
+        let mut req = HttpRequest::new(method, url).bearer(token);
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+
+        let operation = format!("{} {}", method, req.path());
+        let start = Instant::now();
+        let res = match self.client.send(req).await {
+            Ok(res) => {
+                debug!("DigitalOcean {} took {:?}", operation, start.elapsed());
+                res
+            }
+            Err(e) => {
+                debug!(
+                    "DigitalOcean {} failed after {:?}: {}",
+                    operation,
+                    start.elapsed(),
+                    e
+                );
+                return Err(RemoteError::Cloud(e).into());
+            }
+        };
+        if !res.is_success() {
+            let message = serde_json::from_slice::<DigitalOceanErrorResponse>(&res.body)
+                .map(|res| res.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&res.body).into_owned());
+            return Err(RemoteError::Cloud(Error::msg(format!(
+                "DigitalOcean {} failed ({}): {}",
+                operation, res.status, message
+            )))
+            .into());
+        }
+        Ok(res.body)
+    }
+
+    /// Call the API at `path` and parse its JSON response
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let url = format!("{}{}", DIGITALOCEAN_ENDPOINT, path);
+        let body = self.send(method, &url, body).await?;
+        serde_json::from_slice(&body).map_err(|e| {
+            Error::msg(format!(
+                "Invalid JSON from DigitalOcean {} {}: {}",
+                method, path, e
+            ))
+        })
+    }
+
+    /// Every item under `field` in a paginated listing
+    async fn list_all<T: DeserializeOwned>(&self, path: &str, field: &str) -> Result<Vec<T>> {
+        let per_page = DIGITALOCEAN_PAGE_SIZE.to_string();
+        let first = with_query(
+            &format!("{}{}", DIGITALOCEAN_ENDPOINT, path),
+            &[("per_page", per_page.as_str())],
+        );
+        let mut items = Vec::new();
+        let mut next = Some(first);
+        while let Some(url) = next {
+            let body = self.send("GET", &url, None).await?;
+            let mut res: serde_json::Value = serde_json::from_slice(&body)
+                .map_err(|e| Error::msg(format!("Invalid JSON from DigitalOcean: {}", e)))?;
+            let listed: Vec<T> = serde_json::from_value(res[field].take())
+                .map_err(|e| Error::msg(format!("Invalid {} from DigitalOcean: {}", field, e)))?;
+            items.extend(listed);
+            // The API links to the next page in full, keeping the page size
+            next = res["links"]["pages"]["next"].as_str().map(str::to_string);
+        }
+        Ok(items)
+    }
+
+    async fn describe(&self, id: &str) -> Result<Droplet> {
+        let path = format!("/v2/droplets/{}", id);
+        let res: DropletResponse = self.request("GET", &path, None).await?;
+        Ok(res.droplet)
+    }
+
+    /// Run a droplet action like power_on and wait for it to finish, as `doctl --wait` does
+    async fn run_action(&self, id: &str, action: serde_json::Value) -> Result<()> {
+        let path = format!("/v2/droplets/{}/actions", id);
+        let kind = action["type"].as_str().unwrap_or_default().to_string();
+        let res: DropletActionResponse = self.request("POST", &path, Some(action)).await?;
+        let mut action = res.action;
+        let start = Instant::now();
+        loop {
+            match action.status.as_str() {
+                "completed" => return Ok(()),
+                "errored" => {
+                    return Err(RemoteError::Cloud(Error::msg(format!(
+                        "DigitalOcean {} of droplet {} failed",
+                        kind, id
+                    )))
+                    .into())
+                }
+                _ if start.elapsed() > DIGITALOCEAN_ACTION_TIMEOUT => {
+                    return Err(Error::msg(format!(
+                        "Timed out after {:?} waiting for {} of droplet {}",
+                        DIGITALOCEAN_ACTION_TIMEOUT, kind, id
+                    )))
+                }
+                _ => tokio::time::delay_for(DIGITALOCEAN_ACTION_POLL_INTERVAL).await,
+            }
+            let path = format!("/v2/actions/{}", action.id);
+            let res: DropletActionResponse = self.request("GET", &path, None).await?;
+            action = res.action;
+        }
+    }
+
+    /// Run a droplet action and wait for it, returning the state before and after
+    async fn change_state(&self, id: &str, action: &str) -> Result<StateChange> {
+        let previous = self.describe(id).await?;
+        self.run_action(id, serde_json::json!({ "type": action }))
+            .await?;
+        Ok(StateChange {
+            previous: droplet_state(&previous.status),
+            current: self.get_instance(id).await?.state,
+        })
+    }
+
+    fn unsupported<T>(&self, operation: &str) -> Result<T> {
+        Err(Error::msg(format!(
+            "{} is not supported on DigitalOcean",
+            operation
+        )))
+    }
+}
+
+#[async_trait]
+impl InstanceManager for DigitalOceanCloud {
+    async fn list_instances(&self) -> Result<Vec<Instance>> {
+        let droplets: Vec<Droplet> = self.list_all("/v2/droplets", "droplets").await?;
+        Ok(droplets.into_iter().map(Instance::from).collect())
+    }
+
+    async fn get_instance(&self, instance_id: &str) -> Result<Instance> {
+        Ok(self.describe(instance_id).await?.into())
+    }
+
+    async fn start_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "power_on").await
+    }
+
+    async fn stop_instance(&self, instance_id: &str) -> Result<StateChange> {
+        // Shut down cleanly rather than cutting the power
+        self.change_state(instance_id, "shutdown").await
+    }
+
+    async fn reboot_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "reboot").await
+    }
+
+    async fn terminate_instance(&self, instance_id: &str) -> Result<StateChange> {
+        let previous = self.describe(instance_id).await?;
+        let url = format!("{}/v2/droplets/{}", DIGITALOCEAN_ENDPOINT, instance_id);
+        self.send("DELETE", &url, None).await?;
+        Ok(StateChange {
+            previous: droplet_state(&previous.status),
+            current: "terminated".to_string(),
+        })
+    }
+
+    async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()> {
+        let droplet = self.describe(instance_id).await?;
+        if droplet.status != "off" {
+            return Err(Error::msg(format!(
+                "Droplet {} must be powered off to resize, it is {}",
+                instance_id,
+                droplet_state(&droplet.status)
+            )));
+        }
+        // Without resizing the disk only CPU and memory change, so the resize can be undone
+        let resize = serde_json::json!({ "type": "resize", "size": instance_type, "disk": false });
+        self.run_action(instance_id, resize).await
+    }
+
+    async fn add_tags(&self, _instance_id: &str, _tags: &[InstanceTag]) -> Result<()> {
+        self.unsupported("Tagging instances")
+    }
+
+    async fn remove_tags(&self, _instance_id: &str, _keys: &[String]) -> Result<()> {
+        self.unsupported("Tagging instances")
+    }
+
+    async fn get_instance_events(&self, _instance_id: &str) -> Result<Vec<InstanceEvent>> {
+        self.unsupported("Listing events")
+    }
+
+    async fn get_console_output(&self, _instance_id: &str, _latest: bool) -> Result<String> {
+        self.unsupported("Reading console output")
+    }
+
+    async fn get_cpu_utilization(&self, _instance_id: &str, _minutes: u32) -> Result<Option<f64>> {
+        self.unsupported("Reading CPU metrics")
+    }
+
+    async fn set_volume_size(&self, _instance_id: &str, _size_gb: i64) -> Result<()> {
+        self.unsupported("Resizing disks")
+    }
+
+    async fn create_image(
+        &self,
+        _instance_id: &str,
+        _name: &str,
+        _no_reboot: bool,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn get_image_state(&self, _image_id: &str) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn create_snapshot(
+        &self,
+        _instance_id: &str,
+        _description: &str,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating snapshots")
+    }
+
+    async fn launch_instance(&self, _spec: LaunchSpec) -> Result<Instance> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn launch_spec_from(&self, _instance_id: &str) -> Result<LaunchSpec> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn list_key_pairs(&self) -> Result<Vec<String>> {
+        self.unsupported("Listing key pairs")
+    }
+
+    async fn associate_elastic_ip(
+        &self,
+        _instance_id: &str,
+        _allocation_id: Option<&str>,
+    ) -> Result<ElasticIp> {
+        self.unsupported("Associating Elastic IPs")
+    }
+
+    async fn release_elastic_ip(&self, _allocation_id: &str) -> Result<()> {
+        self.unsupported("Releasing Elastic IPs")
+    }
+
+    async fn get_security_groups(&self, _instance_id: &str) -> Result<Vec<SecurityGroup>> {
+        self.unsupported("Listing security groups")
+    }
+
+    async fn list_instance_types(&self, family: Option<&str>) -> Result<Vec<InstanceTypeSummary>> {
+        let sizes: Vec<DropletSize> = self.list_all("/v2/sizes", "sizes").await?;
+        Ok(sizes
+            .into_iter()
+            .filter(|size| {
+                family.is_none_or(|family| size.slug.starts_with(&format!("{}-", family)))
+            })
+            .map(|size| InstanceTypeSummary {
+                instance_type: size.slug,
+                vcpus: size.vcpus,
+                memory_mib: size.memory,
+                network_performance: format!("{} TB transfer", size.transfer),
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerAddress {
+    ip: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HetznerPublicNet {
+    ipv4: Option<HetznerAddress>,
+    ipv6: Option<HetznerAddress>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerName {
+    name: String,
+}
+
+/// A server as returned by the Hetzner Cloud API
+#[derive(Debug, Deserialize)]
+struct HetznerServer {
+    id: u64,
+    name: String,
+    status: String,
+    server_type: HetznerName,
+    #[serde(default)]
+    public_net: HetznerPublicNet,
+    #[serde(default)]
+    private_net: Vec<HetznerAddress>,
+    datacenter: Option<HetznerName>,
+    created: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerServerType {
+    name: String,
+    cores: i64,
+    memory: f64,
+    disk: i64,
+}
+
+/// The EC2 state name the rest of the tool compares against for a Hetzner server status
+fn hetzner_state(status: &str) -> String {
+    match status {
+        "initializing" | "starting" => "pending".to_string(),
+        "off" => "stopped".to_string(),
+        "deleting" => "shutting-down".to_string(),
+        other => other.to_string(),
+    }
+}
+
+impl From<HetznerServer> for Instance {
+    fn from(server: HetznerServer) -> Self {
+        // Servers get a /64 and answer on its first address
+        let ipv6 = server.public_net.ipv6.map(|net| {
+            let prefix = net.ip.split('/').next().unwrap_or_default().to_string();
+            match prefix.ends_with("::") {
+                true => format!("{}1", prefix),
+                false => prefix,
+            }
+        });
+        let mut tags = vec![InstanceTag {
+            key: "Name".to_string(),
+            value: server.name,
+        }];
+        tags.extend(
+            server
+                .labels
+                .into_iter()
+                .map(|(key, value)| InstanceTag { key, value }),
+        );
+        Instance {
+            instance_type: server.server_type.name,
+            instance_id: server.id.to_string(),
+            public_dns: server
+                .public_net
+                .ipv4
+                .map(|addr| addr.ip)
+                .unwrap_or_default(),
+            private_ip: server
+                .private_net
+                .into_iter()
+                .next()
+                .map(|addr| addr.ip)
+                .unwrap_or_default(),
+            tags,
+            state: hetzner_state(&server.status),
+            availability_zone: server.datacenter.map(|dc| dc.name),
+            launch_time: server.created,
+            lifecycle: None,
+            ipv6,
+        }
+    }
+}
+
+/// Environment variable holding a Hetzner Cloud API token
+pub const HCLOUD_TOKEN_VAR: &str = "HCLOUD_TOKEN";
+
+/// Base URL of the Hetzner Cloud API
+const HETZNER_ENDPOINT: &str = "https://api.hetzner.cloud";
+/// How long a server action like poweron may take to finish
+const HETZNER_ACTION_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often a running server action is checked on
+const HETZNER_ACTION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Items per page of Hetzner listings, the most the API allows
+const HETZNER_PAGE_SIZE: u32 = 50;
+
+#[derive(Debug, Deserialize)]
+struct HetznerApiError {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerErrorResponse {
+    error: HetznerApiError,
+}
+
+/// An asynchronous server action, e.g. a poweron, which is "running" until it finishes
+#[derive(Debug, Deserialize)]
+struct HetznerAction {
+    id: u64,
+    status: String,
+    error: Option<HetznerApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerActionResponse {
+    action: HetznerAction,
+}
+
+#[derive(Debug, Deserialize)]
+struct HetznerServerResponse {
+    server: HetznerServer,
+}
+
+/// The token of an `hcloud` CLI context, or of the active one for the "default" profile,
+/// so servers set up for the CLI work without another token
+fn hcloud_context_token(context: &str) -> Option<String> {
+    let path = match std::env::var_os("HCLOUD_CONFIG") {
+        Some(path) if !path.is_empty() => std::path::PathBuf::from(path),
+        _ => dirs::config_dir()?.join("hcloud").join("cli.toml"),
+    };
+    let config: toml::Value = toml::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    let name = match context {
+        "" | "default" => config.get("active_context")?.as_str()?,
+        name => name,
+    };
+    config
+        .get("contexts")?
+        .as_array()?
+        .iter()
+        .find(|ctx| ctx.get("name").and_then(|n| n.as_str()) == Some(name))?
+        .get("token")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Hetzner Cloud servers, managed through the Hetzner Cloud API. Servers are identified by
+/// their numeric ID. Without a `token` the profile names an `hcloud` CLI context whose
+/// token is used.
+pub struct HetznerCloud {
+    client: Box<dyn HttpSend>,
+    token: Option<String>,
+}
+
+impl HetznerCloud {
+    pub fn new(context: &str, token: Option<String>) -> Result<Self> {
+        let token = token.or_else(|| hcloud_context_token(context));
+        Ok(Self::with_client(HyperClient::shared(), token))
+    }
+
+    /// Send API requests through `client`, such as a stand-in returning canned responses
+    pub fn with_client<H: HttpSend + 'static>(client: H, token: Option<String>) -> Self {
+        Self {
+            client: Box::new(client),
+            token,
+        }
+    }
+
+    /// Call the API and parse its JSON response, failing with the API's error message
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<serde_json::Value>,
+    ) -> Result<T> {
+        let token = self.token.as_deref().ok_or_else(|| {
+            Error::msg(format!(
+                "No Hetzner Cloud token found. Set {}, hetzner_token in the config, \
+                 or create an hcloud context",
+                HCLOUD_TOKEN_VAR
+            ))
+        })?;
+        let url = with_query(&format!("{}{}", HETZNER_ENDPOINT, path), query);
+        let mut req = HttpRequest::new(method, &url).bearer(token);
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+
+        let operation = format!("{} {}", method, path);
+        let start = Instant::now();
+        let res = match self.client.send(req).await {
+            Ok(res) => {
+                debug!("Hetzner {} took {:?}", operation, start.elapsed());
+                res
+            }
+            Err(e) => {
+                debug!(
+                    "Hetzner {} failed after {:?}: {}",
+                    operation,
+                    start.elapsed(),
+                    e
+                );
+                return Err(RemoteError::Cloud(e).into());
+            }
+        };
+        if !res.is_success() {
+            let message = serde_json::from_slice::<HetznerErrorResponse>(&res.body)
+                .map(|res| res.error.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&res.body).into_owned());
+            return Err(RemoteError::Cloud(Error::msg(format!(
+                "Hetzner {} failed ({}): {}",
+                operation, res.status, message
+            )))
+            .into());
+        }
+        serde_json::from_slice(&res.body)
+            .map_err(|e| Error::msg(format!("Invalid JSON from Hetzner {}: {}", operation, e)))
+    }
+
+    /// Every item under `field` in a paginated listing
+    async fn list_all<T: DeserializeOwned>(&self, path: &str, field: &str) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        let mut page = Some(1);
+        while let Some(number) = page {
+            let number = number.to_string();
+            let per_page = HETZNER_PAGE_SIZE.to_string();
+            let query = [("page", number.as_str()), ("per_page", per_page.as_str())];
+            let mut res: serde_json::Value = self.request("GET", path, &query, None).await?;
+            let listed: Vec<T> = serde_json::from_value(res[field].take())
+                .map_err(|e| Error::msg(format!("Invalid {} from Hetzner: {}", field, e)))?;
+            items.extend(listed);
+            page = res["meta"]["pagination"]["next_page"].as_u64();
+        }
+        Ok(items)
+    }
+
+    async fn describe(&self, id: &str) -> Result<HetznerServer> {
+        let path = format!("/v1/servers/{}", id);
+        let res: HetznerServerResponse = self.request("GET", &path, &[], None).await?;
+        Ok(res.server)
+    }
+
+    /// Run a server action like poweron and wait for it to finish, as hcloud does
+    async fn run_action(&self, id: &str, action: &str, body: serde_json::Value) -> Result<()> {
+        let path = format!("/v1/servers/{}/actions/{}", id, action);
+        let res: HetznerActionResponse = self.request("POST", &path, &[], Some(body)).await?;
+        let mut action = res.action;
+        let start = Instant::now();
+        loop {
+            match action.status.as_str() {
+                "success" => return Ok(()),
+                "error" => {
+                    let message = action.error.map(|e| e.message).unwrap_or_default();
+                    return Err(RemoteError::Cloud(Error::msg(format!(
+                        "Hetzner {} of server {} failed: {}",
+                        path, id, message
+                    )))
+                    .into());
+                }
+                _ if start.elapsed() > HETZNER_ACTION_TIMEOUT => {
+                    return Err(Error::msg(format!(
+                        "Timed out after {:?} waiting for {} of server {}",
+                        HETZNER_ACTION_TIMEOUT, path, id
+                    )))
+                }
+                _ => tokio::time::delay_for(HETZNER_ACTION_POLL_INTERVAL).await,
+            }
+            let path = format!("/v1/actions/{}", action.id);
+            let res: HetznerActionResponse = self.request("GET", &path, &[], None).await?;
+            action = res.action;
+        }
+    }
+
+    /// Run a server action, returning the state before and after
+    async fn change_state(&self, id: &str, action: &str) -> Result<StateChange> {
+        let previous = self.describe(id).await?;
+        self.run_action(id, action, serde_json::json!({})).await?;
+        Ok(StateChange {
+            previous: hetzner_state(&previous.status),
+            current: self.get_instance(id).await?.state,
+        })
+    }
+
+    /// Replace the server's labels, and with `name` rename it
+    async fn update_server(
+        &self,
+        id: &str,
+        labels: BTreeMap<String, String>,
+        name: Option<String>,
+    ) -> Result<()> {
+        let mut update = serde_json::json!({ "labels": labels });
+        if let Some(name) = name {
+            update["name"] = serde_json::Value::String(name);
+        }
+        let path = format!("/v1/servers/{}", id);
+        let _: HetznerServerResponse = self.request("PUT", &path, &[], Some(update)).await?;
+        Ok(())
+    }
+
+    fn unsupported<T>(&self, operation: &str) -> Result<T> {
+        Err(Error::msg(format!(
+            "{} is not supported on Hetzner Cloud",
+            operation
+        )))
+    }
+}
+
+#[async_trait]
+impl InstanceManager for HetznerCloud {
+    async fn list_instances(&self) -> Result<Vec<Instance>> {
+        let servers: Vec<HetznerServer> = self.list_all("/v1/servers", "servers").await?;
+        Ok(servers.into_iter().map(Instance::from).collect())
+    }
+
+    async fn get_instance(&self, instance_id: &str) -> Result<Instance> {
+        Ok(self.describe(instance_id).await?.into())
+    }
+
+    async fn start_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "poweron").await
+    }
+
+    async fn stop_instance(&self, instance_id: &str) -> Result<StateChange> {
+        // Shut down cleanly rather than cutting the power
+        self.change_state(instance_id, "shutdown").await
+    }
+
+    async fn reboot_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.change_state(instance_id, "reboot").await
+    }
+
+    async fn terminate_instance(&self, instance_id: &str) -> Result<StateChange> {
+        let previous = self.describe(instance_id).await?;
+        let path = format!("/v1/servers/{}", instance_id);
+        let _: HetznerActionResponse = self.request("DELETE", &path, &[], None).await?;
+        Ok(StateChange {
+            previous: hetzner_state(&previous.status),
+            current: "terminated".to_string(),
+        })
+    }
+
+    async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()> {
+        let server = self.describe(instance_id).await?;
+        if server.status != "off" {
+            return Err(Error::msg(format!(
+                "Server {} must be powered off to resize, it is {}",
+                instance_id,
+                hetzner_state(&server.status)
+            )));
+        }
+        // Keeping the disk only changes CPU and memory, so the resize can be undone
+        let body = serde_json::json!({ "server_type": instance_type, "upgrade_disk": false });
+        self.run_action(instance_id, "change_type", body).await
+    }
+
+    async fn add_tags(&self, instance_id: &str, tags: &[InstanceTag]) -> Result<()> {
+        let mut labels = self.describe(instance_id).await?.labels;
+        let mut name = None;
+        for tag in tags {
+            // Name is the server's name, which is listed as a tag alongside its labels
+            match tag.key.as_str() {
+                "Name" => name = Some(tag.value.clone()),
+                _ => {
+                    labels.insert(tag.key.clone(), tag.value.clone());
+                }
+            }
+        }
+        self.update_server(instance_id, labels, name).await
+    }
+
+    async fn remove_tags(&self, instance_id: &str, keys: &[String]) -> Result<()> {
+        if keys.iter().any(|key| key == "Name") {
+            return Err(Error::msg(
+                "The Name tag is the server's name on Hetzner Cloud and can't be removed",
+            ));
+        }
+        let mut labels = self.describe(instance_id).await?.labels;
+        for key in keys {
+            labels.remove(key);
+        }
+        self.update_server(instance_id, labels, None).await
+    }
+
+    async fn get_instance_events(&self, _instance_id: &str) -> Result<Vec<InstanceEvent>> {
+        self.unsupported("Listing events")
+    }
+
+    async fn get_console_output(&self, _instance_id: &str, _latest: bool) -> Result<String> {
+        self.unsupported("Reading console output")
+    }
+
+    async fn get_cpu_utilization(&self, _instance_id: &str, _minutes: u32) -> Result<Option<f64>> {
+        self.unsupported("Reading CPU metrics")
+    }
+
+    async fn set_volume_size(&self, _instance_id: &str, _size_gb: i64) -> Result<()> {
+        self.unsupported("Resizing disks")
+    }
+
+    async fn create_image(
+        &self,
+        _instance_id: &str,
+        _name: &str,
+        _no_reboot: bool,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn get_image_state(&self, _image_id: &str) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn create_snapshot(
+        &self,
+        _instance_id: &str,
+        _description: &str,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating snapshots")
+    }
+
+    async fn launch_instance(&self, _spec: LaunchSpec) -> Result<Instance> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn launch_spec_from(&self, _instance_id: &str) -> Result<LaunchSpec> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn list_key_pairs(&self) -> Result<Vec<String>> {
+        self.unsupported("Listing key pairs")
+    }
+
+    async fn associate_elastic_ip(
+        &self,
+        _instance_id: &str,
+        _allocation_id: Option<&str>,
+    ) -> Result<ElasticIp> {
+        self.unsupported("Associating Elastic IPs")
+    }
+
+    async fn release_elastic_ip(&self, _allocation_id: &str) -> Result<()> {
+        self.unsupported("Releasing Elastic IPs")
+    }
+
+    async fn get_security_groups(&self, _instance_id: &str) -> Result<Vec<SecurityGroup>> {
+        self.unsupported("Listing security groups")
+    }
+
+    async fn list_instance_types(&self, family: Option<&str>) -> Result<Vec<InstanceTypeSummary>> {
+        let types: Vec<HetznerServerType> =
+            self.list_all("/v1/server_types", "server_types").await?;
+        Ok(types
+            .into_iter()
+            .filter(|server_type| family.is_none_or(|family| server_type.name.starts_with(family)))
+            .map(|server_type| InstanceTypeSummary {
+                instance_type: server_type.name,
+                vcpus: server_type.cores,
+                memory_mib: (server_type.memory * 1024.0) as i64,
+                network_performance: format!("{} GB disk", server_type.disk),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpResponse;
+    use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+    use rusoto_mock::{
+        MockCredentialsProvider, MockRequestDispatcher, MultipleMockRequestDispatcher,
+    };
+
+    const INSTANCE_ID: &str = "i-0123456789abcdef0";
+
+    /// An AWS manager answering EC2 calls with `responses`, one per request in order
+    fn mock_aws(responses: Vec<MockRequestDispatcher>) -> AwsCloud {
+        let client = Ec2Client::new_with(
+            MultipleMockRequestDispatcher::new(responses),
+            MockCredentialsProvider,
+            Region::UsEast1,
+        );
+        let cloudwatch = CloudWatchClient::new_with(
+            MockRequestDispatcher::default(),
+            MockCredentialsProvider,
+            Region::UsEast1,
+        );
+        AwsCloud::new(client, cloudwatch)
+    }
+
+    fn body_of(req: &SignedRequest) -> String {
+        match &req.payload {
+            Some(SignedRequestPayload::Buffer(body)) => String::from_utf8(body.to_vec()).unwrap(),
+            _ => String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_tags_overwrites_existing_tag() {
+        let create_tags = MockRequestDispatcher::with_status(200)
+            .with_body("<CreateTagsResponse><return>true</return></CreateTagsResponse>")
+            .with_request_checker(|req| {
+                let body = body_of(req);
+                assert!(body.contains("Action=CreateTags"), "{}", body);
+                assert!(
+                    body.contains(&format!("ResourceId.1={}", INSTANCE_ID)),
+                    "{}",
+                    body
+                );
+                assert!(body.contains("Tag.1.Key=Name"), "{}", body);
+                assert!(body.contains("Tag.1.Value=new"), "{}", body);
+            });
+        // CreateTags replaces the value of a key the instance already has, so the
+        // old tag is never deleted first and this is the only request made
+        let aws = mock_aws(vec![create_tags]);
+        let tags = [InstanceTag {
+            key: "Name".to_string(),
+            value: "new".to_string(),
+        }];
+        aws.add_tags(INSTANCE_ID, &tags).await.unwrap();
+    }
+
+    /// A DescribeInstances page holding one running instance
+    fn instances_page(instance_id: &str, next_token: Option<&str>) -> String {
+        format!(
+            "<DescribeInstancesResponse>\
+               <reservationSet><item><instancesSet><item>\
+                 <instanceId>{}</instanceId>\
+                 <instanceType>t3.micro</instanceType>\
+                 <instanceState><code>16</code><name>running</name></instanceState>\
+               </item></instancesSet></item></reservationSet>\
+               {}\
+             </DescribeInstancesResponse>",
+            instance_id,
+            next_token
+                .map(|token| format!("<nextToken>{}</nextToken>", token))
+                .unwrap_or_default()
+        )
+    }
+
+    #[tokio::test]
+    async fn get_instance_falls_back_to_a_name_like_an_id() {
+        let page = MockRequestDispatcher::with_status(200).with_body(
+            "<DescribeInstancesResponse>\
+               <reservationSet><item><instancesSet><item>\
+                 <instanceId>i-00000000000000001</instanceId>\
+                 <instanceState><code>16</code><name>running</name></instanceState>\
+                 <tagSet><item><key>Name</key><value>i-dev</value></item></tagSet>\
+               </item></instancesSet></item></reservationSet>\
+             </DescribeInstancesResponse>",
+        );
+        let instance = mock_aws(vec![page]).get_instance("i-dev").await.unwrap();
+        assert_eq!(instance.instance_id, "i-00000000000000001");
+    }
+
+    #[tokio::test]
+    async fn list_instances_follows_next_token() {
+        let first = MockRequestDispatcher::with_status(200)
+            .with_body(&instances_page("i-00000000000000001", Some("page-2")))
+            .with_request_checker(|req| assert!(!body_of(req).contains("NextToken")));
+        let second = MockRequestDispatcher::with_status(200)
+            .with_body(&instances_page("i-00000000000000002", None))
+            .with_request_checker(|req| {
+                let body = body_of(req);
+                assert!(body.contains("NextToken=page-2"), "{}", body);
+            });
+        let aws = mock_aws(vec![first, second]);
+        let ids: Vec<String> = aws
+            .list_instances()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|instance| instance.instance_id)
+            .collect();
+        assert_eq!(ids, ["i-00000000000000001", "i-00000000000000002"]);
+    }
+
+    #[tokio::test]
+    async fn start_instance_reports_state_change() {
+        let list =
+            MockRequestDispatcher::with_status(200).with_body(&instances_page(INSTANCE_ID, None));
+        let start = MockRequestDispatcher::with_status(200)
+            .with_body(&format!(
+                "<StartInstancesResponse><instancesSet><item>\
+                   <instanceId>{}</instanceId>\
+                   <currentState><code>0</code><name>pending</name></currentState>\
+                   <previousState><code>80</code><name>stopped</name></previousState>\
+                 </item></instancesSet></StartInstancesResponse>",
+                INSTANCE_ID
+            ))
+            .with_request_checker(|req| {
+                let body = body_of(req);
+                assert!(body.contains("Action=StartInstances"), "{}", body);
+                assert!(
+                    body.contains(&format!("InstanceId.1={}", INSTANCE_ID)),
+                    "{}",
+                    body
+                );
+            });
+        let aws = mock_aws(vec![list, start]);
+        let instances = aws.list_instances().await.unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].state, "running");
+        let change = aws.start_instance(&instances[0].instance_id).await.unwrap();
+        assert_eq!(
+            (change.previous.as_str(), change.current.as_str()),
+            ("stopped", "pending")
+        );
+    }
+
+    type RequestCheck = Box<dyn Fn(&HttpRequest) + Send + Sync>;
+
+    /// A canned response to one REST API request, checking the request first if asked to
+    struct MockResponse {
+        status: u16,
+        body: String,
+        check: Option<RequestCheck>,
+    }
+
+    impl MockResponse {
+        fn new(status: u16, body: &str) -> Self {
+            Self {
+                status,
+                body: body.to_string(),
+                check: None,
+            }
+        }
+
+        fn check<F: Fn(&HttpRequest) + Send + Sync + 'static>(mut self, check: F) -> Self {
+            self.check = Some(Box::new(check));
+            self
+        }
+    }
+
+    /// Answers REST API requests with `MockResponse`s, one per request in order
+    struct MockHttp(std::sync::Mutex<std::collections::VecDeque<MockResponse>>);
+
+    impl MockHttp {
+        fn new(responses: Vec<MockResponse>) -> Self {
+            Self(std::sync::Mutex::new(responses.into()))
+        }
+    }
+
+    #[async_trait]
+    impl HttpSend for MockHttp {
+        async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+            let response = self
+                .0
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| panic!("Unexpected request {}", request.url));
+            if let Some(check) = &response.check {
+                check(&request);
+            }
+            Ok(HttpResponse {
+                status: response.status,
+                body: response.body.into_bytes(),
+            })
+        }
+    }
+
+    fn request_json(req: &HttpRequest) -> serde_json::Value {
+        serde_json::from_slice(&req.body).unwrap()
+    }
+
+    fn mock_hetzner(responses: Vec<MockResponse>) -> HetznerCloud {
+        HetznerCloud::with_client(MockHttp::new(responses), Some("secret".to_string()))
+    }
+
+    fn hetzner_server(status: &str, labels: &str) -> MockResponse {
+        MockResponse::new(
+            200,
+            &format!(
+                r#"{{"server": {{"id": 42, "name": "dev", "status": "{}",
+                    "server_type": {{"name": "cx22"}},
+                    "public_net": {{"ipv4": {{"ip": "203.0.113.7"}}}},
+                    "labels": {}}}}}"#,
+                status, labels
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn hetzner_add_tags_sets_labels_and_name() {
+        let get = hetzner_server("running", r#"{"env": "dev"}"#).check(|req| {
+            assert_eq!(req.url, "https://api.hetzner.cloud/v1/servers/42");
+            assert_eq!(req.method, "GET");
+            assert_eq!(req.header_value("authorization"), Some("Bearer secret"));
+        });
+        // Labels are replaced as a whole, so the existing ones are sent back too
+        let put = hetzner_server("running", "{}").check(|req| {
+            assert_eq!((req.method.as_str(), req.path()), ("PUT", "/v1/servers/42"));
+            assert_eq!(
+                request_json(req),
+                serde_json::json!({"labels": {"env": "dev", "team": "ml"}, "name": "web"})
+            );
+        });
+        let tags = [
+            InstanceTag {
+                key: "team".to_string(),
+                value: "ml".to_string(),
+            },
+            InstanceTag {
+                key: "Name".to_string(),
+                value: "web".to_string(),
+            },
+        ];
+        mock_hetzner(vec![get, put])
+            .add_tags("42", &tags)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn hetzner_start_runs_poweron() {
+        let poweron = MockResponse::new(
+            201,
+            r#"{"action": {"id": 7, "status": "success", "error": null}}"#,
+        )
+        .check(|req| {
+            assert_eq!(
+                (req.method.as_str(), req.path()),
+                ("POST", "/v1/servers/42/actions/poweron")
+            );
+        });
+        let hetzner = mock_hetzner(vec![
+            hetzner_server("off", "{}"),
+            poweron,
+            hetzner_server("running", "{}"),
+        ]);
+        let change = hetzner.start_instance("42").await.unwrap();
+        assert_eq!(
+            (change.previous.as_str(), change.current.as_str()),
+            ("stopped", "running")
+        );
+    }
+
+    #[tokio::test]
+    async fn hetzner_list_follows_next_page() {
+        let first = MockResponse::new(
+            200,
+            r#"{"servers": [], "meta": {"pagination": {"next_page": 2}}}"#,
+        )
+        .check(|req| assert!(req.url.ends_with("?page=1&per_page=50"), "{}", req.url));
+        let second = MockResponse::new(
+            200,
+            r#"{"servers": [], "meta": {"pagination": {"next_page": null}}}"#,
+        )
+        .check(|req| assert!(req.url.ends_with("?page=2&per_page=50"), "{}", req.url));
+        let servers = mock_hetzner(vec![first, second])
+            .list_instances()
+            .await
+            .unwrap();
+        assert!(servers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn hetzner_errors_carry_the_api_message() {
+        let missing = MockResponse::new(
+            404,
+            r#"{"error": {"code": "not_found", "message": "server not found"}}"#,
+        );
+        let error = mock_hetzner(vec![missing])
+            .get_instance("42")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("server not found"), "{}", error);
+    }
+
+    fn mock_digitalocean(responses: Vec<MockResponse>) -> DigitalOceanCloud {
+        DigitalOceanCloud::with_client(MockHttp::new(responses), Some("secret".to_string()))
+    }
+
+    fn droplet(status: &str) -> MockResponse {
+        MockResponse::new(
+            200,
+            &format!(
+                r#"{{"droplet": {{"id": 5, "name": "dev", "status": "{}",
+                    "size_slug": "s-1vcpu-1gb", "region": {{"slug": "nyc3"}}}}}}"#,
+                status
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn digitalocean_terminate_accepts_an_empty_response() {
+        let delete = MockResponse::new(204, "").check(|req| {
+            assert_eq!(
+                (req.method.as_str(), req.url.as_str()),
+                ("DELETE", "https://api.digitalocean.com/v2/droplets/5")
+            );
+            assert_eq!(req.header_value("authorization"), Some("Bearer secret"));
+        });
+        let change = mock_digitalocean(vec![droplet("off"), delete])
+            .terminate_instance("5")
+            .await
+            .unwrap();
+        assert_eq!(
+            (change.previous.as_str(), change.current.as_str()),
+            ("stopped", "terminated")
+        );
+    }
+
+    #[tokio::test]
+    async fn digitalocean_start_runs_power_on() {
+        let power_on = MockResponse::new(201, r#"{"action": {"id": 9, "status": "completed"}}"#)
+            .check(|req| {
+                assert_eq!(req.path(), "/v2/droplets/5/actions");
+                assert_eq!(request_json(req), serde_json::json!({"type": "power_on"}));
+            });
+        let change = mock_digitalocean(vec![droplet("off"), power_on, droplet("active")])
+            .start_instance("5")
+            .await
+            .unwrap();
+        assert_eq!(
+            (change.previous.as_str(), change.current.as_str()),
+            ("stopped", "running")
+        );
+    }
+
+    #[tokio::test]
+    async fn digitalocean_list_follows_next_link() {
+        let next = "https://api.digitalocean.com/v2/droplets?page=2&per_page=200";
+        let first = MockResponse::new(
+            200,
+            &format!(
+                r#"{{"droplets": [], "links": {{"pages": {{"next": "{}"}}}}}}"#,
+                next
+            ),
+        )
+        .check(|req| {
+            assert!(
+                req.url.ends_with("/v2/droplets?per_page=200"),
+                "{}",
+                req.url
+            )
+        });
+        let second = MockResponse::new(200, r#"{"droplets": [], "links": {}}"#)
+            .check(move |req| assert_eq!(req.url, next));
+        let droplets = mock_digitalocean(vec![first, second])
+            .list_instances()
+            .await
+            .unwrap();
+        assert!(droplets.is_empty());
+    }
+
+    fn mock_gcp(zone: Option<&str>, responses: Vec<MockResponse>) -> GcpCloud {
+        GcpCloud::with_client(
+            MockHttp::new(responses),
+            "proj",
+            zone,
+            Some("secret".to_string()),
+        )
+    }
+
+    fn gce_instance(status: &str) -> MockResponse {
+        MockResponse::new(
+            200,
+            &format!(
+                r#"{{"name": "dev", "status": "{}", "labelFingerprint": "fp1",
+                    "machineType": "zones/us-east1-b/machineTypes/e2-small",
+                    "zone": "https://www.googleapis.com/compute/v1/projects/proj/zones/us-east1-b",
+                    "labels": {{"env": "dev"}}}}"#,
+                status
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn gcp_start_waits_for_the_operation() {
+        let base = "https://compute.googleapis.com/compute/v1/projects/proj/zones/us-east1-b";
+        let start =
+            MockResponse::new(200, r#"{"name": "op-1", "status": "RUNNING"}"#).check(move |req| {
+                assert_eq!(req.method, "POST");
+                assert_eq!(req.url, format!("{}/instances/dev/start", base));
+                assert_eq!(req.header_value("authorization"), Some("Bearer secret"));
+            });
+        let wait = MockResponse::new(200, r#"{"name": "op-1", "status": "DONE"}"#)
+            .check(move |req| assert_eq!(req.url, format!("{}/operations/op-1/wait", base)));
+        let gcp = mock_gcp(
+            Some("us-east1-b"),
+            vec![
+                gce_instance("TERMINATED"),
+                start,
+                wait,
+                gce_instance("RUNNING"),
+            ],
+        );
+        let change = gcp.start_instance("dev").await.unwrap();
+        assert_eq!(
+            (change.previous.as_str(), change.current.as_str()),
+            ("stopped", "running")
+        );
+    }
+
+    #[tokio::test]
+    async fn gcp_operation_errors_carry_the_message() {
+        let stop = MockResponse::new(
+            200,
+            r#"{"name": "op-2", "status": "DONE",
+                "error": {"errors": [{"message": "quota exceeded"}]}}"#,
+        );
+        let error = mock_gcp(Some("us-east1-b"), vec![gce_instance("RUNNING"), stop])
+            .stop_instance("dev")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("quota exceeded"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn gcp_add_tags_sends_the_label_fingerprint() {
+        let set_labels =
+            MockResponse::new(200, r#"{"name": "op-3", "status": "DONE"}"#).check(|req| {
+                assert_eq!(
+                    req.path(),
+                    "/compute/v1/projects/proj/zones/us-east1-b/instances/dev/setLabels"
+                );
+                assert_eq!(
+                    request_json(req),
+                    serde_json::json!({
+                        "labels": {"env": "dev", "team": "ml"},
+                        "labelFingerprint": "fp1",
+                    })
+                );
+            });
+        let tags = [InstanceTag {
+            key: "team".to_string(),
+            value: "ml".to_string(),
+        }];
+        mock_gcp(
+            Some("us-east1-b"),
+            vec![gce_instance("RUNNING"), set_labels],
+        )
+        .add_tags("dev", &tags)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn gcp_finds_an_instance_without_a_zone() {
+        let list = MockResponse::new(
+            200,
+            r#"{"items": {
+                "zones/us-east1-a": {"warning": {"code": "NO_RESULTS_ON_PAGE"}},
+                "zones/us-east1-b": {"instances": [{"name": "dev", "status": "RUNNING",
+                    "machineType": "zones/us-east1-b/machineTypes/e2-small",
+                    "zone": "zones/us-east1-b"}]}
+            }}"#,
+        )
+        .check(|req| {
+            assert_eq!(
+                req.url,
+                "https://compute.googleapis.com/compute/v1/projects/proj/aggregated/instances\
+                 ?maxResults=500&filter=name%20%3D%20%22dev%22"
+            );
+        });
+        let instance = mock_gcp(None, vec![list])
+            .get_instance("dev")
+            .await
+            .unwrap();
+        assert_eq!(instance.availability_zone.as_deref(), Some("us-east1-b"));
+        assert_eq!(instance.instance_type, "e2-small");
+    }
+
+    #[test]
+    fn service_account_jwts_verify_with_the_public_key() {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let pem = String::from_utf8(rsa.private_key_to_pem().unwrap()).unwrap();
+        let jwt = signed_jwt(&serde_json::json!({"iss": "me"}), &pem).unwrap();
+
+        let (message, signature) = jwt.rsplit_once('.').unwrap();
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+        let key = openssl::pkey::PKey::from_rsa(rsa).unwrap();
+        let mut verifier =
+            openssl::sign::Verifier::new(openssl::hash::MessageDigest::sha256(), &key).unwrap();
+        verifier.update(message.as_bytes()).unwrap();
+        assert!(verifier.verify(&signature).unwrap());
+    }
+
+    #[test]
+    fn to_instance_handles_missing_fields() {
+        // Every field EC2 leaves out is optional, so an empty instance must
+        // produce an error rather than a panic
+        assert!(to_instance(rusoto_ec2::Instance::default()).is_err());
+
+        let instance = to_instance(rusoto_ec2::Instance {
+            instance_id: Some(INSTANCE_ID.to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(instance.instance_id, INSTANCE_ID);
+        assert_eq!(instance.state, "unknown");
+        assert!(instance.public_dns.is_empty());
+        assert!(instance.tags.is_empty());
+        assert!(instance.ipv6.is_none());
     }
 }
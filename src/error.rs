@@ -0,0 +1,42 @@
+use thiserror::Error;
+
+/// Failures worth telling apart, carried inside `anyhow::Error` so existing `Result`s are
+/// unchanged and callers can recover them with `downcast_ref`
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("No active instance, set one with \"remote instance [alias]\"")]
+    NoActiveInstance,
+    #[error("No instance with alias '{0}' found")]
+    InstanceNotFound(String),
+    #[error("Instance is not running")]
+    NotRunning,
+    #[error("Instance has no public DNS")]
+    NoPublicDns,
+    #[error("Instance address '{0}' does not resolve yet, its DNS may still be propagating")]
+    DnsNotPropagated(String),
+    /// The cloud provider's API or CLI failed
+    #[error(transparent)]
+    Cloud(anyhow::Error),
+}
+
+impl RemoteError {
+    /// The process exit code for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RemoteError::NoActiveInstance => 3,
+            RemoteError::InstanceNotFound(_) => 4,
+            RemoteError::NotRunning => 5,
+            RemoteError::NoPublicDns => 6,
+            RemoteError::Cloud(_) => 7,
+            RemoteError::DnsNotPropagated(_) => 8,
+        }
+    }
+}
+
+/// The process exit code for any error, 1 unless it is a `RemoteError`
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<RemoteError>())
+        .map_or(1, RemoteError::exit_code)
+}
@@ -0,0 +1,229 @@
+//! A cloud provider implemented by an external helper command.
+//!
+//! The helper is run as `<command> <action>` with a JSON request on stdin and must
+//! print a JSON response on stdout:
+//!
+//! | action      | request                                       | response      |
+//! |-------------|-----------------------------------------------|---------------|
+//! | `list`      | `{"profile"}`                                 | `[Instance]`  |
+//! | `get`       | `{"profile", "instance_id"}`                  | `Instance`    |
+//! | `start`     | `{"profile", "instance_id"}`                  | `StateChange` |
+//! | `stop`      | `{"profile", "instance_id"}`                  | `StateChange` |
+//! | `reboot`    | `{"profile", "instance_id"}`                  | `StateChange` |
+//! | `terminate` | `{"profile", "instance_id"}`                  | `StateChange` |
+//! | `resize`    | `{"profile", "instance_id", "instance_type"}` | anything      |
+//!
+//! An `Instance` is `{"instance_id", "instance_type", "state"}` plus the optional
+//! `public_dns`, `private_ip`, `availability_zone`, `launch_time` (RFC 3339),
+//! `lifecycle` (e.g. `"spot"`), `ipv6` and `tags` (`[{"key", "value"}]`). A `StateChange` is
+//! `{"previous", "current"}`. A non-zero exit is an error, and the helper's stderr is shown to the user.
+use std::process::Stdio;
+
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::cloud::{
+    ElasticIp, Instance, InstanceEvent, InstanceManager, InstanceTag, InstanceTypeSummary,
+    LaunchSpec, SecurityGroup, StateChange,
+};
+use crate::error::RemoteError;
+
+#[derive(Debug, Serialize)]
+struct ExternalRequest<'a> {
+    profile: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance_type: Option<&'a str>,
+}
+
+pub struct ExternalCloud {
+    command: String,
+    profile: String,
+}
+
+impl ExternalCloud {
+    pub fn new(command: &str, profile: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            profile: profile.to_string(),
+        }
+    }
+
+    fn request<'a>(
+        &'a self,
+        instance_id: Option<&'a str>,
+        instance_type: Option<&'a str>,
+    ) -> ExternalRequest<'a> {
+        ExternalRequest {
+            profile: &self.profile,
+            instance_id,
+            instance_type,
+        }
+    }
+
+    /// Run the helper for `action` and parse its JSON response
+    async fn call<T: DeserializeOwned>(
+        &self,
+        action: &str,
+        request: &ExternalRequest<'_>,
+    ) -> Result<T> {
+        let mut child = Command::new(&self.command)
+            .arg(action)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| {
+                Error::msg(format!(
+                    "Could not run cloud helper '{}': {}",
+                    self.command, e
+                ))
+            })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&serde_json::to_vec(request)?).await?;
+        }
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(RemoteError::Cloud(Error::msg(format!(
+                "Cloud helper '{}' failed on {} ({})",
+                self.command, action, output.status
+            )))
+            .into());
+        }
+        serde_json::from_slice(&output.stdout).map_err(|e| {
+            Error::msg(format!(
+                "Invalid {} response from cloud helper '{}': {}",
+                action, self.command, e
+            ))
+        })
+    }
+
+    fn unsupported<T>(&self, operation: &str) -> Result<T> {
+        Err(Error::msg(format!(
+            "{} is not supported by external cloud helpers",
+            operation
+        )))
+    }
+}
+
+#[async_trait]
+impl InstanceManager for ExternalCloud {
+    async fn list_instances(&self) -> Result<Vec<Instance>> {
+        self.call("list", &self.request(None, None)).await
+    }
+
+    async fn get_instance(&self, instance_id: &str) -> Result<Instance> {
+        self.call("get", &self.request(Some(instance_id), None))
+            .await
+    }
+
+    async fn start_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.call("start", &self.request(Some(instance_id), None))
+            .await
+    }
+
+    async fn stop_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.call("stop", &self.request(Some(instance_id), None))
+            .await
+    }
+
+    async fn reboot_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.call("reboot", &self.request(Some(instance_id), None))
+            .await
+    }
+
+    async fn terminate_instance(&self, instance_id: &str) -> Result<StateChange> {
+        self.call("terminate", &self.request(Some(instance_id), None))
+            .await
+    }
+
+    async fn set_instance_type(&self, instance_id: &str, instance_type: &str) -> Result<()> {
+        let request = self.request(Some(instance_id), Some(instance_type));
+        self.call::<serde_json::Value>("resize", &request).await?;
+        Ok(())
+    }
+
+    async fn add_tags(&self, _instance_id: &str, _tags: &[InstanceTag]) -> Result<()> {
+        self.unsupported("Tagging instances")
+    }
+
+    async fn remove_tags(&self, _instance_id: &str, _keys: &[String]) -> Result<()> {
+        self.unsupported("Tagging instances")
+    }
+
+    async fn get_instance_events(&self, _instance_id: &str) -> Result<Vec<InstanceEvent>> {
+        self.unsupported("Listing events")
+    }
+
+    async fn get_console_output(&self, _instance_id: &str, _latest: bool) -> Result<String> {
+        self.unsupported("Reading console output")
+    }
+
+    async fn get_cpu_utilization(&self, _instance_id: &str, _minutes: u32) -> Result<Option<f64>> {
+        self.unsupported("Reading CPU metrics")
+    }
+
+    async fn set_volume_size(&self, _instance_id: &str, _size_gb: i64) -> Result<()> {
+        self.unsupported("Resizing disks")
+    }
+
+    async fn create_image(
+        &self,
+        _instance_id: &str,
+        _name: &str,
+        _no_reboot: bool,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn get_image_state(&self, _image_id: &str) -> Result<String> {
+        self.unsupported("Creating images")
+    }
+
+    async fn create_snapshot(
+        &self,
+        _instance_id: &str,
+        _description: &str,
+        _tags: &[InstanceTag],
+    ) -> Result<String> {
+        self.unsupported("Creating snapshots")
+    }
+
+    async fn launch_instance(&self, _spec: LaunchSpec) -> Result<Instance> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn launch_spec_from(&self, _instance_id: &str) -> Result<LaunchSpec> {
+        self.unsupported("Launching instances")
+    }
+
+    async fn list_key_pairs(&self) -> Result<Vec<String>> {
+        self.unsupported("Listing key pairs")
+    }
+
+    async fn associate_elastic_ip(
+        &self,
+        _instance_id: &str,
+        _allocation_id: Option<&str>,
+    ) -> Result<ElasticIp> {
+        self.unsupported("Associating Elastic IPs")
+    }
+
+    async fn release_elastic_ip(&self, _allocation_id: &str) -> Result<()> {
+        self.unsupported("Releasing Elastic IPs")
+    }
+
+    async fn get_security_groups(&self, _instance_id: &str) -> Result<Vec<SecurityGroup>> {
+        self.unsupported("Listing security groups")
+    }
+
+    async fn list_instance_types(&self, _family: Option<&str>) -> Result<Vec<InstanceTypeSummary>> {
+        self.unsupported("Listing instance types")
+    }
+}
@@ -0,0 +1,169 @@
+//! A small HTTP client for the cloud REST APIs that rusoto doesn't cover, behind a trait
+//! so the API clients can be tested without a network.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use hyper_tls::HttpsConnector;
+
+/// An HTTP request to a cloud provider's REST API
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn new(method: &str, url: &str) -> Self {
+        Self {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn bearer(self, token: &str) -> Self {
+        self.header("authorization", &format!("Bearer {}", token))
+    }
+
+    pub fn json(mut self, body: &serde_json::Value) -> Self {
+        self.body = body.to_string().into_bytes();
+        self.header("content-type", "application/json")
+    }
+
+    pub fn form(mut self, fields: &[(&str, &str)]) -> Self {
+        self.body = encode_query(fields).into_bytes();
+        self.header("content-type", "application/x-www-form-urlencoded")
+    }
+
+    /// The value of header `name`, if set
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The path of the URL, without the scheme, host and query
+    pub fn path(&self) -> &str {
+        let after_scheme = self
+            .url
+            .split_once("://")
+            .map_or(&*self.url, |(_, rest)| rest);
+        let path = after_scheme.find('/').map_or("/", |i| &after_scheme[i..]);
+        path.split('?').next().unwrap_or(path)
+    }
+}
+
+/// The status and body of an HTTP response
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends HTTP requests, so the REST API clients can be tested against canned responses
+#[async_trait]
+pub trait HttpSend: Send + Sync {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse>;
+}
+
+/// How long one REST API request may take
+pub const HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An HTTPS client for REST APIs
+#[derive(Clone)]
+pub struct HyperClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+static HYPER_CLIENT: OnceLock<HyperClient> = OnceLock::new();
+
+impl HyperClient {
+    /// The client shared by every REST API, so they reuse one connection pool
+    pub fn shared() -> Self {
+        HYPER_CLIENT
+            .get_or_init(|| HyperClient {
+                client: Client::builder().build(HttpsConnector::new()),
+            })
+            .clone()
+    }
+}
+
+#[async_trait]
+impl HttpSend for HyperClient {
+    async fn send(&self, request: HttpRequest) -> Result<HttpResponse> {
+        let mut builder = hyper::Request::builder()
+            .method(request.method.as_str())
+            .uri(request.url.as_str());
+        for (name, value) in request.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        let req = builder.body(Body::from(request.body))?;
+        let response = async {
+            let res = self.client.request(req).await?;
+            let status = res.status().as_u16();
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            Ok::<_, hyper::Error>(HttpResponse {
+                status,
+                body: body.to_vec(),
+            })
+        };
+        tokio::time::timeout(HTTP_REQUEST_TIMEOUT, response)
+            .await
+            .map_err(|_| {
+                Error::msg(format!(
+                    "Request timed out after {:?}",
+                    HTTP_REQUEST_TIMEOUT
+                ))
+            })?
+            // hyper's message already includes its causes
+            .map_err(|e| Error::msg(e.to_string()))
+    }
+}
+
+/// Percent-encode `fields` as a query string or form body
+pub fn encode_query(fields: &[(&str, &str)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", encode_component(key), encode_component(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn encode_component(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// `url` with `query` appended, if there is any
+pub fn with_query(url: &str, query: &[(&str, &str)]) -> String {
+    match query.is_empty() {
+        true => url.to_string(),
+        false => format!("{}?{}", url, encode_query(query)),
+    }
+}
@@ -1,6 +1,20 @@
 #![warn(rust_2018_idioms)]
+mod cache;
 mod cloud;
+mod error;
+mod external;
+mod http;
+mod pricing;
 mod profile;
+mod schedule;
+mod table;
 
+pub use crate::cache::*;
 pub use crate::cloud::*;
+pub use crate::error::*;
+pub use crate::external::*;
+pub use crate::http::*;
+pub use crate::pricing::*;
 pub use crate::profile::*;
+pub use crate::schedule::*;
+pub use crate::table::*;
@@ -1,15 +1,34 @@
 #![warn(rust_2018_idioms)]
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::net::{IpAddr, TcpStream};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{Error, Result};
-use dirs::home_dir;
-use futures::future::join_all;
-use remote::{AwsCloud, Cloud, InstanceConfig, InstanceManager, ProfileConfig};
+use chrono::{DateTime, Datelike, Local, Utc};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::{info, LevelFilter};
+use remote::{
+    color_state, exit_code, expand_path, expand_tilde, format_uptime, get_config_dir,
+    get_config_path, last_applied, next_run, parse_schedule, redact_config, region_with_endpoint,
+    resolve_region, set_config_path, set_last_applied, write_atomic, AwsCloud, AzureCloud, Cloud,
+    ConfigFormat, DescriptionCache, DigitalOceanCloud, ExternalCloud, GcpCloud, HetznerCloud,
+    Instance, InstanceConfig, InstanceFilter, InstanceManager, InstanceMetric, InstanceSchedule,
+    InstanceTag, LaunchSpec, ListFilter, PriceLookup, ProfileConfig, ProfileCredentials,
+    RemoteError, ScheduledAction, SpotSpec, StateChange, Table, DEFAULT_ADDRESS_TTL,
+    DEFAULT_CACHE_TTL, DIGITALOCEAN_TOKEN_VAR, HCLOUD_TOKEN_VAR, HOURS_PER_MONTH, STATE_FILTER,
+};
+use rusoto_core::Region;
+use serde::Serialize;
+use structopt::clap::{AppSettings, Shell};
 use structopt::StructOpt;
+use tokio::io::{AsyncBufReadExt, BufReader};
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -17,72 +36,747 @@ use structopt::StructOpt;
     author = "Kyle Kosic <kylekosic@gmail.com>",
     about = "Simple CLI for managing remote instances"
 )]
+struct Args {
+    /// Answer yes to any confirmation prompts
+    #[structopt(short = "y", long = "assume-yes", global = true)]
+    assume_yes: bool,
+    /// Print JSON lines instead of text from status, ls, start, stop, reboot and resize
+    #[structopt(long, global = true)]
+    json: bool,
+    /// Config file to use instead of ~/.config/remote/profiles.yaml [env: REMOTE_CONFIG]
+    #[structopt(long, global = true, parse(from_os_str))]
+    config: Option<PathBuf>,
+    /// Print what start, stop, resize and terminate would do without changing anything
+    #[structopt(long, global = true)]
+    dry_run: bool,
+    /// Look up instances live instead of reusing recently cached descriptions
+    #[structopt(long, global = true)]
+    refresh: bool,
+    /// Choose the instance to act on from a list instead of using the active one
+    #[structopt(long, global = true)]
+    pick: bool,
+    /// Restrict the instance's SSH key to its owner (mode 600) if ssh would reject it as too open
+    #[structopt(long, global = true)]
+    fix_permissions: bool,
+    /// Use this cloud profile instead of the configured one for this command, without saving it
+    #[structopt(long, global = true)]
+    profile_override: Option<String>,
+    /// Connect to sshd on this port instead of the instance's ssh_port or 22, without saving it
+    #[structopt(long, global = true)]
+    ssh_port: Option<u16>,
+    /// Operate on at most this many instances at once [default: 5] [env: REMOTE_CONCURRENCY]
+    #[structopt(long, global = true, parse(try_from_str = parse_limit))]
+    concurrency: Option<u32>,
+    /// Log more details on stderr: -v for profiles, regions and commands run, -vv for each
+    /// cloud request, -vvv for everything including dependencies
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+    #[structopt(subcommand)]
+    cmd: Opt,
+}
+
+#[derive(StructOpt, Debug)]
 enum Opt {
     #[structopt(about = "Set the active instance")]
     Instance {
         /// The alias of the instance as set in "new"
-        alias: String,
+        #[structopt(required_unless = "clear")]
+        alias: Option<String>,
+        /// Unset the active instance
+        #[structopt(long, conflicts_with = "alias")]
+        clear: bool,
     },
+    #[structopt(
+        about = "Print the active instance's saved config without contacting the cloud \
+                 (with --verbose, also the absolute paths its keys resolve to)"
+    )]
+    Which,
+    #[structopt(about = "Check the config, tools, credentials and keys for problems")]
+    Doctor,
     #[structopt(about = "Configure a new instance")]
     New {
         /// If specified, will set this as the active instance
         #[structopt(short, long)]
         active: bool,
+        /// Generate a new ed25519 key pair instead of using an existing key
+        #[structopt(long)]
+        generate_key: bool,
+        #[structopt(flatten)]
+        opts: NewOptions,
+    },
+    #[structopt(about = "Add existing instances from a cloud account to the config")]
+    Import {
+        /// The cloud provider to list [default: the configured default cloud]
+        cloud: Option<String>,
+        /// The profile name to use [default: the configured default profile]
+        profile: Option<String>,
+        /// Import every instance not yet configured without asking about each one
+        #[structopt(long)]
+        all: bool,
+        /// Path to the SSH private key for the imported instances [default: asked for]
+        #[structopt(long)]
+        key_path: Option<String>,
+        /// The SSH user name for the imported instances [default: read from the instance tag]
+        #[structopt(long)]
+        user: Option<String>,
+        /// List instances in this AWS region instead of the profile's default, and save it
+        /// with them
+        #[structopt(long)]
+        region: Option<String>,
     },
     #[structopt(about = "Remove an instance by alias")]
     Rm {
         /// Alias of instance to remove
+        #[structopt(required_unless_one = &["all", "group"])]
+        alias: Option<String>,
+        /// Remove all configured instances
+        #[structopt(long, conflicts_with_all = &["alias", "group"])]
+        all: bool,
+        /// Remove every instance in a group, along with the group
+        #[structopt(long, conflicts_with = "alias")]
+        group: Option<String>,
+        /// Also terminate the cloud instance, destroying it permanently
+        #[structopt(long, conflicts_with_all = &["all", "group"])]
+        terminate: bool,
+        /// Remove the instance even if it is still running, without checking
+        #[structopt(short, long, conflicts_with = "terminate")]
+        force: bool,
+    },
+    #[structopt(about = "Change the alias of an instance")]
+    Rename {
+        /// The current alias
+        old: String,
+        /// The new alias
+        new: String,
+    },
+    #[structopt(about = "Change an instance's config, prompting with its current values")]
+    Edit {
+        /// The alias to edit
+        alias: String,
+    },
+    #[structopt(about = "Move an instance's config to another config file")]
+    Move {
+        /// The alias to move
         alias: String,
+        /// The config file to move it to, created if it doesn't exist
+        #[structopt(long, parse(from_os_str))]
+        to: PathBuf,
+    },
+    #[structopt(about = "Copy an instance's config under a new alias")]
+    Duplicate {
+        /// The alias to copy
+        source_alias: String,
+        /// The alias of the copy
+        new_alias: String,
+        /// Instance ID of the copy, prompted for when missing
+        #[structopt(long)]
+        instance_id: Option<String>,
+    },
+    #[structopt(about = "Permanently destroy an instance and remove it from the config")]
+    Terminate {
+        /// Alias of the instance, defaults to the active instance
+        alias: Option<String>,
+        /// Skip retyping the alias to confirm
+        #[structopt(long)]
+        yes: bool,
     },
     #[structopt(about = "Start active instance")]
-    Start,
+    Start {
+        /// Start these instances concurrently instead of the active one
+        aliases: Vec<String>,
+        /// Start every instance in this group concurrently
+        #[structopt(long, conflicts_with_all = &["aliases", "stop-others"])]
+        group: Option<String>,
+        /// Stop all other running instances in the same cloud profile first
+        #[structopt(long, conflicts_with = "aliases")]
+        stop_others: bool,
+        /// Wait until the instance is running and has a public address
+        #[structopt(short, long)]
+        wait: bool,
+        /// Instead of starting now, start on this cron schedule (see "remote schedule apply")
+        #[structopt(long, conflicts_with_all = &["aliases", "group"])]
+        schedule: Option<String>,
+        /// Remove the start schedule
+        #[structopt(long, conflicts_with_all = &["schedule", "aliases", "group"])]
+        no_schedule: bool,
+        /// Look AWS instances up in this region instead of their configured one, for this run only
+        #[structopt(long)]
+        region: Option<String>,
+    },
     #[structopt(about = "Stop active instance")]
-    Stop,
+    Stop {
+        /// Stop these instances concurrently instead of the active one
+        aliases: Vec<String>,
+        /// Stop every instance in this group concurrently
+        #[structopt(long, conflicts_with_all = &["aliases", "schedule", "no-schedule"])]
+        group: Option<String>,
+        /// Instead of stopping now, stop on this cron schedule while "remote daemon" runs
+        #[structopt(long, conflicts_with = "aliases")]
+        schedule: Option<String>,
+        /// Remove the stop schedule
+        #[structopt(long, conflicts_with_all = &["schedule", "aliases"])]
+        no_schedule: bool,
+        /// Look AWS instances up in this region instead of their configured one, for this run only
+        #[structopt(long)]
+        region: Option<String>,
+    },
+    #[structopt(about = "Reboot active instance")]
+    Reboot,
+    #[structopt(about = "Stop the active instance if it is running, or start it if it is stopped")]
+    Toggle,
+    #[structopt(about = "Run scheduled actions on configured instances until interrupted")]
+    Daemon {
+        /// Seconds between schedule checks
+        #[structopt(short, long, default_value = "60")]
+        interval: u64,
+    },
     #[structopt(about = "Get status of active instance")]
     Status {
         /// Optionally show status of all configured instances
         #[structopt(short, long)]
         all: bool,
+        /// Show the status of every instance in this group
+        #[structopt(long, conflicts_with = "all")]
+        group: Option<String>,
+        /// Only show instances matching field=value (state, instance_type or tag:<key>)
+        #[structopt(long = "where", alias = "field-filter")]
+        filters: Vec<InstanceFilter>,
+        /// Print one line per instance
+        #[structopt(short, long)]
+        compact: bool,
+        /// With --all, stop at the first instance that fails
+        #[structopt(long, requires = "all")]
+        fail_fast: bool,
+        /// Also show the ssh destination and key of running instances
+        #[structopt(long, conflicts_with = "compact")]
+        connection: bool,
+        /// Refresh every few seconds until interrupted with Ctrl-C
+        #[structopt(short, long)]
+        watch: bool,
+        /// Also show the average CPU utilization over the last 10 minutes (AWS only)
+        #[structopt(long)]
+        metrics: bool,
+        /// Also show the on-demand hourly price and the cost since launch (AWS only)
+        #[structopt(long)]
+        cost: bool,
+        /// Look AWS instances up in this region instead of their configured one, for this run only
+        #[structopt(long)]
+        region: Option<String>,
+    },
+    #[structopt(about = "Continuously show the status of all configured instances")]
+    Top {
+        /// Seconds between refreshes, at least 1
+        #[structopt(short, long, default_value = "5", parse(try_from_str = parse_interval))]
+        interval: u64,
+        /// Add a column with each running instance's average CPU utilization
+        #[structopt(long)]
+        metrics: bool,
+    },
+    #[structopt(about = "Chart a CloudWatch metric of the active instance in the terminal")]
+    Metrics {
+        /// The metric to chart: cpu, network-in or network-out
+        #[structopt(short, long, default_value = "cpu")]
+        metric: InstanceMetric,
+        /// How far back to chart, e.g. 30m, 6h or 2d
+        #[structopt(short, long, default_value = "1h", parse(try_from_str = parse_since))]
+        since: Duration,
+    },
+    #[structopt(about = "Show scheduled events for the active instance")]
+    Events {
+        /// Optionally show events of all configured instances
+        #[structopt(short, long)]
+        all: bool,
+    },
+    #[structopt(about = "Print the serial console output of the active instance")]
+    Logs {
+        /// Fetch the most recent output instead of the last boot's (Nitro instances only)
+        #[structopt(long)]
+        latest: bool,
     },
     #[structopt(about = "SSH into the active instance")]
     Ssh {
         /// Optional ports to forward to the remote instance
         #[structopt(short, long)]
         ports: Option<Vec<u16>>,
+        /// Connect through this configured instance as a bastion
+        #[structopt(long)]
+        jump: Option<String>,
+        /// List listening ports on the instance and choose which to forward
+        #[structopt(long, conflicts_with = "jump")]
+        discover_ports: bool,
+        /// Connect with mosh instead of ssh (no port forwarding)
+        #[structopt(long, conflicts_with_all = &["ports", "jump", "discover-ports"])]
+        mosh: bool,
+        /// Ask for confirmation if anyone is already logged in to the instance
+        #[structopt(long, conflicts_with = "jump")]
+        warn_if_busy: bool,
+        /// Fall back to the private IP if the public DNS is missing or unreachable
+        #[structopt(long, conflicts_with = "jump")]
+        auto_address: bool,
+        /// Always start a login shell
+        #[structopt(long, conflicts_with = "mosh")]
+        login: bool,
+        /// Always start a non-login shell
+        #[structopt(long, conflicts_with_all = &["mosh", "login"])]
+        no_login: bool,
+        /// Forward the local SSH agent to the instance
+        #[structopt(short = "A", long, conflicts_with = "mosh")]
+        forward_agent: bool,
+        /// Seconds between keepalive messages, overriding the configured interval
+        #[structopt(long, conflicts_with = "mosh")]
+        keepalive: Option<u32>,
+        /// Wait for the instance to accept SSH connections first
+        #[structopt(long, conflicts_with = "jump")]
+        wait_ssh: bool,
+        /// Look AWS instances up in this region instead of their configured one, for this run only
+        #[structopt(long)]
+        region: Option<String>,
+        /// Run the command given after `--` with sudo, feeding it the password from the
+        /// configured password command
+        #[structopt(
+            long,
+            requires = "extra",
+            conflicts_with_all = &["ports", "jump", "discover-ports", "login", "no-login"]
+        )]
+        sudo: bool,
+        /// Close the multiplexed ssh connection to the instance instead of connecting
+        #[structopt(long, conflicts_with_all = &["sudo", "mosh", "ports", "jump", "discover-ports"])]
+        stop_multiplex: bool,
+        /// Extra arguments for ssh, given after `--`
+        #[structopt(last = true, conflicts_with = "mosh")]
+        extra: Vec<String>,
+    },
+    #[structopt(about = "Connect to the active instance with mosh, the same as ssh --mosh")]
+    Mosh {
+        /// Ask for confirmation if anyone is already logged in to the instance
+        #[structopt(long)]
+        warn_if_busy: bool,
+        /// Fall back to the private IP if the public DNS is missing or unreachable
+        #[structopt(long)]
+        auto_address: bool,
+        /// Wait for the instance to accept SSH connections first
+        #[structopt(long)]
+        wait_ssh: bool,
+    },
+    #[structopt(
+        about = "Forward a port from the active instance in the background and open it in a browser"
+    )]
+    Open {
+        /// The port to forward, the same locally and on the instance
+        port: u16,
+        /// Only forward the port, without opening a browser
+        #[structopt(long)]
+        no_browser: bool,
+    },
+    #[structopt(about = "Close a port forward started by `remote open`")]
+    Close {
+        /// The forwarded port
+        port: u16,
+    },
+    #[structopt(
+        about = "Run a command on the active instance",
+        setting = AppSettings::TrailingVarArg
+    )]
+    Exec {
+        /// Run on every running configured instance
+        #[structopt(short, long)]
+        all: bool,
+        /// With --all, print output live as it arrives instead of grouped by instance
+        #[structopt(long, requires = "all")]
+        stream: bool,
+        /// Ports to forward to the instance while the command runs
+        #[structopt(short, long, conflicts_with = "all")]
+        ports: Option<Vec<u16>>,
+        /// The command to run
+        #[structopt(required = true, allow_hyphen_values = true)]
+        command: Vec<String>,
     },
-    #[structopt(about = "Copy a file to the active instance", alias = "up")]
+    #[structopt(about = "Copy files to the active instance", alias = "up")]
     Upload {
-        /// The path of the local file
-        local_file: String,
-        /// The remote path to copy to
-        remote_file: String,
+        /// The local files, then the remote path to copy to (a directory for several files)
+        #[structopt(required = true, min_values = 2)]
+        paths: Vec<String>,
         /// Copy directories recursively
         #[structopt(short, long)]
         recursive: bool,
+        /// Limit the transfer rate in KB/s
+        #[structopt(short, long, parse(try_from_str = parse_limit))]
+        limit: Option<u32>,
+        /// Preserve modification times and modes
+        #[structopt(short, long)]
+        preserve: bool,
+        /// Wait for the instance to accept SSH connections first
+        #[structopt(long)]
+        wait_ssh: bool,
+        /// Compress data in transit, which helps on slow links
+        #[structopt(short = "C", long)]
+        compress: bool,
     },
-    #[structopt(about = "Copy a file from the active instance", alias = "down")]
+    #[structopt(about = "Sync a local directory to the active instance with rsync")]
+    Sync {
+        /// The local directory to sync from
+        local_dir: String,
+        /// The remote directory to sync to
+        remote_dir: String,
+        /// Delete remote files that no longer exist locally
+        #[structopt(long)]
+        delete: bool,
+        /// Limit the transfer rate in KB/s
+        #[structopt(short, long, parse(try_from_str = parse_limit))]
+        limit: Option<u32>,
+        /// Compress data in transit, which helps on slow links
+        #[structopt(short = "C", long)]
+        compress: bool,
+    },
+    #[structopt(about = "Browse and transfer files on the active instance with sftp")]
+    Sftp {
+        /// The remote directory to start in, instead of the home directory
+        dir: Option<String>,
+        /// Compress data in transit, which helps on slow links
+        #[structopt(short = "C", long)]
+        compress: bool,
+    },
+    #[structopt(about = "Copy files from the active instance", alias = "down")]
     Download {
-        /// The path of the remote file
-        remote_file: String,
-        /// The local path to copy to
-        local_file: String,
+        /// The remote files, then the local path to copy to (a directory for several files)
+        #[structopt(required = true, min_values = 2)]
+        paths: Vec<String>,
         /// Copy directories recursively
         #[structopt(short, long)]
         recursive: bool,
+        /// Limit the transfer rate in KB/s
+        #[structopt(short, long, parse(try_from_str = parse_limit))]
+        limit: Option<u32>,
+        /// Preserve modification times and modes
+        #[structopt(short, long)]
+        preserve: bool,
+        /// Wait for the instance to accept SSH connections first
+        #[structopt(long)]
+        wait_ssh: bool,
+        /// Compress data in transit, which helps on slow links
+        #[structopt(short = "C", long)]
+        compress: bool,
     },
     #[structopt(about = "Change the type of the active instance")]
     Resize {
         /// The desired instance type
-        instance_type: String,
+        #[structopt(required_unless = "undo")]
+        instance_type: Option<String>,
+        /// Revert to the type before the last resize
+        #[structopt(long, conflicts_with = "instance-type")]
+        undo: bool,
+        /// Stop a running instance to resize it, then start it again
+        #[structopt(long)]
+        force: bool,
+    },
+    #[structopt(about = "Grow the root volume of the active instance (AWS only)")]
+    Disk {
+        /// The new size in GiB
+        size_gb: i64,
+        /// Also grow the root partition and filesystem over ssh
+        #[structopt(long)]
+        grow: bool,
+    },
+    #[structopt(about = "Create an EBS snapshot or AMI of the active instance")]
+    Snapshot {
+        /// Name of the snapshot or AMI [default: the alias and a timestamp]
+        name: Option<String>,
+        /// Create an AMI of the whole instance instead of a root volume snapshot
+        #[structopt(long)]
+        ami: bool,
+        /// Don't reboot the instance while creating the AMI
+        #[structopt(long, requires = "ami")]
+        no_reboot: bool,
+        /// Wait until the AMI is available
+        #[structopt(long, requires = "ami")]
+        wait: bool,
+    },
+    #[structopt(about = "Launch a new instance from an AMI and configure it")]
+    Restore {
+        /// The AMI to launch from
+        image_id: String,
+        /// Copy instance type, key pair and network settings from this configured instance
+        #[structopt(long)]
+        like: Option<String>,
+        /// The instance type to launch
+        #[structopt(long)]
+        instance_type: Option<String>,
+        /// The name of the cloud key pair to launch with
+        #[structopt(long)]
+        key_name: Option<String>,
+        /// Security group IDs to launch into
+        #[structopt(long)]
+        security_group: Vec<String>,
+        /// The subnet to launch into
+        #[structopt(long)]
+        subnet: Option<String>,
+        /// The cloud provider to use, if not set by --like
+        #[structopt(long, default_value = "aws")]
+        cloud: String,
+        /// The profile name to use, if not set by --like
+        #[structopt(long, default_value = "default")]
+        profile: String,
+    },
+    #[structopt(
+        about = "Launch a brand-new instance and configure it, prompting for missing settings"
+    )]
+    Launch {
+        /// The AMI to launch from
+        #[structopt(long)]
+        image_id: Option<String>,
+        /// The instance type to launch
+        #[structopt(long)]
+        instance_type: Option<String>,
+        /// The name of the cloud key pair to launch with
+        #[structopt(long)]
+        key_name: Option<String>,
+        /// Security group IDs to launch into
+        #[structopt(long)]
+        security_group: Vec<String>,
+        /// The subnet to launch into
+        #[structopt(long)]
+        subnet: Option<String>,
+        /// The cloud provider to use [default: the configured default cloud]
+        #[structopt(long)]
+        cloud: Option<String>,
+        /// The profile name to use [default: the configured default profile]
+        #[structopt(long)]
+        profile: Option<String>,
+        /// The AWS region to launch in [default: the profile's region]
+        #[structopt(long)]
+        region: Option<String>,
+        /// Launch a spot instance instead of an on-demand one (AWS only)
+        #[structopt(long)]
+        spot: bool,
+        /// Highest USD price per hour for a spot instance [default: the on-demand price]
+        #[structopt(long, requires = "spot")]
+        max_price: Option<String>,
+        /// What happens to an interrupted spot instance
+        #[structopt(long, requires = "spot", possible_values = &["terminate", "stop", "hibernate"])]
+        interruption_behavior: Option<String>,
     },
     #[structopt(about = "List configured instances or available instances for a cloud profile")]
     Ls {
         /// The cloud provider to use
         cloud: Option<String>,
-        /// The profile name to use
-        #[structopt(default_value = "default")]
-        profile: String,
+        /// The profile name to use [default: the configured default profile]
+        profile: Option<String>,
+        /// Only list configured instances on this cloud provider
+        #[structopt(long, conflicts_with = "cloud")]
+        only_cloud: Option<String>,
+        /// Show live instances as a table fitted to the terminal width
+        #[structopt(long)]
+        table: bool,
+        /// List configured instances as plain text without looking up their state
+        #[structopt(long, conflicts_with = "cloud")]
+        plain: bool,
+        /// Show the last fetched listing without contacting the cloud provider
+        #[structopt(long, conflicts_with = "refresh")]
+        offline: bool,
+        /// List instances in this AWS region instead of the profile's default
+        #[structopt(long, requires = "cloud")]
+        region: Option<String>,
+        /// Only list instances matching an EC2 filter, e.g. tag:Owner=alice (repeatable, other
+        /// clouds only support instance-state-name)
+        #[structopt(
+            long = "filter",
+            number_of_values = 1,
+            requires = "cloud",
+            conflicts_with = "offline"
+        )]
+        filters: Vec<ListFilter>,
+        /// Only list running instances
+        #[structopt(long, requires = "cloud", conflicts_with = "offline")]
+        running: bool,
+        /// Output format, `json` being the same as --json [default: text]
+        #[structopt(
+            long,
+            possible_values = &["text", "csv", "json"],
+            conflicts_with_all = &["table", "plain"]
+        )]
+        format: Option<ListFormat>,
+    },
+    #[structopt(about = "Show the security groups of the active instance and what they let in")]
+    Sg,
+    #[structopt(about = "List instance types available in a region", alias = "types")]
+    InstanceTypes {
+        /// Only show types whose name contains this, e.g. "g4dn"
+        filter: Option<String>,
+        /// Only show types in this family, e.g. "t3"
+        #[structopt(long)]
+        family: Option<String>,
+        /// The region to query [default: the profile's region]
+        #[structopt(long)]
+        region: Option<String>,
+        /// The profile name to use [default: the configured default profile]
+        #[structopt(long)]
+        profile: Option<String>,
+        /// Add each type's on-demand Linux price per hour
+        #[structopt(long)]
+        cost: bool,
+    },
+    #[structopt(about = "Manage tool configuration")]
+    Config(ConfigOpt),
+    #[structopt(about = "Manage the SSH key of the active instance")]
+    Key(KeyOpt),
+    #[structopt(about = "Authorize a public key on the active instance, e.g. for another user")]
+    CopyId {
+        /// The public key to authorize [default: ~/.ssh/id_rsa.pub]
+        pubkey_path: Option<String>,
+    },
+    #[structopt(about = "Manage the tags of the active instance")]
+    Tag(TagOpt),
+    #[structopt(about = "Manage the ports always forwarded to the active instance")]
+    Ports(PortsOpt),
+    #[structopt(about = "Manage named groups of instances")]
+    Group(GroupOpt),
+    #[structopt(about = "Manage a static public IP for the active instance (AWS only)")]
+    Eip(EipOpt),
+    #[structopt(about = "Apply instance start and stop schedules")]
+    Schedule(ScheduleOpt),
+    #[structopt(about = "Estimate the monthly cost of running configured instances")]
+    Cost {
+        /// Sum the estimate per value of this tag
+        #[structopt(long)]
+        by_tag: Option<String>,
+        /// The profile used for price lookups [default: each instance's own profile]
+        #[structopt(long)]
+        profile: Option<String>,
+    },
+    #[structopt(
+        about = "Estimate monthly and month to date spend per profile, stopped instances included"
+    )]
+    CostReport {
+        /// The profile used for price lookups [default: each instance's own profile]
+        #[structopt(long)]
+        profile: Option<String>,
+    },
+    #[structopt(about = "Print a shell completion script")]
+    Completions {
+        /// The shell to complete for
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    #[structopt(about = "Print an ~/.ssh/config entry for an instance")]
+    SshConfig {
+        /// The alias of the instance [default: the active instance]
+        alias: Option<String>,
+        /// Include every configured instance that is running
+        #[structopt(short, long, conflicts_with = "alias")]
+        all: bool,
+        /// Add or update the entries in ~/.ssh/config instead of printing them
+        #[structopt(long)]
+        write: bool,
+    },
+    #[structopt(about = "Print shell exports of the active instance's connection details")]
+    Env {
+        /// Print `set -gx` commands for fish instead of POSIX exports
+        #[structopt(long)]
+        fish: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum TagOpt {
+    #[structopt(about = "Set a tag, replacing any existing value")]
+    Add { key: String, value: String },
+    #[structopt(about = "Remove a tag")]
+    Rm { key: String },
+}
+
+#[derive(StructOpt, Debug)]
+enum GroupOpt {
+    #[structopt(about = "Add instances to a group, creating it if needed")]
+    Add {
+        name: String,
+        #[structopt(required = true)]
+        aliases: Vec<String>,
+    },
+    #[structopt(about = "Remove instances from a group, or the whole group if none are given")]
+    Rm { name: String, aliases: Vec<String> },
+}
+
+#[derive(StructOpt, Debug)]
+enum PortsOpt {
+    #[structopt(about = "Forward a port on every ssh connection")]
+    Add { port: u16 },
+    #[structopt(about = "Stop forwarding a saved port")]
+    Rm { port: u16 },
+}
+
+#[derive(StructOpt, Debug)]
+enum EipOpt {
+    #[structopt(about = "Associate an Elastic IP, allocating one unless the instance has one")]
+    Associate {
+        /// Use this existing allocation instead
+        #[structopt(long)]
+        allocation_id: Option<String>,
+    },
+    #[structopt(about = "Disassociate and release the instance's Elastic IP")]
+    Release,
+}
+
+#[derive(StructOpt, Debug)]
+enum ScheduleOpt {
+    #[structopt(about = "Start and stop instances whose schedule fired since the last apply")]
+    Apply,
+}
+
+#[derive(StructOpt, Debug)]
+enum KeyOpt {
+    #[structopt(about = "Switch the active instance to a new private key")]
+    Rotate {
+        /// Path to the new private key
+        key_path: String,
+        /// Only update the config, don't authorize the new key on the instance
+        #[structopt(long)]
+        no_install: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ConfigOpt {
+    #[structopt(about = "Print the path of the config file in use")]
+    Path,
+    #[structopt(about = "Print the config file, with any secrets masked")]
+    Show,
+    #[structopt(about = "Check the config file for problems without changing it")]
+    Validate,
+    #[structopt(about = "Copy the config file to a timestamped backup")]
+    Backup {
+        /// Write the backup here instead of ~/.config/remote/backups
+        #[structopt(parse(from_os_str))]
+        path: Option<PathBuf>,
+    },
+    #[structopt(about = "Replace the config file with a backup after checking it")]
+    Restore {
+        /// The backup to restore
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
     },
+    #[structopt(about = "Set the default cloud provider and profile")]
+    SetDefault {
+        /// The default cloud provider
+        #[structopt(long)]
+        cloud: Option<String>,
+        /// The default cloud profile
+        #[structopt(long)]
+        profile: Option<String>,
+    },
+}
+
+fn parse_limit(s: &str) -> Result<u32> {
+    match s.parse::<u32>()? {
+        0 => Err(Error::msg("Limit must be positive")),
+        limit => Ok(limit),
+    }
+}
+
+fn parse_interval(s: &str) -> Result<u64> {
+    match s.parse::<u64>()? {
+        0 => Err(Error::msg("Interval must be at least 1 second")),
+        interval => Ok(interval),
+    }
 }
 
 fn user_input(prompt: &str) -> Result<String> {
@@ -93,25 +787,12 @@ fn user_input(prompt: &str) -> Result<String> {
     Ok(input.trim().to_string())
 }
 
-fn expand_tilde<P>(path_user_input: P) -> Option<PathBuf>
-where
-    P: AsRef<Path>,
-{
-    let p = path_user_input.as_ref();
-    if !p.starts_with("~") {
-        return Some(p.to_path_buf());
+fn confirm(prompt: &str, assume_yes: bool) -> Result<bool> {
+    if assume_yes {
+        return Ok(true);
     }
-    if p == Path::new("~") {
-        return home_dir();
-    }
-    dirs::home_dir().map(|mut h| {
-        if h == Path::new("/") {
-            p.strip_prefix("~").unwrap().to_path_buf()
-        } else {
-            h.push(p.strip_prefix("~/").unwrap());
-            h
-        }
-    })
+    let answer = user_input(&format!("{} [y/N]", prompt))?;
+    Ok(matches!(answer.to_lowercase().as_str(), "y" | "yes"))
 }
 
 fn set_active_instance(alias: &str) -> Result<()> {
@@ -129,237 +810,4824 @@ fn set_active_instance(alias: &str) -> Result<()> {
     Ok(())
 }
 
-async fn new_instance(set_active: bool) -> Result<()> {
+fn rename_instance(old: &str, new: &str) -> Result<()> {
     let mut config = ProfileConfig::get_or_create()?;
-
-    let cloud = user_input("Cloud provider")?;
-    let cloud = Cloud::from_str(&cloud)?;
-    let mut profile = user_input("Cloud profile [default]")?;
-    if profile.as_str() == "" {
-        profile = "default".to_string();
-    };
-    let instance_id = user_input("Instance ID")?;
-    let key_path = user_input("SSH key path")?;
-    let path = expand_tilde(&key_path).unwrap();
-    if !path.exists() {
-        return Err(Error::msg(format!("Could not find key file: {}", key_path)));
-    };
-    let user = user_input("SSH user name")?;
-    let alias = user_input("Alias")?;
-    println!("---");
-
-    let duplicates = config.instances.iter().any(|inst| inst.alias == alias);
-    if duplicates {
+    if config.instances.iter().any(|inst| inst.alias == new) {
         return Err(Error::msg(format!(
             "Instance with alias '{}' already exists",
-            alias
+            new
         )));
-    };
-
-    let instance = InstanceConfig {
-        alias: alias.clone(),
-        instance_id,
-        key_path,
-        user,
-        profile,
-        cloud,
-    };
-    status(&instance).await?;
-
-    config.instances.push(instance);
-    if set_active {
-        config.active = Some(alias)
     }
-
+    config.find_instance_mut(old)?.alias = new.to_string();
+    if config.active.as_deref() == Some(old) {
+        config.active = Some(new.to_string());
+    }
+    for aliases in config.groups.values_mut() {
+        for alias in aliases.iter_mut().filter(|alias| alias.as_str() == old) {
+            *alias = new.to_string();
+        }
+    }
     config.update()?;
+    println!("Renamed instance: {} -> {}", old, new);
     Ok(())
 }
 
-fn remove_instance(alias: &str) -> Result<()> {
+/// Prompt for each of an instance's main fields with its current value, which enter keeps,
+/// and save the result if it is valid
+fn edit_instance(alias: &str) -> Result<()> {
     let mut config = ProfileConfig::get_or_create()?;
-    config.instances = config
-        .instances
+    let mut instance = config.find_instance(alias)?.clone();
+    println!("Press enter to keep a value, or enter - to clear an optional one");
+    instance.cloud = Cloud::from_str(&prompt_keep("Cloud", &instance.cloud.to_string())?)?;
+    if instance.cloud != Cloud::Ssh {
+        instance.instance_id = prompt_keep("Instance ID", &instance.instance_id)?;
+        instance.profile = prompt_keep("Cloud profile", &instance.profile)?;
+    }
+    instance.user = prompt_keep("SSH user name", &instance.user)?;
+    let key_paths = prompt_keep("SSH key path(s)", &instance.key_paths.join(", "))?
+        .split(',')
+        .map(str::trim)
+        .filter(|key_path| !key_path.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    for key_path in key_paths.iter() {
+        let is_new = !instance.key_paths.contains(key_path);
+        if is_new && !expand_path(key_path).is_some_and(|path| path.exists()) {
+            return Err(Error::msg(format!("Could not find key file: {}", key_path)));
+        }
+    }
+    instance.key_paths = key_paths;
+    match instance.cloud {
+        Cloud::Aws => instance.region = prompt_keep_optional("Region", &instance.region)?,
+        Cloud::Gcp => instance.zone = prompt_keep_optional("Zone", &instance.zone)?,
+        Cloud::Azure => {
+            instance.resource_group =
+                prompt_keep_optional("Resource group", &instance.resource_group)?
+        }
+        Cloud::Ssh => instance.address = prompt_keep_optional("Address", &instance.address)?,
+        Cloud::DigitalOcean | Cloud::Hetzner | Cloud::External(_) => {}
+    }
+    *config.find_instance_mut(alias)? = instance;
+    let prefix = format!("{}: ", alias);
+    let problems = config
+        .validate()
         .into_iter()
-        .filter(|inst| inst.alias != alias)
-        .collect();
-    if config.active == Some(alias.to_string()) {
-        config.active = None
-    };
+        .filter(|problem| problem.starts_with(&prefix))
+        .collect::<Vec<_>>();
+    if !problems.is_empty() {
+        return Err(Error::msg(format!(
+            "Not saved, the changes have problems:\n  {}",
+            problems.join("\n  ")
+        )));
+    }
     config.update()?;
-    println!("Removed instance: {}", alias);
+    println!("Updated instance: {}", alias);
+    println!("{}", config.find_instance(alias)?);
     Ok(())
 }
 
-fn get_manager(cloud: &Cloud, profile: &str) -> Result<Box<dyn InstanceManager>> {
-    match cloud {
-        Cloud::Aws => Ok(Box::new(AwsCloud::from_profile(profile)?)),
+/// Ask for a value, keeping `current` when the answer is empty
+fn prompt_keep(prompt: &str, current: &str) -> Result<String> {
+    let answer = user_input(&format!("{} [{}]", prompt, current))?;
+    match answer.is_empty() {
+        true => Ok(current.to_string()),
+        false => Ok(answer),
     }
 }
 
-fn get_active_instance() -> Result<InstanceConfig> {
-    let config = ProfileConfig::get_or_create()?;
-    let active = config
-        .active
-        .ok_or_else(|| Error::msg("No active instance"))?;
-    let instances = config
-        .instances
-        .into_iter()
-        .filter(|x| x.alias == active)
-        .collect::<Vec<InstanceConfig>>();
-    if instances.is_empty() {
+/// Ask for an optional value, keeping `current` when the answer is empty and clearing it on "-"
+fn prompt_keep_optional(prompt: &str, current: &Option<String>) -> Result<Option<String>> {
+    let answer = user_input(&format!(
+        "{} [{}]",
+        prompt,
+        current.as_deref().unwrap_or("none")
+    ))?;
+    match answer.as_str() {
+        "" => Ok(current.clone()),
+        "-" => Ok(None),
+        _ => Ok(Some(answer)),
+    }
+}
+
+/// Move an instance from the current config file to `to`. The destination is written
+/// first, so a failure there leaves the instance where it was.
+fn move_instance(alias: &str, to: &PathBuf) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    let instance = config.find_instance(alias)?.clone();
+    let source = get_config_path()?;
+    let is_same_file = match (std::fs::canonicalize(&source), std::fs::canonicalize(to)) {
+        (Ok(source), Ok(to)) => source == to,
+        _ => false,
+    };
+    if is_same_file {
         return Err(Error::msg(format!(
-            "Active instance '{}' not found in instance list",
-            active
+            "{} is already in {}",
+            alias,
+            to.display()
         )));
-    };
-    Ok(instances[0].to_owned())
-}
+    }
+    if let Some(parent) = to.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut destination = ProfileConfig::load(to)?;
+    if destination.instances.iter().any(|inst| inst.alias == alias) {
+        return Err(Error::msg(format!(
+            "Instance with alias '{}' already exists in {}",
+            alias,
+            to.display()
+        )));
+    }
+    destination.instances.push(instance);
+    destination.to_file(to)?;
 
-pub struct ConnectionInfo {
-    pub user: String,
-    pub address: String,
-    pub key_path: PathBuf,
+    config.instances.retain(|inst| inst.alias != alias);
+    if config.active.as_deref() == Some(alias) {
+        config.active = None;
+    }
+    // Groups only refer to instances in their own file
+    let groups = config.remove_from_groups(&[alias]);
+    config.update()?;
+    println!("Moved instance: {} -> {}", alias, to.display());
+    for name in groups {
+        println!("Removed group: {}", name);
+    }
+    Ok(())
 }
 
-async fn get_active_instance_connection_info() -> Result<ConnectionInfo> {
-    let instance = get_active_instance()?;
-    let manager = get_manager(&instance.cloud, &instance.profile)?;
-    let status = manager.get_instance(&instance.instance_id).await?;
-    if status.state.as_str() != "running" {
-        return Err(Error::msg("Instance is not running"));
-    };
-    if status.public_dns.as_str() == "" {
-        return Err(Error::msg("Instance has no public DNS"));
+/// Add a copy of an instance's config under `new`, with a new instance ID if given
+fn duplicate_instance(source: &str, new: &str, instance_id: Option<String>) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    if config.instances.iter().any(|inst| inst.alias == new) {
+        return Err(Error::msg(format!(
+            "Instance with alias '{}' already exists",
+            new
+        )));
+    }
+    let mut instance = config.find_instance(source)?.clone();
+    let instance_id = match instance_id {
+        Some(instance_id) => instance_id,
+        None => user_input(&format!("Instance ID [{}]", instance.instance_id))?,
     };
-    let key_path = expand_tilde(&instance.key_path)
-        .ok_or_else(|| Error::msg(format!("Could not locate key {}", &instance.key_path)))?;
-    Ok(ConnectionInfo {
-        user: instance.user,
-        address: status.public_dns,
-        key_path,
-    })
+    if !instance_id.is_empty() {
+        instance.instance_id = instance_id;
+    }
+    instance.alias = new.to_string();
+    // Resizes of the source instance don't apply to the copy
+    instance.type_history.clear();
+    config.instances.push(instance);
+    config.update()?;
+    println!("Copied instance: {} -> {}", source, new);
+    Ok(())
 }
 
-fn instance_list() -> Result<()> {
+/// Print the active instance from the config alone, so it works offline
+fn which(verbose: bool) -> Result<()> {
     let config = ProfileConfig::get_or_create()?;
-    let info = config
-        .instances
-        .into_iter()
-        .map(|inst| inst.to_string())
-        .collect::<Vec<_>>()
-        .join("\n---\n");
-    if let Some(active) = config.active {
-        println!("Active instance: {}", active)
+    let instance = match config.active.as_deref() {
+        Some(active) => config.find_instance(active)?,
+        None => {
+            println!("Active instance: none");
+            return Ok(());
+        }
     };
-    println!("Configured instances:\n---\n{}", info);
+    println!("Active instance: {}", instance.alias);
+    println!("{}", instance);
+    if verbose {
+        for key_path in instance.key_paths.iter() {
+            let resolved = expand_path(key_path)
+                .map(|path| std::fs::canonicalize(&path).unwrap_or(path))
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "(could not resolve)".to_string());
+            println!("Resolved Key Path: {}", resolved);
+        }
+    }
     Ok(())
 }
 
-async fn start_instance() -> Result<()> {
-    let instance = get_active_instance()?;
-    let manager = get_manager(&instance.cloud, &instance.profile)?;
-    let state = manager.start_instance(&instance.instance_id).await?;
-    println!(
-        "{} ({}): {} -> {}",
-        instance.alias, instance.instance_id, state.previous, state.current
-    );
-    Ok(())
+/// Counts the failed checks of `remote doctor` as they are printed
+#[derive(Default)]
+struct DoctorReport {
+    problems: usize,
 }
 
-async fn stop_instance() -> Result<()> {
-    let instance = get_active_instance()?;
-    let manager = get_manager(&instance.cloud, &instance.profile)?;
-    let state = manager.stop_instance(&instance.instance_id).await?;
-    println!(
-        "{} ({}): {} -> {}",
-        instance.alias, instance.instance_id, state.previous, state.current
-    );
-    Ok(())
-}
+impl DoctorReport {
+    fn section(&self, name: &str) {
+        println!("{}", name);
+    }
 
-async fn open_ssh(ports: Option<Vec<u16>>) -> Result<()> {
-    let info = get_active_instance_connection_info().await?;
-    let addr = format!("{}@{}", info.user, info.address);
-    let mut c = Command::new("ssh");
-    c.arg("-i");
-    c.arg(info.key_path);
-    c.arg(addr);
-    if let Some(ports) = ports {
-        for p in ports.iter() {
-            c.arg("-L");
-            c.arg(format!("{}:localhost:{}", p, p));
+    fn pass(&self, check: &str) {
+        println!("  ok    {}", check);
+    }
+
+    fn fail(&mut self, check: &str, hint: &str) {
+        self.problems += 1;
+        println!("  FAIL  {}", check);
+        for line in hint.lines() {
+            println!("        {}", line);
         }
     }
-    c.stdout(Stdio::inherit());
-    c.stderr(Stdio::inherit());
-    c.stdin(Stdio::inherit());
-    c.output()?;
-    Ok(())
 }
 
-async fn run_scp(local_path: &str, remote_path: &str, upload: bool, recursive: bool) -> Result<()> {
-    let info = get_active_instance_connection_info().await?;
-    let local_path = local_path.to_string();
-    let remote_path = format!("{}@{}:{}", info.user, info.address, remote_path);
-
-    let mut cmd = Command::new("scp");
-    if recursive {
-        cmd.arg("-r");
-    };
-    cmd.arg("-i").arg(info.key_path);
-    if upload {
-        cmd.arg(local_path).arg(remote_path);
+/// Check the environment remote needs without changing anything, reporting each problem
+/// with a way to fix it
+fn doctor() -> Result<()> {
+    let mut report = DoctorReport::default();
+    let path = get_config_path()?;
+    report.section("Config");
+    let config = if !path.exists() {
+        report.fail(
+            &format!("{} does not exist", path.display()),
+            "Configure an instance with `remote new`",
+        );
+        None
     } else {
-        cmd.arg(remote_path).arg(local_path);
+        match ProfileConfig::from_file(&path) {
+            Ok(config) => {
+                report.pass(&format!("{} parses", path.display()));
+                Some(config)
+            }
+            Err(e) => {
+                let problems = ProfileConfig::validate_file(&path);
+                let hint = match problems.is_empty() {
+                    true => e.to_string(),
+                    false => problems.join("\n"),
+                };
+                report.fail(&format!("{} does not parse", path.display()), &hint);
+                None
+            }
+        }
     };
-    let _ = cmd
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::inherit())
-        .output()?;
-    Ok(())
+
+    report.section("Tools");
+    for program in ["ssh", "scp"] {
+        match find_on_path(program) {
+            Some(found) => report.pass(&format!("{} ({})", program, found.display())),
+            None => report.fail(
+                &format!("{} is not on PATH", program),
+                "Install an OpenSSH client",
+            ),
+        }
+    }
+
+    let instances = config
+        .as_ref()
+        .map_or(&[][..], |config| &config.instances[..]);
+    // AWS is the default cloud, so its credentials matter before anything is configured
+    if instances.is_empty() || instances.iter().any(|inst| inst.cloud == Cloud::Aws) {
+        report.section("AWS");
+        let credentials = match std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+            Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+            _ => expand_tilde("~/.aws/credentials"),
+        };
+        match credentials {
+            Some(path) if path.exists() => {
+                report.pass(&format!("credentials file {}", path.display()))
+            }
+            _ if std::env::var_os("AWS_ACCESS_KEY_ID").is_some() => {
+                report.pass("credentials from AWS_ACCESS_KEY_ID")
+            }
+            path => report.fail(
+                &format!(
+                    "no credentials file at {}",
+                    path.map_or("~/.aws/credentials".to_string(), |path| path
+                        .display()
+                        .to_string())
+                ),
+                "Create one with `aws configure`",
+            ),
+        }
+    }
+
+    if !instances.is_empty() {
+        report.section("Instances");
+    }
+    for inst in instances.iter() {
+        for key_path in inst.key_paths.iter() {
+            doctor_key(&mut report, &inst.alias, key_path);
+        }
+        if inst.key_paths.is_empty() {
+            report.fail(
+                &format!("{}: no key_path", inst.alias),
+                &format!("Set key_path for it in {}", path.display()),
+            );
+        }
+        if inst.cloud == Cloud::Ssh {
+            continue;
+        }
+        match get_instance_manager(inst) {
+            Ok(_) => report.pass(&format!(
+                "{}: {} manager for profile {}",
+                inst.alias, inst.cloud, inst.profile
+            )),
+            Err(e) => report.fail(
+                &format!("{}: {}", inst.alias, e),
+                &format!("Check its cloud, profile and region in {}", path.display()),
+            ),
+        }
+    }
+
+    println!("---");
+    match report.problems {
+        0 => {
+            println!("No problems found");
+            Ok(())
+        }
+        problems => Err(Error::msg(format!("Found {} problem(s)", problems))),
+    }
 }
 
-async fn instance_status(all: bool) -> Result<()> {
-    if all {
-        let instances = ProfileConfig::get_or_create()?.instances;
-        let futures = instances.iter().map(|inst| status(inst));
-        let _ = join_all(futures).await;
-        Ok(())
+/// Check that one of an instance's keys exists and ssh will accept it
+fn doctor_key(report: &mut DoctorReport, alias: &str, key_path: &str) {
+    let path = match expand_path(key_path) {
+        Some(path) if !key_path.is_empty() => path,
+        _ => {
+            report.fail(
+                &format!("{}: could not resolve key path '{}'", alias, key_path),
+                "Set key_path to the private key's location",
+            );
+            return;
+        }
+    };
+    if !path.exists() {
+        report.fail(
+            &format!("{}: key {} does not exist", alias, path.display()),
+            "Fix key_path, or switch keys with `remote key rotate`",
+        );
+    } else if let Err(e) = std::fs::File::open(&path) {
+        report.fail(
+            &format!("{}: key {} is not readable: {}", alias, path.display(), e),
+            &format!("chmod 600 {}", path.display()),
+        );
+    } else if let Some(mode) = open_key_permissions(&path) {
+        report.fail(
+            &format!(
+                "{}: key {} has permissions {:o}, which ssh rejects",
+                alias,
+                path.display(),
+                mode
+            ),
+            &format!(
+                "chmod 600 {}, or rerun a command with --fix-permissions",
+                path.display()
+            ),
+        );
     } else {
-        let instance = get_active_instance()?;
-        status(&instance).await
+        report.pass(&format!("{}: key {}", alias, path.display()));
     }
 }
 
-async fn status(instance: &InstanceConfig) -> Result<()> {
-    let manager = get_manager(&instance.cloud, &instance.profile)?;
-    let status = manager.get_instance(&instance.instance_id).await?;
-    println!("---");
-    println!("Alias: {}", instance.alias);
-    println!("{}", status.to_string());
-    Ok(())
+/// Where `program` would be found on PATH
+fn find_on_path(program: &str) -> Option<PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
 }
 
-async fn instance_resize(instance_type: &str) -> Result<()> {
+fn clear_active_instance() -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    config.active = None;
+    config.update()?;
+    println!("Active instance cleared");
+    Ok(())
+}
+
+/// Generate an ed25519 key pair at `key_path`, refusing to overwrite an existing key
+fn generate_key(key_path: &Path) -> Result<()> {
+    if key_path.exists() {
+        return Err(Error::msg(format!(
+            "Key file {} already exists, not overwriting",
+            key_path.display()
+        )));
+    }
+    if let Some(parent) = key_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let status = Command::new("ssh-keygen")
+        .args(["-t", "ed25519", "-f"])
+        .arg(key_path)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()?;
+    if !status.success() {
+        return Err(Error::msg("ssh-keygen failed to generate a key"));
+    }
+    Ok(())
+}
+
+// Settings for "new" given as flags; anything missing is prompted for. A plain comment
+// since a doc comment would replace the subcommand's about text.
+#[derive(Debug, StructOpt)]
+struct NewOptions {
+    /// The cloud provider [default: the configured default cloud]
+    #[structopt(long)]
+    cloud: Option<String>,
+    /// The cloud profile [default: the configured default profile]
+    #[structopt(long)]
+    profile: Option<String>,
+    /// The instance ID (or name on GCP and Azure)
+    #[structopt(long)]
+    instance_id: Option<String>,
+    /// Path to the SSH private key
+    #[structopt(long)]
+    key_path: Option<String>,
+    /// The SSH user name [default: read from the instance tag]
+    #[structopt(long)]
+    user: Option<String>,
+    /// The alias to configure the instance as
+    #[structopt(long)]
+    alias: Option<String>,
+    /// The GCP zone of the instance
+    #[structopt(long)]
+    zone: Option<String>,
+    /// The Azure resource group of the instance
+    #[structopt(long)]
+    resource_group: Option<String>,
+    /// The AWS region of the instance [default: the profile's region]
+    #[structopt(long)]
+    region: Option<String>,
+    /// An IAM role to assume with the profile's credentials on AWS
+    #[structopt(long)]
+    role_arn: Option<String>,
+    /// Host to reach the instance through with ssh's ProxyJump
+    #[structopt(long)]
+    jump_host: Option<String>,
+    /// The host name or IP of a plain ssh host (--cloud ssh)
+    #[structopt(long)]
+    address: Option<String>,
+}
+
+/// Use `value` if given, otherwise prompt when interactive. Blank answers are none.
+fn flag_or_prompt(
+    value: Option<String>,
+    interactive: bool,
+    prompt: &str,
+) -> Result<Option<String>> {
+    match value {
+        Some(value) => Ok(Some(value)),
+        None if interactive => Ok(Some(user_input(prompt)?).filter(|answer| !answer.is_empty())),
+        None => Ok(None),
+    }
+}
+
+/// The usual SSH user of the instance's OS image, to offer as the default. This is only a
+/// hint, so failures are ignored.
+async fn guess_ssh_user(instance: &InstanceConfig) -> Option<String> {
+    if instance.cloud == Cloud::Ssh {
+        return None;
+    }
+    let manager = get_instance_manager(instance).ok()?;
+    manager
+        .guess_ssh_user(&instance.instance_id)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// List the account's registered key pairs to match a local key against, if the cloud
+/// can. This is only a hint, so failures are ignored.
+async fn print_key_pairs(account: &InstanceConfig) {
+    let names = match get_instance_manager(account) {
+        Ok(manager) => manager.list_key_pairs().await.unwrap_or_default(),
+        Err(_) => return,
+    };
+    if !names.is_empty() {
+        println!(
+            "Key pairs registered with {}: {}",
+            account.cloud,
+            names.join(", ")
+        );
+    }
+}
+
+async fn new_instance(set_active: bool, generate: bool, opts: NewOptions) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    // Prompt only while a required value is missing, so scripts can pass every flag
+    let interactive = (opts.instance_id.is_none() && opts.address.is_none())
+        || opts.alias.is_none()
+        || (opts.key_path.is_none() && !generate);
+
+    let default_cloud = config.default_cloud.clone().unwrap_or_default();
+    let cloud = match flag_or_prompt(
+        opts.cloud,
+        interactive,
+        &format!("Cloud provider [{}]", default_cloud),
+    )? {
+        Some(cloud) => Cloud::from_str(&cloud)?,
+        None => default_cloud,
+    };
+    let default_profile = config.profile_or_default();
+    let profile = flag_or_prompt(
+        opts.profile,
+        interactive && cloud != Cloud::Ssh,
+        &format!("Cloud profile [{}]", default_profile),
+    )?
+    .unwrap_or(default_profile);
+    // Catch a missing profile before prompting for the rest
+    if cloud == Cloud::Aws {
+        ProfileCredentials::new(&profile).check().await?;
+        AwsCloud::from_profile(&profile)?;
+    }
+    let address = match cloud {
+        Cloud::Ssh => Some(match opts.address {
+            Some(address) => address,
+            None => user_input("Host address")?,
+        }),
+        _ => None,
+    };
+    let instance_id = match (opts.instance_id, &address) {
+        (Some(instance_id), _) => instance_id,
+        // Plain hosts have no cloud ID, so they go by their address
+        (None, Some(address)) => address.clone(),
+        (None, None) => user_input("Instance ID")?,
+    };
+    let zone = match cloud {
+        Cloud::Gcp => flag_or_prompt(
+            opts.zone,
+            interactive,
+            "Zone [looked up from instance name]",
+        )?,
+        _ => None,
+    };
+    let resource_group = match cloud {
+        Cloud::Azure => Some(
+            flag_or_prompt(opts.resource_group, interactive, "Resource group")?
+                .ok_or_else(|| Error::msg("Azure instances need a resource group"))?,
+        ),
+        _ => None,
+    };
+    let region = match cloud {
+        Cloud::Aws => flag_or_prompt(opts.region, interactive, "Region [profile default]")?,
+        _ => None,
+    };
+    if let Some(region) = &region {
+        Region::from_str(region)?;
+    }
+    let role_arn = match cloud {
+        Cloud::Aws => flag_or_prompt(opts.role_arn, interactive, "IAM role ARN to assume [none]")?,
+        _ => None,
+    };
+    let key_path = match generate {
+        true => {
+            let default = get_config_dir()?.join("keys").join(&instance_id);
+            let key_path = flag_or_prompt(
+                opts.key_path,
+                interactive,
+                &format!("SSH key path [{}]", default.display()),
+            )?
+            .unwrap_or_else(|| default.display().to_string());
+            let path = expand_path(&key_path).unwrap();
+            generate_key(&path)?;
+            let public_key = read_public_key(&path)?;
+            println!(
+                "Register this public key with your cloud provider:\n{}",
+                public_key
+            );
+            key_path
+        }
+        false => match opts.key_path {
+            Some(key_path) => key_path,
+            None => {
+                let account = InstanceConfig {
+                    profile: profile.clone(),
+                    cloud: cloud.clone(),
+                    region: region.clone(),
+                    role_arn: role_arn.clone(),
+                    resource_group: resource_group.clone(),
+                    ..Default::default()
+                };
+                print_key_pairs(&account).await;
+                user_input("SSH key path")?
+            }
+        },
+    };
+    let path = expand_path(&key_path).unwrap();
+    if !path.exists() {
+        return Err(Error::msg(format!("Could not find key file: {}", key_path)));
+    };
+    let user = match opts.user {
+        Some(user) => user,
+        None if interactive => {
+            let account = InstanceConfig {
+                instance_id: instance_id.clone(),
+                profile: profile.clone(),
+                cloud: cloud.clone(),
+                region: region.clone(),
+                role_arn: role_arn.clone(),
+                zone: zone.clone(),
+                resource_group: resource_group.clone(),
+                ..Default::default()
+            };
+            match guess_ssh_user(&account).await {
+                Some(guess) => Some(user_input(&format!("SSH user name [{}]", guess))?)
+                    .filter(|answer| !answer.is_empty())
+                    .unwrap_or(guess),
+                None => user_input("SSH user name [from instance tag]")?,
+            }
+        }
+        None => String::new(),
+    };
+    let jump_host = flag_or_prompt(opts.jump_host, interactive, "Jump host [none]")?;
+    let alias = match opts.alias {
+        Some(alias) => alias,
+        None => user_input("Alias")?,
+    };
+    println!("---");
+
+    let duplicates = config.instances.iter().any(|inst| inst.alias == alias);
+    if duplicates {
+        return Err(Error::msg(format!(
+            "Instance with alias '{}' already exists",
+            alias
+        )));
+    };
+
+    let instance = InstanceConfig {
+        alias: alias.clone(),
+        instance_id,
+        key_paths: vec![key_path],
+        user,
+        profile,
+        cloud,
+        zone,
+        resource_group,
+        region,
+        jump_host,
+        address,
+        role_arn,
+        ..Default::default()
+    };
+    // There is no cloud to check plain ssh hosts against
+    if instance.cloud != Cloud::Ssh {
+        status(
+            &instance,
+            &StatusOptions::default(),
+            &StatusLookup {
+                cache: &Mutex::default(),
+                managers: &ManagerPool::default(),
+                prices: None,
+            },
+        )
+        .await?;
+    }
+
+    config.instances.push(instance);
+    if set_active {
+        config.active = Some(alias)
+    }
+
+    config.update()?;
+    Ok(())
+}
+
+/// An alias for an imported instance from its Name tag or ID, made unique among `taken`
+fn import_alias(instance: &Instance, taken: &[String]) -> String {
+    let base = instance
+        .name()
+        .map(|name| name.split_whitespace().collect::<Vec<_>>().join("-"))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| instance.instance_id.clone());
+    let mut alias = base.clone();
+    let mut n = 2;
+    while taken.contains(&alias) {
+        alias = format!("{}-{}", base, n);
+        n += 1;
+    }
+    alias
+}
+
+/// Ask for an SSH key path until it names an existing file, blank keeping `default`
+fn prompt_key_path(prompt: &str, default: Option<&str>) -> Result<String> {
+    loop {
+        let prompt = match default {
+            Some(default) => format!("{} [{}]", prompt, default),
+            None => prompt.to_string(),
+        };
+        let answer = user_input(&prompt)?;
+        let key_path = match (answer.is_empty(), default) {
+            (false, _) => answer,
+            (true, Some(default)) => default.to_string(),
+            (true, None) => {
+                return Err(Error::msg("An SSH key path is needed to import instances"))
+            }
+        };
+        if expand_path(&key_path).is_some_and(|path| path.exists()) {
+            return Ok(key_path);
+        }
+        println!("Could not find key file: {}", key_path);
+    }
+}
+
+#[derive(Debug)]
+struct ImportOptions {
+    all: bool,
+    key_path: Option<String>,
+    user: Option<String>,
+    region: Option<String>,
+}
+
+/// Configure the listed instances that aren't already, asking about each one unless `--all`
+async fn import_instances(
+    cloud: Option<String>,
+    profile: Option<String>,
+    opts: ImportOptions,
+) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    let cloud = match cloud {
+        Some(cloud) => Cloud::from_str(&cloud)?,
+        None => config.default_cloud.clone().unwrap_or_default(),
+    };
+    let profile = profile.unwrap_or_else(|| config.profile_or_default());
+    let manager = get_manager(&cloud, &profile, opts.region.as_deref())?;
+    let listed = manager.list_instances().await?;
+
+    let (mut imported, mut skipped) = (0, 0);
+    if let Some(key_path) = &opts.key_path {
+        if !expand_path(key_path).is_some_and(|path| path.exists()) {
+            return Err(Error::msg(format!("Could not find key file: {}", key_path)));
+        }
+    }
+    // Asked for once with --all, and otherwise offered as the default for the next instance
+    let mut key_path = opts.key_path.clone();
+    for instance in listed {
+        let configured = config
+            .instances
+            .iter()
+            .any(|inst| inst.cloud == cloud && inst.instance_id == instance.instance_id);
+        if configured || matches!(instance.state.as_str(), "terminated" | "shutting-down") {
+            skipped += 1;
+            continue;
+        }
+        let taken = config
+            .instances
+            .iter()
+            .map(|inst| inst.alias.clone())
+            .collect::<Vec<_>>();
+        let mut alias = import_alias(&instance, &taken);
+        let user;
+        if opts.all {
+            if key_path.is_none() {
+                key_path = Some(prompt_key_path(
+                    "SSH key path for all imported instances",
+                    None,
+                )?);
+            }
+            user = opts.user.clone().unwrap_or_default();
+        } else {
+            println!("---");
+            println!(
+                "{} ({}, {}, {})",
+                instance.name().unwrap_or("-"),
+                instance.instance_id,
+                instance.instance_type,
+                color_state(&instance.state)
+            );
+            if !confirm("Import it?", false)? {
+                skipped += 1;
+                continue;
+            }
+            let answer = user_input(&format!("Alias [{}]", alias))?;
+            if !answer.is_empty() {
+                if taken.contains(&answer) {
+                    return Err(Error::msg(format!(
+                        "Instance with alias '{}' already exists",
+                        answer
+                    )));
+                }
+                alias = answer;
+            }
+            key_path = Some(prompt_key_path("SSH key path", key_path.as_deref())?);
+            user = match &opts.user {
+                Some(user) => user.clone(),
+                None => {
+                    let guess = manager
+                        .guess_ssh_user(&instance.instance_id)
+                        .await
+                        .ok()
+                        .flatten();
+                    let prompt = match &guess {
+                        Some(guess) => format!("SSH user name [{}]", guess),
+                        None => "SSH user name [from instance tag]".to_string(),
+                    };
+                    Some(user_input(&prompt)?)
+                        .filter(|answer| !answer.is_empty())
+                        .or(guess)
+                        .unwrap_or_default()
+                }
+            };
+        }
+        let key_path = key_path.clone().unwrap_or_default();
+        println!("Imported {} as {}", instance.instance_id, alias);
+        config.instances.push(InstanceConfig {
+            alias,
+            instance_id: instance.instance_id,
+            key_paths: vec![key_path],
+            user,
+            profile: profile.clone(),
+            cloud: cloud.clone(),
+            region: opts.region.clone(),
+            ..Default::default()
+        });
+        imported += 1;
+    }
+    if imported > 0 {
+        config.update()?;
+    }
+    println!("---");
+    println!(
+        "Imported {} instance(s), skipped {} already configured, terminated or declined",
+        imported, skipped
+    );
+    Ok(())
+}
+
+/// Remove an instance from the config, first confirming if it is still running since it
+/// would keep billing without being managed
+async fn remove_instance(alias: &str, force: bool, assume_yes: bool) -> Result<()> {
+    let instance = ProfileConfig::get_or_create()?
+        .find_instance(alias)?
+        .clone();
+    if !force && instance.cloud != Cloud::Ssh {
+        // Only a warning, so an unreachable cloud can't block cleaning up the config
+        let state = match get_instance_manager(&instance) {
+            Ok(manager) => manager
+                .get_instance(&instance.instance_id)
+                .await
+                .map(|status| status.state),
+            Err(e) => Err(e),
+        };
+        match state {
+            Ok(state) if state == "running" => {
+                println!(
+                    "{} is still running on {}, and removing it from the config won't stop it",
+                    alias, instance.cloud
+                );
+                if !confirm("Remove it anyway?", assume_yes)? {
+                    return Err(Error::msg("Aborted"));
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Could not check whether {} is running: {}", alias, e),
+        }
+    }
+    remove_instance_config(alias)?;
+    println!(
+        "This only removed the local config, use --terminate to also destroy the cloud instance"
+    );
+    Ok(())
+}
+
+fn remove_instance_config(alias: &str) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    config.instances.retain(|inst| inst.alias != alias);
+    if config.active == Some(alias.to_string()) {
+        config.active = None;
+        println!("{} was the active instance, none is active now", alias);
+    };
+    let groups = config.remove_from_groups(&[alias]);
+    config.update()?;
+    println!("Removed instance: {}", alias);
+    for name in groups {
+        println!("Removed group: {}", name);
+    }
+    Ok(())
+}
+
+/// Terminate an instance in the cloud, then remove it from the config
+async fn terminate_and_remove(alias: &str, assume_yes: bool, dry_run: bool) -> Result<()> {
+    let instance = ProfileConfig::get_or_create()?
+        .find_instance(alias)?
+        .clone();
+    let manager = get_instance_manager(&instance)?;
+    let status = manager.get_instance(&instance.instance_id).await?;
+    println!(
+        "This will permanently terminate {} on {} and remove it from the config:\n{}",
+        instance.alias, instance.cloud, status
+    );
+    if dry_run {
+        print_dry_run("terminate", &instance);
+        return Ok(());
+    }
+    if !assume_yes && user_input(&format!("Type the alias '{}' to confirm", alias))? != alias {
+        return Err(Error::msg("Aborted"));
+    }
+    let state = manager.terminate_instance(&instance.instance_id).await?;
+    DescriptionCache::invalidate(&instance.cloud, &instance.profile, &instance.instance_id)?;
+    println!(
+        "{} ({}): {} -> {}",
+        instance.alias, instance.instance_id, state.previous, state.current
+    );
+    remove_instance_config(alias)
+}
+
+fn update_group(opt: GroupOpt) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    let name = match opt {
+        GroupOpt::Add { name, aliases } => {
+            for alias in aliases.iter() {
+                config.find_instance(alias)?;
+            }
+            let members = config.groups.entry(name.clone()).or_default();
+            for alias in aliases {
+                if !members.contains(&alias) {
+                    members.push(alias);
+                }
+            }
+            name
+        }
+        GroupOpt::Rm { name, aliases } => {
+            let members = config
+                .groups
+                .get_mut(&name)
+                .ok_or_else(|| Error::msg(format!("No group named '{}' found", name)))?;
+            members.retain(|alias| !aliases.is_empty() && !aliases.contains(alias));
+            if members.is_empty() {
+                config.groups.remove(&name);
+            }
+            name
+        }
+    };
+    config.update()?;
+    match config.groups.get(&name) {
+        Some(members) => println!("Group {}: {}", name, members.join(", ")),
+        None => println!("Removed group: {}", name),
+    }
+    Ok(())
+}
+
+fn remove_instances(all: bool, group: Option<String>, assume_yes: bool) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    let aliases = match &group {
+        Some(name) => config
+            .groups
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::msg(format!("No group named '{}' found", name)))?,
+        None if all => config
+            .instances
+            .iter()
+            .map(|inst| inst.alias.clone())
+            .collect(),
+        None => Vec::new(),
+    };
+    if aliases.is_empty() {
+        println!("No instances to remove");
+        return Ok(());
+    }
+    println!("Instances to remove: {}", aliases.join(", "));
+    if !confirm("Remove these instances?", assume_yes)? {
+        return Err(Error::msg("Aborted"));
+    }
+
+    config
+        .instances
+        .retain(|inst| !aliases.contains(&inst.alias));
+    if let Some(active) = &config.active {
+        if aliases.contains(active) {
+            config.active = None
+        }
+    };
+    let removed = aliases.iter().map(String::as_str).collect::<Vec<_>>();
+    let mut groups = config.remove_from_groups(&removed);
+    if let Some(name) = &group {
+        if config.groups.remove(name).is_some() {
+            groups.push(name.clone());
+        }
+    }
+    config.update()?;
+    for alias in aliases.iter() {
+        println!("Removed instance: {}", alias);
+    }
+    for name in groups {
+        println!("Removed group: {}", name);
+    }
+    Ok(())
+}
+
+/// An API token from the environment, which takes precedence over one in the config
+fn env_token(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|token| !token.is_empty())
+}
+
+/// The manager for a cloud profile. `region` only applies to AWS, which otherwise
+/// uses the region from the environment or the default profile.
+fn get_manager(
+    cloud: &Cloud,
+    profile: &str,
+    region: Option<&str>,
+) -> Result<Box<dyn InstanceManager>> {
+    let profile = PROFILE_OVERRIDE.get().map_or(profile, String::as_str);
+    info!("Using {} profile {}", cloud, profile);
+    match cloud {
+        Cloud::Aws => Ok(Box::new(AwsCloud::from_profile_in_region(
+            profile,
+            aws_region(profile, region.map(|region| (region, "--region")))?,
+        )?)),
+        Cloud::Gcp => Ok(Box::new(GcpCloud::new(profile, None))),
+        Cloud::Azure => Ok(Box::new(AzureCloud::new(profile, None))),
+        Cloud::DigitalOcean => {
+            let token = env_token(DIGITALOCEAN_TOKEN_VAR)
+                .or(ProfileConfig::get_or_create()?.digitalocean_token);
+            Ok(Box::new(DigitalOceanCloud::new(profile, token)))
+        }
+        Cloud::Hetzner => {
+            let token =
+                env_token(HCLOUD_TOKEN_VAR).or(ProfileConfig::get_or_create()?.hetzner_token);
+            Ok(Box::new(HetznerCloud::new(profile, token)?))
+        }
+        Cloud::External(command) => Ok(Box::new(ExternalCloud::new(command, profile))),
+        Cloud::Ssh => Err(Error::msg(
+            "Managing instances is not supported for ssh hosts, they can only be connected to",
+        )),
+    }
+}
+
+/// The manager for a configured instance, including its provider specific location
+fn get_instance_manager(instance: &InstanceConfig) -> Result<Box<dyn InstanceManager>> {
+    let profile = PROFILE_OVERRIDE.get().unwrap_or(&instance.profile);
+    info!(
+        "Using {} profile {} for {}",
+        instance.cloud, profile, instance.alias
+    );
+    match &instance.cloud {
+        Cloud::Gcp => Ok(Box::new(GcpCloud::new(profile, instance.zone.as_deref()))),
+        Cloud::Azure => {
+            let group = instance
+                .resource_group
+                .as_deref()
+                .ok_or_else(|| Error::msg("Azure instances need a resource_group"))?;
+            Ok(Box::new(AzureCloud::new(profile, Some(group))))
+        }
+        Cloud::Aws => {
+            let configured = match (REGION_OVERRIDE.get(), &instance.region) {
+                (Some(region), _) => Some((region.as_str(), "--region")),
+                (None, Some(region)) => Some((region.as_str(), "the instance config")),
+                (None, None) => None,
+            };
+            let mut region = aws_region(profile, configured)?;
+            if let Some(endpoint) = &instance.endpoint {
+                region = region_with_endpoint(region, endpoint);
+            }
+            Ok(Box::new(AwsCloud::from_profile_with_role(
+                profile,
+                region,
+                instance.role_arn.as_deref(),
+            )?))
+        }
+        cloud => get_manager(cloud, profile, instance.region.as_deref()),
+    }
+}
+
+/// The instance settings `get_instance_manager` builds a manager from
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct ManagerKey {
+    cloud: String,
+    profile: String,
+    region: Option<String>,
+    zone: Option<String>,
+    resource_group: Option<String>,
+    endpoint: Option<String>,
+    role_arn: Option<String>,
+}
+
+impl ManagerKey {
+    fn of(instance: &InstanceConfig) -> Self {
+        Self {
+            cloud: instance.cloud.to_string(),
+            profile: instance.profile.clone(),
+            region: instance.region.clone(),
+            zone: instance.zone.clone(),
+            resource_group: instance.resource_group.clone(),
+            endpoint: instance.endpoint.clone(),
+            role_arn: instance.role_arn.clone(),
+        }
+    }
+}
+
+/// Managers shared by instances with the same cloud, profile and location, so looking up
+/// many instances builds one client per profile and region rather than one each
+#[derive(Default)]
+struct ManagerPool {
+    managers: Mutex<BTreeMap<ManagerKey, Arc<dyn InstanceManager>>>,
+}
+
+impl ManagerPool {
+    fn get(&self, instance: &InstanceConfig) -> Result<Arc<dyn InstanceManager>> {
+        let mut managers = self.managers.lock().unwrap();
+        let key = ManagerKey::of(instance);
+        if let Some(manager) = managers.get(&key) {
+            return Ok(manager.clone());
+        }
+        let manager: Arc<dyn InstanceManager> = get_instance_manager(instance)?.into();
+        managers.insert(key, manager.clone());
+        Ok(manager)
+    }
+}
+
+/// Price lookups shared by instances with the same profile, so each instance is priced with
+/// its own profile's credentials unless one profile is given for all of them
+#[derive(Default)]
+struct PricePool {
+    profile: Option<String>,
+    lookups: Mutex<BTreeMap<String, Arc<PriceLookup>>>,
+}
+
+impl PricePool {
+    fn new(profile: Option<String>) -> Self {
+        Self {
+            profile,
+            ..Self::default()
+        }
+    }
+
+    fn get(&self, instance: &InstanceConfig) -> Result<Arc<PriceLookup>> {
+        let profile = self.profile.as_deref().unwrap_or(&instance.profile);
+        let mut lookups = self.lookups.lock().unwrap();
+        if let Some(lookup) = lookups.get(profile) {
+            return Ok(lookup.clone());
+        }
+        let lookup = Arc::new(PriceLookup::from_profile(profile)?);
+        lookups.insert(profile.to_string(), lookup.clone());
+        Ok(lookup)
+    }
+
+    /// The on-demand hourly price of an instance in its own region
+    async fn hourly_price(&self, instance: &InstanceConfig, status: &Instance) -> Result<f64> {
+        let region = status
+            .region()
+            .ok_or_else(|| Error::msg("Unknown region"))?;
+        self.get(instance)?
+            .hourly_price(&status.instance_type, region)
+            .await
+    }
+}
+
+/// Set by `--profile-override` to use another cloud profile for this run, without saving it
+static PROFILE_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+/// Resolve the region for an AWS profile, logging where it came from
+fn aws_region(profile: &str, configured: Option<(&str, &str)>) -> Result<Region> {
+    let resolved = resolve_region(profile, configured)?;
+    info!(
+        "Using region {} for profile {} (from {})",
+        resolved.region.name(),
+        profile,
+        resolved.source
+    );
+    Ok(resolved.region)
+}
+
+/// Set by `--region` to look AWS instances up in another region for this run
+static REGION_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+/// Use `region` for AWS instances for the rest of the command, skipping cached descriptions
+/// which may be from the configured region
+fn override_region(region: Option<String>) -> Result<()> {
+    if let Some(region) = region {
+        Region::from_str(&region)?;
+        REFRESH_DESCRIPTIONS.store(true, Ordering::Relaxed);
+        let _ = REGION_OVERRIDE.set(region);
+    }
+    Ok(())
+}
+
+/// Set by `--pick` to choose the instance from a list even when one is active
+static PICK_INSTANCE: AtomicBool = AtomicBool::new(false);
+
+/// The instance chosen from the list, used for the rest of the command
+static PICKED_ALIAS: OnceLock<String> = OnceLock::new();
+
+fn get_active_instance() -> Result<InstanceConfig> {
+    let config = ProfileConfig::get_or_create()?;
+    let active = match (PICKED_ALIAS.get(), &config.active) {
+        (Some(alias), _) => alias.clone(),
+        (None, Some(active)) if !PICK_INSTANCE.load(Ordering::Relaxed) => active.clone(),
+        (None, _) => pick_instance(&config)?,
+    };
+    let instances = config
+        .instances
+        .into_iter()
+        .filter(|x| x.alias == active)
+        .collect::<Vec<InstanceConfig>>();
+    if instances.is_empty() {
+        return Err(Error::msg(format!(
+            "Active instance '{}' not found in instance list",
+            active
+        )));
+    };
+    Ok(instances[0].to_owned())
+}
+
+/// Ask which configured instance to use from a numbered list, offering to make it the
+/// active one. Without an interactive terminal this errors rather than waiting on input.
+fn pick_instance(config: &ProfileConfig) -> Result<String> {
+    if !std::io::stdin().is_terminal() || config.instances.is_empty() {
+        return Err(match config.active {
+            Some(_) => Error::msg("--pick needs an interactive terminal and configured instances"),
+            None => RemoteError::NoActiveInstance.into(),
+        });
+    }
+    for (i, inst) in config.instances.iter().enumerate() {
+        println!(
+            "{:>3}) {} ({} {})",
+            i + 1,
+            inst.alias,
+            inst.cloud,
+            inst.instance_id
+        );
+    }
+    let answer = user_input("Instance number or alias")?;
+    let alias = match answer.parse::<usize>() {
+        Ok(n) if n >= 1 && n <= config.instances.len() => config.instances[n - 1].alias.clone(),
+        _ => config.find_instance(&answer)?.alias.clone(),
+    };
+    if config.active.as_ref() != Some(&alias)
+        && confirm(&format!("Make {} the active instance?", alias), false)?
+    {
+        set_active_instance(&alias)?;
+    }
+    let _ = PICKED_ALIAS.set(alias.clone());
+    Ok(alias)
+}
+
+pub struct ConnectionInfo {
+    pub user: String,
+    pub address: String,
+    /// Keys ssh tries in order
+    pub key_paths: Vec<PathBuf>,
+    /// Extra `-o` options passed to every ssh-based command
+    pub options: Vec<String>,
+    /// The SSH port, when it isn't `DEFAULT_SSH_PORT`
+    pub port: Option<u16>,
+}
+
+impl ConnectionInfo {
+    pub fn destination(&self) -> String {
+        format!("{}@{}", self.user, self.address)
+    }
+
+    /// `user@address:path` for scp and rsync, which need IPv6 addresses in brackets
+    pub fn remote_path(&self, path: &str) -> String {
+        if self.address.contains(':') {
+            format!("{}@[{}]:{}", self.user, self.address, path)
+        } else {
+            format!("{}:{}", self.destination(), path)
+        }
+    }
+
+    /// The key and options arguments shared by ssh, scp and mosh
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for key_path in self.key_paths.iter() {
+            args.push("-i".to_string());
+            args.push(key_path.display().to_string());
+        }
+        for option in self.options.iter() {
+            args.push("-o".to_string());
+            args.push(option.clone());
+        }
+        // scp spells ssh's -p as -P and reads -p as preserving times, so pass it as an option
+        if let Some(port) = self.port {
+            args.push("-o".to_string());
+            args.push(format!("Port={}", port));
+        }
+        args
+    }
+
+    /// An ssh command line with `ssh_args` quoted, for options that a shell splits up
+    pub fn ssh_command(&self) -> String {
+        std::iter::once("ssh".to_string())
+            .chain(self.ssh_args().iter().map(|arg| shell_quote(arg)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn ssh_port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_SSH_PORT)
+    }
+}
+
+/// The port sshd listens on unless an instance's `ssh_port` or `--ssh-port` says otherwise
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// Set by `--ssh-port` to connect on another port for this run, without saving it
+static SSH_PORT_OVERRIDE: OnceLock<u16> = OnceLock::new();
+
+async fn get_active_instance_connection_info() -> Result<ConnectionInfo> {
+    let instance = get_active_instance()?;
+    get_connection_info(instance, AddressMode::Public).await
+}
+
+/// Which of an instance's addresses to connect to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AddressMode {
+    Public,
+    Private,
+    /// The public DNS if it accepts SSH connections, otherwise the private IP
+    Auto,
+}
+
+/// How long `AddressMode::Auto` waits for the public address to accept a connection
+const AUTO_ADDRESS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Whether `address` accepts TCP connections on the SSH port
+async fn accepts_ssh(address: &str, port: u16) -> bool {
+    let addrs = match tokio::net::lookup_host((address, port)).await {
+        Ok(addrs) => addrs,
+        Err(_) => return false,
+    };
+    for addr in addrs {
+        let connect = tokio::net::TcpStream::connect(addr);
+        if let Ok(Ok(_)) = tokio::time::timeout(AUTO_ADDRESS_TIMEOUT, connect).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// How long `--wait-ssh` waits for the SSH port to open
+const SSH_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long to wait between attempts to connect to the SSH port
+const SSH_WAIT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Wait until `address` accepts connections on the SSH port, printing dots on stderr
+async fn wait_for_ssh(address: &str, port: u16) -> Result<()> {
+    let start = Instant::now();
+    let mut waited = false;
+    while !accepts_ssh(address, port).await {
+        if start.elapsed() >= SSH_WAIT_TIMEOUT {
+            if waited {
+                eprintln!();
+            }
+            return Err(Error::msg(format!(
+                "Timed out waiting for {} to accept SSH connections",
+                address
+            )));
+        }
+        if !waited {
+            eprint!("Waiting for SSH on {}", address);
+            waited = true;
+        }
+        eprint!(".");
+        std::io::stderr().flush()?;
+        tokio::time::delay_for(SSH_WAIT_INTERVAL).await;
+    }
+    if waited {
+        eprintln!(" ready");
+    }
+    Ok(())
+}
+
+/// Resolve how to reach a running instance at the address `mode` picks
+async fn get_connection_info(
+    instance: InstanceConfig,
+    mode: AddressMode,
+) -> Result<ConnectionInfo> {
+    if instance.cloud == Cloud::Ssh {
+        let status = ssh_host_status(&instance)?;
+        return connection_info_from(&instance, status, mode).await;
+    }
+    let refresh = REFRESH_DESCRIPTIONS.load(Ordering::Relaxed);
+    let status = fetch_connection_status(&instance, refresh).await?;
+    let info = connection_info_from(&instance, status, mode).await?;
+    // A jump host resolves the address itself, which may only work from its network
+    if !ProfileConfig::get_or_create()?.verify_dns || instance.jump_host.is_some() {
+        return Ok(info);
+    }
+    verify_dns(&instance, info, mode).await
+}
+
+/// Times the instance is looked up again when its address doesn't resolve
+const DNS_RETRIES: u32 = 3;
+
+/// Delay before looking the instance up again
+const DNS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Make sure the address to connect to resolves, looking the instance up again in case
+/// the cloud provider returned a stale DNS name or one that hasn't propagated yet
+async fn verify_dns(
+    instance: &InstanceConfig,
+    mut info: ConnectionInfo,
+    mode: AddressMode,
+) -> Result<ConnectionInfo> {
+    let port = info.port.unwrap_or(DEFAULT_SSH_PORT);
+    for attempt in 1..=DNS_RETRIES {
+        if info.address.parse::<IpAddr>().is_ok() {
+            return Ok(info);
+        }
+        let resolved = match tokio::net::lookup_host((info.address.as_str(), port)).await {
+            Ok(mut addresses) => addresses.next().is_some(),
+            Err(e) => {
+                info!("Could not resolve {}: {}", info.address, e);
+                false
+            }
+        };
+        if resolved {
+            return Ok(info);
+        }
+        if attempt == DNS_RETRIES {
+            break;
+        }
+        info!(
+            "Looking up {} again in {}s ({}/{})",
+            instance.alias,
+            DNS_RETRY_INTERVAL.as_secs(),
+            attempt,
+            DNS_RETRIES - 1
+        );
+        tokio::time::delay_for(DNS_RETRY_INTERVAL).await;
+        let status = fetch_connection_status(instance, true).await?;
+        info = connection_info_from(instance, status, mode).await?;
+    }
+    Err(RemoteError::DnsNotPropagated(info.address).into())
+}
+
+/// Set by `--refresh` so connecting always looks the instance up
+static REFRESH_DESCRIPTIONS: AtomicBool = AtomicBool::new(false);
+
+/// Describe an instance to connect to, reusing a cached running description younger
+/// than the configured `address_cache_ttl` unless `refresh`, so repeated commands skip the cloud API
+async fn fetch_connection_status(instance: &InstanceConfig, refresh: bool) -> Result<Instance> {
+    let key = DescriptionCache::key(
+        &instance.cloud,
+        &instance.profile,
+        Some(&instance.instance_id),
+    );
+    let mut cache = DescriptionCache::load();
+    if !refresh {
+        let ttl = ProfileConfig::get_or_create()?
+            .address_cache_ttl
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_ADDRESS_TTL);
+        if let Some(cached) = cache.get(&key, ttl) {
+            if cached[0].state == "running" {
+                return Ok(cached[0].clone());
+            }
+        }
+    }
+    let manager = get_instance_manager(instance)?;
+    let status = manager.get_instance(&instance.instance_id).await?;
+    cache.put(key, vec![status.clone()]);
+    cache.save()?;
+    Ok(status)
+}
+
+/// A plain ssh host described as an always running instance at its configured address
+fn ssh_host_status(instance: &InstanceConfig) -> Result<Instance> {
+    let address = instance
+        .address
+        .clone()
+        .ok_or_else(|| Error::msg(format!("No address configured for {}", instance.alias)))?;
+    Ok(Instance {
+        instance_type: String::new(),
+        instance_id: instance.instance_id.clone(),
+        public_dns: address.clone(),
+        private_ip: address,
+        tags: Vec::new(),
+        state: "running".to_string(),
+        availability_zone: None,
+        launch_time: None,
+        lifecycle: None,
+        ipv6: None,
+    })
+}
+
+/// Resolve how to reach an instance from an already fetched description
+async fn connection_info_from(
+    instance: &InstanceConfig,
+    status: Instance,
+    mode: AddressMode,
+) -> Result<ConnectionInfo> {
+    if status.state.as_str() != "running" {
+        return Err(RemoteError::NotRunning.into());
+    };
+    let config = ProfileConfig::get_or_create()?;
+    let user = resolve_user(&config, instance, &status)?;
+    let options = config.ssh_options(instance)?;
+    let port = SSH_PORT_OVERRIDE.get().copied().or(instance.ssh_port);
+    let ssh_port = port.unwrap_or(DEFAULT_SSH_PORT);
+    let address = match mode {
+        AddressMode::Private => {
+            if status.private_ip.as_str() == "" {
+                return Err(Error::msg("Instance has no private IP"));
+            };
+            status.private_ip
+        }
+        AddressMode::Public => {
+            if let Some(elastic_ip) = &instance.elastic_ip {
+                elastic_ip.clone()
+            } else if status.public_dns.as_str() != "" {
+                status.public_dns
+            } else if let Some(ipv6) = status.ipv6 {
+                ipv6
+            } else if instance.jump_host.is_some() && status.private_ip.as_str() != "" {
+                // The jump host can reach instances that are only on a private network
+                status.private_ip
+            } else {
+                return Err(RemoteError::NoPublicDns.into());
+            }
+        }
+        AddressMode::Auto => {
+            let public_dns_ok =
+                status.public_dns.as_str() != "" && accepts_ssh(&status.public_dns, ssh_port).await;
+            let ipv6_ok = match (&status.ipv6, public_dns_ok) {
+                (Some(ipv6), false) => accepts_ssh(ipv6, ssh_port).await,
+                _ => false,
+            };
+            if public_dns_ok {
+                println!("Connecting via public DNS {}", status.public_dns);
+                status.public_dns
+            } else if let (Some(ipv6), true) = (status.ipv6, ipv6_ok) {
+                println!("Connecting via IPv6 address {}", ipv6);
+                ipv6
+            } else if status.private_ip.as_str() != "" {
+                println!(
+                    "Public DNS unavailable, connecting via private IP {}",
+                    status.private_ip
+                );
+                status.private_ip
+            } else {
+                return Err(Error::msg(
+                    "Instance has neither a reachable public DNS nor a private IP",
+                ));
+            }
+        }
+    };
+    if instance.key_paths.is_empty() {
+        return Err(Error::msg(format!(
+            "No SSH key configured for {}",
+            instance.alias
+        )));
+    }
+    let key_paths = instance
+        .key_paths
+        .iter()
+        .map(|key_path| {
+            let path = expand_path(key_path)
+                .ok_or_else(|| Error::msg(format!("Could not locate key {}", key_path)))?;
+            check_key_permissions(&path)?;
+            Ok(path)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ConnectionInfo {
+        user,
+        address,
+        key_paths,
+        options,
+        port,
+    })
+}
+
+/// Set by `--fix-permissions` to tighten keys that are readable by others
+static FIX_KEY_PERMISSIONS: AtomicBool = AtomicBool::new(false);
+
+/// Fail early with a fix if ssh would refuse the key for being readable by group or others
+#[cfg(unix)]
+fn check_key_permissions(key_path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A missing key is reported by ssh itself
+    let mode = match open_key_permissions(key_path) {
+        Some(mode) => mode,
+        None => return Ok(()),
+    };
+    if FIX_KEY_PERMISSIONS.load(Ordering::Relaxed) {
+        std::fs::set_permissions(key_path, std::fs::Permissions::from_mode(0o600))?;
+        eprintln!(
+            "Changed permissions of {} from {:o} to 600",
+            key_path.display(),
+            mode
+        );
+        return Ok(());
+    }
+    Err(Error::msg(format!(
+        "Key {} has permissions {:o}, which ssh rejects as too open. Fix them with\n  \
+         chmod 600 {}\nor rerun with --fix-permissions",
+        key_path.display(),
+        mode,
+        key_path.display()
+    )))
+}
+
+#[cfg(not(unix))]
+fn check_key_permissions(_key_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// The key's permission bits if group or others can access it
+#[cfg(unix)]
+fn open_key_permissions(key_path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(key_path).ok()?.permissions().mode() & 0o777;
+    Some(mode).filter(|mode| mode & 0o077 != 0)
+}
+
+#[cfg(not(unix))]
+fn open_key_permissions(_key_path: &Path) -> Option<u32> {
+    None
+}
+
+/// Use the configured user, then the instance's user tag, then the global default
+fn resolve_user(
+    config: &ProfileConfig,
+    instance: &InstanceConfig,
+    status: &Instance,
+) -> Result<String> {
+    if !instance.user.is_empty() {
+        return Ok(instance.user.clone());
+    }
+    let tag_key = config.user_tag_or_default();
+    status
+        .tags
+        .iter()
+        .find(|tag| tag.key == tag_key)
+        .map(|tag| tag.value.clone())
+        .or_else(|| config.default_user.clone())
+        .ok_or_else(|| {
+            Error::msg(format!(
+                "No SSH user for {}: set one in the config or tag the instance with {}",
+                instance.alias, tag_key
+            ))
+        })
+}
+
+async fn instance_list(only_cloud: Option<String>, plain: bool, format: ListFormat) -> Result<()> {
+    let config = ProfileConfig::get_or_create()?;
+    let only_cloud = only_cloud
+        .map(|cloud| Cloud::from_str(&cloud))
+        .transpose()?;
+    let instances = config
+        .instances
+        .into_iter()
+        .filter(|inst| only_cloud.as_ref().is_none_or(|cloud| &inst.cloud == cloud));
+    if format == ListFormat::Csv {
+        let instances = instances.collect::<Vec<_>>();
+        let fetches = instances.iter().map(|inst| async move {
+            let status = async {
+                get_instance_manager(inst)?
+                    .get_instance(&inst.instance_id)
+                    .await
+            };
+            (inst, status.await)
+        });
+        let mut writer = csv_writer()?;
+        for (inst, status) in run_bounded(fetches).await {
+            match status {
+                Ok(status) => writer.serialize(CsvInstance::new(&inst.alias, &status))?,
+                Err(e) => {
+                    eprintln!("Could not look up {}: {}", inst.alias, e);
+                    writer.serialize(CsvInstance {
+                        alias: &inst.alias,
+                        instance_id: &inst.instance_id,
+                        state: "error",
+                        ..CsvInstance::default()
+                    })?;
+                }
+            }
+            // Flushed per row so rows stay in order with the errors on stderr
+            writer.flush()?;
+        }
+        return Ok(());
+    }
+    let json = format == ListFormat::Json;
+    if !plain && !json {
+        if let Some(active) = config.active.as_deref() {
+            println!("Active instance: {}", active)
+        };
+        let instances = instances.collect::<Vec<_>>();
+        println!("{}", fleet_table(&instances, false).await.render_stdout());
+        return Ok(());
+    }
+    if json {
+        for inst in instances {
+            print_json(ConfiguredOutput {
+                active: config.active.as_deref() == Some(inst.alias.as_str()),
+                config: &inst,
+            })?;
+        }
+        return Ok(());
+    }
+    let info = instances
+        .map(|inst| inst.to_string())
+        .collect::<Vec<_>>()
+        .join("\n---\n");
+    if let Some(active) = config.active {
+        println!("Active instance: {}", active)
+    };
+    println!("Configured instances:\n---\n{}", info);
+    Ok(())
+}
+
+/// How long `start --wait` waits for the instance to become reachable
+const START_WAIT_TIMEOUT: Duration = Duration::from_secs(300);
+
+async fn start_instance(
+    stop_others: bool,
+    json: bool,
+    wait: bool,
+    assume_yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let config = ProfileConfig::get_or_create()?;
+    let instance = get_active_instance()?;
+    if stop_others || config.single_running {
+        let others = config
+            .instances
+            .into_iter()
+            .filter(|inst| inst.cloud == instance.cloud && inst.profile == instance.profile)
+            .collect::<Vec<_>>();
+        stop_instances_except(&others, &[&instance.alias], json, assume_yes, dry_run).await?;
+    }
+    change_state(
+        get_instance_manager(&instance)?.as_ref(),
+        &instance,
+        StateAction::Start,
+        json,
+        wait,
+        assume_yes,
+        dry_run,
+    )
+    .await
+}
+
+/// Print the change `--dry-run` skipped making to an instance
+fn print_dry_run(action: &str, instance: &InstanceConfig) {
+    println!(
+        "Dry run: would {} {} ({}) on {}",
+        action, instance.alias, instance.instance_id, instance.cloud
+    );
+}
+
+/// Poll until the instance is running with a public address, printing progress on stderr
+/// so it doesn't mix with JSON output
+async fn wait_until_reachable(
+    manager: &dyn InstanceManager,
+    instance: &InstanceConfig,
+) -> Result<()> {
+    let reachable = |status: &Instance| {
+        let reachable = status.state == "running" && !status.public_dns.is_empty();
+        if !reachable {
+            eprintln!("Waiting for {} ({})...", instance.alias, status.state);
+        }
+        reachable
+    };
+    let status = manager
+        .wait_until(
+            &instance.instance_id,
+            "reachable",
+            START_WAIT_TIMEOUT,
+            &reachable,
+        )
+        .await?;
+    eprintln!("{} is running at {}", instance.alias, status.public_dns);
+    Ok(())
+}
+
+async fn stop_instance(json: bool, assume_yes: bool, dry_run: bool) -> Result<()> {
+    let instance = get_active_instance()?;
+    change_state(
+        get_instance_manager(&instance)?.as_ref(),
+        &instance,
+        StateAction::Stop,
+        json,
+        false,
+        assume_yes,
+        dry_run,
+    )
+    .await
+}
+
+/// Ask for the alias to be retyped before stopping an instance tagged `protected=true`
+async fn confirm_stop_protected(
+    manager: &dyn InstanceManager,
+    instance: &InstanceConfig,
+    assume_yes: bool,
+) -> Result<()> {
+    if assume_yes
+        || !manager
+            .get_instance(&instance.instance_id)
+            .await?
+            .is_protected()
+    {
+        return Ok(());
+    }
+    println!("{} is tagged protected=true", instance.alias);
+    let prompt = format!("Type the alias '{}' to stop it", instance.alias);
+    if user_input(&prompt)? != instance.alias {
+        return Err(Error::msg("Aborted"));
+    }
+    Ok(())
+}
+
+/// The members of `group` if given, otherwise `aliases`
+fn group_or_aliases(group: Option<String>, aliases: Vec<String>) -> Result<Vec<String>> {
+    match group {
+        Some(name) => ProfileConfig::get_or_create()?.group_aliases(&name),
+        None => Ok(aliases),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StateAction {
+    Start,
+    Stop,
+}
+
+impl StateAction {
+    fn verb(self) -> &'static str {
+        match self {
+            StateAction::Start => "start",
+            StateAction::Stop => "stop",
+        }
+    }
+}
+
+/// Start or stop one instance and print the change, confirming first if a stop would hit
+/// a protected instance. With `dry_run` the cloud isn't called at all.
+async fn change_state(
+    manager: &dyn InstanceManager,
+    instance: &InstanceConfig,
+    action: StateAction,
+    json: bool,
+    wait: bool,
+    assume_yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        print_dry_run(action.verb(), instance);
+        return Ok(());
+    }
+    let state = match action {
+        StateAction::Start => manager.start_instance(&instance.instance_id).await?,
+        StateAction::Stop => {
+            confirm_stop_protected(manager, instance, assume_yes).await?;
+            manager.stop_instance(&instance.instance_id).await?
+        }
+    };
+    DescriptionCache::invalidate(&instance.cloud, &instance.profile, &instance.instance_id)?;
+    print_state_change(instance, state, json)?;
+    if wait {
+        wait_until_reachable(manager, instance).await?;
+    }
+    Ok(())
+}
+
+/// Start or stop the instances with these aliases concurrently, printing each result as it
+/// completes. A failure doesn't stop the others, and failures are counted up at the end.
+async fn change_states(
+    aliases: &[String],
+    action: StateAction,
+    json: bool,
+    wait: bool,
+    assume_yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let config = ProfileConfig::get_or_create()?;
+    let instances = aliases
+        .iter()
+        .map(|alias| config.find_instance(alias).cloned())
+        .collect::<Result<Vec<_>>>()?;
+    if dry_run {
+        for inst in instances.iter() {
+            print_dry_run(action.verb(), inst);
+        }
+        return Ok(());
+    }
+    if let StateAction::Stop = action {
+        // Confirm one at a time up front, so prompts don't interleave with results
+        for inst in instances.iter() {
+            confirm_stop_protected(get_instance_manager(inst)?.as_ref(), inst, assume_yes).await?;
+        }
+    }
+    let futures = instances.iter().map(|inst| async move {
+        // Protected instances were confirmed above
+        let result: Result<()> = async {
+            let manager = get_instance_manager(inst)?;
+            change_state(manager.as_ref(), inst, action, json, wait, true, false).await
+        }
+        .await;
+        let result = result.map_err(|e| Error::msg(format!("{}: {}", inst.alias, e)));
+        if let Err(e) = &result {
+            eprintln!("Error: {}", e);
+        }
+        result
+    });
+    let failed = run_bounded(futures)
+        .await
+        .into_iter()
+        .filter(Result::is_err)
+        .count();
+    match failed {
+        0 => Ok(()),
+        n => Err(Error::msg(format!(
+            "{} of {} instances failed",
+            n,
+            instances.len()
+        ))),
+    }
+}
+
+/// Stop or start the active instance depending on its state, leaving it alone mid-transition
+async fn toggle_instance(json: bool, assume_yes: bool, dry_run: bool) -> Result<()> {
+    let instance = get_active_instance()?;
+    let status = get_instance_manager(&instance)?
+        .get_instance(&instance.instance_id)
+        .await?;
+    match status.state.as_str() {
+        "running" => stop_instance(json, assume_yes, dry_run).await,
+        "stopped" => start_instance(false, json, false, assume_yes, dry_run).await,
+        "pending" | "stopping" => {
+            eprintln!(
+                "{} is {}, so toggling now could conflict with it. Follow it with `remote status --watch`",
+                instance.alias, status.state
+            );
+            Ok(())
+        }
+        state => Err(Error::msg(format!(
+            "Can't toggle {}, it is {}",
+            instance.alias, state
+        ))),
+    }
+}
+
+async fn reboot_instance(json: bool) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    let state = manager.reboot_instance(&instance.instance_id).await?;
+    DescriptionCache::invalidate(&instance.cloud, &instance.profile, &instance.instance_id)?;
+    print_state_change(&instance, state, json)
+}
+
+async fn update_tags(opt: TagOpt) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    match opt {
+        TagOpt::Add { key, value } => {
+            manager
+                .add_tags(&instance.instance_id, &[InstanceTag { key, value }])
+                .await?
+        }
+        TagOpt::Rm { key } => manager.remove_tags(&instance.instance_id, &[key]).await?,
+    }
+    DescriptionCache::invalidate(&instance.cloud, &instance.profile, &instance.instance_id)?;
+    println!("{}", manager.get_instance(&instance.instance_id).await?);
+    Ok(())
+}
+
+/// Add or remove a port the active instance always forwards
+fn update_forward_ports(opt: PortsOpt) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    let active = get_active_instance()?.alias;
+    let instance = config.find_instance_mut(&active)?;
+    match opt {
+        PortsOpt::Add { port } => {
+            if !instance.forward_ports.contains(&port) {
+                instance.forward_ports.push(port);
+            }
+        }
+        PortsOpt::Rm { port } => instance.forward_ports.retain(|&saved| saved != port),
+    }
+    let ports = instance
+        .forward_ports
+        .iter()
+        .map(u16::to_string)
+        .collect::<Vec<_>>();
+    config.update()?;
+    match ports.is_empty() {
+        true => println!("{} forwards no ports by default", active),
+        false => println!("{} forwards ports {} by default", active, ports.join(", ")),
+    }
+    Ok(())
+}
+
+async fn update_elastic_ip(opt: EipOpt, assume_yes: bool) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    let mut config = ProfileConfig::get_or_create()?;
+    match opt {
+        EipOpt::Associate { allocation_id } => {
+            let allocation_id = allocation_id.or_else(|| instance.elastic_ip_allocation_id.clone());
+            let elastic_ip = manager
+                .associate_elastic_ip(&instance.instance_id, allocation_id.as_deref())
+                .await?;
+            let saved = config.find_instance_mut(&instance.alias)?;
+            saved.elastic_ip_allocation_id = Some(elastic_ip.allocation_id.clone());
+            saved.elastic_ip = Some(elastic_ip.public_ip.clone());
+            config.update()?;
+            println!(
+                "{} is at {} ({})",
+                instance.alias, elastic_ip.public_ip, elastic_ip.allocation_id
+            );
+        }
+        EipOpt::Release => {
+            let allocation_id = instance
+                .elastic_ip_allocation_id
+                .clone()
+                .ok_or_else(|| Error::msg(format!("{} has no Elastic IP", instance.alias)))?;
+            let prompt = format!(
+                "Release {}? The address can't be gotten back",
+                instance.elastic_ip.as_deref().unwrap_or(&allocation_id)
+            );
+            if !confirm(&prompt, assume_yes)? {
+                return Err(Error::msg("Aborted"));
+            }
+            manager.release_elastic_ip(&allocation_id).await?;
+            let saved = config.find_instance_mut(&instance.alias)?;
+            saved.elastic_ip_allocation_id = None;
+            saved.elastic_ip = None;
+            config.update()?;
+            println!("Released the Elastic IP of {}", instance.alias);
+        }
+    }
+    DescriptionCache::invalidate(&instance.cloud, &instance.profile, &instance.instance_id)?;
+    Ok(())
+}
+
+/// Set or clear the cron schedule the active instance is started or stopped on
+fn set_schedule(action: ScheduledAction, schedule: Option<String>) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    let timezone = config.schedule_timezone;
+    let active = get_active_instance()?.alias;
+    let next = match &schedule {
+        Some(expr) => next_run(&parse_schedule(expr)?, timezone),
+        None => None,
+    };
+    let instance = config.find_instance_mut(&active)?;
+    *instance
+        .schedule
+        .get_or_insert_with(Default::default)
+        .expr_mut(action) = schedule;
+    if instance
+        .schedule
+        .as_ref()
+        .is_some_and(InstanceSchedule::is_empty)
+    {
+        instance.schedule = None;
+    }
+    config.update()?;
+    match next {
+        Some(next) => println!(
+            "{} will {} on schedule, next at {} (requires \"remote daemon\" or \"remote schedule apply\")",
+            active,
+            action,
+            next.format("%Y-%m-%d %H:%M")
+        ),
+        None => println!("Removed {} schedule for {}", action, active),
+    }
+    Ok(())
+}
+
+/// What a run of the schedules did, by alias
+#[derive(Debug, Default)]
+struct ScheduleSummary {
+    started: Vec<String>,
+    stopped: Vec<String>,
+    skipped: Vec<String>,
+    failed: Vec<String>,
+}
+
+/// Start or stop instances whose schedule fired within (after, until], skipping
+/// instances that are already in the scheduled state
+async fn run_schedules(after: DateTime<Utc>, until: DateTime<Utc>) -> Result<ScheduleSummary> {
+    let config = ProfileConfig::get_or_create()?;
+    let timezone = config.schedule_timezone;
+    let mut summary = ScheduleSummary::default();
+    let mut due = Vec::new();
+    for inst in config.instances.iter() {
+        let schedule = match &inst.schedule {
+            Some(schedule) => schedule,
+            None => continue,
+        };
+        match schedule.due_action(timezone, after, until) {
+            Ok(Some(action)) => due.push((inst, action)),
+            Ok(None) => {}
+            Err(e) => {
+                println!("Error: {}: {}", inst.alias, e);
+                summary.failed.push(inst.alias.clone());
+            }
+        }
+    }
+    let aliases = due
+        .iter()
+        .map(|(inst, _)| inst.alias.clone())
+        .collect::<Vec<_>>();
+    let futures = due.into_iter().map(|(inst, action)| async move {
+        let manager = get_instance_manager(inst)?;
+        let status = manager.get_instance(&inst.instance_id).await?;
+        // Only act on instances that have settled in the other state
+        let from = match action {
+            ScheduledAction::Start => "stopped",
+            ScheduledAction::Stop => "running",
+        };
+        if status.state != from {
+            println!(
+                "{}: scheduled {} skipped, already {}",
+                inst.alias, action, status.state
+            );
+            return Ok(None);
+        }
+        let state = match action {
+            ScheduledAction::Start => manager.start_instance(&inst.instance_id).await?,
+            ScheduledAction::Stop => manager.stop_instance(&inst.instance_id).await?,
+        };
+        DescriptionCache::invalidate(&inst.cloud, &inst.profile, &inst.instance_id)?;
+        println!(
+            "{} ({}): {} on schedule, {} -> {}",
+            inst.alias,
+            inst.instance_id,
+            match action {
+                ScheduledAction::Start => "started",
+                ScheduledAction::Stop => "stopped",
+            },
+            state.previous,
+            state.current
+        );
+        Ok::<_, Error>(Some(action))
+    });
+    for (alias, result) in aliases.into_iter().zip(run_bounded(futures).await) {
+        match result {
+            Ok(Some(ScheduledAction::Start)) => summary.started.push(alias),
+            Ok(Some(ScheduledAction::Stop)) => summary.stopped.push(alias),
+            Ok(None) => summary.skipped.push(alias),
+            Err(e) => {
+                println!("Error: {}: {}", alias, e);
+                summary.failed.push(alias);
+            }
+        }
+    }
+    Ok(summary)
+}
+
+/// How far back the first `remote schedule apply` looks for schedules that fired
+const SCHEDULE_APPLY_LOOKBACK: Duration = Duration::from_secs(60 * 60);
+
+/// Act on schedules that fired since the last apply, for running from cron or a timer
+async fn schedule_apply() -> Result<()> {
+    let now = Utc::now();
+    let after = last_applied()
+        .unwrap_or_else(|| now - chrono::Duration::from_std(SCHEDULE_APPLY_LOOKBACK).unwrap());
+    let summary = run_schedules(after, now).await?;
+    set_last_applied(now)?;
+    let list = |aliases: &[String]| match aliases.is_empty() {
+        true => "none".to_string(),
+        false => aliases.join(", "),
+    };
+    println!("Started: {}", list(&summary.started));
+    println!("Stopped: {}", list(&summary.stopped));
+    println!("Skipped: {}", list(&summary.skipped));
+    if !summary.failed.is_empty() {
+        return Err(Error::msg(format!(
+            "Schedules failed for {}",
+            summary.failed.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+async fn daemon(interval: Duration) -> Result<()> {
+    println!(
+        "Checking instance schedules every {}s, Ctrl-C to exit",
+        interval.as_secs()
+    );
+    let mut last_check = Utc::now();
+    loop {
+        tokio::time::delay_for(interval).await;
+        let now = Utc::now();
+        // The config is reloaded each tick so schedule changes apply without a restart
+        if let Err(e) = run_schedules(last_check, now).await {
+            println!("Error: {}", e);
+        }
+        last_check = now;
+    }
+}
+
+/// Version of the JSON output format. Fields may be added within a version, but
+/// removing or changing one bumps it.
+const JSON_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+struct JsonEnvelope<T> {
+    schema_version: u32,
+    #[serde(flatten)]
+    body: T,
+}
+
+/// Print one line of versioned JSON output
+fn print_json<T: Serialize>(body: T) -> Result<()> {
+    let envelope = JsonEnvelope {
+        schema_version: JSON_SCHEMA_VERSION,
+        body,
+    };
+    println!("{}", serde_json::to_string(&envelope)?);
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+struct StatusOutput<'a> {
+    alias: &'a str,
+    instance: &'a Instance,
+    /// Average CPU percent over the last `CPU_WINDOW_MINUTES`, only with `--metrics`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpu_utilization: Option<f64>,
+    /// On-demand USD price per hour, only with `--cost`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price_per_hour: Option<f64>,
+    /// Estimated USD cost since the instance was launched, only with `--cost`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    running_cost: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfiguredOutput<'a> {
+    active: bool,
+    config: &'a InstanceConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct ListedOutput<'a> {
+    instance: &'a Instance,
+}
+
+#[derive(Debug, Serialize)]
+struct ResizeOutput<'a> {
+    alias: &'a str,
+    instance_id: &'a str,
+    previous_type: &'a str,
+    instance_type: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct StateChangeOutput<'a> {
+    alias: &'a str,
+    instance_id: &'a str,
+    #[serde(flatten)]
+    state: StateChange,
+}
+
+fn print_state_change(instance: &InstanceConfig, state: StateChange, json: bool) -> Result<()> {
+    if json {
+        print_json(StateChangeOutput {
+            alias: &instance.alias,
+            instance_id: &instance.instance_id,
+            state,
+        })?;
+    } else {
+        println!(
+            "{} ({}): {} -> {}",
+            instance.alias, instance.instance_id, state.previous, state.current
+        );
+    }
+    Ok(())
+}
+
+/// Stop every running instance in `instances` whose alias is not excluded,
+/// after confirming the list with the user.
+async fn stop_instances_except(
+    instances: &[InstanceConfig],
+    exclude: &[&str],
+    json: bool,
+    assume_yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let futures = instances
+        .iter()
+        .filter(|inst| !exclude.contains(&inst.alias.as_str()))
+        .map(|inst| async move {
+            let manager = get_instance_manager(inst)?;
+            let status = manager.get_instance(&inst.instance_id).await?;
+            Ok::<_, Error>((inst, status.state))
+        });
+    let running = run_bounded(futures)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|(_, state)| state.as_str() == "running")
+        .map(|(inst, _)| inst)
+        .collect::<Vec<_>>();
+    if running.is_empty() {
+        return Ok(());
+    }
+
+    let aliases = running
+        .iter()
+        .map(|inst| inst.alias.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    // With --json only the state changes go to stdout
+    match json {
+        true => eprintln!("Other running instances: {}", aliases),
+        false => println!("Other running instances: {}", aliases),
+    }
+    if dry_run {
+        for inst in running {
+            print_dry_run("stop", inst);
+        }
+        return Ok(());
+    }
+    if !confirm("Stop these instances?", assume_yes)? {
+        return Err(Error::msg("Aborted"));
+    }
+    // Protected instances also need their alias typed, one at a time before any stop
+    for inst in running.iter() {
+        confirm_stop_protected(get_instance_manager(inst)?.as_ref(), inst, assume_yes).await?;
+    }
+
+    let futures = running.into_iter().map(|inst| async move {
+        let manager = get_instance_manager(inst)?;
+        change_state(
+            manager.as_ref(),
+            inst,
+            StateAction::Stop,
+            json,
+            false,
+            true,
+            false,
+        )
+        .await
+    });
+    run_bounded(futures)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    Ok(())
+}
+
+/// Run a command on the instance and capture its output
+fn capture_remote(info: &ConnectionInfo, command: &str) -> Result<std::process::Output> {
+    let output = Command::new("ssh")
+        .args(info.ssh_args())
+        .arg(info.destination())
+        .arg(command)
+        .stdin(Stdio::null())
+        .logged()
+        .output()?;
+    Ok(output)
+}
+
+/// Parse the local ports out of `ss -tln` or `netstat -tln` output, leaving out sshd's
+fn parse_listening_ports(output: &str, ssh_port: u16) -> Vec<u16> {
+    let mut ports = output
+        .lines()
+        .filter_map(|line| {
+            line.split_whitespace()
+                .find(|field| field.contains(':'))?
+                .rsplit(':')
+                .next()?
+                .parse::<u16>()
+                .ok()
+        })
+        .filter(|port| *port != ssh_port)
+        .collect::<Vec<_>>();
+    ports.sort_unstable();
+    ports.dedup();
+    ports
+}
+
+fn discover_ports(info: &ConnectionInfo) -> Result<Vec<u16>> {
+    let output = capture_remote(info, "ss -tln 2>/dev/null || netstat -tln 2>/dev/null")?;
+    if !output.status.success() {
+        println!("Could not list ports on the instance (is ss or netstat installed?)");
+        return Ok(Vec::new());
+    }
+    let listening =
+        parse_listening_ports(&String::from_utf8_lossy(&output.stdout), info.ssh_port());
+    if listening.is_empty() {
+        println!("No listening ports found");
+        return Ok(Vec::new());
+    }
+    println!("Listening ports:");
+    for port in listening.iter() {
+        println!("  {}", port);
+    }
+    let selected = user_input("Ports to forward (comma separated)")?;
+    selected
+        .split(',')
+        .map(str::trim)
+        .filter(|port| !port.is_empty())
+        .map(|port| {
+            let port = port.parse::<u16>()?;
+            match listening.contains(&port) {
+                true => Ok(port),
+                false => Err(Error::msg(format!("Port {} is not listening", port))),
+            }
+        })
+        .collect()
+}
+
+fn open_mosh(info: &ConnectionInfo) -> Result<()> {
+    let status = Command::new("mosh")
+        .arg(format!("--ssh={}", info.ssh_command()))
+        .arg(info.destination())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit())
+        .logged()
+        .status()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                Error::msg("Could not find mosh, install it from https://mosh.org")
+            }
+            _ => e.into(),
+        })?;
+    if !status.success() {
+        return Err(Error::msg("mosh exited with an error"));
+    }
+    Ok(())
+}
+
+/// How long `remote open` waits for the forwarded port to accept connections
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The file recording the ssh process forwarding `port`
+fn tunnel_pid_path(port: u16) -> Result<PathBuf> {
+    let dir = get_config_dir()?.join("tunnels");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join(port.to_string()))
+}
+
+/// The pid of the ssh process forwarding `port`, if it is still running
+fn running_tunnel(port: u16) -> Result<Option<u32>> {
+    let path = tunnel_pid_path(port)?;
+    let pid = match std::fs::read_to_string(&path) {
+        Ok(raw) => raw.trim().parse::<u32>().ok(),
+        Err(_) => return Ok(None),
+    };
+    let alive = pid.is_some_and(|pid| {
+        Command::new("kill")
+            .arg("-0")
+            .arg(pid.to_string())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    });
+    if !alive {
+        std::fs::remove_file(&path)?;
+        return Ok(None);
+    }
+    Ok(pid)
+}
+
+/// Forward `port` from the active instance with a background ssh, then open it in a browser
+async fn open_tunnel(port: u16, no_browser: bool) -> Result<()> {
+    if let Some(pid) = running_tunnel(port)? {
+        println!("Port {} is already forwarded (ssh pid {})", port, pid);
+    } else {
+        let info = get_active_instance_connection_info().await?;
+        // Not `ssh -f`: it forks, and the pid of the process left forwarding is needed for `remote close`
+        let mut child = Command::new("ssh")
+            .args(info.ssh_args())
+            .arg("-N")
+            .arg("-o")
+            .arg("ExitOnForwardFailure=yes")
+            .args(port_forward_args(&[port]))
+            .arg(info.destination())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .logged()
+            .spawn()?;
+        let start = Instant::now();
+        while TcpStream::connect(("localhost", port)).is_err() {
+            if let Some(status) = child.try_wait()? {
+                return Err(Error::msg(format!(
+                    "ssh exited before forwarding port {} ({})",
+                    port, status
+                )));
+            }
+            if start.elapsed() >= TUNNEL_TIMEOUT {
+                child.kill()?;
+                return Err(Error::msg(format!(
+                    "Timed out waiting for port {} to be forwarded",
+                    port
+                )));
+            }
+            tokio::time::delay_for(Duration::from_millis(200)).await;
+        }
+        std::fs::write(tunnel_pid_path(port)?, child.id().to_string())?;
+        println!(
+            "Forwarding port {} (ssh pid {}), stop with `remote close {}`",
+            port,
+            child.id(),
+            port
+        );
+    }
+    let url = format!("http://localhost:{}", port);
+    if no_browser {
+        println!("{}", url);
+        return Ok(());
+    }
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    let status = Command::new(opener)
+        .arg(&url)
+        .status()
+        .map_err(|e| Error::msg(format!("Could not run {} to open {}: {}", opener, url, e)))?;
+    if !status.success() {
+        return Err(Error::msg(format!("{} could not open {}", opener, url)));
+    }
+    Ok(())
+}
+
+/// Stop the ssh process started by `remote open` for `port`
+fn close_tunnel(port: u16) -> Result<()> {
+    let pid = running_tunnel(port)?
+        .ok_or_else(|| Error::msg(format!("No port forward open for port {}", port)))?;
+    let status = Command::new("kill").arg(pid.to_string()).status()?;
+    if !status.success() {
+        return Err(Error::msg(format!("Could not stop ssh pid {}", pid)));
+    }
+    std::fs::remove_file(tunnel_pid_path(port)?)?;
+    println!("Closed port {} (ssh pid {})", port, pid);
+    Ok(())
+}
+
+/// Maximum number of instances operated on at once, unless `--concurrency` or
+/// `REMOTE_CONCURRENCY` set another limit
+const DEFAULT_CONCURRENCY: usize = 5;
+
+/// Environment variable setting how many instances are operated on at once
+const CONCURRENCY_VAR: &str = "REMOTE_CONCURRENCY";
+
+/// Set by `--concurrency` to operate on more or fewer instances at once
+static CONCURRENCY: OnceLock<usize> = OnceLock::new();
+
+/// How many instances to operate on at once
+fn concurrency() -> usize {
+    if let Some(limit) = CONCURRENCY.get() {
+        return *limit;
+    }
+    std::env::var(CONCURRENCY_VAR)
+        .ok()
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .filter(|&limit| limit > 0)
+        .unwrap_or(DEFAULT_CONCURRENCY)
+}
+
+/// Run futures with at most `concurrency()` in flight, keeping their order
+async fn run_bounded<F, T>(futures: impl IntoIterator<Item = F>) -> Vec<T>
+where
+    F: std::future::Future<Output = T>,
+{
+    stream::iter(futures)
+        .buffered(concurrency())
+        .collect()
+        .await
+}
+
+/// `-L` arguments forwarding each port to the same port on the instance
+fn port_forward_args(ports: &[u16]) -> Vec<String> {
+    ports
+        .iter()
+        .flat_map(|p| vec!["-L".to_string(), format!("{}:localhost:{}", p, p)])
+        .collect()
+}
+
+/// Run a command over ssh with inherited stdio, returning its exit code
+fn exec(info: &ConnectionInfo, command: &str, ports: &[u16]) -> Result<i32> {
+    let status = Command::new("ssh")
+        .args(info.ssh_args())
+        .arg(info.destination())
+        .args(port_forward_args(ports))
+        .arg(command)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit())
+        .logged()
+        .status()?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Run a command over ssh, prefixing each output line with the alias
+async fn exec_labeled(alias: &str, info: &ConnectionInfo, command: &str) -> Result<i32> {
+    let mut cmd = Command::new("ssh");
+    cmd.args(info.ssh_args())
+        .arg(info.destination())
+        .arg(command)
+        .logged();
+    let mut child = tokio::process::Command::from(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let print_stdout = async {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            println!("[{}] {}", alias, line);
+        }
+        Ok::<_, Error>(())
+    };
+    let print_stderr = async {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(line) = lines.next_line().await? {
+            eprintln!("[{}] {}", alias, line);
+        }
+        Ok::<_, Error>(())
+    };
+    let (out, err, status) = futures::join!(print_stdout, print_stderr, child);
+    out?;
+    err?;
+    Ok(status?.code().unwrap_or(1))
+}
+
+async fn exec_active(command: &[String], ports: &[u16]) -> Result<i32> {
+    let info = get_active_instance_connection_info().await?;
+    exec(&info, &command.join(" "), ports)
+}
+
+/// Environment variable overriding `sudo_password_command` in the config
+const SUDO_PASSWORD_COMMAND_VAR: &str = "REMOTE_SUDO_PW_CMD";
+
+/// Run the configured local command that prints the sudo password.
+///
+/// Only the command is ever configured: the password itself is never written to the
+/// config, and it is only held in memory long enough to pipe it to the remote sudo.
+fn sudo_password() -> Result<String> {
+    let command = match std::env::var(SUDO_PASSWORD_COMMAND_VAR) {
+        Ok(command) => command,
+        Err(_) => ProfileConfig::get_or_create()?
+            .sudo_password_command
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "No sudo password command, set sudo_password_command in the config or {}",
+                    SUDO_PASSWORD_COMMAND_VAR
+                ))
+            })?,
+    };
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::msg("The sudo password command failed"));
+    }
+    let password = String::from_utf8(output.stdout)?;
+    Ok(password.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Quote a string for a POSIX shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Quote a string for fish, which only treats backslashes and quotes specially inside single quotes
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Print the active instance's connection details as shell variables, for
+/// `eval "$(remote env)"`
+async fn print_env(fish: bool) -> Result<()> {
+    let instance = get_active_instance()?;
+    let info = get_connection_info(instance.clone(), AddressMode::Public).await?;
+    let region = match (&instance.region, &instance.cloud) {
+        (Some(region), _) => Some(region.clone()),
+        (None, Cloud::Aws) => resolve_region(&instance.profile, None)
+            .ok()
+            .map(|resolved| resolved.region.name().to_string()),
+        (None, _) => None,
+    };
+    let mut vars = vec![
+        ("REMOTE_ALIAS", instance.alias.clone()),
+        ("REMOTE_HOST", info.address.clone()),
+        ("REMOTE_USER", info.user.clone()),
+        ("REMOTE_INSTANCE_ID", instance.instance_id.clone()),
+    ];
+    if let Some(key_path) = info.key_paths.first() {
+        vars.push(("REMOTE_KEY", key_path.display().to_string()));
+    }
+    if let Some(region) = region {
+        vars.push(("REMOTE_REGION", region));
+    }
+    for (name, value) in vars {
+        match fish {
+            true => println!("set -gx {} {};", name, fish_quote(&value)),
+            false => println!("export {}={}", name, shell_quote(&value)),
+        }
+    }
+    Ok(())
+}
+
+/// Ask the ssh ControlMaster for the active instance to exit, closing the shared connection
+async fn stop_multiplexing() -> Result<()> {
+    if !ProfileConfig::get_or_create()?.multiplex {
+        return Err(Error::msg(
+            "Multiplexing is not enabled, set multiplex: true in the config",
+        ));
+    }
+    let instance = get_active_instance()?;
+    let info = get_connection_info(instance.clone(), AddressMode::Public).await?;
+    let output = Command::new("ssh")
+        .args(info.ssh_args())
+        .arg("-O")
+        .arg("exit")
+        .arg(info.destination())
+        .stdin(Stdio::null())
+        .logged()
+        .output()?;
+    match output.status.success() {
+        true => println!("Closed the multiplexed connection to {}", instance.alias),
+        false => println!("No multiplexed connection to {} is open", instance.alias),
+    }
+    Ok(())
+}
+
+/// Run a command under sudo on the active instance, piping the password to `sudo -S`.
+///
+/// Only `sudo -S -v` reads the password, from ssh's stdin, and the command then runs with
+/// `sudo -n` and its input from /dev/null. When sudo doesn't prompt, because credentials are
+/// cached or the rule is NOPASSWD, the unread password is discarded instead of reaching the
+/// command. Both run in one ssh session since sudo only reuses credentials within a session.
+async fn ssh_sudo(command: &[String]) -> Result<i32> {
+    let info = get_active_instance_connection_info().await?;
+    let password = sudo_password()?;
+    let remote_command = format!(
+        "sudo -S -p '' -v && sudo -n sh -c {} </dev/null",
+        shell_quote(&command.join(" "))
+    );
+    let mut child = Command::new("ssh")
+        .args(info.ssh_args())
+        .arg(info.destination())
+        .arg(remote_command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .logged()
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // ssh may exit without reading it, e.g. when it can't connect, leaving its exit code to report
+        if let Err(e) = writeln!(stdin, "{}", password) {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(child.wait()?.code().unwrap_or(1))
+}
+
+/// Run a command over ssh, capturing its output
+async fn exec_captured(info: &ConnectionInfo, command: &str) -> Result<std::process::Output> {
+    let mut cmd = Command::new("ssh");
+    cmd.args(info.ssh_args())
+        .arg(info.destination())
+        .arg(command)
+        .logged();
+    let output = tokio::process::Command::from(cmd)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+    Ok(output)
+}
+
+/// How running a command on one instance of `exec --all` ended
+enum ExecOutcome {
+    Skipped(Error),
+    /// The exit code, with the output unless it was streamed
+    Finished(i32, Option<std::process::Output>),
+    Failed(Error),
+}
+
+/// Run a command on every running instance, returning 1 if any run failed
+///
+/// Output is grouped per instance in alias order once all runs finish, unless `stream`
+/// is set, in which case lines are printed as they arrive prefixed with the alias.
+async fn exec_all(command: &[String], stream: bool) -> Result<i32> {
+    let command = command.join(" ");
+    let mut instances = ProfileConfig::get_or_create()?.instances;
+    instances.sort_by(|a, b| a.alias.cmp(&b.alias));
+    let futures = instances.iter().map(|inst| {
+        let command = &command;
+        async move {
+            let info = match get_connection_info(inst.clone(), AddressMode::Public).await {
+                Ok(info) => info,
+                Err(e) => return ExecOutcome::Skipped(e),
+            };
+            let result = match stream {
+                true => exec_labeled(&inst.alias, &info, command)
+                    .await
+                    .map(|code| ExecOutcome::Finished(code, None)),
+                false => exec_captured(&info, command).await.map(|output| {
+                    ExecOutcome::Finished(output.status.code().unwrap_or(1), Some(output))
+                }),
+            };
+            result.unwrap_or_else(ExecOutcome::Failed)
+        }
+    });
+    let results = run_bounded(futures).await;
+
+    let mut failed = Vec::new();
+    let mut succeeded = 0;
+    for (inst, outcome) in instances.iter().zip(results) {
+        let code = match outcome {
+            ExecOutcome::Skipped(e) => {
+                println!("[{}] skipped: {}", inst.alias, e);
+                continue;
+            }
+            ExecOutcome::Failed(e) => {
+                failed.push(format!("{} ({})", inst.alias, e));
+                continue;
+            }
+            ExecOutcome::Finished(code, output) => {
+                if let Some(output) = output {
+                    println!("=== {} ===", inst.alias);
+                    std::io::stdout().write_all(&output.stdout)?;
+                    std::io::stderr().write_all(&output.stderr)?;
+                }
+                code
+            }
+        };
+        match code {
+            0 => succeeded += 1,
+            code => failed.push(format!("{} (exit code {})", inst.alias, code)),
+        }
+    }
+    println!("---");
+    println!("{} succeeded, {} failed", succeeded, failed.len());
+    for failure in failed.iter() {
+        println!("Failed: {}", failure);
+    }
+    Ok(if failed.is_empty() { 0 } else { 1 })
+}
+
+/// Active sessions on the instance from `who`, or None if it couldn't be run
+fn remote_sessions(info: &ConnectionInfo) -> Result<Option<Vec<String>>> {
+    let output = capture_remote(info, "who")?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let sessions = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok(Some(sessions))
+}
+
+/// Whether to go ahead connecting, asking first if others are logged in
+fn confirm_not_busy(info: &ConnectionInfo, assume_yes: bool) -> Result<bool> {
+    let sessions = match remote_sessions(info)? {
+        Some(sessions) => sessions,
+        None => {
+            println!("Could not list sessions on the instance, skipping busy check");
+            return Ok(true);
+        }
+    };
+    if sessions.is_empty() {
+        return Ok(true);
+    }
+    println!("Already logged in:");
+    for session in sessions.iter() {
+        println!("  {}", session);
+    }
+    confirm("Connect anyway?", assume_yes)
+}
+
+#[derive(Debug, Default)]
+struct SshOptions {
+    ports: Option<Vec<u16>>,
+    jump: Option<String>,
+    discover_ports: bool,
+    mosh: bool,
+    warn_if_busy: bool,
+    auto_address: bool,
+    /// Force a login (true) or non-login (false) shell instead of the ssh default
+    login_shell: Option<bool>,
+    forward_agent: bool,
+    keepalive: Option<u32>,
+    wait_ssh: bool,
+    /// Passed to ssh as is, after the destination
+    extra: Vec<String>,
+}
+
+async fn open_ssh(opts: SshOptions, assume_yes: bool) -> Result<()> {
+    let mode = match opts.auto_address {
+        true => AddressMode::Auto,
+        false => AddressMode::Public,
+    };
+    let instance = get_active_instance()?;
+    if opts.mosh {
+        let info = get_connection_info(instance, mode).await?;
+        if opts.wait_ssh {
+            wait_for_ssh(&info.address, info.ssh_port()).await?;
+        }
+        if opts.warn_if_busy && !confirm_not_busy(&info, assume_yes)? {
+            return Ok(());
+        }
+        return open_mosh(&info);
+    }
+    let mut c = Command::new("ssh");
+    let info = match opts.jump {
+        Some(alias) => {
+            let config = ProfileConfig::get_or_create()?;
+            let bastion =
+                get_connection_info(config.find_instance(&alias)?.clone(), AddressMode::Public)
+                    .await?;
+            // ProxyJump can't carry the bastion's key, so proxy through it explicitly
+            c.arg("-o").arg(format!(
+                "ProxyCommand={} -W %h:%p {}",
+                bastion.ssh_command(),
+                shell_quote(&bastion.destination())
+            ));
+            get_connection_info(instance.clone(), AddressMode::Private).await?
+        }
+        None => get_connection_info(instance.clone(), mode).await?,
+    };
+    if opts.wait_ssh {
+        wait_for_ssh(&info.address, info.ssh_port()).await?;
+    }
+    if opts.warn_if_busy && !confirm_not_busy(&info, assume_yes)? {
+        return Ok(());
+    }
+    if opts.forward_agent || instance.forward_agent {
+        c.arg("-A");
+    }
+    if let Some(keepalive) = opts.keepalive {
+        // ssh uses the first value given for an option, so this wins over the config's
+        c.arg("-o")
+            .arg(format!("ServerAliveInterval={}", keepalive));
+    }
+    let mut ports = instance.forward_ports;
+    let mut requested = opts.ports.unwrap_or_default();
+    if opts.discover_ports {
+        requested.extend(discover_ports(&info)?);
+    }
+    for port in requested {
+        if !ports.contains(&port) {
+            ports.push(port);
+        }
+    }
+    c.args(info.ssh_args());
+    c.arg(info.destination());
+    c.args(port_forward_args(&ports));
+    // ssh keeps the first -o and every -i it's given, so these only add to what is set above
+    c.args(&opts.extra);
+    // Run through the remote shell so $SHELL is the remote user's shell
+    match opts.login_shell {
+        Some(true) => c.arg("-t").arg("exec $SHELL -l"),
+        Some(false) => c.arg("-t").arg("exec $SHELL"),
+        None => &mut c,
+    };
+    c.stdout(Stdio::inherit());
+    c.stderr(Stdio::inherit());
+    c.stdin(Stdio::inherit());
+    c.logged().output()?;
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct CopyOptions {
+    recursive: bool,
+    limit: Option<u32>,
+    preserve: bool,
+    wait_ssh: bool,
+    compress: bool,
+}
+
+/// Split copy arguments into the destination (the last path) and the sources before it
+fn split_destination(paths: &[String]) -> Result<(&str, &[String])> {
+    match paths.split_last() {
+        Some((destination, sources)) if !sources.is_empty() => Ok((destination, sources)),
+        _ => Err(Error::msg("Give at least one source and a destination")),
+    }
+}
+
+/// Copy `sources` to `destination` in a single scp call. Uploads copy local sources to
+/// a remote destination and downloads the other way around.
+async fn run_scp(
+    sources: &[String],
+    destination: &str,
+    upload: bool,
+    opts: &CopyOptions,
+) -> Result<()> {
+    let mut destination = destination.to_string();
+    if sources.len() > 1 {
+        // scp copies several sources into a directory, so make sure it is one
+        if upload {
+            if !destination.ends_with('/') {
+                destination.push('/');
+            }
+        } else if !Path::new(&destination).is_dir() {
+            return Err(Error::msg(format!(
+                "{} must be an existing directory when copying several files",
+                destination
+            )));
+        }
+    }
+    let info = get_active_instance_connection_info().await?;
+    if opts.wait_ssh {
+        wait_for_ssh(&info.address, info.ssh_port()).await?;
+    }
+    let remote = |path: &str| info.remote_path(path);
+
+    let mut cmd = Command::new("scp");
+    if opts.recursive {
+        cmd.arg("-r");
+    };
+    if opts.preserve {
+        cmd.arg("-p");
+    };
+    if opts.compress || get_active_instance()?.compress {
+        cmd.arg("-C");
+    };
+    if let Some(limit) = opts.limit {
+        // scp takes the limit in Kbit/s
+        cmd.arg("-l").arg((u64::from(limit) * 8).to_string());
+    };
+    cmd.args(info.ssh_args());
+    if upload {
+        cmd.args(sources).arg(remote(&destination));
+    } else {
+        cmd.args(sources.iter().map(|source| remote(source)))
+            .arg(destination);
+    };
+    let _ = cmd
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit())
+        .logged()
+        .output()?;
+    Ok(())
+}
+
+/// How `remote sync` sends files: `delete` removes remote files missing locally, `limit`
+/// caps bandwidth in KB/s and `compress` compresses in transit
+#[derive(Debug, Default)]
+struct SyncOptions {
+    delete: bool,
+    limit: Option<u32>,
+    compress: bool,
+}
+
+/// Sync `local_dir` to `remote_dir` on the active instance, only sending changed files
+async fn run_rsync(local_dir: &str, remote_dir: &str, opts: &SyncOptions) -> Result<()> {
+    let info = get_active_instance_connection_info().await?;
+    let mut cmd = Command::new("rsync");
+    // -a preserves times and modes, which rsync needs to skip unchanged files
+    cmd.arg("-a")
+        .arg("--progress")
+        .arg("-e")
+        .arg(info.ssh_command());
+    if opts.delete {
+        cmd.arg("--delete");
+    }
+    // rsync compresses and limits itself rather than through ssh, and takes KB/s directly
+    if opts.compress || get_active_instance()?.compress {
+        cmd.arg("-z");
+    }
+    if let Some(limit) = opts.limit {
+        cmd.arg(format!("--bwlimit={}", limit));
+    }
+    let status = cmd
+        .arg(local_dir)
+        .arg(info.remote_path(remote_dir))
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit())
+        .logged()
+        .status()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                Error::msg("Could not find rsync, install it to use sync")
+            }
+            _ => e.into(),
+        })?;
+    if !status.success() {
+        return Err(Error::msg("rsync exited with an error"));
+    }
+    Ok(())
+}
+
+/// Open an interactive sftp session on the active instance, starting in `dir` if given
+async fn open_sftp(dir: Option<&str>, compress: bool) -> Result<()> {
+    let info = get_active_instance_connection_info().await?;
+    let mut cmd = Command::new("sftp");
+    if compress || get_active_instance()?.compress {
+        cmd.arg("-C");
+    }
+    let destination = match dir {
+        Some(dir) => info.remote_path(dir),
+        None if info.address.contains(':') => format!("{}@[{}]", info.user, info.address),
+        None => info.destination(),
+    };
+    let status = cmd
+        .args(info.ssh_args())
+        .arg(destination)
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdin(Stdio::inherit())
+        .logged()
+        .status()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Error::msg(
+                "Could not find sftp, install an OpenSSH client (it is usually packaged with ssh)",
+            ),
+            _ => e.into(),
+        })?;
+    if !status.success() {
+        return Err(Error::msg("sftp exited with an error"));
+    }
+    Ok(())
+}
+
+/// Derive the public key for a private key file
+fn read_public_key(key_path: &Path) -> Result<String> {
+    let output = Command::new("ssh-keygen")
+        .arg("-y")
+        .arg("-f")
+        .arg(key_path)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::msg(format!(
+            "Could not read public key from {}",
+            key_path.display()
+        )));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Append a public key to the remote authorized_keys unless it's already there, returning
+/// whether it was added
+fn authorize_key(info: &ConnectionInfo, public_key: &str) -> Result<bool> {
+    let script = "read -r key; mkdir -p ~/.ssh && chmod 700 ~/.ssh \
+        && touch ~/.ssh/authorized_keys && chmod 600 ~/.ssh/authorized_keys \
+        && if grep -qxF \"$key\" ~/.ssh/authorized_keys; then echo present; \
+        else echo \"$key\" >> ~/.ssh/authorized_keys && echo added; fi";
+    let mut child = Command::new("ssh")
+        .args(info.ssh_args())
+        .arg(info.destination())
+        .arg(script)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .logged()
+        .spawn()?;
+    if let Some(stdin) = child.stdin.as_mut() {
+        writeln!(stdin, "{}", public_key)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::msg("Failed to authorize key on instance"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim() == "added")
+}
+
+/// Read the single key from a public key file, catching private keys passed by mistake
+fn read_public_key_file(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        Error::msg(format!(
+            "Could not read public key {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    if contents.contains("PRIVATE KEY") {
+        return Err(Error::msg(format!(
+            "{} is a private key, pass its .pub file instead",
+            path.display()
+        )));
+    }
+    let keys = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+    match keys.as_slice() {
+        [key] => Ok(key.to_string()),
+        [] => Err(Error::msg(format!("No public key in {}", path.display()))),
+        _ => Err(Error::msg(format!(
+            "{} has {} keys, expected one",
+            path.display(),
+            keys.len()
+        ))),
+    }
+}
+
+async fn copy_id(pubkey_path: Option<&str>) -> Result<()> {
+    let pubkey_path = pubkey_path.unwrap_or("~/.ssh/id_rsa.pub");
+    let path =
+        expand_path(pubkey_path).ok_or_else(|| Error::msg("Could not find home directory"))?;
+    let public_key = read_public_key_file(&path)?;
+    let instance = get_active_instance()?;
+    let info = get_connection_info(instance.clone(), AddressMode::Public).await?;
+    match authorize_key(&info, &public_key)? {
+        true => println!("Authorized {} on {}", pubkey_path, instance.alias),
+        false => println!(
+            "{} is already authorized on {}",
+            pubkey_path, instance.alias
+        ),
+    }
+    Ok(())
+}
+
+/// Running configured instances with their hourly price, printing any that failed
+async fn running_costs(
+    config: &ProfileConfig,
+    prices: &PricePool,
+) -> Vec<(InstanceConfig, Instance, f64)> {
+    let futures = config.instances.iter().map(|inst| async move {
+        let manager = get_instance_manager(inst)?;
+        let status = manager.get_instance(&inst.instance_id).await?;
+        if status.state != "running" {
+            return Ok(None);
+        }
+        let hourly = instance_price(inst, &status, prices).await?;
+        Ok::<_, Error>(Some((inst.clone(), status, hourly)))
+    });
+    let mut costs = Vec::new();
+    for (inst, result) in config.instances.iter().zip(run_bounded(futures).await) {
+        match result {
+            Ok(Some(cost)) => costs.push(cost),
+            Ok(None) => {}
+            Err(e) => println!("Error: {}: {}", inst.alias, e),
+        }
+    }
+    costs
+}
+
+async fn cost(by_tag: Option<String>, profile: Option<String>) -> Result<()> {
+    let config = ProfileConfig::get_or_create()?;
+    let prices = PricePool::new(profile);
+    let costs = running_costs(&config, &prices).await;
+    let total: f64 = costs
+        .iter()
+        .map(|(_, _, hourly)| hourly * HOURS_PER_MONTH)
+        .sum();
+
+    let table = match by_tag {
+        Some(tag_key) => {
+            let mut groups: BTreeMap<String, (usize, f64)> = BTreeMap::new();
+            for (_, status, hourly) in costs.iter() {
+                let group = status
+                    .tags
+                    .iter()
+                    .find(|tag| tag.key == tag_key)
+                    .map(|tag| tag.value.clone())
+                    .unwrap_or_else(|| "untagged".to_string());
+                let entry = groups.entry(group).or_default();
+                entry.0 += 1;
+                entry.1 += hourly * HOURS_PER_MONTH;
+            }
+            let mut table = Table::new(&[&tag_key, "INSTANCES", "MONTHLY (USD)"]);
+            for (group, (count, monthly)) in groups {
+                table.push(vec![group, count.to_string(), format!("{:.2}", monthly)]);
+            }
+            table
+        }
+        None => {
+            let mut table = Table::new(&["ALIAS", "TYPE", "HOURLY (USD)", "MONTHLY (USD)"]);
+            for (inst, status, hourly) in costs.iter() {
+                table.push(vec![
+                    inst.alias.clone(),
+                    status.instance_type.clone(),
+                    format!("{:.4}", hourly),
+                    format!("{:.2}", hourly * HOURS_PER_MONTH),
+                ]);
+            }
+            table
+        }
+    };
+    println!("{}", table.render_stdout());
+    println!("---");
+    println!(
+        "Estimated total: ${:.2}/month (on-demand Linux pricing)",
+        total
+    );
+    Ok(())
+}
+
+/// Hours an instance has run since the start of this month (UTC), counting only its
+/// current run since that is all the launch time tells us
+fn hours_this_month(status: &Instance) -> Option<f64> {
+    let uptime = status.uptime()?;
+    let now = Utc::now();
+    let month_start = now
+        .date_naive()
+        .with_day(1)?
+        .and_hms_opt(0, 0, 0)?
+        .and_utc();
+    let running = uptime.min(now - month_start);
+    Some(running.num_seconds().max(0) as f64 / 3600.0)
+}
+
+async fn cost_report(profile: Option<String>) -> Result<()> {
+    let config = ProfileConfig::get_or_create()?;
+    let prices = PricePool::new(profile);
+    let managers = ManagerPool::default();
+    let (prices, managers) = (&prices, &managers);
+    let futures = config.instances.iter().map(|inst| async move {
+        let status = managers.get(inst)?.get_instance(&inst.instance_id).await?;
+        let hourly = instance_price(inst, &status, prices).await?;
+        Ok::<_, Error>((status, hourly))
+    });
+    let results = run_bounded(futures).await;
+
+    let mut profiles: BTreeMap<&str, Vec<(&InstanceConfig, Instance, f64)>> = BTreeMap::new();
+    let mut unknown = Vec::new();
+    for (inst, result) in config.instances.iter().zip(results) {
+        match result {
+            Ok((status, hourly)) => profiles
+                .entry(&inst.profile)
+                .or_default()
+                .push((inst, status, hourly)),
+            Err(e) => unknown.push((inst, e)),
+        }
+    }
+
+    let (mut monthly_total, mut to_date_total) = (0.0, 0.0);
+    for (profile, lines) in profiles {
+        let mut table = Table::new(&[
+            "ALIAS",
+            "TYPE",
+            "STATE",
+            "HOURLY (USD)",
+            "MONTHLY 24/7 (USD)",
+            "MONTH TO DATE (USD)",
+        ]);
+        let (mut monthly, mut to_date) = (0.0, 0.0);
+        for (inst, status, hourly) in lines {
+            let cost_to_date = hours_this_month(&status).map(|hours| hours * hourly);
+            monthly += hourly * HOURS_PER_MONTH;
+            to_date += cost_to_date.unwrap_or(0.0);
+            table.push(vec![
+                inst.alias.clone(),
+                status.instance_type.clone(),
+                status.state.clone(),
+                format!("{:.4}", hourly),
+                format!("{:.2}", hourly * HOURS_PER_MONTH),
+                cost_to_date.map_or_else(|| "-".to_string(), |cost| format!("{:.2}", cost)),
+            ]);
+        }
+        println!("Profile: {}", profile);
+        println!("{}", table.render_stdout());
+        println!(
+            "Subtotal: ${:.2}/month if run 24/7, ${:.2} so far this month",
+            monthly, to_date
+        );
+        println!();
+        monthly_total += monthly;
+        to_date_total += to_date;
+    }
+    if !unknown.is_empty() {
+        println!("Unknown price, not included in the totals:");
+        for (inst, e) in unknown.iter() {
+            println!("  {} (profile {}): {}", inst.alias, inst.profile, e);
+        }
+        println!();
+    }
+    println!("---");
+    println!(
+        "Estimated total: ${:.2}/month if run 24/7, ${:.2} so far this month (on-demand Linux pricing)",
+        monthly_total, to_date_total
+    );
+    if !unknown.is_empty() {
+        println!(
+            "Excludes {} of {} instances with unknown prices",
+            unknown.len(),
+            config.instances.len()
+        );
+    }
+    Ok(())
+}
+
+/// Host name of the ~/.ssh/config entry for an instance alias
+fn ssh_config_host(alias: &str) -> String {
+    format!("remote-{}", alias)
+}
+
+fn ssh_config_entry(instance: &InstanceConfig, info: &ConnectionInfo) -> String {
+    let mut entry = format!(
+        "Host {}\n    HostName {}\n    User {}\n",
+        ssh_config_host(&instance.alias),
+        info.address,
+        info.user,
+    );
+    for key_path in info.key_paths.iter() {
+        entry.push_str(&format!("    IdentityFile {}\n", key_path.display()));
+    }
+    entry.push_str(&format!("    Port {}\n", info.ssh_port()));
+    if instance.forward_agent {
+        entry.push_str("    ForwardAgent yes\n");
+    }
+    for port in instance.forward_ports.iter() {
+        entry.push_str(&format!("    LocalForward {} localhost:{}\n", port, port));
+    }
+    entry
+}
+
+/// Markers around the part of ~/.ssh/config that `ssh-config --write` manages
+const SSH_CONFIG_BEGIN: &str = "# BEGIN remote";
+const SSH_CONFIG_END: &str = "# END remote";
+
+/// Split an ssh config into the text before, inside and after the managed block
+fn split_managed_block(config: &str) -> (String, String, String) {
+    let (mut before, mut block, mut after) = (String::new(), String::new(), String::new());
+    let mut section = &mut before;
+    let mut seen_begin = false;
+    for line in config.lines() {
+        match line.trim() {
+            SSH_CONFIG_BEGIN if !seen_begin => {
+                seen_begin = true;
+                section = &mut block;
+                continue;
+            }
+            SSH_CONFIG_END if seen_begin => {
+                section = &mut after;
+                continue;
+            }
+            _ => {}
+        }
+        section.push_str(line);
+        section.push('\n');
+    }
+    (before, block, after)
+}
+
+/// Drop the `Host` block for `host` from an ssh config
+fn remove_ssh_config_entry(config: &str, host: &str) -> String {
+    let header = format!("Host {}", host);
+    let mut kept = String::new();
+    let mut skipping = false;
+    for line in config.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("Host ") || trimmed.starts_with("Match ") {
+            skipping = line.trim() == header;
+        }
+        if !skipping {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    kept
+}
+
+/// The hosts of the `Host` blocks in an ssh config
+fn ssh_config_hosts(config: &str) -> Vec<String> {
+    config
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Host "))
+        .map(|host| host.trim().to_string())
+        .collect()
+}
+
+/// Replace the `Host` block for `host` in an ssh config, or append it if there is none
+fn upsert_ssh_config_entry(config: &str, host: &str, entry: &str) -> String {
+    let header = format!("Host {}", host);
+    let mut lines = config.lines().peekable();
+    let mut updated = String::new();
+    let mut replaced = false;
+    while let Some(line) = lines.next() {
+        if line.trim() != header {
+            updated.push_str(line);
+            updated.push('\n');
+            continue;
+        }
+        // Skip the old block up to the next Host or Match section
+        while let Some(next) = lines.peek() {
+            let next = next.trim_start();
+            if next.starts_with("Host ") || next.starts_with("Match ") {
+                break;
+            }
+            lines.next();
+        }
+        updated.push_str(entry);
+        replaced = true;
+    }
+    if !replaced {
+        if !updated.is_empty() && !updated.ends_with("\n\n") {
+            updated.push('\n');
+        }
+        updated.push_str(entry);
+    }
+    updated
+}
+
+async fn ssh_config(alias: Option<String>, all: bool, write: bool) -> Result<()> {
+    let instances = match alias {
+        _ if all => ProfileConfig::get_or_create()?.instances,
+        Some(alias) => vec![ProfileConfig::get_or_create()?
+            .find_instance(&alias)?
+            .clone()],
+        None => vec![get_active_instance()?],
+    };
+    let futures = instances.iter().map(|inst| async move {
+        let info = get_connection_info(inst.clone(), AddressMode::Public).await;
+        (inst, info)
+    });
+    let mut entries = Vec::new();
+    for (inst, info) in run_bounded(futures).await {
+        match info {
+            Ok(info) => entries.push((ssh_config_host(&inst.alias), ssh_config_entry(inst, &info))),
+            // With --all, stopped instances are left out rather than failing the rest
+            Err(e) if all => eprintln!("Skipping {}: {}", inst.alias, e),
+            Err(e) => return Err(e),
+        }
+    }
+    if !write {
+        for (_, entry) in entries.iter() {
+            print!("{}", entry);
+        }
+        return Ok(());
+    }
+    let ssh_dir =
+        expand_tilde("~/.ssh").ok_or_else(|| Error::msg("Could not find home directory"))?;
+    std::fs::create_dir_all(&ssh_dir)?;
+    let path = ssh_dir.join("config");
+    let current = match std::fs::read_to_string(&path) {
+        Ok(current) => current,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let (mut before, mut block, mut after) = split_managed_block(&current);
+    // With --all the block is rewritten for the configured instances, pruning entries of
+    // removed ones. Instances skipped above keep the entry they already have.
+    let mut pruned = Vec::new();
+    if all {
+        let configured = instances
+            .iter()
+            .map(|inst| ssh_config_host(&inst.alias))
+            .collect::<Vec<_>>();
+        for host in ssh_config_hosts(&block) {
+            if !configured.contains(&host) {
+                block = remove_ssh_config_entry(&block, &host);
+                pruned.push(host);
+            }
+        }
+    }
+    for (host, entry) in entries.iter() {
+        // Entries written before the managed block existed move into it
+        before = remove_ssh_config_entry(&before, host);
+        after = remove_ssh_config_entry(&after, host);
+        block = upsert_ssh_config_entry(&block, host, entry);
+    }
+    if !before.is_empty() && !before.ends_with("\n\n") {
+        before.push('\n');
+    }
+    if !block.ends_with('\n') {
+        block.push('\n');
+    }
+    let updated = format!(
+        "{}{}\n{}{}\n{}",
+        before, SSH_CONFIG_BEGIN, block, SSH_CONFIG_END, after
+    );
+    std::fs::write(&path, updated)?;
+    for (host, _) in entries.iter() {
+        println!("Wrote {} to {}", host, path.display());
+    }
+    for host in pruned.iter() {
+        println!("Removed {} from {}", host, path.display());
+    }
+    Ok(())
+}
+
+async fn rotate_key(key_path: &str, install: bool) -> Result<()> {
+    let path = expand_path(key_path).unwrap();
+    if !path.exists() {
+        return Err(Error::msg(format!("Could not find key file: {}", key_path)));
+    };
+    let mut config = ProfileConfig::get_or_create()?;
+    let active = config.active.clone().ok_or(RemoteError::NoActiveInstance)?;
+    if install {
+        let public_key = read_public_key(&path)?;
+        let info = get_active_instance_connection_info().await?;
+        authorize_key(&info, &public_key)?;
+        println!("Authorized new key on {}", active);
+    }
+    config.find_instance_mut(&active)?.key_paths = vec![key_path.to_string()];
+    config.update()?;
+    println!("Key path for {}: {}", active, key_path);
+    println!("Note: the old key is still authorized on the instance");
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct StatusOptions {
+    filters: Vec<InstanceFilter>,
+    compact: bool,
+    refresh: bool,
+    fail_fast: bool,
+    connection: bool,
+    metrics: bool,
+    cost: bool,
+    json: bool,
+}
+
+async fn instance_status(all: bool, group: Option<&str>, opts: &StatusOptions) -> Result<()> {
+    // Refreshing skips cached descriptions but still saves the ones it fetched, keeping
+    // every other entry
+    let cache = Mutex::new(DescriptionCache::load());
+    let prices = match opts.cost {
+        true => Some(PricePool::default()),
+        false => None,
+    };
+    let prices = prices.as_ref();
+    let managers = ManagerPool::default();
+    let lookup = StatusLookup {
+        cache: &cache,
+        managers: &managers,
+        prices,
+    };
+    let result = if all {
+        let instances = ProfileConfig::get_or_create()?.instances;
+        status_all(&instances, opts, &lookup).await
+    } else if let Some(name) = group {
+        let config = ProfileConfig::get_or_create()?;
+        let aliases = config.group_aliases(name)?;
+        let instances = config
+            .instances
+            .into_iter()
+            .filter(|inst| aliases.contains(&inst.alias))
+            .collect::<Vec<_>>();
+        status_all(&instances, opts, &lookup).await
+    } else {
+        let instance = get_active_instance()?;
+        status(&instance, opts, &lookup).await.map(|_| ())
+    };
+    cache.into_inner().unwrap().save()?;
+    result
+}
+
+/// How often `status --watch` refreshes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Redraw the status every `WATCH_INTERVAL` until Ctrl-C, showing errors without exiting
+async fn watch_status(all: bool, group: Option<&str>, opts: StatusOptions) -> Result<()> {
+    if opts.json {
+        return Err(Error::msg("--watch can't be combined with --json"));
+    }
+    // Each refresh should show live data rather than the cached descriptions
+    let opts = StatusOptions {
+        refresh: true,
+        ..opts
+    };
+    loop {
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "{}  refreshing every {}s, Ctrl-C to quit",
+            Local::now().format("%H:%M:%S"),
+            WATCH_INTERVAL.as_secs()
+        );
+        tokio::select! {
+            result = instance_status(all, group, &opts) => {
+                if let Err(e) = result {
+                    println!("Error: {}", e);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(WATCH_INTERVAL) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    Ok(())
+}
+
+/// Show a failed instance in place of its status, keeping JSON output on stdout parseable
+fn print_status_error(alias: &str, e: &Error, opts: &StatusOptions) {
+    if opts.json {
+        eprintln!("Error: {}: {}", alias, e);
+    } else if opts.compact {
+        println!("{}  ERROR: {}", alias, e);
+    } else {
+        println!("---");
+        println!("Alias: {} — ERROR: {}", alias, e);
+    }
+}
+
+/// What `status --all` totals up from each instance it showed
+struct StatusTotals {
+    state: String,
+    hourly: Option<f64>,
+}
+
+/// Count the shown instances by state after their statuses, with the hourly price of the
+/// running ones if known, and name the instances that failed
+fn print_status_summary(shown: &[StatusTotals], failed: &[&str], opts: &StatusOptions) {
+    if opts.json {
+        return;
+    }
+    if !opts.compact {
+        println!("---");
+    }
+    let count = |state: &str| shown.iter().filter(|totals| totals.state == state).count();
+    let (running, stopped) = (count("running"), count("stopped"));
+    let mut summary = format!(
+        "Summary: {} running, {} stopped, {} other",
+        running,
+        stopped,
+        shown.len() - running - stopped
+    );
+    if opts.cost {
+        let hourly = shown
+            .iter()
+            .filter(|totals| totals.state == "running")
+            .filter_map(|totals| totals.hourly)
+            .sum::<f64>();
+        summary.push_str(&format!(", about ${:.4}/hour running", hourly));
+    }
+    println!("{}", summary);
+    if !failed.is_empty() {
+        println!("Failed: {}", failed.join(", "));
+    }
+}
+
+/// What `status` looks instances up with, shared by every instance of a `status --all`
+struct StatusLookup<'a> {
+    cache: &'a Mutex<DescriptionCache>,
+    managers: &'a ManagerPool,
+    prices: Option<&'a PricePool>,
+}
+
+/// Show the status of every instance, printing failures inline and failing if any did
+async fn status_all(
+    instances: &[InstanceConfig],
+    opts: &StatusOptions,
+    lookup: &StatusLookup<'_>,
+) -> Result<()> {
+    let futures = instances.iter().map(|inst| async move {
+        let result = status(inst, opts, lookup).await;
+        if let (Err(e), false) = (&result, opts.fail_fast) {
+            print_status_error(&inst.alias, e, opts);
+        }
+        result.map_err(|e| Error::msg(format!("{}: {}", inst.alias, e)))
+    });
+    if opts.fail_fast {
+        let shown = stream::iter(futures)
+            .buffered(concurrency())
+            .try_collect::<Vec<_>>()
+            .await?;
+        let shown = shown.into_iter().flatten().collect::<Vec<_>>();
+        print_status_summary(&shown, &[], opts);
+        return Ok(());
+    }
+    let results = run_bounded(futures).await;
+    let failed = instances
+        .iter()
+        .zip(results.iter())
+        .filter(|(_, result)| result.is_err())
+        .map(|(inst, _)| inst.alias.as_str())
+        .collect::<Vec<_>>();
+    let shown = results
+        .into_iter()
+        .filter_map(|result| result.ok().flatten())
+        .collect::<Vec<_>>();
+    print_status_summary(&shown, &failed, opts);
+    match failed.len() {
+        0 => Ok(()),
+        n => Err(Error::msg(format!(
+            "{} of {} instances failed",
+            n,
+            instances.len()
+        ))),
+    }
+}
+
+/// Describe an instance, reusing a cached description if it's fresh enough and `refresh`
+/// isn't set
+async fn fetch_instance(
+    instance: &InstanceConfig,
+    lookup: &StatusLookup<'_>,
+    refresh: bool,
+) -> Result<Instance> {
+    let cache = lookup.cache;
+    let key = DescriptionCache::key(
+        &instance.cloud,
+        &instance.profile,
+        Some(&instance.instance_id),
+    );
+    if let (Some(cached), false) = (cache.lock().unwrap().get(&key, DEFAULT_CACHE_TTL), refresh) {
+        return Ok(cached[0].clone());
+    }
+    let manager = lookup.managers.get(instance)?;
+    let status = manager.get_instance(&instance.instance_id).await?;
+    cache.lock().unwrap().put(key, vec![status.clone()]);
+    Ok(status)
+}
+
+/// The on-demand hourly price of an instance, or why it can't be found
+async fn instance_price(
+    instance: &InstanceConfig,
+    status: &Instance,
+    prices: &PricePool,
+) -> Result<f64> {
+    if instance.cloud != Cloud::Aws {
+        return Err(Error::msg("Prices are only looked up for AWS instances"));
+    }
+    prices.hourly_price(instance, status).await
+}
+
+async fn status(
+    instance: &InstanceConfig,
+    opts: &StatusOptions,
+    lookup: &StatusLookup<'_>,
+) -> Result<Option<StatusTotals>> {
+    let status = fetch_instance(instance, lookup, opts.refresh).await?;
+    if !opts.filters.iter().all(|filter| filter.matches(&status)) {
+        return Ok(None);
+    }
+    let mut totals = StatusTotals {
+        state: status.state.clone(),
+        hourly: None,
+    };
+    // Stopped instances report no datapoints, so don't ask. Providers without metrics
+    // show n/a rather than failing the rest of the status.
+    let cpu = match opts.metrics && status.state == "running" {
+        true => match lookup
+            .managers
+            .get(instance)?
+            .get_cpu_utilization(&status.instance_id, CPU_WINDOW_MINUTES)
+            .await
+        {
+            Ok(cpu) => cpu,
+            Err(e) => {
+                info!("No CPU utilization for {}: {}", instance.alias, e);
+                None
+            }
+        },
+        false => None,
+    };
+    let cpu_string = cpu
+        .map(|cpu| format!("{:.1}%", cpu))
+        .unwrap_or_else(|| "n/a".to_string());
+    let hourly = match lookup.prices {
+        Some(prices) => instance_price(instance, &status, prices).await.ok(),
+        None => None,
+    };
+    totals.hourly = hourly;
+    let running_cost = hourly
+        .zip(status.uptime())
+        .map(|(hourly, uptime)| hourly * uptime.num_seconds().max(0) as f64 / 3600.0);
+    let hourly_string = hourly
+        .map(|hourly| format!("${:.4}/hour", hourly))
+        .unwrap_or_else(|| "unknown".to_string());
+    if opts.json {
+        print_json(StatusOutput {
+            alias: &instance.alias,
+            instance: &status,
+            cpu_utilization: cpu,
+            price_per_hour: hourly,
+            running_cost,
+        })?;
+        return Ok(Some(totals));
+    }
+    if opts.compact {
+        let dns = match status.public_dns.as_str() {
+            "" => "-",
+            dns => dns,
+        };
+        let mut line = format!(
+            "{}  {}  {}  {}  {}",
+            instance.alias,
+            status.instance_id,
+            status.instance_type,
+            color_state(&status.state),
+            dns
+        );
+        if opts.metrics {
+            line.push_str(&format!("  {}", cpu_string));
+        }
+        if opts.cost {
+            line.push_str(&format!("  {}", hourly_string));
+        }
+        println!("{}", line);
+        return Ok(Some(totals));
+    }
+    println!("---");
+    println!("Alias: {}", instance.alias);
+    println!("{}", status);
+    if opts.metrics {
+        println!(
+            "CPU Utilization: {} ({} minute average)",
+            cpu_string, CPU_WINDOW_MINUTES
+        );
+    }
+    if opts.cost {
+        match running_cost {
+            Some(cost) => println!("Cost: {} (about ${:.2} since launch)", hourly_string, cost),
+            None => println!("Cost: {}", hourly_string),
+        }
+    }
+    if opts.connection {
+        // Stopped instances have nothing to connect to, so just leave this out
+        if let Ok(info) = connection_info_from(instance, status, AddressMode::Public).await {
+            println!("Connect: {}", info.destination());
+            for key_path in info.key_paths.iter() {
+                println!("Key: {}", key_path.display());
+            }
+        }
+    }
+    Ok(Some(totals))
+}
+
+/// Minutes of CloudWatch data averaged by `status --metrics`
+const CPU_WINDOW_MINUTES: u32 = 10;
+
+/// How many datapoints `remote metrics` aims to chart, one character each
+const SPARKLINE_WIDTH: u64 = 60;
+
+/// Parse a time window like "90s", "30m", "6h" or "2d"
+fn parse_since(s: &str) -> Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(split);
+    let amount = amount
+        .parse::<u64>()
+        .map_err(|_| Error::msg(format!("Invalid time window '{}', e.g. 30m or 6h", s)))?;
+    let seconds = match unit {
+        "s" => amount,
+        "m" | "" => amount * 60,
+        "h" => amount * 3600,
+        "d" => amount * 86400,
+        _ => {
+            return Err(Error::msg(format!(
+                "Invalid time window '{}', use s, m, h or d",
+                s
+            )))
+        }
+    };
+    match seconds {
+        0 => Err(Error::msg("The time window must be positive")),
+        seconds => Ok(Duration::from_secs(seconds)),
+    }
+}
+
+/// `seconds` in the largest unit that divides it, e.g. "5m" or "1h"
+fn format_window(seconds: u64) -> String {
+    match seconds {
+        s if s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// A metric value for display, with byte counts in KB, MB or GB
+fn format_metric_value(metric: InstanceMetric, value: f64) -> String {
+    if metric == InstanceMetric::Cpu {
+        return format!("{:.1}%", value);
+    }
+    match value {
+        v if v >= 1e9 => format!("{:.1} GB", v / 1e9),
+        v if v >= 1e6 => format!("{:.1} MB", v / 1e6),
+        v if v >= 1e3 => format!("{:.1} KB", v / 1e3),
+        v => format!("{:.0} B", v),
+    }
+}
+
+/// One block character per value, scaled from the lowest to the highest
+fn sparkline(values: &[f64]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let low = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let high = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    values
+        .iter()
+        .map(|value| match high - low {
+            range if range > 0.0 => {
+                BLOCKS[(((value - low) / range) * (BLOCKS.len() - 1) as f64).round() as usize]
+            }
+            _ => BLOCKS[0],
+        })
+        .collect()
+}
+
+/// Chart `metric` of the active instance over the last `since` as a sparkline
+async fn instance_metrics(metric: InstanceMetric, since: Duration) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    // Whole minutes, as CloudWatch needs, spread over about one character each
+    let period = (since.as_secs() / SPARKLINE_WIDTH).div_ceil(60).max(1) * 60;
+    let series = manager
+        .get_metric_series(
+            &instance.instance_id,
+            metric,
+            since,
+            Duration::from_secs(period),
+        )
+        .await?;
+    println!(
+        "{} of {} over the last {} ({}, {} periods)",
+        metric.cloudwatch_name(),
+        instance.alias,
+        format_window(since.as_secs()),
+        metric.unit(),
+        format_window(period)
+    );
+    if series.is_empty() {
+        println!("No datapoints, the instance may have been stopped the whole time");
+        return Ok(());
+    }
+    let values = series.iter().map(|(_, value)| *value).collect::<Vec<_>>();
+    println!("{}", sparkline(&values));
+    let (first, last) = (series[0].0, series[series.len() - 1].0);
+    println!(
+        "{} to {}",
+        first.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+        last.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+    );
+    let low = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let high = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    println!(
+        "min {}  avg {}  max {}",
+        format_metric_value(metric, low),
+        format_metric_value(metric, values.iter().sum::<f64>() / values.len() as f64),
+        format_metric_value(metric, high)
+    );
+    Ok(())
+}
+
+/// Switch the terminal between reading single key presses and line input
+fn set_key_input(enabled: bool) -> Result<()> {
+    let mode: &[&str] = match enabled {
+        true => &["-icanon", "-echo"],
+        false => &["icanon", "echo"],
+    };
+    Command::new("stty")
+        .args(mode)
+        .stdin(Stdio::inherit())
+        .status()?;
+    Ok(())
+}
+
+/// Fetch every instance's status with bounded concurrency into a table
+/// With `metrics`, a CPU column is added for running instances
+async fn fleet_table(instances: &[InstanceConfig], metrics: bool) -> Table {
+    let fetches = instances.iter().map(|inst| async move {
+        let manager = match get_instance_manager(inst) {
+            Ok(manager) => manager,
+            Err(e) => return (inst, Err(e), None),
+        };
+        let status = manager.get_instance(&inst.instance_id).await;
+        let cpu = match &status {
+            // Providers without metrics count as having no datapoints
+            Ok(status) if metrics && status.state == "running" => manager
+                .get_cpu_utilization(&status.instance_id, CPU_WINDOW_MINUTES)
+                .await
+                .unwrap_or(None),
+            _ => None,
+        };
+        (inst, status, cpu)
+    });
+    let mut columns = vec!["ALIAS", "STATE", "ID", "TYPE", "ZONE", "UPTIME"];
+    if metrics {
+        columns.push("CPU");
+    }
+    columns.push("DNS");
+    let mut table = Table::new(&columns);
+    table.set_state_column(1);
+    for (inst, status, cpu) in run_bounded(fetches).await {
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                table.push(vec![inst.alias.clone(), "error".to_string(), e.to_string()]);
+                continue;
+            }
+        };
+        let uptime = status
+            .uptime()
+            .map(format_uptime)
+            .unwrap_or_else(|| "-".to_string());
+        let mut row = vec![
+            inst.alias.clone(),
+            status.state,
+            status.instance_id,
+            status.instance_type,
+            status.availability_zone.unwrap_or_else(|| "-".to_string()),
+            uptime,
+        ];
+        if metrics {
+            row.push(
+                cpu.map(|cpu| format!("{:.1}%", cpu))
+                    .unwrap_or_else(|| "n/a".to_string()),
+            );
+        }
+        row.push(status.public_dns);
+        table.push(row);
+    }
+    table
+}
+
+/// Redraw a fleet table every `interval` until q or Ctrl-C is pressed
+async fn top(interval: Duration, metrics: bool) -> Result<()> {
+    if term_size::dimensions_stdout().is_none() {
+        return Err(Error::msg(
+            "top needs a terminal, use \"status --all --compact\" in scripts",
+        ));
+    }
+    let instances = ProfileConfig::get_or_create()?.instances;
+    let (quit_tx, mut quit_rx) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let quit = std::io::Read::bytes(std::io::stdin()).any(|byte| matches!(byte, Ok(b'q')));
+        if quit {
+            let _ = quit_tx.send(());
+        }
+    });
+    set_key_input(true)?;
+    // Hide the cursor while redrawing
+    print!("\x1b[?25l");
+    loop {
+        let table = tokio::select! {
+            table = fleet_table(&instances, metrics) => table,
+            _ = &mut quit_rx => break,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        print!("\x1b[2J\x1b[H");
+        println!(
+            "{}  refreshing every {}s, q to quit\n",
+            Local::now().format("%H:%M:%S"),
+            interval.as_secs()
+        );
+        println!("{}", table.render_stdout());
+        if std::io::stdout().flush().is_err() {
+            break;
+        }
+        tokio::select! {
+            _ = tokio::time::delay_for(interval) => {}
+            _ = &mut quit_rx => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    print!("\x1b[?25h");
+    set_key_input(false)
+}
+
+async fn instance_events(all: bool) -> Result<()> {
+    if all {
+        let instances = ProfileConfig::get_or_create()?.instances;
+        let futures = instances.iter().map(|inst| async move {
+            let result = events(inst).await;
+            if let Err(e) = &result {
+                println!("---");
+                println!("Alias: {} — ERROR: {}", inst.alias, e);
+            }
+            result
+        });
+        let failed = run_bounded(futures)
+            .await
+            .into_iter()
+            .filter(Result::is_err)
+            .count();
+        match failed {
+            0 => Ok(()),
+            n => Err(Error::msg(format!(
+                "{} of {} instances failed",
+                n,
+                instances.len()
+            ))),
+        }
+    } else {
+        let instance = get_active_instance()?;
+        events(&instance).await
+    }
+}
+
+async fn instance_logs(latest: bool) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    let output = manager
+        .get_console_output(&instance.instance_id, latest)
+        .await?;
+    match output.trim().is_empty() {
+        true => println!("No console output available for {} yet", instance.alias),
+        false => print!("{}", output),
+    }
+    Ok(())
+}
+
+async fn events(instance: &InstanceConfig) -> Result<()> {
+    let manager = get_instance_manager(instance)?;
+    let events = manager.get_instance_events(&instance.instance_id).await?;
+    println!("---");
+    println!("Alias: {}", instance.alias);
+    if events.is_empty() {
+        println!("No scheduled events");
+    }
+    for event in events.iter() {
+        println!("{}", event);
+    }
+    Ok(())
+}
+
+/// Number of previous instance types remembered for `resize --undo`
+const TYPE_HISTORY_LEN: usize = 10;
+
+fn print_resize(
+    instance: &InstanceConfig,
+    previous_type: &str,
+    instance_type: &str,
+    json: bool,
+) -> Result<()> {
+    if json {
+        return print_json(ResizeOutput {
+            alias: &instance.alias,
+            instance_id: &instance.instance_id,
+            previous_type,
+            instance_type,
+        });
+    }
+    println!(
+        "Set {} ({}) from {} to {}",
+        instance.alias, instance.instance_id, previous_type, instance_type
+    );
+    Ok(())
+}
+
+/// How long `resize --force` waits for the instance to stop
+const RESIZE_STOP_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Change the type of an instance, which must be stopped first. With `force` a running
+/// instance is stopped, resized and started again, once confirmed if it is protected.
+/// Progress goes to stderr so it doesn't mix with JSON output.
+async fn set_type_when_stopped(
+    manager: &dyn InstanceManager,
+    instance: &InstanceConfig,
+    status: &Instance,
+    instance_type: &str,
+    force: bool,
+    assume_yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let restart = match (status.state.as_str(), force) {
+        ("stopped", _) => false,
+        ("running", true) | ("stopping", true) => status.state == "running",
+        ("running", false) | ("stopping", false) => {
+            return Err(Error::msg(format!(
+                "{} is {}, stop it before resizing or use --force to stop and restart it",
+                instance.alias, status.state
+            )))
+        }
+        (state, _) => {
+            return Err(Error::msg(format!(
+                "{} is {}, it must be stopped to resize",
+                instance.alias, state
+            )))
+        }
+    };
+    if dry_run {
+        let action = match (status.state.as_str(), restart) {
+            ("stopped", _) => "resize",
+            (_, true) => "stop, resize and restart",
+            (_, false) => "wait for the stop and resize",
+        };
+        println!(
+            "Dry run: would {} {} ({}) from {} to {}",
+            action, instance.alias, instance.instance_id, status.instance_type, instance_type
+        );
+        return Ok(());
+    }
+    if status.state != "stopped" {
+        if restart {
+            confirm_stop_protected(manager, instance, assume_yes).await?;
+            eprintln!("Stopping {}", instance.alias);
+            manager.stop_instance(&instance.instance_id).await?;
+        }
+        eprintln!("Waiting for {} to stop", instance.alias);
+        manager
+            .wait_for_state(&instance.instance_id, "stopped", RESIZE_STOP_TIMEOUT)
+            .await?;
+    }
+    eprintln!("Resizing {} to {}", instance.alias, instance_type);
+    manager
+        .set_instance_type(&instance.instance_id, instance_type)
+        .await?;
+    if restart {
+        eprintln!("Starting {}", instance.alias);
+        manager.start_instance(&instance.instance_id).await?;
+    }
+    Ok(())
+}
+
+/// How many similar types to suggest for a type that isn't offered
+const SIMILAR_TYPES_SHOWN: usize = 8;
+
+/// Fail early, suggesting types of the same family, if `instance_type` isn't offered where the
+/// instance runs. Clouds that can't list their types are left to reject it themselves.
+async fn check_type_offered(
+    manager: &dyn InstanceManager,
+    status: &Instance,
+    instance_type: &str,
+) -> Result<()> {
+    let zone = status.availability_zone.as_deref();
+    let offered = match manager.list_offered_types(zone).await {
+        Ok(offered) if !offered.is_empty() => offered,
+        _ => return Ok(()),
+    };
+    if offered.iter().any(|offered| offered == instance_type) {
+        return Ok(());
+    }
+    let family = instance_type.split('.').next().unwrap_or(instance_type);
+    let similar = offered
+        .iter()
+        .filter(|offered| offered.split('.').next() == Some(family))
+        .take(SIMILAR_TYPES_SHOWN)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let mut message = format!(
+        "{} is not offered in {}",
+        instance_type,
+        zone.unwrap_or("this region")
+    );
+    if !similar.is_empty() {
+        message.push_str(&format!(", similar types: {}", similar.join(", ")));
+    }
+    Err(Error::msg(message))
+}
+
+async fn instance_resize(
+    instance_type: &str,
+    force: bool,
+    json: bool,
+    assume_yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    let status = manager.get_instance(&instance.instance_id).await?;
+    check_type_offered(manager.as_ref(), &status, instance_type).await?;
+    set_type_when_stopped(
+        manager.as_ref(),
+        &instance,
+        &status,
+        instance_type,
+        force,
+        assume_yes,
+        dry_run,
+    )
+    .await?;
+    if dry_run {
+        return Ok(());
+    }
+    let previous = status.instance_type;
+    DescriptionCache::invalidate(&instance.cloud, &instance.profile, &instance.instance_id)?;
+
+    let mut config = ProfileConfig::get_or_create()?;
+    let history = &mut config.find_instance_mut(&instance.alias)?.type_history;
+    history.push(previous.clone());
+    if history.len() > TYPE_HISTORY_LEN {
+        history.remove(0);
+    }
+    config.update()?;
+    print_resize(&instance, &previous, instance_type, json)
+}
+
+async fn instance_resize_undo(
+    force: bool,
+    json: bool,
+    assume_yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    let status = manager.get_instance(&instance.instance_id).await?;
+    let previous = instance
+        .type_history
+        .last()
+        .ok_or_else(|| Error::msg(format!("No resize history for {}", instance.alias)))?;
+    set_type_when_stopped(
+        manager.as_ref(),
+        &instance,
+        &status,
+        previous,
+        force,
+        assume_yes,
+        dry_run,
+    )
+    .await?;
+    if dry_run {
+        return Ok(());
+    }
+    let current = status.instance_type;
+    DescriptionCache::invalidate(&instance.cloud, &instance.profile, &instance.instance_id)?;
+
+    let mut config = ProfileConfig::get_or_create()?;
+    config
+        .find_instance_mut(&instance.alias)?
+        .type_history
+        .pop();
+    config.update()?;
+    print_resize(&instance, &current, previous, json)
+}
+
+/// Grow the root partition and its filesystem to fill the disk, for ext4 and xfs roots
+const GROW_ROOT_SCRIPT: &str = "set -e
+root=$(findmnt -n -o SOURCE /)
+if [ -e /sys/class/block/$(basename $root)/partition ]; then
+  sudo growpart /dev/$(lsblk -no PKNAME $root) $(cat /sys/class/block/$(basename $root)/partition) || true
+fi
+case $(findmnt -n -o FSTYPE /) in
+  xfs) sudo xfs_growfs / ;;
+  *) sudo resize2fs $root ;;
+esac";
+
+async fn instance_disk(size_gb: i64, grow: bool, assume_yes: bool) -> Result<()> {
     let instance = get_active_instance()?;
-    let manager = get_manager(&instance.cloud, &instance.profile)?;
+    let prompt = format!(
+        "Grow the root volume of {} to {} GiB? Volumes can't be shrunk again",
+        instance.alias, size_gb
+    );
+    if !confirm(&prompt, assume_yes)? {
+        return Err(Error::msg("Aborted"));
+    }
+    let manager = get_instance_manager(&instance)?;
     manager
-        .set_instance_type(&instance.instance_id, instance_type)
+        .set_volume_size(&instance.instance_id, size_gb)
         .await?;
     println!(
-        "Set {} ({}) to {}",
-        instance.alias, instance.instance_id, instance_type
+        "Root volume of {} resized to {} GiB",
+        instance.alias, size_gb
+    );
+    if !grow {
+        println!(
+            "Note: grow the partition and filesystem on the instance to use the space \
+             (growpart and resize2fs or xfs_growfs), or pass --grow to do it automatically"
+        );
+        return Ok(());
+    }
+    let info = get_active_instance_connection_info().await?;
+    match exec(&info, GROW_ROOT_SCRIPT, &[])? {
+        0 => Ok(()),
+        code => Err(Error::msg(format!(
+            "Growing the filesystem failed with exit code {}",
+            code
+        ))),
+    }
+}
+
+/// How long `snapshot --ami --wait` waits for the AMI to become available
+const IMAGE_WAIT_TIMEOUT: Duration = Duration::from_secs(1800);
+
+struct SnapshotOptions {
+    name: Option<String>,
+    ami: bool,
+    no_reboot: bool,
+    wait: bool,
+}
+
+async fn instance_snapshot(opts: SnapshotOptions, assume_yes: bool) -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    let timestamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let name = opts
+        .name
+        .unwrap_or_else(|| format!("{}-{}", instance.alias, timestamp));
+    let tags = vec![
+        InstanceTag {
+            key: "Name".to_string(),
+            value: name.clone(),
+        },
+        InstanceTag {
+            key: "remote:alias".to_string(),
+            value: instance.alias.clone(),
+        },
+        InstanceTag {
+            key: "remote:timestamp".to_string(),
+            value: timestamp,
+        },
+    ];
+
+    if opts.ami {
+        if opts.no_reboot {
+            println!("Warning: without a reboot the file system of the AMI may be inconsistent");
+        } else {
+            println!(
+                "Creating an AMI reboots {} to guarantee a consistent file system (use --no-reboot to skip)",
+                instance.alias
+            );
+            if !confirm("Continue?", assume_yes)? {
+                return Err(Error::msg("Aborted"));
+            }
+        }
+        let image_id = manager
+            .create_image(&instance.instance_id, &name, opts.no_reboot, &tags)
+            .await?;
+        println!("Created AMI {} ({})", image_id, name);
+        if opts.wait {
+            println!("Waiting for {} to be available...", image_id);
+            manager
+                .wait_for_image(&image_id, IMAGE_WAIT_TIMEOUT)
+                .await?;
+            println!("AMI {} is available", image_id);
+        }
+    } else {
+        let snapshot_id = manager
+            .create_snapshot(&instance.instance_id, &name, &tags)
+            .await?;
+        println!("Created snapshot {} ({})", snapshot_id, name);
+    }
+    Ok(())
+}
+
+/// How long to wait for a newly launched instance to start running
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+struct RestoreOptions {
+    image_id: String,
+    like: Option<String>,
+    instance_type: Option<String>,
+    key_name: Option<String>,
+    security_groups: Vec<String>,
+    subnet: Option<String>,
+    cloud: String,
+    profile: String,
+    region: Option<String>,
+    spot: Option<SpotSpec>,
+}
+
+/// Prompt for `prompt` unless `value` was given, treating a blank answer as none
+fn input_or(value: Option<String>, prompt: &str) -> Result<Option<String>> {
+    match value {
+        Some(value) => Ok(Some(value)),
+        None => Ok(Some(user_input(prompt)?).filter(|answer| !answer.is_empty())),
+    }
+}
+
+async fn instance_launch(mut opts: RestoreOptions) -> Result<()> {
+    if opts.image_id.is_empty() {
+        opts.image_id = user_input("AMI ID")?;
+    }
+    if opts.image_id.is_empty() {
+        return Err(Error::msg("An AMI ID is required"));
+    }
+    opts.instance_type = input_or(opts.instance_type, "Instance type")?;
+    opts.key_name = input_or(opts.key_name, "Key pair name [none]")?;
+    if opts.security_groups.is_empty() {
+        opts.security_groups = user_input("Security group IDs, comma separated [default]")?
+            .split(',')
+            .map(|group| group.trim().to_string())
+            .filter(|group| !group.is_empty())
+            .collect();
+    }
+    opts.subnet = input_or(opts.subnet, "Subnet ID [default]")?;
+    instance_restore(opts).await
+}
+
+async fn instance_restore(opts: RestoreOptions) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    let reference = match &opts.like {
+        Some(alias) => Some(config.find_instance(alias)?.clone()),
+        None => None,
+    };
+    let (cloud, profile) = match &reference {
+        Some(inst) => (inst.cloud.clone(), inst.profile.clone()),
+        None => (Cloud::from_str(&opts.cloud)?, opts.profile.clone()),
+    };
+    let manager = match &reference {
+        Some(inst) => get_instance_manager(inst)?,
+        None => get_manager(&cloud, &profile, opts.region.as_deref())?,
+    };
+
+    let mut spec = match &reference {
+        Some(inst) => manager.launch_spec_from(&inst.instance_id).await?,
+        None => LaunchSpec::default(),
+    };
+    spec.image_id = opts.image_id;
+    if let Some(instance_type) = opts.instance_type {
+        spec.instance_type = instance_type;
+    }
+    if opts.key_name.is_some() {
+        spec.key_name = opts.key_name;
+    }
+    if !opts.security_groups.is_empty() {
+        spec.security_group_ids = opts.security_groups;
+    }
+    if opts.subnet.is_some() {
+        spec.subnet_id = opts.subnet;
+    }
+    if spec.instance_type.is_empty() {
+        return Err(Error::msg("An --instance-type is required without --like"));
+    }
+    spec.spot = opts.spot;
+
+    let alias = user_input("Alias")?;
+    if config.instances.iter().any(|inst| inst.alias == alias) {
+        return Err(Error::msg(format!(
+            "Instance with alias '{}' already exists",
+            alias
+        )));
+    };
+    let (key_paths, user) = match &reference {
+        Some(inst) => (inst.key_paths.clone(), inst.user.clone()),
+        None => {
+            let key_path = user_input("SSH key path")?;
+            let path = expand_path(&key_path).unwrap();
+            if !path.exists() {
+                return Err(Error::msg(format!("Could not find key file: {}", key_path)));
+            };
+            (vec![key_path], user_input("SSH user name")?)
+        }
+    };
+    spec.tags.push(InstanceTag {
+        key: "Name".to_string(),
+        value: alias.clone(),
+    });
+
+    let launched = manager.launch_instance(spec).await?;
+    let instance = InstanceConfig {
+        alias,
+        instance_id: launched.instance_id,
+        key_paths,
+        user,
+        profile,
+        cloud,
+        region: reference.and_then(|inst| inst.region).or(opts.region),
+        ..Default::default()
+    };
+    // Save before waiting so an instance that is slow to start isn't left running unconfigured
+    config.instances.push(instance.clone());
+    config.update()?;
+    println!(
+        "Launched {} as {}, waiting for it to start...",
+        instance.instance_id, instance.alias
     );
+    manager
+        .wait_for_state(&instance.instance_id, "running", LAUNCH_TIMEOUT)
+        .await
+        .map_err(|e| {
+            Error::msg(format!(
+                "{}. It was saved as '{}', check on it with \"remote status\" or remove it \
+                 with \"remote rm {} --terminate\"",
+                e, instance.alias, instance.alias
+            ))
+        })?;
+    status(
+        &instance,
+        &StatusOptions::default(),
+        &StatusLookup {
+            cache: &Mutex::default(),
+            managers: &ManagerPool::default(),
+            prices: None,
+        },
+    )
+    .await?;
     Ok(())
 }
 
-async fn instance_list_cloud(cloud: &str, profile: &str) -> Result<()> {
+#[derive(Debug, Default)]
+struct ListOptions {
+    refresh: bool,
+    table: bool,
+    offline: bool,
+    format: ListFormat,
+    region: Option<String>,
+    filters: Vec<ListFilter>,
+}
+
+/// How `ls` prints instances
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ListFormat {
+    #[default]
+    Text,
+    Csv,
+    Json,
+}
+
+impl ListFormat {
+    /// JSON if the global --json flag was given
+    fn or_json(self, json: bool) -> Self {
+        if json {
+            ListFormat::Json
+        } else {
+            self
+        }
+    }
+}
+
+impl FromStr for ListFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(ListFormat::Text),
+            "csv" => Ok(ListFormat::Csv),
+            "json" => Ok(ListFormat::Json),
+            _ => Err(Error::msg(format!("Unknown list format '{}'", s))),
+        }
+    }
+}
+
+const CSV_LIST_HEADER: [&str; 6] = [
+    "alias",
+    "instance_id",
+    "type",
+    "state",
+    "public_dns",
+    "tags",
+];
+
+/// A CSV writer on stdout that has already written `CSV_LIST_HEADER`, so the header is
+/// there even when no instances follow
+fn csv_writer() -> Result<csv::Writer<std::io::Stdout>> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(std::io::stdout());
+    writer.write_record(CSV_LIST_HEADER)?;
+    Ok(writer)
+}
+
+/// An instance as a CSV row, with its tags as `key=value` pairs separated by semicolons
+#[derive(Default, Serialize)]
+struct CsvInstance<'a> {
+    alias: &'a str,
+    instance_id: &'a str,
+    instance_type: &'a str,
+    state: &'a str,
+    public_dns: &'a str,
+    tags: String,
+}
+
+impl<'a> CsvInstance<'a> {
+    fn new(alias: &'a str, inst: &'a Instance) -> Self {
+        let tags = inst
+            .tags
+            .iter()
+            .map(|tag| format!("{}={}", tag.key, tag.value))
+            .collect::<Vec<_>>()
+            .join(";");
+        Self {
+            alias,
+            instance_id: &inst.instance_id,
+            instance_type: &inst.instance_type,
+            state: &inst.state,
+            public_dns: &inst.public_dns,
+            tags,
+        }
+    }
+}
+
+async fn instance_list_cloud(cloud: &str, profile: &str, opts: &ListOptions) -> Result<()> {
     let cl = Cloud::from_str(cloud)?;
-    let manager = get_manager(&cl, profile)?;
-    let instances = manager
-        .list_instances()
-        .await?
+    let region = opts.region.as_deref();
+    let mut cache = DescriptionCache::load();
+    // Listings of other regions are cached separately
+    let scope = match region {
+        Some(region) => format!("{}/{}", profile, region),
+        None => profile.to_string(),
+    };
+    let key = DescriptionCache::key(&cl, &scope, None);
+    let instances = match cache.get(&key, DEFAULT_CACHE_TTL) {
+        // Filtered listings are fetched every time and not cached, so they can't stand in for
+        // the full listing
+        _ if !opts.filters.is_empty() => {
+            get_manager(&cl, profile, region)?
+                .list_instances_filtered(&opts.filters)
+                .await?
+        }
+        Some(cached) if !opts.refresh && !opts.offline => cached.clone(),
+        _ if opts.offline => offline_listing(&cache, &key)?,
+        _ => {
+            let fetched = async { get_manager(&cl, profile, region)?.list_instances().await }.await;
+            match fetched {
+                Ok(instances) => {
+                    cache.put(key, instances.clone());
+                    cache.save()?;
+                    instances
+                }
+                Err(e) => {
+                    eprintln!("Could not reach {}: {}", cloud, e);
+                    offline_listing(&cache, &key).map_err(|_| e)?
+                }
+            }
+        }
+    };
+    match opts.format {
+        ListFormat::Json => {
+            for instance in instances.iter() {
+                print_json(ListedOutput { instance })?;
+            }
+            return Ok(());
+        }
+        ListFormat::Csv => {
+            // Name live instances by their configured alias where there is one
+            let config = ProfileConfig::get_or_create()?;
+            let mut writer = csv_writer()?;
+            for instance in instances.iter() {
+                let alias = config
+                    .instances
+                    .iter()
+                    .find(|inst| inst.cloud == cl && inst.instance_id == instance.instance_id)
+                    .map(|inst| inst.alias.as_str())
+                    .unwrap_or_default();
+                writer.serialize(CsvInstance::new(alias, instance))?;
+            }
+            writer.flush()?;
+            return Ok(());
+        }
+        ListFormat::Text => {}
+    }
+    if opts.table {
+        println!("{}", instance_table(&instances).render_stdout());
+        return Ok(());
+    }
+    let instances = instances
         .into_iter()
         .map(|inst| inst.to_string())
         .collect::<Vec<String>>()
@@ -368,32 +5636,953 @@ async fn instance_list_cloud(cloud: &str, profile: &str) -> Result<()> {
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let opt = Opt::from_args();
-    match opt {
-        Opt::Instance { alias } => set_active_instance(&alias)?,
-        Opt::New { active } => new_instance(active).await?,
-        Opt::Rm { alias } => remove_instance(&alias)?,
-        Opt::Start => start_instance().await?,
-        Opt::Stop => stop_instance().await?,
-        Opt::Ssh { ports } => open_ssh(ports).await?,
+/// The last saved listing, announcing that it may be out of date
+fn offline_listing(cache: &DescriptionCache, key: &str) -> Result<Vec<Instance>> {
+    let (fetched_at, instances) = cache
+        .get_last(key)
+        .ok_or_else(|| Error::msg("No saved listing to show offline"))?;
+    eprintln!(
+        "OFFLINE: showing listing saved at {}, it may be out of date",
+        fetched_at.format("%Y-%m-%d %H:%M:%S")
+    );
+    Ok(instances.clone())
+}
+
+fn instance_table(instances: &[Instance]) -> Table {
+    let mut table = Table::new(&["ID", "TYPE", "STATE", "ZONE", "NAME", "DNS"]);
+    table.set_state_column(2);
+    for inst in instances {
+        let name = inst.name().unwrap_or_default().to_string();
+        table.push(vec![
+            inst.instance_id.clone(),
+            inst.instance_type.clone(),
+            inst.state.clone(),
+            inst.availability_zone
+                .clone()
+                .unwrap_or_else(|| "-".to_string()),
+            name,
+            inst.public_dns.clone(),
+        ]);
+    }
+    table
+}
+
+async fn instance_types(
+    filter: Option<&str>,
+    family: Option<&str>,
+    region: Option<&str>,
+    profile: Option<String>,
+    cost: bool,
+) -> Result<()> {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => ProfileConfig::get_or_create()?.profile_or_default(),
+    };
+    let manager = get_manager(&Cloud::Aws, &profile, region)?;
+    let mut types = manager.list_instance_types(family).await?;
+    if let Some(filter) = filter {
+        types.retain(|info| info.instance_type.contains(filter));
+    }
+    if types.is_empty() {
+        println!("No matching instance types");
+        return Ok(());
+    }
+    let prices = match cost {
+        true => {
+            let region = aws_region(&profile, region.map(|region| (region, "--region")))?;
+            let prices = PriceLookup::from_profile(&profile)?;
+            let lookups = types.iter().map(|info| {
+                let (prices, region) = (&prices, region.name());
+                async move { prices.hourly_price(&info.instance_type, region).await.ok() }
+            });
+            run_bounded(lookups).await
+        }
+        false => Vec::new(),
+    };
+    let mut columns = vec!["TYPE", "VCPUS", "MEMORY (GiB)", "NETWORK"];
+    if cost {
+        columns.push("PRICE/HOUR");
+    }
+    let mut table = Table::new(&columns);
+    for (i, info) in types.into_iter().enumerate() {
+        let mut row = vec![
+            info.instance_type,
+            info.vcpus.to_string(),
+            format!("{:.1}", info.memory_mib as f64 / 1024.0),
+            info.network_performance,
+        ];
+        if cost {
+            row.push(match prices[i] {
+                Some(hourly) => format!("${:.4}", hourly),
+                None => "unknown".to_string(),
+            });
+        }
+        table.push(row);
+    }
+    println!("{}", table.render_stdout());
+    Ok(())
+}
+
+async fn security_groups() -> Result<()> {
+    let instance = get_active_instance()?;
+    let manager = get_instance_manager(&instance)?;
+    let groups = manager.get_security_groups(&instance.instance_id).await?;
+    if groups.is_empty() {
+        println!("{} has no security groups", instance.alias);
+        return Ok(());
+    }
+    let mut table = Table::new(&["GROUP", "NAME", "PROTOCOL", "PORTS", "SOURCE"]);
+    for group in groups {
+        if group.inbound.is_empty() {
+            table.push(vec![
+                group.group_id.clone(),
+                group.name.clone(),
+                "-".to_string(),
+                "-".to_string(),
+                "no inbound rules".to_string(),
+            ]);
+        }
+        for rule in group.inbound.iter() {
+            table.push(vec![
+                group.group_id.clone(),
+                group.name.clone(),
+                rule.protocol.clone(),
+                rule.port_range(),
+                rule.source.clone(),
+            ]);
+        }
+    }
+    println!("{}", table.render_stdout());
+    Ok(())
+}
+
+fn set_defaults(cloud: Option<String>, profile: Option<String>) -> Result<()> {
+    let mut config = ProfileConfig::get_or_create()?;
+    if let Some(cloud) = cloud {
+        config.default_cloud = Some(Cloud::from_str(&cloud)?);
+    }
+    if let Some(profile) = profile {
+        config.default_profile = Some(profile);
+    }
+    config.update()?;
+    match &config.default_cloud {
+        Some(cloud) => println!("Default cloud: {}", cloud),
+        None => println!("Default cloud: none"),
+    }
+    println!("Default profile: {}", config.profile_or_default());
+    Ok(())
+}
+
+fn validate_config() -> Result<()> {
+    let path = get_config_path()?;
+    let problems = ProfileConfig::validate_file(&path);
+    if problems.is_empty() {
+        println!("{}: ok", path.display());
+        return Ok(());
+    }
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    Err(Error::msg(format!(
+        "Found {} problem(s) in {}",
+        problems.len(),
+        path.display()
+    )))
+}
+
+/// Print the config file as written, masking the values of secret fields
+fn show_config() -> Result<()> {
+    let path = get_config_path()?;
+    if !path.exists() {
+        return Err(Error::msg(format!("No config at {}", path.display())));
+    }
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| Error::msg(format!("Could not read {}: {}", path.display(), e)))?;
+    print!(
+        "{}",
+        redact_config(&contents, ConfigFormat::from_path(&path))
+    );
+    Ok(())
+}
+
+/// How many timestamped config backups are kept before the oldest are removed
+const CONFIG_BACKUPS_KEPT: usize = 10;
+
+/// Copy the config to `path`, or to a new timestamped file in the backups directory
+fn backup_config(path: Option<PathBuf>) -> Result<PathBuf> {
+    let config_path = get_config_path()?;
+    if !config_path.exists() {
+        return Err(Error::msg(format!(
+            "No config at {} to back up",
+            config_path.display()
+        )));
+    }
+    if let Some(path) = path {
+        // A path in another format gets the config rewritten in that format
+        match ConfigFormat::from_path(&path) == ConfigFormat::from_path(&config_path) {
+            true => {
+                std::fs::copy(&config_path, &path)?;
+            }
+            false => ProfileConfig::from_file(&config_path)?.to_file(&path)?,
+        }
+        println!("Backed up {} to {}", config_path.display(), path.display());
+        return Ok(path);
+    }
+    let dir = get_config_dir()?.join("backups");
+    std::fs::create_dir_all(&dir)?;
+    let stem = config_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "profiles".to_string());
+    let extension = config_path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    // Milliseconds keep backups made in the same second, e.g. by a restore right after
+    // a backup, from replacing each other
+    let path = dir.join(format!(
+        "{}-{}{}",
+        stem,
+        Local::now().format("%Y%m%d-%H%M%S%3f"),
+        extension
+    ));
+    std::fs::copy(&config_path, &path)?;
+    println!("Backed up {} to {}", config_path.display(), path.display());
+    // Timestamped names sort by age, so everything but the newest few goes
+    let prefix = format!("{}-", stem);
+    let mut backups = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|backup| {
+            backup
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+        })
+        .collect::<Vec<_>>();
+    backups.sort();
+    let excess = backups.len().saturating_sub(CONFIG_BACKUPS_KEPT);
+    for old in backups.into_iter().take(excess) {
+        std::fs::remove_file(old)?;
+    }
+    Ok(path)
+}
+
+/// Replace the config with the backup at `path` once it has been checked, backing up
+/// the current config first
+fn restore_config(path: &Path, assume_yes: bool) -> Result<()> {
+    let path = path.to_path_buf();
+    // Read up front, as backing up the current config may prune this very backup
+    let raw = std::fs::read(&path)?;
+    let restored = match ProfileConfig::from_file(&path) {
+        Ok(config) => config,
+        Err(_) => {
+            for problem in ProfileConfig::validate_file(&path).iter() {
+                println!("{}", problem);
+            }
+            return Err(Error::msg(format!(
+                "{} is not a valid config, nothing was restored",
+                path.display()
+            )));
+        }
+    };
+    for problem in restored.validate().iter() {
+        println!("Warning: {}", problem);
+    }
+    let config_path = get_config_path()?;
+    let prompt = format!("Replace {} with {}?", config_path.display(), path.display());
+    if !confirm(&prompt, assume_yes)? {
+        return Ok(());
+    }
+    if config_path.exists() {
+        backup_config(None)?;
+    }
+    // A backup in another format is rewritten in the live config's format
+    match ConfigFormat::from_path(&path) == ConfigFormat::from_path(&config_path) {
+        true => write_atomic(&config_path, &raw)?,
+        false => restored.to_file(&config_path)?,
+    }
+    println!("Restored {} from {}", config_path.display(), path.display());
+    Ok(())
+}
+
+/// Log warnings by default, and more with each `-v`. Other crates' messages are left out
+/// below trace level so `-v` and `-vv` stay readable.
+fn init_logging(verbosity: u8) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::new();
+    match level {
+        LevelFilter::Trace => builder.filter_level(level),
+        _ => builder.filter_module("remote", level),
+    };
+    builder
+        .format(|buf, record| writeln!(buf, "[{}] {}", record.level(), record.args()))
+        .try_init()
+        .ok();
+}
+
+/// Log a command line before running it, as a step in a `Command` builder chain
+trait LogCommand {
+    fn logged(&mut self) -> &mut Self;
+}
+
+impl LogCommand for Command {
+    fn logged(&mut self) -> &mut Self {
+        info!("Running {:?}", self);
+        self
+    }
+}
+
+async fn run() -> Result<()> {
+    let args = Args::from_args();
+    if let Some(path) = args.config {
+        set_config_path(path);
+    }
+    REFRESH_DESCRIPTIONS.store(args.refresh, Ordering::Relaxed);
+    PICK_INSTANCE.store(args.pick, Ordering::Relaxed);
+    FIX_KEY_PERMISSIONS.store(args.fix_permissions, Ordering::Relaxed);
+    init_logging(args.verbose);
+    if let Some(limit) = args.concurrency {
+        let _ = CONCURRENCY.set(limit as usize);
+    }
+    if let Some(profile) = args.profile_override {
+        let _ = PROFILE_OVERRIDE.set(profile);
+    }
+    if let Some(port) = args.ssh_port {
+        let _ = SSH_PORT_OVERRIDE.set(port);
+    }
+    match args.cmd {
+        Opt::Instance { alias, clear } => match alias {
+            Some(alias) if !clear => set_active_instance(&alias)?,
+            _ => clear_active_instance()?,
+        },
+        Opt::Which => which(args.verbose > 0)?,
+        Opt::Doctor => doctor()?,
+        Opt::New {
+            active,
+            generate_key,
+            opts,
+        } => new_instance(active, generate_key, opts).await?,
+        Opt::Import {
+            cloud,
+            profile,
+            all,
+            key_path,
+            user,
+            region,
+        } => {
+            let opts = ImportOptions {
+                all,
+                key_path,
+                user,
+                region,
+            };
+            import_instances(cloud, profile, opts).await?
+        }
+        Opt::Rm {
+            alias,
+            all,
+            group,
+            terminate,
+            force,
+        } => match alias {
+            Some(alias) if terminate => {
+                terminate_and_remove(&alias, args.assume_yes, args.dry_run).await?
+            }
+            Some(alias) => remove_instance(&alias, force, args.assume_yes).await?,
+            None => remove_instances(all, group, args.assume_yes)?,
+        },
+        Opt::Rename { old, new } => rename_instance(&old, &new)?,
+        Opt::Edit { alias } => edit_instance(&alias)?,
+        Opt::Move { alias, to } => move_instance(&alias, &to)?,
+        Opt::Duplicate {
+            source_alias,
+            new_alias,
+            instance_id,
+        } => duplicate_instance(&source_alias, &new_alias, instance_id)?,
+        Opt::Terminate { alias, yes } => {
+            let alias = match alias {
+                Some(alias) => alias,
+                None => get_active_instance()?.alias,
+            };
+            terminate_and_remove(&alias, yes || args.assume_yes, args.dry_run).await?
+        }
+        Opt::Start {
+            aliases,
+            group,
+            stop_others,
+            wait,
+            schedule,
+            no_schedule,
+            region,
+        } => {
+            override_region(region)?;
+            match (schedule, no_schedule, group_or_aliases(group, aliases)?) {
+                (Some(schedule), _, _) => set_schedule(ScheduledAction::Start, Some(schedule))?,
+                (None, true, _) => set_schedule(ScheduledAction::Start, None)?,
+                (None, false, aliases) if aliases.is_empty() => {
+                    start_instance(stop_others, args.json, wait, args.assume_yes, args.dry_run)
+                        .await?
+                }
+                (None, false, aliases) => {
+                    change_states(
+                        &aliases,
+                        StateAction::Start,
+                        args.json,
+                        wait,
+                        args.assume_yes,
+                        args.dry_run,
+                    )
+                    .await?
+                }
+            }
+        }
+        Opt::Stop {
+            aliases,
+            group,
+            schedule,
+            no_schedule,
+            region,
+        } => {
+            override_region(region)?;
+            match (schedule, no_schedule, group_or_aliases(group, aliases)?) {
+                (Some(schedule), _, _) => set_schedule(ScheduledAction::Stop, Some(schedule))?,
+                (None, true, _) => set_schedule(ScheduledAction::Stop, None)?,
+                (None, false, aliases) if !aliases.is_empty() => {
+                    change_states(
+                        &aliases,
+                        StateAction::Stop,
+                        args.json,
+                        false,
+                        args.assume_yes,
+                        args.dry_run,
+                    )
+                    .await?
+                }
+                (None, false, _) => stop_instance(args.json, args.assume_yes, args.dry_run).await?,
+            }
+        }
+        Opt::Reboot => reboot_instance(args.json).await?,
+        Opt::Toggle => toggle_instance(args.json, args.assume_yes, args.dry_run).await?,
+        Opt::Daemon { interval } => daemon(Duration::from_secs(interval)).await?,
+        Opt::Ssh {
+            ports,
+            jump,
+            discover_ports,
+            mosh,
+            warn_if_busy,
+            auto_address,
+            login,
+            no_login,
+            forward_agent,
+            keepalive,
+            wait_ssh,
+            region,
+            sudo,
+            stop_multiplex,
+            extra,
+        } => {
+            override_region(region)?;
+            if stop_multiplex {
+                return stop_multiplexing().await;
+            }
+            if sudo {
+                let code = ssh_sudo(&extra).await?;
+                if code != 0 {
+                    std::process::exit(code);
+                }
+                return Ok(());
+            }
+            let shell = match (login, no_login) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+            let opts = SshOptions {
+                ports,
+                jump,
+                discover_ports,
+                mosh,
+                warn_if_busy,
+                auto_address,
+                login_shell: shell,
+                forward_agent,
+                keepalive,
+                wait_ssh,
+                extra,
+            };
+            open_ssh(opts, args.assume_yes).await?
+        }
+        Opt::Mosh {
+            warn_if_busy,
+            auto_address,
+            wait_ssh,
+        } => {
+            let opts = SshOptions {
+                mosh: true,
+                warn_if_busy,
+                auto_address,
+                wait_ssh,
+                ..Default::default()
+            };
+            open_ssh(opts, args.assume_yes).await?
+        }
+        Opt::Open { port, no_browser } => open_tunnel(port, no_browser).await?,
+        Opt::Close { port } => close_tunnel(port)?,
+        Opt::Exec {
+            all,
+            stream,
+            ports,
+            command,
+        } => {
+            let code = match all {
+                true => exec_all(&command, stream).await?,
+                false => exec_active(&command, &ports.unwrap_or_default()).await?,
+            };
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
         Opt::Upload {
-            local_file,
-            remote_file,
+            paths,
             recursive,
-        } => run_scp(&local_file, &remote_file, true, recursive).await?,
+            limit,
+            preserve,
+            wait_ssh,
+            compress,
+        } => {
+            let opts = CopyOptions {
+                recursive,
+                limit,
+                preserve,
+                wait_ssh,
+                compress,
+            };
+            let (remote_file, local_files) = split_destination(&paths)?;
+            run_scp(local_files, remote_file, true, &opts).await?
+        }
+        Opt::Sync {
+            local_dir,
+            remote_dir,
+            delete,
+            limit,
+            compress,
+        } => {
+            let opts = SyncOptions {
+                delete,
+                limit,
+                compress,
+            };
+            run_rsync(&local_dir, &remote_dir, &opts).await?
+        }
+        Opt::Sftp { dir, compress } => open_sftp(dir.as_deref(), compress).await?,
         Opt::Download {
-            remote_file,
-            local_file,
+            paths,
             recursive,
-        } => run_scp(&local_file, &remote_file, false, recursive).await?,
-        Opt::Status { all } => instance_status(all).await?,
-        Opt::Resize { instance_type } => instance_resize(&instance_type).await?,
-        Opt::Ls { cloud, profile } => match cloud {
-            Some(cloud) => instance_list_cloud(&cloud, &profile).await?,
-            None => instance_list()?,
+            limit,
+            preserve,
+            wait_ssh,
+            compress,
+        } => {
+            let opts = CopyOptions {
+                recursive,
+                limit,
+                preserve,
+                wait_ssh,
+                compress,
+            };
+            let (local_file, remote_files) = split_destination(&paths)?;
+            run_scp(remote_files, local_file, false, &opts).await?
+        }
+        Opt::Status {
+            all,
+            group,
+            filters,
+            compact,
+            fail_fast,
+            connection,
+            watch,
+            metrics,
+            cost,
+            region,
+        } => {
+            override_region(region)?;
+            let opts = StatusOptions {
+                filters,
+                compact,
+                refresh: REFRESH_DESCRIPTIONS.load(Ordering::Relaxed),
+                fail_fast,
+                connection,
+                metrics,
+                cost,
+                json: args.json,
+            };
+            match watch {
+                true => watch_status(all, group.as_deref(), opts).await?,
+                false => instance_status(all, group.as_deref(), &opts).await?,
+            }
+        }
+        Opt::Top { interval, metrics } => top(Duration::from_secs(interval), metrics).await?,
+        Opt::Metrics { metric, since } => instance_metrics(metric, since).await?,
+        Opt::Events { all } => instance_events(all).await?,
+        Opt::Logs { latest } => instance_logs(latest).await?,
+        Opt::Resize {
+            instance_type,
+            undo,
+            force,
+        } => match instance_type {
+            Some(instance_type) if !undo => {
+                instance_resize(
+                    &instance_type,
+                    force,
+                    args.json,
+                    args.assume_yes,
+                    args.dry_run,
+                )
+                .await?
+            }
+            _ => instance_resize_undo(force, args.json, args.assume_yes, args.dry_run).await?,
+        },
+        Opt::Restore {
+            image_id,
+            like,
+            instance_type,
+            key_name,
+            security_group,
+            subnet,
+            cloud,
+            profile,
+        } => {
+            instance_restore(RestoreOptions {
+                image_id,
+                like,
+                instance_type,
+                key_name,
+                security_groups: security_group,
+                subnet,
+                cloud,
+                profile,
+                region: None,
+                spot: None,
+            })
+            .await?
+        }
+        Opt::Launch {
+            image_id,
+            instance_type,
+            key_name,
+            security_group,
+            subnet,
+            cloud,
+            profile,
+            region,
+            spot,
+            max_price,
+            interruption_behavior,
+        } => {
+            let config = ProfileConfig::get_or_create()?;
+            let cloud = match cloud {
+                Some(cloud) => cloud,
+                None => config.default_cloud.clone().unwrap_or_default().to_string(),
+            };
+            instance_launch(RestoreOptions {
+                image_id: image_id.unwrap_or_default(),
+                like: None,
+                instance_type,
+                key_name,
+                security_groups: security_group,
+                subnet,
+                cloud,
+                profile: profile.unwrap_or_else(|| config.profile_or_default()),
+                region,
+                spot: match spot {
+                    true => Some(SpotSpec {
+                        max_price,
+                        interruption_behavior,
+                    }),
+                    false => None,
+                },
+            })
+            .await?
+        }
+        Opt::Disk { size_gb, grow } => instance_disk(size_gb, grow, args.assume_yes).await?,
+        Opt::Snapshot {
+            name,
+            ami,
+            no_reboot,
+            wait,
+        } => {
+            let opts = SnapshotOptions {
+                name,
+                ami,
+                no_reboot,
+                wait,
+            };
+            instance_snapshot(opts, args.assume_yes).await?
+        }
+        Opt::Ls {
+            cloud,
+            profile,
+            only_cloud,
+            table,
+            plain,
+            offline,
+            region,
+            mut filters,
+            running,
+            format,
+        } => match cloud {
+            Some(cloud) => {
+                let profile = match profile {
+                    Some(profile) => profile,
+                    None => ProfileConfig::get_or_create()?.profile_or_default(),
+                };
+                if running {
+                    filters.push(ListFilter {
+                        name: STATE_FILTER.to_string(),
+                        values: vec!["running".to_string()],
+                    });
+                }
+                let opts = ListOptions {
+                    refresh: args.refresh,
+                    table,
+                    offline,
+                    format: format.unwrap_or_default().or_json(args.json),
+                    region,
+                    filters,
+                };
+                instance_list_cloud(&cloud, &profile, &opts).await?
+            }
+            None => {
+                instance_list(
+                    only_cloud,
+                    plain,
+                    format.unwrap_or_default().or_json(args.json),
+                )
+                .await?
+            }
         },
+        Opt::Sg => security_groups().await?,
+        Opt::InstanceTypes {
+            filter,
+            family,
+            region,
+            profile,
+            cost,
+        } => {
+            instance_types(
+                filter.as_deref(),
+                family.as_deref(),
+                region.as_deref(),
+                profile,
+                cost,
+            )
+            .await?
+        }
+        Opt::Config(ConfigOpt::SetDefault { cloud, profile }) => set_defaults(cloud, profile)?,
+        Opt::Config(ConfigOpt::Path) => println!("{}", get_config_path()?.display()),
+        Opt::Config(ConfigOpt::Show) => show_config()?,
+        Opt::Config(ConfigOpt::Validate) => validate_config()?,
+        Opt::Config(ConfigOpt::Backup { path }) => {
+            backup_config(path)?;
+        }
+        Opt::Config(ConfigOpt::Restore { path }) => restore_config(&path, args.assume_yes)?,
+        Opt::Cost { by_tag, profile } => cost(by_tag, profile).await?,
+        Opt::CostReport { profile } => cost_report(profile).await?,
+        Opt::SshConfig { alias, all, write } => ssh_config(alias, all, write).await?,
+        Opt::Env { fish } => print_env(fish).await?,
+        Opt::Tag(opt) => update_tags(opt).await?,
+        Opt::Ports(opt) => update_forward_ports(opt)?,
+        Opt::Eip(opt) => update_elastic_ip(opt, args.assume_yes).await?,
+        Opt::Schedule(ScheduleOpt::Apply) => schedule_apply().await?,
+        Opt::Group(opt) => update_group(opt)?,
+        Opt::Completions { shell } => {
+            Args::clap().gen_completions_to("remote", shell, &mut std::io::stdout())
+        }
+        Opt::Key(KeyOpt::Rotate {
+            key_path,
+            no_install,
+        }) => rotate_key(&key_path, !no_install).await?,
+        Opt::CopyId { pubkey_path } => copy_id(pubkey_path.as_deref()).await?,
     };
     Ok(())
 }
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use remote::{ElasticIp, InstanceEvent, InstanceTypeSummary, SecurityGroup};
+
+    /// A manager for checking that dry runs leave the cloud alone, panicking on any call
+    struct UntouchedManager;
+
+    fn called<T>(method: &str) -> T {
+        panic!("dry run called {}", method)
+    }
+
+    #[async_trait]
+    impl InstanceManager for UntouchedManager {
+        async fn list_instances(&self) -> Result<Vec<Instance>> {
+            called("list_instances")
+        }
+        async fn get_instance(&self, _instance_id: &str) -> Result<Instance> {
+            called("get_instance")
+        }
+        async fn start_instance(&self, _instance_id: &str) -> Result<StateChange> {
+            called("start_instance")
+        }
+        async fn stop_instance(&self, _instance_id: &str) -> Result<StateChange> {
+            called("stop_instance")
+        }
+        async fn reboot_instance(&self, _instance_id: &str) -> Result<StateChange> {
+            called("reboot_instance")
+        }
+        async fn terminate_instance(&self, _instance_id: &str) -> Result<StateChange> {
+            called("terminate_instance")
+        }
+        async fn set_instance_type(&self, _instance_id: &str, _instance_type: &str) -> Result<()> {
+            called("set_instance_type")
+        }
+        async fn set_volume_size(&self, _instance_id: &str, _size_gb: i64) -> Result<()> {
+            called("set_volume_size")
+        }
+        async fn add_tags(&self, _instance_id: &str, _tags: &[InstanceTag]) -> Result<()> {
+            called("add_tags")
+        }
+        async fn remove_tags(&self, _instance_id: &str, _keys: &[String]) -> Result<()> {
+            called("remove_tags")
+        }
+        async fn get_instance_events(&self, _instance_id: &str) -> Result<Vec<InstanceEvent>> {
+            called("get_instance_events")
+        }
+        async fn get_console_output(&self, _instance_id: &str, _latest: bool) -> Result<String> {
+            called("get_console_output")
+        }
+        async fn get_cpu_utilization(
+            &self,
+            _instance_id: &str,
+            _minutes: u32,
+        ) -> Result<Option<f64>> {
+            called("get_cpu_utilization")
+        }
+        async fn create_image(
+            &self,
+            _instance_id: &str,
+            _name: &str,
+            _no_reboot: bool,
+            _tags: &[InstanceTag],
+        ) -> Result<String> {
+            called("create_image")
+        }
+        async fn get_image_state(&self, _image_id: &str) -> Result<String> {
+            called("get_image_state")
+        }
+        async fn create_snapshot(
+            &self,
+            _instance_id: &str,
+            _description: &str,
+            _tags: &[InstanceTag],
+        ) -> Result<String> {
+            called("create_snapshot")
+        }
+        async fn launch_instance(&self, _spec: LaunchSpec) -> Result<Instance> {
+            called("launch_instance")
+        }
+        async fn launch_spec_from(&self, _instance_id: &str) -> Result<LaunchSpec> {
+            called("launch_spec_from")
+        }
+        async fn list_instance_types(
+            &self,
+            _family: Option<&str>,
+        ) -> Result<Vec<InstanceTypeSummary>> {
+            called("list_instance_types")
+        }
+        async fn get_security_groups(&self, _instance_id: &str) -> Result<Vec<SecurityGroup>> {
+            called("get_security_groups")
+        }
+        async fn list_key_pairs(&self) -> Result<Vec<String>> {
+            called("list_key_pairs")
+        }
+        async fn associate_elastic_ip(
+            &self,
+            _instance_id: &str,
+            _allocation_id: Option<&str>,
+        ) -> Result<ElasticIp> {
+            called("associate_elastic_ip")
+        }
+        async fn release_elastic_ip(&self, _allocation_id: &str) -> Result<()> {
+            called("release_elastic_ip")
+        }
+    }
+
+    fn instance_config() -> InstanceConfig {
+        InstanceConfig {
+            alias: "dev".to_string(),
+            instance_id: "i-0123456789abcdef0".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn status(state: &str) -> Instance {
+        Instance {
+            instance_type: "t3.micro".to_string(),
+            instance_id: "i-0123456789abcdef0".to_string(),
+            public_dns: String::new(),
+            private_ip: String::new(),
+            tags: Vec::new(),
+            state: state.to_string(),
+            availability_zone: None,
+            launch_time: None,
+            lifecycle: None,
+            ipv6: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_start_and_stop_leave_the_cloud_alone() {
+        let instance = instance_config();
+        for action in [StateAction::Start, StateAction::Stop] {
+            change_state(
+                &UntouchedManager,
+                &instance,
+                action,
+                false,
+                true,
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_resize_leaves_the_cloud_alone() {
+        let instance = instance_config();
+        for state in ["stopped", "running", "stopping"] {
+            set_type_when_stopped(
+                &UntouchedManager,
+                &instance,
+                &status(state),
+                "t3.large",
+                true,
+                false,
+                true,
+            )
+            .await
+            .unwrap();
+        }
+    }
+}
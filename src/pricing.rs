@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{Error, Result};
+use rusoto_core::Region;
+use rusoto_pricing::{Filter, GetProductsRequest, Pricing, PricingClient};
+use serde_json::Value;
+
+use crate::cloud::{shared_http_client, ProfileCredentials};
+
+/// Hours in an average month, used to turn hourly prices into monthly estimates
+pub const HOURS_PER_MONTH: f64 = 730.0;
+
+/// Looks up on-demand Linux prices for instance types, remembering earlier answers
+pub struct PriceLookup {
+    client: PricingClient,
+    prices: Mutex<HashMap<(String, String), f64>>,
+}
+
+impl PriceLookup {
+    pub fn from_profile(profile: &str) -> Result<Self> {
+        // The pricing API is only served from a few regions, but covers every region
+        let client = PricingClient::new_with(
+            shared_http_client()?,
+            ProfileCredentials::new(profile),
+            Region::UsEast1,
+        );
+        Ok(Self {
+            client,
+            prices: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The on-demand hourly price in USD of `instance_type` in `region`
+    pub async fn hourly_price(&self, instance_type: &str, region: &str) -> Result<f64> {
+        let key = (instance_type.to_string(), region.to_string());
+        if let Some(price) = self.prices.lock().unwrap().get(&key) {
+            return Ok(*price);
+        }
+        let location = region_location(region)
+            .ok_or_else(|| Error::msg(format!("No pricing location known for {}", region)))?;
+        let term = |field: &str, value: &str| Filter {
+            field: field.to_string(),
+            type_: "TERM_MATCH".to_string(),
+            value: value.to_string(),
+        };
+        let req = GetProductsRequest {
+            service_code: Some("AmazonEC2".to_string()),
+            filters: Some(vec![
+                term("instanceType", instance_type),
+                term("location", location),
+                term("operatingSystem", "Linux"),
+                term("tenancy", "Shared"),
+                term("preInstalledSw", "NA"),
+                term("capacitystatus", "Used"),
+            ]),
+            ..Default::default()
+        };
+        let res = self.client.get_products(req).await?;
+        let price = res
+            .price_list
+            .unwrap_or_default()
+            .iter()
+            .find_map(|product| on_demand_price(product))
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "No on-demand price found for {} in {}",
+                    instance_type, region
+                ))
+            })?;
+        self.prices.lock().unwrap().insert(key, price);
+        Ok(price)
+    }
+}
+
+/// Pull the USD hourly price out of a pricing API product document
+fn on_demand_price(product: &str) -> Option<f64> {
+    let product: Value = serde_json::from_str(product).ok()?;
+    let terms = product.get("terms")?.get("OnDemand")?.as_object()?;
+    terms.values().find_map(|term| {
+        term.get("priceDimensions")?
+            .as_object()?
+            .values()
+            .find_map(|dimension| dimension.get("pricePerUnit")?.get("USD")?.as_str())?
+            .parse::<f64>()
+            .ok()
+    })
+}
+
+/// The location name the pricing API uses for a region code
+fn region_location(region: &str) -> Option<&'static str> {
+    let location = match region {
+        "us-east-1" => "US East (N. Virginia)",
+        "us-east-2" => "US East (Ohio)",
+        "us-west-1" => "US West (N. California)",
+        "us-west-2" => "US West (Oregon)",
+        "ca-central-1" => "Canada (Central)",
+        "eu-central-1" => "EU (Frankfurt)",
+        "eu-west-1" => "EU (Ireland)",
+        "eu-west-2" => "EU (London)",
+        "eu-west-3" => "EU (Paris)",
+        "eu-north-1" => "EU (Stockholm)",
+        "eu-south-1" => "EU (Milan)",
+        "ap-east-1" => "Asia Pacific (Hong Kong)",
+        "ap-south-1" => "Asia Pacific (Mumbai)",
+        "ap-northeast-1" => "Asia Pacific (Tokyo)",
+        "ap-northeast-2" => "Asia Pacific (Seoul)",
+        "ap-northeast-3" => "Asia Pacific (Osaka)",
+        "ap-southeast-1" => "Asia Pacific (Singapore)",
+        "ap-southeast-2" => "Asia Pacific (Sydney)",
+        "sa-east-1" => "South America (Sao Paulo)",
+        "me-south-1" => "Middle East (Bahrain)",
+        "af-south-1" => "Africa (Cape Town)",
+        _ => return None,
+    };
+    Some(location)
+}
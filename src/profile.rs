@@ -1,99 +1,878 @@
 use anyhow::{Error, Result};
 use dirs::home_dir;
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::string::ToString;
+use std::sync::OnceLock;
+
+use crate::error::RemoteError;
+use crate::schedule::{InstanceSchedule, ScheduleTimezone};
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct ProfileConfig {
     pub active: Option<String>,
     pub instances: Vec<InstanceConfig>,
+    /// Stop other running instances in the same cloud profile on start
+    #[serde(default)]
+    pub single_running: bool,
+    /// Cloud provider used when none is given
+    #[serde(default)]
+    pub default_cloud: Option<Cloud>,
+    /// Cloud profile used when none is given
+    #[serde(default)]
+    pub default_profile: Option<String>,
+    /// Named groups of instance aliases
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    /// Instance tag holding the SSH user for instances without one configured
+    #[serde(default)]
+    pub user_tag: Option<String>,
+    /// SSH user when neither the instance config nor its tags set one
+    #[serde(default)]
+    pub default_user: Option<String>,
+    /// Default seconds to wait for SSH to connect
+    #[serde(default)]
+    pub connect_timeout: Option<u32>,
+    /// Default seconds between SSH keepalive messages
+    #[serde(default)]
+    pub server_alive_interval: Option<u32>,
+    /// Default number of unanswered keepalives before SSH disconnects
+    #[serde(default)]
+    pub server_alive_count_max: Option<u32>,
+    /// Local command printing the remote sudo password, e.g. "pass show dev/sudo"
+    #[serde(default)]
+    pub sudo_password_command: Option<String>,
+    /// Time zone instance schedules are evaluated in
+    #[serde(default)]
+    pub schedule_timezone: ScheduleTimezone,
+    /// Reuse one ssh connection per instance across commands (ssh ControlMaster)
+    #[serde(default)]
+    pub multiplex: bool,
+    /// Seconds a running instance's address is reused when connecting, 60 if unset
+    #[serde(default)]
+    pub address_cache_ttl: Option<u64>,
+    /// Check the address resolves before connecting, looking the instance up again if not
+    #[serde(default)]
+    pub verify_dns: bool,
+    /// DigitalOcean API token, used when `DIGITALOCEAN_TOKEN` isn't set
+    #[serde(default)]
+    pub digitalocean_token: Option<String>,
+    /// Hetzner Cloud API token, used when `HCLOUD_TOKEN` isn't set
+    #[serde(default)]
+    pub hetzner_token: Option<String>,
+    /// The file the config was read from, which `update` writes back to
+    #[serde(skip)]
+    path: PathBuf,
 }
 
 impl ProfileConfig {
+    /// Load the config in use. A file that can't be read reports every problem found
+    /// in it, and problems in one that can are printed as warnings once per run.
     pub fn get_or_create() -> Result<Self> {
         let path = get_config_path()?;
+        let config = Self::load(&path).map_err(|e| {
+            let problems = Self::validate_file(&path);
+            match problems.is_empty() {
+                true => e,
+                false => Error::msg(format!(
+                    "Found {} problem(s) in {}:\n  {}",
+                    problems.len(),
+                    path.display(),
+                    problems.join("\n  ")
+                )),
+            }
+        })?;
+        if CONFIG_WARNED.set(()).is_ok() {
+            let problems = config.validate();
+            if !problems.is_empty() {
+                eprintln!("Warning: problems in {}:", path.display());
+                for problem in problems.iter() {
+                    eprintln!("  {}", problem);
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Read the config at `path`, creating an empty one if it doesn't exist
+    pub fn load(path: &PathBuf) -> Result<Self> {
         match path.exists() {
-            true => ProfileConfig::from_file(&path),
-            false => ProfileConfig::init(&path),
+            true => ProfileConfig::from_file(path),
+            false => ProfileConfig::init(path),
+        }
+    }
+
+    /// Check invariants serde can't, returning a description of each problem
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, inst) in self.instances.iter().enumerate() {
+            if self.instances[..i]
+                .iter()
+                .any(|other| other.alias == inst.alias)
+            {
+                problems.push(format!("{}: duplicate alias", inst.alias));
+            }
+            if inst.key_paths.iter().all(|path| path.is_empty()) {
+                problems.push(format!("{}: key_path is empty", inst.alias));
+            }
+            for key_path in inst.key_paths.iter().filter(|path| !path.is_empty()) {
+                if !expand_path(key_path).is_some_and(|path| path.exists()) {
+                    problems.push(format!(
+                        "{}: key file {} does not exist",
+                        inst.alias, key_path
+                    ));
+                }
+            }
+            if inst.cloud == Cloud::Azure && inst.resource_group.is_none() {
+                problems.push(format!(
+                    "{}: resource_group is required on Azure",
+                    inst.alias
+                ));
+            }
+            if inst.cloud == Cloud::Ssh && inst.address.is_none() {
+                problems.push(format!("{}: address is required on ssh hosts", inst.alias));
+            }
+        }
+        for (name, aliases) in self.groups.iter() {
+            for alias in aliases.iter() {
+                if self.find_instance(alias).is_err() {
+                    problems.push(format!(
+                        "{}: group member {} is not configured",
+                        name, alias
+                    ));
+                }
+            }
+        }
+        if let Some(active) = &self.active {
+            if !self.instances.iter().any(|inst| &inst.alias == active) {
+                problems.push(format!("{}: active instance is not configured", active));
+            }
+        }
+        problems
+    }
+
+    /// Validate a config file, also reporting unknown clouds and bad fields in
+    /// every instance that would otherwise fail deserialization at the first one
+    pub fn validate_file(path: &PathBuf) -> Vec<String> {
+        let raw = match std::fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(e) => return vec![format!("Could not read {}: {}", path.display(), e)],
+        };
+        let format = ConfigFormat::from_path(path);
+        // Checked as YAML either way, which JSON is already valid as
+        let parsed = match format {
+            ConfigFormat::Toml => toml::from_str::<toml::Value>(&raw)
+                .map_err(Error::from)
+                .and_then(|value| Ok(serde_yaml::to_value(value)?)),
+            ConfigFormat::Yaml | ConfigFormat::Json => {
+                serde_yaml::from_str(&raw).map_err(Error::from)
+            }
+        };
+        let value: serde_yaml::Value = match parsed {
+            Ok(value) => value,
+            Err(e) => return vec![format!("Invalid {}: {}", format.name(), e)],
+        };
+        let mut problems = Vec::new();
+        if let Some(instances) = value.get("instances").and_then(|v| v.as_sequence()) {
+            for (i, inst) in instances.iter().enumerate() {
+                let alias = match inst.get("alias").and_then(|v| v.as_str()) {
+                    Some(alias) => alias.to_string(),
+                    None => format!("instance {}", i + 1),
+                };
+                let cloud = inst.get("cloud").and_then(|v| v.as_str()).unwrap_or("");
+                let mut inst = inst.clone();
+                if let Err(e) = Cloud::from_str(cloud) {
+                    problems.push(format!("{}: {}", alias, e));
+                    // Keep checking its other fields as if the cloud were valid
+                    if let Some(fields) = inst.as_mapping_mut() {
+                        fields.insert("cloud".into(), Cloud::Aws.to_string().into());
+                    }
+                }
+                if let Err(e) = serde_yaml::from_value::<InstanceConfig>(inst) {
+                    problems.push(format!("{}: {}", alias, e));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            return problems;
+        }
+        match serde_yaml::from_value::<ProfileConfig>(value) {
+            Ok(config) => config.validate(),
+            Err(e) => vec![format!("Invalid config: {}", e)],
+        }
+    }
+
+    pub fn find_instance(&self, alias: &str) -> Result<&InstanceConfig> {
+        self.instances
+            .iter()
+            .find(|inst| inst.alias == alias)
+            .ok_or_else(|| RemoteError::InstanceNotFound(alias.to_string()).into())
+    }
+
+    /// The aliases in group `name`, failing if the group is missing or empty, or any of
+    /// its members is missing
+    pub fn group_aliases(&self, name: &str) -> Result<Vec<String>> {
+        let aliases = self
+            .groups
+            .get(name)
+            .ok_or_else(|| Error::msg(format!("No group named '{}' found", name)))?;
+        // An empty list would otherwise fall back to the active instance
+        if aliases.is_empty() {
+            return Err(Error::msg(format!("Group '{}' has no members", name)));
+        }
+        let dangling = aliases
+            .iter()
+            .filter(|alias| self.find_instance(alias).is_err())
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !dangling.is_empty() {
+            return Err(Error::msg(format!(
+                "Group '{}' has members that are not configured: {}",
+                name,
+                dangling.join(", ")
+            )));
+        }
+        Ok(aliases.clone())
+    }
+
+    /// Take `aliases` out of every group, removing the groups this leaves empty. Returns
+    /// the names of the removed groups.
+    pub fn remove_from_groups(&mut self, aliases: &[&str]) -> Vec<String> {
+        let mut removed = Vec::new();
+        self.groups.retain(|name, members| {
+            let before = members.len();
+            members.retain(|member| !aliases.contains(&member.as_str()));
+            let emptied = members.is_empty() && before > 0;
+            if emptied {
+                removed.push(name.clone());
+            }
+            !emptied
+        });
+        removed.sort();
+        removed
+    }
+
+    pub fn find_instance_mut(&mut self, alias: &str) -> Result<&mut InstanceConfig> {
+        self.instances
+            .iter_mut()
+            .find(|inst| inst.alias == alias)
+            .ok_or_else(|| RemoteError::InstanceNotFound(alias.to_string()).into())
+    }
+
+    pub fn user_tag_or_default(&self) -> &str {
+        self.user_tag.as_deref().unwrap_or("SSHUser")
+    }
+
+    /// SSH `-o` options for an instance, preferring its own settings over the global ones
+    pub fn ssh_options(&self, instance: &InstanceConfig) -> Result<Vec<String>> {
+        let connect_timeout = instance
+            .connect_timeout
+            .or(self.connect_timeout)
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+        let server_alive_interval = instance
+            .server_alive_interval
+            .or(self.server_alive_interval)
+            .unwrap_or(DEFAULT_SERVER_ALIVE_INTERVAL);
+        let mut options = vec![
+            format!("ConnectTimeout={}", connect_timeout),
+            format!("ServerAliveInterval={}", server_alive_interval),
+        ];
+        if let Some(count_max) = instance
+            .server_alive_count_max
+            .or(self.server_alive_count_max)
+        {
+            options.push(format!("ServerAliveCountMax={}", count_max));
+        }
+        if let Some(jump_host) = &instance.jump_host {
+            options.push(format!("ProxyJump={}", jump_host));
         }
+        if self.multiplex {
+            let control_path = get_config_dir()?.join("cm-%r@%h:%p");
+            options.push("ControlMaster=auto".to_string());
+            options.push(format!("ControlPath={}", control_path.display()));
+            options.push(format!("ControlPersist={}", CONTROL_PERSIST_SECS));
+        }
+        Ok(options)
+    }
+
+    pub fn profile_or_default(&self) -> String {
+        self.default_profile
+            .clone()
+            .unwrap_or_else(|| "default".to_string())
     }
 
     pub fn update(&self) -> Result<()> {
-        let path = get_config_path()?;
-        self.to_file(&path)
+        match self.path.as_os_str().is_empty() {
+            true => self.to_file(&get_config_path()?),
+            false => self.to_file(&self.path),
+        }
     }
 
-    pub fn init(path: &PathBuf) -> Result<Self> {
-        let config = Self::default();
+    pub fn init(path: &Path) -> Result<Self> {
+        let config = Self {
+            path: path.to_path_buf(),
+            ..Self::default()
+        };
         config.to_file(path)?;
         Ok(config)
     }
 
     pub fn from_file(path: &PathBuf) -> Result<Self> {
-        let file = ::std::fs::OpenOptions::new().read(true).open(path)?;
-        let config: ProfileConfig = serde_yaml::from_reader(&file)?;
+        let raw = ::std::fs::read_to_string(path)?;
+        let mut config: ProfileConfig = match ConfigFormat::from_path(path) {
+            ConfigFormat::Yaml => serde_yaml::from_str(&raw)?,
+            ConfigFormat::Toml => toml::from_str(&raw)?,
+            ConfigFormat::Json => serde_json::from_str(&raw)?,
+        };
+        config.path = path.clone();
         Ok(config)
     }
 
-    pub fn to_file(&self, path: &PathBuf) -> Result<()> {
-        let file = ::std::fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(path)?;
-        serde_yaml::to_writer(&file, &self)?;
-        Ok(())
+    pub fn to_file(&self, path: &Path) -> Result<()> {
+        let serialized = match ConfigFormat::from_path(path) {
+            ConfigFormat::Yaml => serde_yaml::to_string(&self)?,
+            // Going through a toml::Value writes plain values before tables, as TOML requires
+            ConfigFormat::Toml => toml::to_string_pretty(&toml::Value::try_from(self)?)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(&self)?,
+        };
+        write_atomic(path, serialized.as_bytes())
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Replace a file's contents by writing a temporary file next to it and renaming it over
+/// the original, so an interrupted write leaves the old file intact
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    // Write through a symlinked config rather than replacing the link
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::msg(format!("Not a file path: {}", path.display())))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.tmp-{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    let write = || -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&temp_path)?;
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &path)
+    };
+    write().map_err(|e| {
+        let _ = std::fs::remove_file(&temp_path);
+        Error::msg(format!(
+            "Could not save {}, it was left unchanged: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// The format of a config file, chosen by its extension and defaulting to YAML
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigFormat::Yaml => "YAML",
+            ConfigFormat::Toml => "TOML",
+            ConfigFormat::Json => "JSON",
+        }
+    }
+}
+
+/// Seconds to wait for SSH to connect unless configured otherwise
+pub const DEFAULT_CONNECT_TIMEOUT: u32 = 10;
+/// Seconds between SSH keepalive messages unless configured otherwise
+pub const DEFAULT_SERVER_ALIVE_INTERVAL: u32 = 60;
+/// Seconds a multiplexed ssh connection stays open after its last session
+const CONTROL_PERSIST_SECS: u32 = 60;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct InstanceConfig {
     pub alias: String,
+    /// The cloud's instance ID, or on AWS the value of the instance's Name tag
     pub instance_id: String,
-    pub key_path: String,
+    /// SSH keys ssh tries in order. Written as a single string when there is only one, as
+    /// configs from before keys could be listed have it
+    #[serde(
+        rename = "key_path",
+        alias = "key_paths",
+        deserialize_with = "deserialize_key_paths",
+        serialize_with = "serialize_key_paths"
+    )]
+    pub key_paths: Vec<String>,
     pub user: String,
     pub profile: String,
     pub cloud: Cloud,
+    /// GCP zone of the instance, looked up from its name when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    /// Azure resource group of the instance, required for Azure instances
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_group: Option<String>,
+    /// AWS region of the instance, defaulting to the environment or default profile's region
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// AWS API endpoint to use instead of the region's, e.g. a LocalStack URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// IAM role to assume with the profile's credentials on AWS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role_arn: Option<String>,
+    /// Fixed host name or IP of a `cloud: ssh` host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Allocation ID of the Elastic IP associated by `remote eip associate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elastic_ip_allocation_id: Option<String>,
+    /// The Elastic IP's address, used to connect instead of the public DNS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elastic_ip: Option<String>,
+    /// Host to connect through with ssh's ProxyJump, e.g. "user@bastion.example.com"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jump_host: Option<String>,
+    /// The port sshd listens on, when it isn't 22
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssh_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_alive_interval: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_alive_count_max: Option<u32>,
+    /// Compress upload, download and sync transfers by default
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compress: bool,
+    /// Forward the local SSH agent on `remote ssh`, e.g. to clone private repos
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forward_agent: bool,
+    /// Ports always forwarded by `remote ssh`, alongside any passed with `--ports`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forward_ports: Vec<u16>,
+    /// Instance types this instance was resized from, most recent last
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub type_history: Vec<String>,
+    /// Recurring times to act on the instance, applied by `remote daemon`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<InstanceSchedule>,
 }
 
-impl ToString for InstanceConfig {
-    fn to_string(&self) -> String {
-        format!(
+impl fmt::Display for InstanceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "Alias: {}\n\
              Instance ID: {}\n\
              Key Path: {}\n\
              User: {}\n\
-             Cloud: {:?}\n\
+             Cloud: {}\n\
              Profile: {}",
-            self.alias, self.instance_id, self.key_path, self.user, self.cloud, self.profile
+            self.alias,
+            self.instance_id,
+            self.key_paths.join(", "),
+            self.user,
+            self.cloud,
+            self.profile
         )
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyPaths {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn deserialize_key_paths<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    Ok(match KeyPaths::deserialize(deserializer)? {
+        KeyPaths::One(path) => vec![path],
+        KeyPaths::Many(paths) => paths,
+    })
+}
+
+fn serialize_key_paths<S: Serializer>(paths: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    match paths {
+        [path] => serializer.serialize_str(path),
+        paths => paths.serialize(serializer),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum Cloud {
+    #[default]
     Aws,
+    Gcp,
+    Azure,
+    DigitalOcean,
+    Hetzner,
+    /// A provider implemented by an external helper command, see `ExternalCloud`
+    External(String),
+    /// A plain host reached at a fixed `address`, with no cloud to manage it
+    Ssh,
+}
+
+/// Prefix of `Cloud::External` providers in the config, e.g. "external:remote-gcp"
+const EXTERNAL_PREFIX: &str = "external:";
+
+impl fmt::Display for Cloud {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cloud::Aws => write!(f, "aws"),
+            Cloud::Gcp => write!(f, "gcp"),
+            Cloud::Azure => write!(f, "azure"),
+            Cloud::DigitalOcean => write!(f, "digitalocean"),
+            Cloud::Hetzner => write!(f, "hetzner"),
+            Cloud::External(command) => write!(f, "external:{}", command),
+            Cloud::Ssh => write!(f, "ssh"),
+        }
+    }
 }
 
 impl FromStr for Cloud {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The helper command keeps its case, only the prefix is case insensitive
+        if s.len() > EXTERNAL_PREFIX.len()
+            && s.is_char_boundary(EXTERNAL_PREFIX.len())
+            && s[..EXTERNAL_PREFIX.len()].eq_ignore_ascii_case(EXTERNAL_PREFIX)
+        {
+            return Ok(Cloud::External(s[EXTERNAL_PREFIX.len()..].to_string()));
+        }
         match s.to_lowercase().as_str() {
             "aws" => Ok(Cloud::Aws),
-            _ => Err(Error::msg("Unsupported cloud provider")),
+            "gcp" | "gce" => Ok(Cloud::Gcp),
+            "azure" | "az" => Ok(Cloud::Azure),
+            "digitalocean" | "do" => Ok(Cloud::DigitalOcean),
+            "hetzner" | "hcloud" => Ok(Cloud::Hetzner),
+            "ssh" => Ok(Cloud::Ssh),
+            _ => Err(Error::msg(format!("Unsupported cloud provider '{}'", s))),
         }
     }
 }
 
-pub fn get_config_path() -> Result<PathBuf> {
+// Serialized through Display/FromStr so hand-edited configs can use any casing
+impl Serialize for Cloud {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cloud {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Cloud::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+pub fn get_config_dir() -> Result<PathBuf> {
     let path = home_dir()
         .ok_or_else(|| Error::msg("Could not find home directory"))?
         .join(".config/remote");
     if !path.exists() {
         ::std::fs::create_dir_all(&path)?;
     };
-    Ok(path.join("profiles.yaml"))
+    Ok(path)
+}
+
+/// Config fields holding secrets, masked by `remote config show`. A token or password
+/// field added for a cloud provider must be listed here.
+const SECRET_FIELDS: &[&str] = &["digitalocean_token", "hetzner_token"];
+
+/// Mask the values of `SECRET_FIELDS` in the text of a config file written in `format`,
+/// keeping everything else, including comments and layout, as written
+pub fn redact_config(contents: &str, format: ConfigFormat) -> String {
+    let (separator, written) = match format {
+        ConfigFormat::Yaml | ConfigFormat::Json => (':', ": "),
+        ConfigFormat::Toml => ('=', " = "),
+    };
+    let mut redacted = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let entry = line.trim_start().trim_start_matches("- ");
+        let secret = entry.split_once(separator).filter(|(key, value)| {
+            SECRET_FIELDS.contains(&key.trim().trim_matches(|c| c == '"' || c == '\''))
+                && !value.trim().is_empty()
+        });
+        match secret {
+            Some((key, value)) => {
+                let indent = &line[..line.len() - entry.len()];
+                // A JSON entry keeps the comma separating it from the next one
+                let comma = match format {
+                    ConfigFormat::Json if value.trim_end().ends_with(',') => ",",
+                    _ => "",
+                };
+                let newline = if line.ends_with('\n') { "\n" } else { "" };
+                redacted.push_str(&format!(
+                    "{}{}{}\"********\"{}{}",
+                    indent,
+                    key.trim_end(),
+                    written,
+                    comma,
+                    newline
+                ));
+            }
+            None => redacted.push_str(line),
+        }
+    }
+    redacted
+}
+
+/// Environment variable naming a config file to use instead of the default one
+const CONFIG_PATH_VAR: &str = "REMOTE_CONFIG";
+
+/// Config file given with `--config`, taking precedence over `REMOTE_CONFIG`
+static CONFIG_PATH_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set once problems in the loaded config have been warned about
+static CONFIG_WARNED: OnceLock<()> = OnceLock::new();
+
+/// Use `path` as the config file for the rest of the process
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH_OVERRIDE.set(path);
+}
+
+pub fn get_config_path() -> Result<PathBuf> {
+    if let Some(path) = CONFIG_PATH_OVERRIDE.get() {
+        return Ok(path.clone());
+    }
+    match std::env::var_os(CONFIG_PATH_VAR) {
+        Some(path) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Ok(get_config_dir()?.join("profiles.yaml")),
+    }
+}
+
+/// Expand a leading `~` to the user's home directory
+pub fn expand_tilde<P>(path_user_input: P) -> Option<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    let p = path_user_input.as_ref();
+    if !p.starts_with("~") {
+        return Some(p.to_path_buf());
+    }
+    if p == Path::new("~") {
+        return home_dir();
+    }
+    home_dir().map(|mut h| {
+        if h == Path::new("/") {
+            p.strip_prefix("~").unwrap().to_path_buf()
+        } else {
+            h.push(p.strip_prefix("~/").unwrap());
+            h
+        }
+    })
+}
+
+/// Expand `$VAR` and `${VAR}` references and then a leading `~`, the way a shell would for
+/// paths such as `$HOME/.ssh/id_ed25519`. Unset variables are left as written.
+pub fn expand_path<P>(path_user_input: P) -> Option<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    let p = path_user_input.as_ref();
+    match p.to_str() {
+        Some(raw) if raw.contains('$') => expand_tilde(expand_env_vars(raw)),
+        _ => expand_tilde(p),
+    }
+}
+
+fn expand_env_vars(raw: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = raw;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, reference_len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        match std::env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[start..start + 1 + reference_len]),
+        }
+        rest = &after[reference_len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_config() -> ProfileConfig {
+        let mut groups = HashMap::new();
+        groups.insert("web".to_string(), vec!["dev".to_string()]);
+        ProfileConfig {
+            active: Some("dev".to_string()),
+            instances: vec![
+                InstanceConfig {
+                    alias: "dev".to_string(),
+                    instance_id: "i-0123456789abcdef0".to_string(),
+                    key_paths: vec!["~/.ssh/dev.pem".to_string()],
+                    user: "ubuntu".to_string(),
+                    profile: "default".to_string(),
+                    cloud: Cloud::Aws,
+                    region: Some("us-west-2".to_string()),
+                    ssh_port: Some(2222),
+                    forward_ports: vec![8888],
+                    schedule: Some(InstanceSchedule {
+                        start: None,
+                        stop: Some("0 19 * * *".to_string()),
+                    }),
+                    ..Default::default()
+                },
+                InstanceConfig {
+                    alias: "helper".to_string(),
+                    instance_id: "box-1".to_string(),
+                    key_paths: vec!["~/.ssh/a".to_string(), "~/.ssh/b".to_string()],
+                    user: "root".to_string(),
+                    profile: "default".to_string(),
+                    cloud: Cloud::External("remote-helper".to_string()),
+                    ..Default::default()
+                },
+            ],
+            groups,
+            multiplex: true,
+            connect_timeout: Some(5),
+            ..Default::default()
+        }
+    }
+
+    /// A config as JSON, to compare configs that don't implement `PartialEq`
+    fn as_value(config: &ProfileConfig) -> serde_json::Value {
+        serde_json::to_value(config).unwrap()
+    }
+
+    #[test]
+    fn paths_expand_like_a_shell() {
+        let home = home_dir().unwrap();
+        assert_eq!(expand_path("~/x"), Some(home.join("x")));
+        assert_eq!(expand_path("~"), Some(home.clone()));
+        assert_eq!(
+            expand_path("/etc/ssh/key"),
+            Some(PathBuf::from("/etc/ssh/key"))
+        );
+
+        let env_home = PathBuf::from(std::env::var("HOME").unwrap());
+        assert_eq!(expand_path("$HOME/x"), Some(env_home.join("x")));
+        assert_eq!(expand_path("${HOME}/x"), Some(env_home.join("x")));
+    }
+
+    #[test]
+    fn unset_variables_are_left_as_written() {
+        let unset = "$REMOTE_TEST_UNSET/x ${REMOTE_TEST_UNSET} $ ${unclosed";
+        assert_eq!(expand_env_vars(unset), unset);
+    }
+
+    #[test]
+    fn failed_write_leaves_the_file_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profiles.yaml");
+        std::fs::write(&path, "active: dev\n").unwrap();
+        // A directory where the temporary file goes makes creating it fail
+        let temp_path = dir
+            .path()
+            .join(format!(".profiles.yaml.tmp-{}", std::process::id()));
+        std::fs::create_dir(&temp_path).unwrap();
+
+        let error = write_atomic(&path, b"active: other\n").unwrap_err();
+        assert!(error.to_string().contains("left unchanged"), "{}", error);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "active: dev\n");
+
+        std::fs::remove_dir(&temp_path).unwrap();
+        write_atomic(&path, b"active: other\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "active: other\n");
+    }
+
+    #[test]
+    fn config_round_trips_in_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = sample_config();
+        for name in ["profiles.yaml", "profiles.toml", "profiles.json"] {
+            let path = dir.path().join(name);
+            config.to_file(&path).unwrap();
+            let parsed = ProfileConfig::from_file(&path).unwrap();
+            assert_eq!(as_value(&parsed), as_value(&config), "{}", name);
+            assert_eq!(parsed.path, path);
+        }
+    }
+
+    #[test]
+    fn redact_config_masks_secrets_in_every_format() {
+        let cases = [
+            (
+                ConfigFormat::Yaml,
+                "# tokens\nhetzner_token: abc123\nactive: dev\n",
+                "# tokens\nhetzner_token: \"********\"\nactive: dev\n",
+            ),
+            (
+                ConfigFormat::Toml,
+                "# tokens\ndigitalocean_token = \"abc123\"\nactive = \"dev\"\n",
+                "# tokens\ndigitalocean_token = \"********\"\nactive = \"dev\"\n",
+            ),
+            (
+                ConfigFormat::Json,
+                "{\n  \"hetzner_token\": \"abc123\",\n  \"digitalocean_token\": \"xyz\"\n}",
+                "{\n  \"hetzner_token\": \"********\",\n  \"digitalocean_token\": \"********\"\n}",
+            ),
+        ];
+        for (format, contents, expected) in cases.iter() {
+            assert_eq!(
+                redact_config(contents, *format),
+                *expected,
+                "{}",
+                format.name()
+            );
+        }
+    }
+
+    #[test]
+    fn removed_instances_leave_their_groups() {
+        let mut config = sample_config();
+        config.groups.insert(
+            "all".to_string(),
+            vec!["dev".to_string(), "helper".to_string()],
+        );
+        let removed = config.remove_from_groups(&["dev"]);
+        assert_eq!(removed, ["web"]);
+        assert!(!config.groups.contains_key("web"));
+        assert_eq!(config.groups["all"], ["helper"]);
+    }
 }
@@ -0,0 +1,222 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Error, Result};
+use chrono::{DateTime, Local, Utc};
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+
+use crate::profile::get_config_dir;
+
+/// Recurring times to act on an instance, as cron expressions
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct InstanceSchedule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<String>,
+}
+
+impl InstanceSchedule {
+    pub fn is_empty(&self) -> bool {
+        self.start.is_none() && self.stop.is_none()
+    }
+
+    /// The expression for `action`, to change or clear it
+    pub fn expr_mut(&mut self, action: ScheduledAction) -> &mut Option<String> {
+        match action {
+            ScheduledAction::Start => &mut self.start,
+            ScheduledAction::Stop => &mut self.stop,
+        }
+    }
+
+    /// The action that fired within (after, until], or the one that fired last if both did
+    pub fn due_action(
+        &self,
+        timezone: ScheduleTimezone,
+        after: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Option<ScheduledAction>> {
+        let mut due = None;
+        for (action, expr) in [
+            (ScheduledAction::Start, &self.start),
+            (ScheduledAction::Stop, &self.stop),
+        ] {
+            let expr = match expr {
+                Some(expr) => expr,
+                None => continue,
+            };
+            let fired = match last_run(&parse_schedule(expr)?, timezone, until) {
+                Some(fired) if fired > after => fired,
+                _ => continue,
+            };
+            if due.is_none_or(|(_, other)| fired > other) {
+                due = Some((action, fired));
+            }
+        }
+        Ok(due.map(|(action, _)| action))
+    }
+}
+
+/// An action an instance can be scheduled for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduledAction {
+    Start,
+    Stop,
+}
+
+impl fmt::Display for ScheduledAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduledAction::Start => write!(f, "start"),
+            ScheduledAction::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+/// The time zone schedules are evaluated in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleTimezone {
+    #[default]
+    Local,
+    Utc,
+}
+
+/// Parse a cron expression. Standard 5 field expressions ("0 20 * * 1-5") are
+/// accepted along with the 6 and 7 field forms that include seconds and years.
+pub fn parse_schedule(expr: &str) -> Result<Schedule> {
+    let fields: Vec<&str> = expr.split_whitespace().collect();
+    let full = match fields.as_slice() {
+        [minute, hour, day, month, weekday] => format!(
+            "0 {} {} {} {} {}",
+            minute,
+            hour,
+            day,
+            month,
+            to_cron_weekdays(weekday)
+        ),
+        _ => expr.to_string(),
+    };
+    Schedule::from_str(&full).map_err(|e| Error::msg(format!("Invalid schedule '{}': {}", expr, e)))
+}
+
+/// Renumber standard cron weekdays (0 or 7 = Sunday) to the cron crate's (1 = Sunday).
+/// Numeric ranges are expanded into lists first, so "5-7" (Friday to Sunday) becomes
+/// "6,7,1" instead of the backwards range "6-1". Names and "*" are left to the cron crate.
+fn to_cron_weekdays(field: &str) -> String {
+    field
+        .split(',')
+        .map(to_cron_weekday_list)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Renumber one comma separated part of a weekday field, like "3", "1-5" or "1-7/2"
+fn to_cron_weekday_list(part: &str) -> String {
+    let (range, step) = match part.split_once('/') {
+        Some((range, step)) => match step.parse::<usize>() {
+            Ok(step) if step > 0 => (range, Some(step)),
+            _ => return part.to_string(),
+        },
+        None => (part, None),
+    };
+    let bounds = match range.split_once('-') {
+        Some((start, end)) => start.parse::<u32>().ok().zip(end.parse::<u32>().ok()),
+        // A single day with a step runs to the end of the week, like "1/2"
+        None => range
+            .parse::<u32>()
+            .ok()
+            .map(|day| (day, if step.is_some() { 7 } else { day })),
+    };
+    let (start, end) = match bounds {
+        Some((start, end)) if start <= end && end <= 7 => (start, end),
+        _ => return part.to_string(),
+    };
+    let mut days = Vec::new();
+    for day in (start..=end).step_by(step.unwrap_or(1)) {
+        let day = day % 7 + 1;
+        if !days.contains(&day) {
+            days.push(day);
+        }
+    }
+    days.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The last time the schedule fired before `until`
+pub fn last_run(
+    schedule: &Schedule,
+    timezone: ScheduleTimezone,
+    until: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    match timezone {
+        ScheduleTimezone::Local => schedule
+            .after(&until.with_timezone(&Local))
+            .next_back()
+            .map(|time| time.with_timezone(&Utc)),
+        ScheduleTimezone::Utc => schedule.after(&until).next_back(),
+    }
+}
+
+/// The next time the schedule fires, in local time for display
+pub fn next_run(schedule: &Schedule, timezone: ScheduleTimezone) -> Option<DateTime<Local>> {
+    let next = match timezone {
+        ScheduleTimezone::Local => schedule.upcoming(Local).next(),
+        ScheduleTimezone::Utc => schedule
+            .upcoming(Utc)
+            .next()
+            .map(|time| time.with_timezone(&Local)),
+    };
+    next
+}
+
+/// When `remote schedule apply` last ran, which the next run picks up from
+pub fn last_applied() -> Option<DateTime<Utc>> {
+    let raw = std::fs::read_to_string(get_applied_path().ok()?).ok()?;
+    DateTime::parse_from_rfc3339(raw.trim())
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+pub fn set_last_applied(time: DateTime<Utc>) -> Result<()> {
+    std::fs::write(get_applied_path()?, time.to_rfc3339())?;
+    Ok(())
+}
+
+fn get_applied_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join("schedule_applied"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn weekdays_are_renumbered() {
+        assert_eq!(to_cron_weekdays("0"), "1");
+        assert_eq!(to_cron_weekdays("7"), "1");
+        assert_eq!(to_cron_weekdays("0,6"), "1,7");
+        assert_eq!(to_cron_weekdays("1-5"), "2,3,4,5,6");
+        assert_eq!(to_cron_weekdays("1-5/2"), "2,4,6");
+        assert_eq!(to_cron_weekdays("*"), "*");
+        assert_eq!(to_cron_weekdays("*/2"), "*/2");
+        assert_eq!(to_cron_weekdays("MON-FRI"), "MON-FRI");
+    }
+
+    #[test]
+    fn weekday_range_ending_on_sunday() {
+        assert_eq!(to_cron_weekdays("5-7"), "6,7,1");
+        assert_eq!(to_cron_weekdays("0-7"), "1,2,3,4,5,6,7");
+        let schedule = parse_schedule("0 9 * * 5-7").unwrap();
+        // 2024-01-05 is a Friday
+        for (day, included) in [(4, false), (5, true), (6, true), (7, true), (8, false)] {
+            let time = Utc.with_ymd_and_hms(2024, 1, day, 9, 0, 0).unwrap();
+            assert_eq!(schedule.includes(time), included, "2024-01-{:02}", day);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+/// Separator printed between table columns
+const COLUMN_GAP: &str = "  ";
+/// Columns are never shrunk below this many characters
+const MIN_COLUMN_WIDTH: usize = 8;
+
+/// A plain-text table with a header row
+#[derive(Debug, Clone)]
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    /// Column of instance states, colored by state when color is enabled
+    state_column: Option<usize>,
+}
+
+impl Table {
+    pub fn new(headers: &[&str]) -> Self {
+        Table {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            rows: Vec::new(),
+            state_column: None,
+        }
+    }
+
+    /// Color the cells of `column` by instance state
+    pub fn set_state_column(&mut self, column: usize) {
+        self.state_column = Some(column);
+    }
+
+    pub fn push(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    /// Render the table, eliding the widest columns so each line fits in `max_width`
+    pub fn render(&self, max_width: Option<usize>) -> String {
+        let mut widths = self.natural_widths();
+        if let Some(max_width) = max_width {
+            fit_widths(&mut widths, max_width);
+        }
+        let state_column = self.state_column.filter(|_| color_enabled());
+        std::iter::once(format_row(&self.headers, &widths, None))
+            .chain(
+                self.rows
+                    .iter()
+                    .map(|row| format_row(row, &widths, state_column)),
+            )
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Render for stdout, fitting to the terminal width when attached to one
+    pub fn render_stdout(&self) -> String {
+        self.render(terminal_width())
+    }
+
+    fn natural_widths(&self) -> Vec<usize> {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.chars().count()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        widths
+    }
+}
+
+/// Whether to color output: stdout is a terminal and NO_COLOR isn't set
+pub fn color_enabled() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && terminal_width().is_some()
+}
+
+/// An instance state colored green when running, red when stopped and yellow in between
+pub fn color_state(state: &str) -> String {
+    match color_enabled() {
+        true => paint_state(state),
+        false => state.to_string(),
+    }
+}
+
+fn paint_state(state: &str) -> String {
+    let code = match state {
+        "running" => "32",
+        "stopped" | "terminated" => "31",
+        "" | "-" | "error" | "unknown" => return state.to_string(),
+        _ => "33",
+    };
+    format!("\x1b[{}m{}\x1b[0m", code, state)
+}
+
+/// The terminal width, or None if stdout isn't a terminal
+fn terminal_width() -> Option<usize> {
+    term_size::dimensions_stdout().map(|(width, _)| width)
+}
+
+fn fit_widths(widths: &mut [usize], max_width: usize) {
+    let gaps = COLUMN_GAP.len() * widths.len().saturating_sub(1);
+    while widths.iter().sum::<usize>() + gaps > max_width {
+        let widest = match widths.iter_mut().max() {
+            Some(widest) if *widest > MIN_COLUMN_WIDTH => widest,
+            _ => break,
+        };
+        *widest -= 1;
+    }
+}
+
+fn format_row(row: &[String], widths: &[usize], state_column: Option<usize>) -> String {
+    widths
+        .iter()
+        .enumerate()
+        .map(|(i, width)| {
+            let cell = elide(row.get(i).map(String::as_str).unwrap_or(""), *width);
+            // Pad outside the color codes so they don't count towards the width
+            let padding = " ".repeat(width.saturating_sub(cell.chars().count()));
+            match state_column == Some(i) {
+                true => format!("{}{}", paint_state(&cell), padding),
+                false => format!("{}{}", cell, padding),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(COLUMN_GAP)
+        .trim_end()
+        .to_string()
+}
+
+fn elide(cell: &str, width: usize) -> String {
+    if cell.chars().count() <= width {
+        return cell.to_string();
+    }
+    let mut elided: String = cell.chars().take(width.saturating_sub(1)).collect();
+    elided.push('…');
+    elided
+}